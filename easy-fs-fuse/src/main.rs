@@ -10,6 +10,9 @@ use clap::{Arg, App};
 
 /// Use a block size of 512 bytes
 const BLOCK_SZ: usize = 512;
+/// Blocks of the swap area after the file system, `SWAP_PAGES` pages in
+/// the kernel config
+const SWAP_BLOCKS: u64 = 1024 * 8;
 
 /// Wrapper for turning a File into a BlockDevice
 struct BlockFile(Mutex<File>);
@@ -60,7 +63,8 @@ fn easy_fs_pack() -> std::io::Result<()> {
             .write(true)
             .create(true)
             .open(format!("{}{}", target_path, "fs.img"))?;
-        f.set_len(14000 * 512).unwrap();
+        // the kernel keeps its swap area in the blocks after the file system
+        f.set_len((14000 + SWAP_BLOCKS) * 512).unwrap();
         f
     })));
     let efs = EasyFileSystem::create(
@@ -167,5 +171,20 @@ fn efs_test() -> std::io::Result<()> {
     random_str_test(1000 * BLOCK_SZ);
     random_str_test(2000 * BLOCK_SZ);
 
+    // direct I/O bypasses the block cache but must stay coherent with it
+    filea.clear();
+    let direct_data = [0x5au8; 4 * BLOCK_SZ];
+    assert_eq!(filea.write_at_direct(0, &direct_data), 4 * BLOCK_SZ);
+    let mut cached_buffer = [0u8; 4 * BLOCK_SZ];
+    assert_eq!(filea.read_at(0, &mut cached_buffer), 4 * BLOCK_SZ);
+    assert_eq!(cached_buffer, direct_data);
+    filea.write_at(BLOCK_SZ, greet_str.as_bytes());
+    let mut direct_buffer = [0u8; 4 * BLOCK_SZ];
+    assert_eq!(filea.read_at_direct(0, &mut direct_buffer), 4 * BLOCK_SZ);
+    assert_eq!(
+        &direct_buffer[BLOCK_SZ..BLOCK_SZ + greet_str.len()],
+        greet_str.as_bytes(),
+    );
+
     Ok(())
 }
@@ -3,9 +3,10 @@ use super::{
     BlockDevice,
 };
 use alloc::collections::VecDeque;
+use alloc::vec::Vec;
 use alloc::sync::Arc;
 use lazy_static::*;
-use spin::Mutex;
+use crate::lock::Mutex;
 
 /// Cached block inside memory
 pub struct BlockCache {
@@ -120,6 +121,24 @@ impl BlockCacheManager {
     }
 }
 
+impl BlockCacheManager {
+    /// Drop the cached copy of a block, which is written back first if dirty
+    pub fn invalidate(&mut self, block_id: usize) {
+        if let Some(idx) = self.queue
+            .iter()
+            .position(|pair| pair.0 == block_id) {
+            self.queue.remove(idx);
+        }
+    }
+    /// Cached copy of a block, if any
+    pub fn cached(&self, block_id: usize) -> Option<Arc<Mutex<BlockCache>>> {
+        self.queue
+            .iter()
+            .find(|pair| pair.0 == block_id)
+            .map(|pair| Arc::clone(&pair.1))
+    }
+}
+
 lazy_static! {
     /// The global block cache manager
     pub static ref BLOCK_CACHE_MANAGER: Mutex<BlockCacheManager> = Mutex::new(
@@ -136,9 +155,29 @@ pub fn get_block_cache(
 }
 
 /// Sync all block cache to block device
+///
+/// A block cache is locked before the manager while a block is read or
+/// written, so it is never locked with the manager held.
 pub fn block_cache_sync_all() {
-    let manager = BLOCK_CACHE_MANAGER.lock();
-    for (_, cache) in manager.queue.iter() {
+    let caches: Vec<_> = BLOCK_CACHE_MANAGER.lock()
+        .queue
+        .iter()
+        .map(|pair| Arc::clone(&pair.1))
+        .collect();
+    for cache in caches {
         cache.lock().sync();
     }
 }
+
+/// Sync the block cache of a single block to block device
+pub fn block_cache_sync(block_id: usize) {
+    let cache = BLOCK_CACHE_MANAGER.lock().cached(block_id);
+    if let Some(cache) = cache {
+        cache.lock().sync();
+    }
+}
+
+/// Drop the cached copy of a block so that it can be accessed on the block device directly
+pub fn block_cache_invalidate(block_id: usize) {
+    BLOCK_CACHE_MANAGER.lock().invalidate(block_id);
+}
@@ -1,5 +1,6 @@
 use alloc::sync::Arc;
-use spin::Mutex;
+use alloc::collections::BTreeMap;
+use crate::lock::Mutex;
 use super::{
     BlockDevice,
     Bitmap,
@@ -19,6 +20,29 @@ pub struct EasyFileSystem {
     pub data_bitmap: Bitmap,
     pub inode_area_start_block: u32,
     pub data_area_start_block: u32,
+    quotas: BTreeMap<u32, Quota>,
+}
+
+/// Disk usage limits and current usage of a user
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Quota {
+    /// Max number of blocks, 0 for no limit
+    pub block_limit: u32,
+    /// Max number of inodes, 0 for no limit
+    pub inode_limit: u32,
+    /// Number of blocks owned
+    pub blocks_used: u32,
+    /// Number of inodes owned
+    pub inodes_used: u32,
+}
+
+impl Quota {
+    /// Whether `blocks` more blocks and `inodes` more inodes fit in the limits
+    pub fn allows(&self, blocks: u32, inodes: u32) -> bool {
+        (self.block_limit == 0 || self.blocks_used + blocks <= self.block_limit)
+            && (self.inode_limit == 0 || self.inodes_used + inodes <= self.inode_limit)
+    }
 }
 
 /// A data block of block size
@@ -50,6 +74,7 @@ impl EasyFileSystem {
             data_bitmap,
             inode_area_start_block: 1 + inode_bitmap_blocks,
             data_area_start_block: 1 + inode_total_blocks + data_bitmap_blocks,
+            quotas: BTreeMap::new(),
         };
         // clear all blocks
         for i in 0..total_blocks {
@@ -110,6 +135,7 @@ impl EasyFileSystem {
                     ),
                     inode_area_start_block: 1 + super_block.inode_bitmap_blocks,
                     data_area_start_block: 1 + inode_total_blocks + super_block.data_bitmap_blocks,
+                    quotas: BTreeMap::new(),
                 };
                 Arc::new(Mutex::new(efs))
             })
@@ -142,6 +168,10 @@ impl EasyFileSystem {
     pub fn alloc_inode(&mut self) -> u32 {
         self.inode_bitmap.alloc(&self.block_device).unwrap() as u32
     }
+    /// Deallocate an inode
+    pub fn dealloc_inode(&mut self, inode_id: u32) {
+        self.inode_bitmap.dealloc(&self.block_device, inode_id as usize);
+    }
     /// Allocate a data block
     pub fn alloc_data(&mut self) -> u32 {
         self.data_bitmap.alloc(&self.block_device).unwrap() as u32 + self.data_area_start_block
@@ -161,4 +191,60 @@ impl EasyFileSystem {
             (block_id - self.data_area_start_block) as usize
         )
     }
+    /// Get the quota of a user
+    pub fn get_quota(&self, uid: u32) -> Quota {
+        self.quotas.get(&uid).copied().unwrap_or_else(|| self.usage_of(uid))
+    }
+    /// Set the limits of a user, counting what it already owns the first time
+    pub fn set_quota(&mut self, uid: u32, block_limit: u32, inode_limit: u32) {
+        if !self.quotas.contains_key(&uid) {
+            let usage = self.usage_of(uid);
+            self.quotas.insert(uid, usage);
+        }
+        let quota = self.quotas.get_mut(&uid).unwrap();
+        quota.block_limit = block_limit;
+        quota.inode_limit = inode_limit;
+    }
+    /// Charge blocks and inodes to a user, failing if that exceeds its quota
+    pub fn charge(&mut self, uid: u32, blocks: u32, inodes: u32) -> bool {
+        if let Some(quota) = self.quotas.get_mut(&uid) {
+            if !quota.allows(blocks, inodes) {
+                return false;
+            }
+            quota.blocks_used += blocks;
+            quota.inodes_used += inodes;
+        }
+        true
+    }
+    /// Give back blocks and inodes charged to a user
+    pub fn uncharge(&mut self, uid: u32, blocks: u32, inodes: u32) {
+        if let Some(quota) = self.quotas.get_mut(&uid) {
+            quota.blocks_used = quota.blocks_used.saturating_sub(blocks);
+            quota.inodes_used = quota.inodes_used.saturating_sub(inodes);
+        }
+    }
+    /// Count the inodes and file blocks owned by a user by scanning all inodes
+    fn usage_of(&self, uid: u32) -> Quota {
+        let mut usage = Quota::default();
+        for inode_id in 0..self.inode_bitmap.maximum() {
+            if !self.inode_bitmap.is_allocated(&self.block_device, inode_id) {
+                continue;
+            }
+            let (block_id, block_offset) = self.get_disk_inode_pos(inode_id as u32);
+            get_block_cache(
+                block_id as usize,
+                Arc::clone(&self.block_device)
+            )
+            .lock()
+            .read(block_offset, |disk_inode: &DiskInode| {
+                if disk_inode.owner == uid {
+                    usage.inodes_used += 1;
+                    if disk_inode.is_file() {
+                        usage.blocks_used += DiskInode::total_blocks(disk_inode.size);
+                    }
+                }
+            });
+        }
+        usage
+    }
 }
@@ -7,10 +7,14 @@ use super::{
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 
-/// Magic number for sanity check
-const EFS_MAGIC: u32 = 0x3b800001;
+/// Magic number for sanity check, whose low byte is the format version
+///
+/// Version 2 gave [`DiskInode`] an owner in place of its last direct block,
+/// so version 1 images are refused rather than misread; rebuild them with
+/// easy-fs-fuse.
+const EFS_MAGIC: u32 = 0x3b800002;
 /// The max number of direct inodes
-const INODE_DIRECT_COUNT: usize = 28;
+const INODE_DIRECT_COUNT: usize = 27;
 /// The max length of inode name
 const NAME_LENGTH_LIMIT: usize = 27;
 /// The max number of indirect1 inodes
@@ -73,6 +77,9 @@ impl SuperBlock {
     }
 }
 
+/// The inode may not be written, truncated, linked or unlinked
+pub const INODE_IMMUTABLE: u8 = 1 << 0;
+
 /// Type of a disk inode
 #[derive(PartialEq)]
 pub enum DiskInodeType {
@@ -92,7 +99,12 @@ pub struct DiskInode {
     pub direct: [u32; INODE_DIRECT_COUNT],
     pub indirect1: u32,
     pub indirect2: u32,
+    /// Uid of the user whose quota the inode and its data are charged to
+    pub owner: u32,
     type_: DiskInodeType,
+    /// Attribute flags such as [`INODE_IMMUTABLE`], kept in what used to
+    /// be padding so the layout does not change
+    pub flags: u8,
 }
 
 impl DiskInode {
@@ -103,7 +115,9 @@ impl DiskInode {
         self.direct.iter_mut().for_each(|v| *v = 0);
         self.indirect1 = 0;
         self.indirect2 = 0;
+        self.owner = 0;
         self.type_ = type_;
+        self.flags = 0;
     }
     /// Whether this inode is a directory
     pub fn is_dir(&self) -> bool {
@@ -9,12 +9,14 @@ mod efs;
 mod bitmap;
 mod vfs;
 mod block_cache;
+mod lock;
 
 /// Use a block size of 512 bytes
 pub const BLOCK_SZ: usize = 512;
 pub use block_dev::BlockDevice;
-pub use efs::EasyFileSystem;
+pub use efs::{EasyFileSystem, Quota};
 pub use vfs::Inode;
+pub use lock::{LockObserver, set_lock_observer};
 use layout::*;
 use bitmap::Bitmap;
-use block_cache::{get_block_cache, block_cache_sync_all};
+use block_cache::{get_block_cache, block_cache_sync, block_cache_sync_all, block_cache_invalidate};
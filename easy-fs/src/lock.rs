@@ -0,0 +1,70 @@
+use core::any::type_name;
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+use spin::Once;
+
+/// Trait for watching the locks of the filesystem,
+/// so that the kernel can check their order along with its own locks
+///
+/// Locks are told apart by class, the type of the mutex.
+pub trait LockObserver: Sync {
+    /// A lock of `class` was taken at `site`
+    fn acquired(&self, class: &'static str, site: &'static Location<'static>);
+    /// A lock of `class` was released
+    fn released(&self, class: &'static str);
+}
+
+static OBSERVER: Once<&'static dyn LockObserver> = Once::new();
+
+/// Tell `observer` about every lock taken from now on,
+/// only the first observer set is kept
+pub fn set_lock_observer(observer: &'static dyn LockObserver) {
+    OBSERVER.call_once(|| observer);
+}
+
+/// A spin mutex reporting to the lock observer
+pub struct Mutex<T> {
+    inner: spin::Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self { inner: spin::Mutex::new(value) }
+    }
+    #[track_caller]
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        let guard = self.inner.lock();
+        if let Some(observer) = OBSERVER.get() {
+            observer.acquired(type_name::<Self>(), Location::caller());
+        }
+        MutexGuard { guard: Some(guard) }
+    }
+}
+
+/// Access to the data of a locked [`Mutex`], unlocking it when dropped
+pub struct MutexGuard<'a, T> {
+    guard: Option<spin::MutexGuard<'a, T>>,
+}
+
+impl<'a, T> Deref for MutexGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<'a, T> DerefMut for MutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+impl<'a, T> Drop for MutexGuard<'a, T> {
+    fn drop(&mut self) {
+        // unlock before telling, like the kernel locks do
+        drop(self.guard.take());
+        if let Some(observer) = OBSERVER.get() {
+            observer.released(type_name::<Mutex<T>>());
+        }
+    }
+}
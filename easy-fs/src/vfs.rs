@@ -4,14 +4,18 @@ use super::{
     DiskInodeType,
     DirEntry,
     EasyFileSystem,
+    Quota,
     DIRENT_SZ,
+    INODE_IMMUTABLE,
     get_block_cache,
+    block_cache_sync,
     block_cache_sync_all,
+    block_cache_invalidate,
 };
 use alloc::sync::Arc;
 use alloc::string::String;
 use alloc::vec::Vec;
-use spin::{Mutex, MutexGuard};
+use crate::lock::{Mutex, MutexGuard};
 use crate::BLOCK_SZ;
 
 /// Virtual filesystem layer over easy-fs
@@ -56,6 +60,28 @@ impl Inode {
             0
         })
     }
+    /// Whether the inode is a regular file
+    pub fn is_file(&self) -> bool {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| disk_inode.is_file())
+    }
+    /// Whether the inode is immutable, see [`INODE_IMMUTABLE`]
+    pub fn is_immutable(&self) -> bool {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| disk_inode.flags & INODE_IMMUTABLE != 0)
+    }
+    /// Make the inode immutable or mutable again
+    pub fn set_immutable(&self, immutable: bool) {
+        let _fs = self.fs.lock();
+        self.modify_disk_inode(|disk_inode| {
+            if immutable {
+                disk_inode.flags |= INODE_IMMUTABLE;
+            } else {
+                disk_inode.flags &= !INODE_IMMUTABLE;
+            }
+        });
+        block_cache_sync_all();
+    }
     /// get block id
     pub fn get_block_id(&self) -> u32 {
         self.block_id as u32
@@ -121,7 +147,13 @@ impl Inode {
     pub fn unlink(&self, name: &str) -> isize {
         if let Some(inode) = self.find(name) {
             if self.get_nlink(inode.block_id as u32, inode.block_offset) == 1 {
+                // the last link goes, and the inode with it
                 inode.clear();
+                let ino = inode.get_ino();
+                let owner = inode.read_disk_inode(|disk_inode| disk_inode.owner);
+                let mut fs = self.fs.lock();
+                fs.uncharge(owner, 0, 1);
+                fs.dealloc_inode(ino);
             }
         }
         self.modify_disk_inode(|root_inode| {
@@ -207,25 +239,36 @@ impl Inode {
             })
         })
     }
-    /// Increase the size of a disk inode
+    /// Increase the size of a disk inode, failing if the blocks of a file
+    /// would exceed the quota of its owner
     fn increase_size(
         &self,
         new_size: u32,
         disk_inode: &mut DiskInode,
         fs: &mut MutexGuard<EasyFileSystem>,
-    ) {
+    ) -> bool {
         if new_size < disk_inode.size {
-            return;
+            return true;
         }
         let blocks_needed = disk_inode.blocks_num_needed(new_size);
+        if disk_inode.is_file() && !fs.charge(disk_inode.owner, blocks_needed, 0) {
+            return false;
+        }
         let mut v: Vec<u32> = Vec::new();
         for _ in 0..blocks_needed {
             v.push(fs.alloc_data());
         }
         disk_inode.increase_size(new_size, v, &self.block_device);
+        true
     }
     /// Create inode under current inode by name
     pub fn create(&self, name: &str) -> Option<Arc<Inode>> {
+        self.create_with_owner(name, 0)
+    }
+    /// Create inode owned by `owner` under current inode by name
+    ///
+    /// Returns `None` if the name exists or the owner is out of inode quota.
+    pub fn create_with_owner(&self, name: &str, owner: u32) -> Option<Arc<Inode>> {
         let mut fs = self.fs.lock();
         if self.modify_disk_inode(|root_inode| {
             // assert it is a directory
@@ -235,6 +278,9 @@ impl Inode {
         }).is_some() {
             return None;
         }
+        if !fs.charge(owner, 0, 1) {
+            return None;
+        }
         // create a new file
         // alloc a inode with an indirect block
         let new_inode_id = fs.alloc_inode();
@@ -246,6 +292,7 @@ impl Inode {
             Arc::clone(&self.block_device)
         ).lock().modify(new_inode_block_offset, |new_inode: &mut DiskInode| {
             new_inode.initialize(DiskInodeType::File);
+            new_inode.owner = owner;
         });
         self.modify_disk_inode(|root_inode| {
             // append file in the dirent
@@ -301,16 +348,105 @@ impl Inode {
             disk_inode.read_at(offset, buf, &self.block_device)
         })
     }
+    /// Load the data blocks backing a range of current inode into the block cache
+    pub fn read_ahead(&self, offset: usize, len: usize) {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| {
+            let end = (offset + len).min(disk_inode.size as usize);
+            if offset >= end {
+                return;
+            }
+            for inner_id in offset / BLOCK_SZ..(end + BLOCK_SZ - 1) / BLOCK_SZ {
+                let block_id = disk_inode.get_block_id(inner_id as u32, &self.block_device);
+                get_block_cache(block_id as usize, Arc::clone(&self.block_device));
+            }
+        })
+    }
     /// Write data to current inode
+    ///
+    /// Nothing is written if growing the file exceeds the quota of its owner.
     pub fn write_at(&self, offset: usize, buf: &[u8]) -> usize {
         let mut fs = self.fs.lock();
         let size = self.modify_disk_inode(|disk_inode| {
-            self.increase_size((offset + buf.len()) as u32, disk_inode, &mut fs);
+            if !self.increase_size((offset + buf.len()) as u32, disk_inode, &mut fs) {
+                return 0;
+            }
             disk_inode.write_at(offset, buf, &self.block_device)
         });
         block_cache_sync_all();
         size
     }
+    /// Read data from current inode straight from the block device
+    ///
+    /// `offset` and the length of `buf` must be multiples of `BLOCK_SZ`.
+    pub fn read_at_direct(&self, offset: usize, buf: &mut [u8]) -> usize {
+        assert!(offset % BLOCK_SZ == 0 && buf.len() % BLOCK_SZ == 0);
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| {
+            let end = (offset + buf.len()).min(disk_inode.size as usize);
+            let mut start = offset;
+            let mut read_size = 0usize;
+            while start < end {
+                let block_id = disk_inode.get_block_id(
+                    (start / BLOCK_SZ) as u32,
+                    &self.block_device,
+                ) as usize;
+                // write back a dirty cached copy before reading the device
+                block_cache_invalidate(block_id);
+                let block_read_size = (end - start).min(BLOCK_SZ);
+                let dst = &mut buf[read_size..read_size + BLOCK_SZ];
+                if block_read_size == BLOCK_SZ {
+                    self.block_device.read_block(block_id, dst);
+                } else {
+                    // the last block of the file is only partially valid
+                    let mut data_block = [0u8; BLOCK_SZ];
+                    self.block_device.read_block(block_id, &mut data_block);
+                    dst[..block_read_size].copy_from_slice(&data_block[..block_read_size]);
+                }
+                read_size += block_read_size;
+                start += block_read_size;
+            }
+            read_size
+        })
+    }
+    /// Write data to current inode straight to the block device
+    ///
+    /// `offset` and the length of `buf` must be multiples of `BLOCK_SZ`.
+    pub fn write_at_direct(&self, offset: usize, buf: &[u8]) -> usize {
+        assert!(offset % BLOCK_SZ == 0 && buf.len() % BLOCK_SZ == 0);
+        let mut fs = self.fs.lock();
+        let size = self.modify_disk_inode(|disk_inode| {
+            if !self.increase_size((offset + buf.len()) as u32, disk_inode, &mut fs) {
+                return 0;
+            }
+            for (i, data_block) in buf.chunks(BLOCK_SZ).enumerate() {
+                let block_id = disk_inode.get_block_id(
+                    (offset / BLOCK_SZ + i) as u32,
+                    &self.block_device,
+                ) as usize;
+                // a stale cached copy must not shadow the data written below
+                block_cache_invalidate(block_id);
+                self.block_device.write_block(block_id, data_block);
+            }
+            buf.len()
+        });
+        block_cache_sync_all();
+        size
+    }
+    /// Write back the cached data blocks backing a range of current inode
+    pub fn sync_range(&self, offset: usize, len: usize) {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| {
+            let end = offset.saturating_add(len).min(disk_inode.size as usize);
+            if offset >= end {
+                return;
+            }
+            for inner_id in offset / BLOCK_SZ..(end + BLOCK_SZ - 1) / BLOCK_SZ {
+                let block_id = disk_inode.get_block_id(inner_id as u32, &self.block_device);
+                block_cache_sync(block_id as usize);
+            }
+        })
+    }
     /// Clear the data in current inode
     pub fn clear(&self) {
         let mut fs = self.fs.lock();
@@ -318,10 +454,21 @@ impl Inode {
             let size = disk_inode.size;
             let data_blocks_dealloc = disk_inode.clear_size(&self.block_device);
             assert!(data_blocks_dealloc.len() == DiskInode::total_blocks(size) as usize);
+            if disk_inode.is_file() {
+                fs.uncharge(disk_inode.owner, data_blocks_dealloc.len() as u32, 0);
+            }
             for data_block in data_blocks_dealloc.into_iter() {
                 fs.dealloc_data(data_block);
             }
         });
         block_cache_sync_all();
     }
+    /// Get the quota of a user on the filesystem of current inode
+    pub fn get_quota(&self, uid: u32) -> Quota {
+        self.fs.lock().get_quota(uid)
+    }
+    /// Set the limits of a user on the filesystem of current inode
+    pub fn set_quota(&self, uid: u32, block_limit: u32, inode_limit: u32) {
+        self.fs.lock().set_quota(uid, block_limit, inode_limit);
+    }
 }
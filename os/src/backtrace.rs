@@ -0,0 +1,50 @@
+//! Kernel stack traces following the frame pointers
+//!
+//! The kernel is built with frame pointers, so every frame keeps the
+//! return address and the caller's frame pointer right below its own
+//! frame pointer. Traces are return addresses, to be looked up in the
+//! symbols of the kernel.
+
+use crate::config::{KERNEL_STACK_SIZE, PAGE_SIZE, PID_MAX, TRAMPOLINE};
+use core::mem::size_of;
+
+/// Whether `fp` may be a frame pointer on a kernel stack or the boot stack
+fn on_kernel_stack(fp: usize) -> bool {
+    extern "C" {
+        fn sbss_with_stack();
+        fn sbss();
+    }
+    let kernel_stacks = TRAMPOLINE - PID_MAX * (KERNEL_STACK_SIZE + PAGE_SIZE)..TRAMPOLINE;
+    let boot_stack = sbss_with_stack as usize + 2 * size_of::<usize>()..=sbss as usize;
+    fp % size_of::<usize>() == 0
+        && (kernel_stacks.contains(&fp) && fp - kernel_stacks.start >= 2 * size_of::<usize>()
+            || boot_stack.contains(&fp))
+}
+
+/// Frame pointer of the calling function
+#[inline(always)]
+pub fn frame_pointer() -> usize {
+    let fp: usize;
+    unsafe {
+        core::arch::asm!("mv {}, s0", out(reg) fp);
+    }
+    fp
+}
+
+/// Return addresses of the frames from `fp` on, 0 where the frame
+/// pointers ran out
+pub fn backtrace<const N: usize>(mut fp: usize) -> [usize; N] {
+    let mut trace = [0; N];
+    for ra in trace.iter_mut() {
+        if !on_kernel_stack(fp) {
+            break;
+        }
+        let prev;
+        unsafe {
+            *ra = *(fp as *const usize).sub(1);
+            prev = *(fp as *const usize).sub(2);
+        }
+        fp = prev;
+    }
+    trace
+}
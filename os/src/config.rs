@@ -1,17 +1,66 @@
 //! Constants used in rCore
 
+/// Size of the user stack mapped by exec
 pub const USER_STACK_SIZE: usize = 4096 * 2;
+/// Largest size the user stack may grow to on page faults
+pub const USER_STACK_MAX: usize = 0x10_0000;
 pub const KERNEL_STACK_SIZE: usize = 4096 * 2;
 pub const KERNEL_HEAP_SIZE: usize = 0x20_0000;
+/// First block of the swap area, right after the easy-fs image of
+/// easy-fs-fuse
+pub const SWAP_START_BLOCK: usize = 14000;
+/// Pages the swap area holds, appended to the image by easy-fs-fuse
+pub const SWAP_PAGES: usize = 1024;
+/// Free frames below which kswapd is woken, and a user page fault first
+/// swaps pages out
+pub const FRAMES_LOW: usize = 64;
+/// Free frames kswapd swaps out up to
+pub const FRAMES_HIGH: usize = 128;
 pub const MEMORY_END: usize = 0x88000000;
 pub const PAGE_SIZE: usize = 0x1000;
 pub const PAGE_SIZE_BITS: usize = 0xc;
 pub const MAX_SYSCALL_NUM: usize = 500;
+pub const TASK_COMM_LEN: usize = 16;
+/// PIDs are below this, and reused only after the allocator wraps around
+pub const PID_MAX: usize = 4096;
+/// Number of harts the kernel schedules tasks on
+pub const NUM_HARTS: usize = 1;
+/// Longest path, including the NUL, copied from user space
+pub const PATH_MAX: usize = 256;
+/// Longest argument or environment string, including the NUL
+pub const MAX_ARG_STRLEN: usize = 4096;
+/// Most bytes of argument and environment strings and their pointers
+/// passed to a program, leaving it the rest of the stack mapped by exec
+pub const ARG_MAX: usize = USER_STACK_SIZE / 2;
+
+/// End of the lower half of the Sv39 address space, below which the
+/// kernel places mappings of its own choosing
+pub const MMAP_TOP: usize = 1 << 38;
 
 pub const TRAMPOLINE: usize = usize::MAX - PAGE_SIZE + 1;
 pub const TRAP_CONTEXT: usize = TRAMPOLINE - PAGE_SIZE;
+pub const TIME_PAGE: usize = TRAP_CONTEXT - PAGE_SIZE;
 pub const CLOCK_FREQ: usize = 12500000;
+/// Timer interrupts per second at boot, adjustable with sys_sched_tune
+pub const TICKS_PER_SEC: usize = 100;
+/// Ticks a task runs before it is preempted at boot, adjustable with
+/// sys_sched_tune
+pub const TIME_SLICE_TICKS: usize = 1;
+/// Stride of a task of priority 1; strides grow by `BIG_STRIDE / priority`
+/// per time slice and wrap around
 pub const BIG_STRIDE: usize = 88888888;
+/// Scheduling policies, see task/scheduler.rs
+#[allow(unused)]
+#[derive(Copy, Clone, PartialEq)]
+pub enum SchedPolicy {
+    /// Stride scheduling by priority
+    Stride,
+    /// Multilevel feedback queue
+    Mlfq,
+    /// Smallest virtual runtime first, weighted by priority
+    Cfs,
+}
+pub const SCHED_POLICY: SchedPolicy = SchedPolicy::Stride;
 pub const MMIO: &[(usize, usize)] = &[
     (0x10001000, 0x1000),
 ];
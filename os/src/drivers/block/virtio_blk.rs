@@ -3,16 +3,15 @@ use virtio_drivers::{VirtIOBlk, VirtIOHeader};
 use crate::mm::{
     PhysAddr,
     VirtAddr,
-    frame_alloc,
-    frame_dealloc,
+    frame_alloc_contiguous,
     PhysPageNum,
-    FrameTracker,
+    FrameBlock,
     PageTable,
-    StepByOne,
     kernel_token,
 };
 use super::BlockDevice;
 use crate::sync::UPSafeCell;
+use crate::task::current_account_block_io;
 use alloc::vec::Vec;
 use lazy_static::*;
 
@@ -22,18 +21,20 @@ const VIRTIO0: usize = 0x10001000;
 pub struct VirtIOBlock(UPSafeCell<VirtIOBlk<'static>>);
 
 lazy_static! {
-    static ref QUEUE_FRAMES: UPSafeCell<Vec<FrameTracker>> = unsafe { 
+    static ref QUEUE_FRAMES: UPSafeCell<Vec<FrameBlock>> = unsafe { 
         UPSafeCell::new(Vec::new())
     };
 }
 
 impl BlockDevice for VirtIOBlock {
     fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+        current_account_block_io(false);
         self.0.exclusive_access()
         .read_block(block_id, buf)
         .expect("Error when reading VirtIOBlk");
     }
     fn write_block(&self, block_id: usize, buf: &[u8]) {
+        current_account_block_io(true);
         self.0.exclusive_access()
         .write_block(block_id, buf)
         .expect("Error when writing VirtIOBlk");
@@ -53,23 +54,17 @@ impl VirtIOBlock {
 
 #[no_mangle]
 pub extern "C" fn virtio_dma_alloc(pages: usize) -> PhysAddr {
-    let mut ppn_base = PhysPageNum(0);
-    for i in 0..pages {
-        let frame = frame_alloc().unwrap();
-        if i == 0 { ppn_base = frame.ppn; }
-        assert_eq!(frame.ppn.0, ppn_base.0 + i);
-        QUEUE_FRAMES.exclusive_access().push(frame);
-    }
+    let order = pages.next_power_of_two().trailing_zeros() as usize;
+    let block = frame_alloc_contiguous(order).unwrap();
+    let ppn_base = block.ppn;
+    QUEUE_FRAMES.exclusive_access().push(block);
     ppn_base.into()
 }
 
 #[no_mangle]
-pub extern "C" fn virtio_dma_dealloc(pa: PhysAddr, pages: usize) -> i32 {
-    let mut ppn_base: PhysPageNum = pa.into();
-    for _ in 0..pages {
-        frame_dealloc(ppn_base);
-        ppn_base.step();
-    }
+pub extern "C" fn virtio_dma_dealloc(pa: PhysAddr, _pages: usize) -> i32 {
+    let ppn_base: PhysPageNum = pa.into();
+    QUEUE_FRAMES.exclusive_access().retain(|block| block.ppn != ppn_base);
     0
 }
 
@@ -0,0 +1,91 @@
+//! Error injection for testing kernel error paths
+//!
+//! With the `fault-inject` feature, a rule set through `sys_fault_inject`
+//! makes the `nth` operation of a kind issued while a chosen process runs
+//! fail, only where the kernel can report the failure:
+//!
+//! - a frame allocation returns None, which fails the syscall or kills the
+//!   process on a page fault,
+//! - a heap allocation made through [`crate::mm::try_alloc`] fails with
+//!   -ENOMEM, the others having no way to fail but a panic,
+//! - a file read or write fails with -EIO before reaching the block
+//!   device, as easy-fs cannot report device errors.
+//!
+//! Without the feature nothing ever fails.
+
+use crate::sync::TicketLock;
+use crate::task::try_current_pid;
+
+/// What can be made to fail
+#[derive(Copy, Clone, PartialEq)]
+pub enum FaultKind {
+    FrameAlloc = 0,
+    /// Heap allocations through [`crate::mm::try_alloc`]
+    HeapAlloc = 1,
+    /// File reads and writes
+    BlockIo = 2,
+}
+
+impl FaultKind {
+    #[allow(unused)]
+    pub fn from_usize(kind: usize) -> Option<Self> {
+        match kind {
+            0 => Some(Self::FrameAlloc),
+            1 => Some(Self::HeapAlloc),
+            2 => Some(Self::BlockIo),
+            _ => None,
+        }
+    }
+}
+
+/// Fail the `nth` operation of a kind issued by process `pid`
+#[derive(Copy, Clone)]
+struct FaultRule {
+    pid: usize,
+    nth: usize,
+    /// Operations seen so far
+    count: usize,
+}
+
+/// Rules indexed by [`FaultKind`]
+static FAULT_RULES: TicketLock<[Option<FaultRule>; 3]> = TicketLock::new([None; 3]);
+
+/// Make the `nth` (counting from 1) operation of `kind` by process `pid`
+/// fail once, or clear the rule of `kind` if `nth` is 0
+#[allow(unused)]
+pub fn set_fault_rule(kind: FaultKind, pid: usize, nth: usize) {
+    FAULT_RULES.lock()[kind as usize] = if nth == 0 {
+        None
+    } else {
+        Some(FaultRule { pid, nth, count: 0 })
+    };
+}
+
+/// Count an operation of `kind`, returning whether it has to fail
+///
+/// Called from the allocators, so it gives up rather than spinning or
+/// panicking when the rules or the current task are held.
+pub fn should_fail(kind: FaultKind) -> bool {
+    if !cfg!(feature = "fault-inject") {
+        return false;
+    }
+    let pid = match try_current_pid() {
+        Some(pid) => pid,
+        None => return false,
+    };
+    let mut rules = match FAULT_RULES.try_lock() {
+        Some(rules) => rules,
+        None => return false,
+    };
+    let slot = &mut rules[kind as usize];
+    if let Some(rule) = slot {
+        if rule.pid == pid {
+            rule.count += 1;
+            if rule.count == rule.nth {
+                *slot = None;
+                return true;
+            }
+        }
+    }
+    false
+}
@@ -1,29 +1,72 @@
 use easy_fs::{
     EasyFileSystem,
     Inode,
+    Quota,
+    BLOCK_SZ,
 };
 use crate::drivers::BLOCK_DEVICE;
-use crate::sync::UPSafeCell;
+use crate::sync::{PreemptGuard, UPSafeCell};
+use alloc::collections::BTreeMap;
 use alloc::sync::Arc;
 use lazy_static::*;
 use bitflags::*;
-use alloc::vec::Vec;
 use crate::fs::{StatMode};
 use super::File;
-use crate::mm::UserBuffer;
+use crate::mm::{PageSource, UserBuffer};
+use crate::mm::{get_user, put_user};
+use crate::fault_inject::{should_fail, FaultKind};
+use crate::syscall::errno::{EACCES, EDQUOT, EINVAL, EIO, ENOENT, EPERM, ETXTBSY};
+use crate::task::{current_uid, current_user_token};
 
 /// A wrapper around a filesystem inode
 /// to implement File trait atop
 pub struct OSInode {
     readable: bool,
     writable: bool,
+    /// Whether block-aligned I/O bypasses the block cache
+    direct: bool,
+    /// Inode number of a program file opened by [`open_exec`], which keeps
+    /// the file from being changed until it is dropped
+    text_ino: Option<u32>,
     inner: UPSafeCell<OSInodeInner>,
 }
 
+/// ioctl reading the attribute flags of a file
+const FS_IOC_GETFLAGS: usize = 0x80086601;
+/// ioctl setting the attribute flags of a file
+const FS_IOC_SETFLAGS: usize = 0x40086602;
+/// Attribute flag of immutable files
+const FS_IMMUTABLE_FL: i32 = 0x10;
+
+/// Number of blocks to read ahead once sequential access is detected
+const READ_AHEAD_BLOCKS: usize = 8;
+
 /// The OS inode inner in 'UPSafeCell'
 pub struct OSInodeInner {
     offset: usize,
     inode: Arc<Inode>,
+    /// Where the last read ended, used to detect sequential access
+    last_read_end: usize,
+    /// Blocks before this offset have already been read ahead
+    read_ahead_end: usize,
+}
+
+impl OSInodeInner {
+    /// Read from current offset, and read ahead the following blocks
+    /// if the file is being read sequentially
+    ///
+    /// Block I/O is synchronous, so blocks are read ahead right away.
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        let sequential = self.offset == self.last_read_end;
+        let read_size = self.inode.read_at(self.offset, buf);
+        self.offset += read_size;
+        self.last_read_end = self.offset;
+        if sequential && read_size > 0 && self.offset >= self.read_ahead_end {
+            self.inode.read_ahead(self.offset, READ_AHEAD_BLOCKS * BLOCK_SZ);
+            self.read_ahead_end = self.offset + READ_AHEAD_BLOCKS * BLOCK_SZ;
+        }
+        read_size
+    }
 }
 
 impl OSInode {
@@ -31,48 +74,84 @@ impl OSInode {
     pub fn new(
         readable: bool,
         writable: bool,
+        direct: bool,
         inode: Arc<Inode>,
     ) -> Self {
         Self {
             readable,
             writable,
+            direct,
+            text_ino: None,
             inner: unsafe { UPSafeCell::new(OSInodeInner {
                 offset: 0,
                 inode,
+                last_read_end: 0,
+                read_ahead_end: 0,
             })},
         }
     }
-    /// Read all data inside a inode into vector
-    pub fn read_all(&self) -> Vec<u8> {
+    /// Write all of `data` from the current offset, returning how much
+    /// was written
+    pub fn write_all(&self, data: &[u8]) -> usize {
         let mut inner = self.inner.exclusive_access();
-        let mut buffer = [0u8; 512];
-        let mut v: Vec<u8> = Vec::new();
-        loop {
-            let len = inner.inode.read_at(inner.offset, &mut buffer);
-            if len == 0 {
-                break;
+        if inner.inode.is_immutable() || text_busy(inner.inode.get_ino()) {
+            return 0;
+        }
+        let write_size = inner.inode.write_at(inner.offset, data);
+        inner.offset += write_size;
+        write_size
+    }
+}
+
+/// Programs are paged in from their files, see [`MemorySet::from_elf`]
+///
+/// [`MemorySet::from_elf`]: crate::mm::MemorySet::from_elf
+impl PageSource for OSInode {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+        self.inner.exclusive_access().inode.read_at(offset, buf)
+    }
+}
+
+impl Drop for OSInode {
+    fn drop(&mut self) {
+        if let Some(ino) = self.text_ino {
+            let mut text_maps = TEXT_MAPS.exclusive_access();
+            let maps = text_maps.get_mut(&ino).unwrap();
+            *maps -= 1;
+            if *maps == 0 {
+                text_maps.remove(&ino);
             }
-            inner.offset += len;
-            v.extend_from_slice(&buffer[..len]);
         }
-        v
     }
 }
 
 lazy_static! {
     /// The root of all inodes, or '/' in short
+    ///
+    /// easy-fs takes spin locks, so its users hold a [`PreemptGuard`].
     pub static ref ROOT_INODE: Arc<Inode> = {
         let efs = EasyFileSystem::open(BLOCK_DEVICE.clone());
         Arc::new(EasyFileSystem::root_inode(&efs))
     };
+    /// How many program files opened by [`open_exec`] are alive, by inode
+    /// number; they live as long as the address spaces paged in from them
+    static ref TEXT_MAPS: UPSafeCell<BTreeMap<u32, usize>> =
+        unsafe { UPSafeCell::new(BTreeMap::new()) };
+}
+
+/// Whether a running program is paged in from the file `ino`
+fn text_busy(ino: u32) -> bool {
+    TEXT_MAPS.exclusive_access().contains_key(&ino)
 }
 
 pub fn get_nlink(target_block_id: u32, target_block_offset: usize) -> u32 {
+    let _preempt = PreemptGuard::new();
     ROOT_INODE.get_nlink(target_block_id, target_block_offset)
 }
 
 /// List all files in the filesystems
 pub fn list_apps() {
+    let _preempt = PreemptGuard::new();
     println!("/**** APPS ****");
     for app in ROOT_INODE.ls() {
         println!("{}", app);
@@ -88,6 +167,9 @@ bitflags! {
         const RDWR = 1 << 1;
         const CREATE = 1 << 9;
         const TRUNC = 1 << 10;
+        /// Bypass the block cache for block-aligned reads and writes,
+        /// other reads and writes still go through the block cache
+        const DIRECT = 1 << 14;
     }
 }
 
@@ -96,7 +178,7 @@ impl OpenFlags {
     /// does not check validity for simplicity
     /// returns (readable, writable)
     pub fn read_write(&self) -> (bool, bool) {
-        if self.is_empty() {
+        if (*self - Self::DIRECT).is_empty() {
             (true, false)
         } else if self.contains(Self::WRONLY) {
             (false, true)
@@ -108,7 +190,9 @@ impl OpenFlags {
 
 /// Open a file by path
 pub fn open_file(name: &str, flags: OpenFlags) -> Option<Arc<OSInode>> {
+    let _preempt = PreemptGuard::new();
     let (readable, writable) = flags.read_write();
+    let direct = flags.contains(OpenFlags::DIRECT);
     if flags.contains(OpenFlags::CREATE) {
         if let Some(inode) = ROOT_INODE.find(name) {
             // clear size
@@ -116,15 +200,17 @@ pub fn open_file(name: &str, flags: OpenFlags) -> Option<Arc<OSInode>> {
             Some(Arc::new(OSInode::new(
                 readable,
                 writable,
+                direct,
                 inode,
             )))
         } else {
             // create file
-            ROOT_INODE.create(name)
+            ROOT_INODE.create_with_owner(name, current_uid())
                 .map(|inode| {
                     Arc::new(OSInode::new(
                         readable,
                         writable,
+                        direct,
                         inode,
                     ))
                 })
@@ -138,13 +224,74 @@ pub fn open_file(name: &str, flags: OpenFlags) -> Option<Arc<OSInode>> {
                 Arc::new(OSInode::new(
                     readable,
                     writable,
+                    direct,
                     inode
                 ))
             })
     }
 }
 
+/// Open a program file to load with [`MemorySet::from_elf`]
+///
+/// Its pages are read on demand, so until the returned file is dropped
+/// the file may not be written, truncated or unlinked.
+///
+/// [`MemorySet::from_elf`]: crate::mm::MemorySet::from_elf
+pub fn open_exec(name: &str) -> Option<Arc<OSInode>> {
+    let _preempt = PreemptGuard::new();
+    let inode = ROOT_INODE.find(name)?;
+    let ino = inode.get_ino();
+    *TEXT_MAPS.exclusive_access().entry(ino).or_insert(0) += 1;
+    let mut file = OSInode::new(true, false, false, inode);
+    file.text_ino = Some(ino);
+    Some(Arc::new(file))
+}
+
+/// Whether the file exists and a running program is paged in from it, in
+/// which case it may not be opened for writing, truncated or unlinked
+pub fn file_text_busy(name: &str) -> bool {
+    let _preempt = PreemptGuard::new();
+    ROOT_INODE.find(name).map_or(false, |inode| text_busy(inode.get_ino()))
+}
+
+bitflags! {
+    /// Modes for checking the accessibility of a file
+    pub struct AccessMode: u32 {
+        const F_OK = 0;
+        const X_OK = 1 << 0;
+        const W_OK = 1 << 1;
+        const R_OK = 1 << 2;
+    }
+}
+
+/// Check whether a file can be accessed with the given mode
+///
+/// easy-fs keeps no permission bits, so every existing file is
+/// readable and writable; only regular files are executable.
+pub fn access_file(name: &str, mode: AccessMode) -> isize {
+    let _preempt = PreemptGuard::new();
+    if let Some(inode) = ROOT_INODE.find(name) {
+        if mode.contains(AccessMode::X_OK) && !inode.is_file() {
+            return -EACCES;
+        }
+        0
+    } else {
+        -ENOENT
+    }
+}
+
+/// Whether the file exists and is immutable, in which case it may not be
+/// opened for writing, truncated, linked or unlinked
+pub fn file_immutable(name: &str) -> bool {
+    let _preempt = PreemptGuard::new();
+    ROOT_INODE.find(name).map_or(false, |inode| inode.is_immutable())
+}
+
 pub fn link_file(old_name: &str, new_name: &str) -> isize {
+    let _preempt = PreemptGuard::new();
+    if file_immutable(old_name) {
+        return -EPERM;
+    }
     if let Some(mut old_inode) = ROOT_INODE.find(old_name) {
         let old_ino = old_inode.get_ino() as u32;
         return ROOT_INODE.link(old_ino, new_name);
@@ -153,9 +300,28 @@ pub fn link_file(old_name: &str, new_name: &str) -> isize {
 }
 
 pub fn unlink_file(_name: &str) -> isize {
+    let _preempt = PreemptGuard::new();
+    if file_immutable(_name) {
+        return -EPERM;
+    }
+    if file_text_busy(_name) {
+        return -ETXTBSY;
+    }
     ROOT_INODE.unlink(_name)
 }
 
+/// Get the disk quota and usage of a user
+pub fn get_quota(uid: u32) -> Quota {
+    let _preempt = PreemptGuard::new();
+    ROOT_INODE.get_quota(uid)
+}
+
+/// Limit the blocks and inodes a user may own, 0 meaning no limit
+pub fn set_quota(uid: u32, block_limit: u32, inode_limit: u32) {
+    let _preempt = PreemptGuard::new();
+    ROOT_INODE.set_quota(uid, block_limit, inode_limit);
+}
+
 impl File for OSInode {
     fn readable(&self) -> bool { self.readable }
     fn writable(&self) -> bool { self.writable }
@@ -187,28 +353,100 @@ impl File for OSInode {
         let inner = self.inner.exclusive_access();
         inner.inode.get_block_offset()
     }
-    fn read(&self, mut buf: UserBuffer) -> usize {
-        let mut inner = self.inner.exclusive_access();
+    fn read(&self, mut buf: UserBuffer) -> isize {
+        // easy-fs cannot report a device error, so injected ones fail the
+        // whole read before it gets there
+        if should_fail(FaultKind::BlockIo) {
+            return -EIO;
+        }
         let mut total_read_size = 0usize;
         for slice in buf.buffers.iter_mut() {
-            let read_size = inner.inode.read_at(inner.offset, *slice);
+            // borrowed a page at a time, so a long read may be preempted
+            // between pages
+            let mut inner = self.inner.exclusive_access();
+            let read_size = if self.direct && inner.offset % BLOCK_SZ == 0 && slice.len() % BLOCK_SZ == 0 {
+                let read_size = inner.inode.read_at_direct(inner.offset, *slice);
+                inner.offset += read_size;
+                read_size
+            } else {
+                inner.read(*slice)
+            };
             if read_size == 0 {
                 break;
             }
-            inner.offset += read_size;
             total_read_size += read_size;
         }
-        total_read_size
+        total_read_size as isize
     }
-    fn write(&self, buf: UserBuffer) -> usize {
-        let mut inner = self.inner.exclusive_access();
+    fn write(&self, buf: UserBuffer) -> isize {
+        let inner = self.inner.exclusive_access();
+        // the file may have been made immutable after it was opened
+        if inner.inode.is_immutable() {
+            return -EPERM;
+        }
+        // or a program may have been started from it since
+        if text_busy(inner.inode.get_ino()) {
+            return -ETXTBSY;
+        }
+        drop(inner);
+        if should_fail(FaultKind::BlockIo) {
+            return -EIO;
+        }
         let mut total_write_size = 0usize;
         for slice in buf.buffers.iter() {
-            let write_size = inner.inode.write_at(inner.offset, *slice);
-            assert_eq!(write_size, slice.len());
+            // borrowed a page at a time, so a long write may be preempted
+            // between pages
+            let mut inner = self.inner.exclusive_access();
+            let write_size = if self.direct && inner.offset % BLOCK_SZ == 0 && slice.len() % BLOCK_SZ == 0 {
+                inner.inode.write_at_direct(inner.offset, *slice)
+            } else {
+                inner.inode.write_at(inner.offset, *slice)
+            };
+            // easy-fs only writes nothing when the owner runs out of quota
+            if write_size < slice.len() {
+                if total_write_size == 0 {
+                    return -EDQUOT;
+                }
+                break;
+            }
             inner.offset += write_size;
             total_write_size += write_size;
         }
-        total_write_size
+        total_write_size as isize
+    }
+    fn sync_range(&self, offset: usize, len: usize) -> isize {
+        let inner = self.inner.exclusive_access();
+        inner.inode.sync_range(offset, len);
+        0
+    }
+    /// Read or set the attribute flags, of which only `FS_IMMUTABLE_FL`
+    /// is supported; only root may set them
+    fn ioctl(&self, cmd: usize, arg: usize) -> isize {
+        let inner = self.inner.exclusive_access();
+        let token = current_user_token();
+        match cmd {
+            FS_IOC_GETFLAGS => {
+                let flags = if inner.inode.is_immutable() { FS_IMMUTABLE_FL } else { 0 };
+                match put_user(token, arg as *mut i32, flags) {
+                    Ok(()) => 0,
+                    Err(err) => err,
+                }
+            }
+            FS_IOC_SETFLAGS => {
+                let flags = match get_user(token, arg as *const i32) {
+                    Ok(flags) => flags,
+                    Err(err) => return err,
+                };
+                if flags & !FS_IMMUTABLE_FL != 0 {
+                    return -EINVAL;
+                }
+                if current_uid() != 0 {
+                    return -EPERM;
+                }
+                inner.inode.set_immutable(flags & FS_IMMUTABLE_FL != 0);
+                0
+            }
+            _ => -EINVAL,
+        }
     }
 }
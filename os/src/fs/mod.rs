@@ -1,7 +1,9 @@
 mod stdio;
 mod inode;
+mod pressure;
 
 use crate::mm::UserBuffer;
+use crate::syscall::errno::{ENOTTY, ESPIPE};
 
 /// The common abstraction of all IO resources
 pub trait File : Send + Sync {
@@ -12,13 +14,23 @@ pub trait File : Send + Sync {
     fn get_nlink(&self, target_block_id: u32, target_block_offset: usize) -> u32;
     fn get_block_id(&self) -> u32;
     fn get_block_offset(&self) -> usize;
-    fn read(&self, buf: UserBuffer) -> usize;
-    fn write(&self, buf: UserBuffer) -> usize;
+    /// Read from the file, returning the number of bytes read or a negative errno
+    fn read(&self, buf: UserBuffer) -> isize;
+    /// Write to the file, returning the number of bytes written or a negative errno
+    fn write(&self, buf: UserBuffer) -> isize;
+    /// Device-specific control, only terminals support any
+    fn ioctl(&self, _cmd: usize, _arg: usize) -> isize {
+        -ENOTTY
+    }
+    /// Write back cached data in a byte range, only files have any
+    fn sync_range(&self, _offset: usize, _len: usize) -> isize {
+        -ESPIPE
+    }
 }
 
 /// The stat of a inode
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct Stat {
     /// ID of device containing file
     pub dev: u64,
@@ -44,5 +56,7 @@ bitflags! {
     }
 }
 
-pub use stdio::{Stdin, Stdout};
-pub use inode::{OSInode, open_file, link_file, unlink_file, get_nlink, OpenFlags, list_apps};
+pub use stdio::{tty_poll, Stdin, Stdout};
+pub use pressure::{notify_memory_pressure, PressureFile};
+pub use easy_fs::Quota;
+pub use inode::{OSInode, open_file, open_exec, file_immutable, file_text_busy, link_file, unlink_file, access_file, get_nlink, get_quota, set_quota, OpenFlags, AccessMode, list_apps};
@@ -0,0 +1,115 @@
+//! Memory pressure notification
+//!
+//! `sys_mempressure` opens a file that the frame allocator marks each time
+//! free frames drop below the threshold it was opened with. Reading it
+//! gives the number of such drops since the last read as a `u64`, blocking
+//! until there is one unless it was opened non-blocking, when it reads
+//! nothing instead. There is no poll, so a program sheds memory after a
+//! non-blocking read between its own work, or lets a child block on it.
+
+use super::{File, StatMode};
+use crate::mm::UserBuffer;
+use crate::sync::UPSafeCell;
+use crate::task::{current_task, suspend_current_and_run_next};
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use lazy_static::*;
+
+/// A threshold watched for a pressure file
+struct PressureWatch {
+    /// Free frames below which there is pressure
+    threshold: usize,
+    /// Whether free frames were below the threshold when last checked
+    below: AtomicBool,
+    /// Drops below the threshold not read yet
+    events: AtomicU64,
+}
+
+lazy_static! {
+    /// Watches of the pressure files still open
+    static ref WATCHES: UPSafeCell<Vec<Weak<PressureWatch>>> = unsafe { UPSafeCell::new(Vec::new()) };
+}
+
+/// Count a drop below the threshold of each watch `free` frames are below
+///
+/// Called from the frame allocator, so it gives up if the watches are
+/// borrowed.
+pub fn notify_memory_pressure(free: usize) {
+    let mut watches = match WATCHES.try_exclusive_access() {
+        Some(watches) => watches,
+        None => return,
+    };
+    watches.retain(|watch| match watch.upgrade() {
+        Some(watch) => {
+            let below = free < watch.threshold;
+            if below && !watch.below.swap(true, Ordering::Relaxed) {
+                watch.events.fetch_add(1, Ordering::Relaxed);
+            } else if !below {
+                watch.below.store(false, Ordering::Relaxed);
+            }
+            true
+        }
+        None => false,
+    });
+}
+
+/// A file counting the drops of free frames below a threshold
+pub struct PressureFile {
+    watch: Arc<PressureWatch>,
+    nonblock: bool,
+}
+
+impl PressureFile {
+    pub fn new(threshold: usize, nonblock: bool) -> Self {
+        let watch = Arc::new(PressureWatch {
+            threshold,
+            below: AtomicBool::new(false),
+            events: AtomicU64::new(0),
+        });
+        WATCHES.exclusive_access().push(Arc::downgrade(&watch));
+        Self { watch, nonblock }
+    }
+}
+
+impl File for PressureFile {
+    fn readable(&self) -> bool { true }
+    fn writable(&self) -> bool { false }
+    fn get_ino(&self) -> u32 { 0 }
+    fn get_mode(&self) -> StatMode { StatMode::NULL }
+    fn get_nlink(&self, _target_block_id: u32, _target_block_offset: usize) -> u32 { 0 }
+    fn get_block_id(&self) -> u32 { 0 }
+    fn get_block_offset(&self) -> usize { 0 }
+    fn read(&self, buf: UserBuffer) -> isize {
+        if buf.len() < core::mem::size_of::<u64>() {
+            return 0;
+        }
+        let events = loop {
+            let events = self.watch.events.swap(0, Ordering::Relaxed);
+            if events > 0 || self.nonblock {
+                break events;
+            }
+            // return early so that a signal is delivered
+            let task = current_task().unwrap();
+            let inner = task.inner_exclusive_access();
+            if !(inner.signals - inner.signal_mask).is_empty() {
+                return 0;
+            }
+            drop(inner);
+            drop(task);
+            suspend_current_and_run_next();
+        };
+        if events == 0 {
+            return 0;
+        }
+        for (byte, value) in buf.into_iter().zip(events.to_ne_bytes()) {
+            unsafe {
+                *byte = value;
+            }
+        }
+        core::mem::size_of::<u64>() as isize
+    }
+    fn write(&self, _buf: UserBuffer) -> isize {
+        -1
+    }
+}
@@ -1,8 +1,133 @@
 use crate::fs::{StatMode};
 use super::File;
-use crate::mm::{UserBuffer};
+use crate::mm::{get_user, put_user, UserBuffer};
 use crate::sbi::console_getchar;
-use crate::task::suspend_current_and_run_next;
+use crate::sync::UPSafeCell;
+use crate::task::{all_tasks, current_task, current_user_token, send_signal, suspend_current_and_run_next, SignalFlags};
+use alloc::collections::VecDeque;
+use lazy_static::*;
+
+/// ioctl making the console the controlling terminal of the caller's session
+const TIOCSCTTY: usize = 0x540E;
+/// ioctl reading the foreground process group
+const TIOCGPGRP: usize = 0x540F;
+/// ioctl setting the foreground process group
+const TIOCSPGRP: usize = 0x5410;
+/// ioctl giving up the controlling terminal
+const TIOCNOTTY: usize = 0x5422;
+
+/// The console as a terminal
+struct Tty {
+    /// Session the console is the controlling terminal of
+    session: Option<usize>,
+    /// Process group receiving terminal-generated signals
+    foreground_pgid: usize,
+    /// Characters read from the console but not by any process yet
+    input: VecDeque<u8>,
+}
+
+lazy_static! {
+    /// The console is the controlling terminal of initproc's session at boot
+    static ref TTY: UPSafeCell<Tty> = unsafe {
+        UPSafeCell::new(Tty {
+            session: Some(0),
+            foreground_pgid: 0,
+            input: VecDeque::new(),
+        })
+    };
+}
+
+/// Drain the console into the input buffer, turning Ctrl-C, Ctrl-\ and
+/// Ctrl-Z into signals to the foreground process group
+///
+/// Polled on every tick so that the signals arrive while nobody reads.
+pub fn tty_poll() {
+    loop {
+        let c = console_getchar();
+        if c == 0 || c == usize::MAX {
+            return;
+        }
+        let signal = match c as u8 {
+            0x03 => SignalFlags::SIGINT,
+            0x1c => SignalFlags::SIGQUIT,
+            0x1a => SignalFlags::SIGTSTP,
+            ch => {
+                TTY.exclusive_access().input.push_back(ch);
+                continue;
+            }
+        };
+        let tty = TTY.exclusive_access();
+        let (session, pgid) = match tty.session {
+            Some(session) => (session, tty.foreground_pgid),
+            None => continue,
+        };
+        drop(tty);
+        for task in all_tasks() {
+            let inner = task.inner_exclusive_access();
+            if inner.sid == session && inner.pgid == pgid {
+                drop(inner);
+                send_signal(task, signal);
+            }
+        }
+    }
+}
+
+/// Terminal ioctls shared by stdin and stdout
+fn tty_ioctl(cmd: usize, arg: usize) -> isize {
+    let token = current_user_token();
+    let task = current_task().unwrap();
+    let inner = task.inner_exclusive_access();
+    let (sid, is_leader) = (inner.sid, inner.sid == task.getpid());
+    drop(inner);
+    let mut tty = TTY.exclusive_access();
+    match cmd {
+        TIOCSCTTY => {
+            if !is_leader || tty.session.is_some() {
+                return -1;
+            }
+            tty.session = Some(sid);
+            tty.foreground_pgid = sid;
+            0
+        }
+        TIOCNOTTY => {
+            if tty.session != Some(sid) {
+                return -1;
+            }
+            if is_leader {
+                tty.session = None;
+            }
+            0
+        }
+        TIOCGPGRP => {
+            if tty.session != Some(sid) {
+                return -1;
+            }
+            match put_user(token, arg as *mut i32, tty.foreground_pgid as i32) {
+                Ok(()) => 0,
+                Err(err) => err,
+            }
+        }
+        TIOCSPGRP => {
+            if tty.session != Some(sid) {
+                return -1;
+            }
+            let pgid = match get_user(token, arg as *const i32) {
+                Ok(pgid) => pgid as usize,
+                Err(err) => return err,
+            };
+            // the group must belong to the session of the terminal
+            if !all_tasks().iter().any(|task| {
+                let inner = task.inner_exclusive_access();
+                inner.pgid == pgid && inner.sid == sid
+            }) {
+                return -1;
+            }
+            tty.foreground_pgid = pgid;
+            0
+        }
+        _ => -1,
+    }
+}
 
 /// The standard input
 pub struct Stdin;
@@ -17,26 +142,35 @@ impl File for Stdin {
     fn get_nlink(&self, target_block_id: u32, target_block_offset: usize) -> u32 { 0 }
     fn get_block_id(&self) -> u32 { 0 }
     fn get_block_offset(&self) -> usize { 0 }
-    fn read(&self, mut user_buf: UserBuffer) -> usize {
+    fn read(&self, mut user_buf: UserBuffer) -> isize {
         assert_eq!(user_buf.len(), 1);
         // busy loop
-        let mut c: usize;
+        let ch: u8;
         loop {
-            c = console_getchar();
-            if c == 0 {
-                suspend_current_and_run_next();
-                continue;
-            } else {
+            tty_poll();
+            if let Some(c) = TTY.exclusive_access().input.pop_front() {
+                ch = c;
                 break;
             }
+            // return early so that a signal, e.g. from Ctrl-C, is delivered
+            let task = current_task().unwrap();
+            let inner = task.inner_exclusive_access();
+            if !(inner.signals - inner.signal_mask).is_empty() {
+                return 0;
+            }
+            drop(inner);
+            drop(task);
+            suspend_current_and_run_next();
         }
-        let ch = c as u8;
         unsafe { user_buf.buffers[0].as_mut_ptr().write_volatile(ch); }
         1
     }
-    fn write(&self, _user_buf: UserBuffer) -> usize {
+    fn write(&self, _user_buf: UserBuffer) -> isize {
         panic!("Cannot write to stdin!");
     }
+    fn ioctl(&self, cmd: usize, arg: usize) -> isize {
+        tty_ioctl(cmd, arg)
+    }
 }
 
 impl File for Stdout {
@@ -47,13 +181,16 @@ impl File for Stdout {
     fn get_nlink(&self, target_block_id: u32, target_block_offset: usize) -> u32 { 0 }
     fn get_block_id(&self) -> u32 { 0 }
     fn get_block_offset(&self) -> usize { 0 }
-    fn read(&self, _user_buf: UserBuffer) -> usize{
+    fn read(&self, _user_buf: UserBuffer) -> isize {
         panic!("Cannot read from stdout!");
     }
-    fn write(&self, user_buf: UserBuffer) -> usize {
+    fn write(&self, user_buf: UserBuffer) -> isize {
         for buffer in user_buf.buffers.iter() {
             print!("{}", core::str::from_utf8(*buffer).unwrap());
         }
-        user_buf.len()
+        user_buf.len() as isize
+    }
+    fn ioctl(&self, cmd: usize, arg: usize) -> isize {
+        tty_ioctl(cmd, arg)
     }
 }
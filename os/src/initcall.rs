@@ -0,0 +1,67 @@
+//! Kernel initialization in stages with declared dependencies
+//!
+//! Each subsystem registers an [`InitCall`] in [`INITCALLS`] naming the
+//! stages it needs. [`run_initcalls`] runs every stage after its
+//! dependencies and logs how long it took.
+
+use crate::timer::get_time_us;
+
+/// A stage of kernel initialization
+pub struct InitCall {
+    pub name: &'static str,
+    /// Stages that must run first
+    pub deps: &'static [&'static str],
+    pub func: fn(),
+}
+
+/// All initialization stages, in no particular order
+const INITCALLS: &[InitCall] = &[
+    InitCall { name: "mm", deps: &[], func: crate::mm::init },
+    InitCall { name: "remap_test", deps: &["mm"], func: crate::mm::remap_test },
+    InitCall { name: "stride_test", deps: &[], func: crate::task::stride_test },
+    InitCall { name: "trap", deps: &[], func: crate::trap::init },
+    InitCall { name: "time_page", deps: &["mm"], func: crate::timer::init_time_page },
+    InitCall { name: "counters", deps: &[], func: crate::timer::init_counters },
+    InitCall { name: "timer", deps: &["trap", "time_page"], func: start_timer },
+    InitCall { name: "lockdep", deps: &["mm"], func: crate::sync::init_lockdep },
+    InitCall { name: "fs", deps: &["mm", "lockdep"], func: crate::fs::list_apps },
+    InitCall { name: "initproc", deps: &["fs", "time_page"], func: crate::task::add_initproc },
+    InitCall { name: "kswapd", deps: &["initproc"], func: crate::task::kswapd_init },
+];
+
+/// Enable timer interrupts and arm the first one
+fn start_timer() {
+    crate::trap::enable_timer_interrupt();
+    crate::timer::set_next_trigger();
+}
+
+/// Run every stage once its dependencies have run, panicking on unknown
+/// or circular dependencies
+///
+/// The heap is not set up before the `mm` stage, so nothing is allocated.
+pub fn run_initcalls() {
+    const NUM: usize = INITCALLS.len();
+    let mut done = [false; NUM];
+    let is_done = |done: &[bool; NUM], name: &str| {
+        let idx = INITCALLS
+            .iter()
+            .position(|call| call.name == name)
+            .unwrap_or_else(|| panic!("unknown init stage {}", name));
+        done[idx]
+    };
+    let boot_start = get_time_us();
+    for _ in 0..NUM {
+        let (idx, call) = INITCALLS
+            .iter()
+            .enumerate()
+            .find(|(idx, call)| {
+                !done[*idx] && call.deps.iter().all(|dep| is_done(&done, dep))
+            })
+            .unwrap_or_else(|| panic!("circular dependencies between init stages"));
+        let start = get_time_us();
+        (call.func)();
+        done[idx] = true;
+        info!("[kernel] init {} done in {} us", call.name, get_time_us() - start);
+    }
+    info!("[kernel] all init stages done in {} us", get_time_us() - boot_start);
+}
@@ -2,6 +2,7 @@
 
 use crate::console::ANSICON;
 use crate::sbi::shutdown;
+use crate::task::current_task_comm;
 
 use core::panic::PanicInfo;
 
@@ -25,5 +26,14 @@ fn panic(info: &PanicInfo) -> ! {
             info.message().unwrap()
         );
     }
+    if let Some((pid, comm)) = current_task_comm() {
+        println_colorized!(
+            "[kernel] Current task: pid {} ({})",
+            ANSICON::FgRed,
+            ANSICON::BgDefault,
+            pid,
+            comm
+        );
+    }
     shutdown()
 }
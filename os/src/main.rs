@@ -18,6 +18,7 @@
 #![no_std]
 #![no_main]
 #![feature(panic_info_message)]
+#![feature(asm_const)]
 #![feature(alloc_error_handler)]
 
 #[macro_use]
@@ -40,7 +41,10 @@ mod task;
 mod timer;
 mod trap;
 mod drivers;
+mod fault_inject;
+mod backtrace;
 mod fs;
+mod initcall;
 
 core::arch::global_asm!(include_str!("entry.asm"));
 
@@ -62,13 +66,7 @@ pub fn rust_main() -> ! {
     clear_bss();
     logging::init();
     println!("[kernel] Hello, world!");
-    mm::init();
-    mm::remap_test();
-    trap::init();
-    trap::enable_timer_interrupt();
-    timer::set_next_trigger();
-    fs::list_apps();
-    task::add_initproc();
+    initcall::run_initcalls();
     task::run_tasks();
     panic!("Unreachable in rust_main!");
 }
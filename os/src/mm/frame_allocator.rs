@@ -1,24 +1,57 @@
 //! Implementation of [`FrameAllocator`] which
 //! controls all the frames in the operating system.
+//!
+//! Frames are handed out zeroed, so no data passes from a process to the
+//! next one owning the frame. By default they are zeroed when allocated;
+//! with [`set_scrub_on_free`] they are zeroed as soon as they are freed
+//! instead, so old data does not linger in free memory either.
 
 use super::{PhysAddr, PhysPageNum};
-use crate::config::MEMORY_END;
-use crate::sync::UPSafeCell;
+use crate::config::{MEMORY_END, PAGE_SIZE};
+use crate::fault_inject::{should_fail, FaultKind};
+use crate::fs::notify_memory_pressure;
+use crate::sync::SpinNoIrq;
+use crate::task::{oom_kill, wakeup_kswapd};
+use alloc::collections::BTreeSet;
+use alloc::vec;
 use alloc::vec::Vec;
 use core::fmt::{self, Debug, Formatter};
+use core::sync::atomic::{AtomicBool, Ordering};
 use lazy_static::*;
 
+/// Whether frames are zeroed when freed rather than when allocated
+static SCRUB_ON_FREE: AtomicBool = AtomicBool::new(false);
+
+/// Get whether frames are zeroed when freed rather than when allocated
+pub fn scrub_on_free() -> bool {
+    SCRUB_ON_FREE.load(Ordering::Relaxed)
+}
+
+/// Zero frames when they are freed if `on_free`, else when they are
+/// allocated; frames freed before stay as they are until allocated
+pub fn set_scrub_on_free(on_free: bool) {
+    SCRUB_ON_FREE.store(on_free, Ordering::Relaxed);
+}
+
+/// Zero frame `ppn`, only reading it if it is zero already, the fast path
+/// for frames never written to
+fn scrub(ppn: PhysPageNum) {
+    let words = ppn.get_mut::<[u64; PAGE_SIZE / 8]>();
+    if words.iter().any(|&word| word != 0) {
+        words.fill(0);
+    }
+}
+
 /// manage a frame which has the same lifecycle as the tracker
 pub struct FrameTracker {
     pub ppn: PhysPageNum,
 }
 
 impl FrameTracker {
-    pub fn new(ppn: PhysPageNum) -> Self {
-        // page cleaning
-        let bytes_array = ppn.get_bytes_array();
-        for i in bytes_array {
-            *i = 0;
+    /// Track frame `ppn`, zeroing it unless it is known to be zero
+    pub fn new(ppn: PhysPageNum, zeroed: bool) -> Self {
+        if !zeroed {
+            scrub(ppn);
         }
         Self { ppn }
     }
@@ -36,68 +69,171 @@ impl Drop for FrameTracker {
     }
 }
 
+/// manage `1 << order` contiguous frames from `ppn` with the lifecycle of
+/// the tracker, see [`frame_alloc_contiguous`]
+pub struct FrameBlock {
+    pub ppn: PhysPageNum,
+    pub order: usize,
+}
+
+impl FrameBlock {
+    /// Track the block, zeroing the frames for which `zeroed` is false
+    pub fn new(ppn: PhysPageNum, order: usize, mut zeroed: impl FnMut(PhysPageNum) -> bool) -> Self {
+        for i in 0..1 << order {
+            let frame = PhysPageNum(ppn.0 + i);
+            if !zeroed(frame) {
+                scrub(frame);
+            }
+        }
+        Self { ppn, order }
+    }
+}
+
+impl Debug for FrameBlock {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_fmt(format_args!("FrameBlock:PPN={:#x},order={}", self.ppn.0, self.order))
+    }
+}
+
+impl Drop for FrameBlock {
+    fn drop(&mut self) {
+        let on_free = scrub_on_free();
+        if on_free {
+            for i in 0..1 << self.order {
+                scrub(PhysPageNum(self.ppn.0 + i));
+            }
+        }
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        allocator.dealloc_contiguous(self.ppn, self.order);
+        if on_free {
+            for i in 0..1 << self.order {
+                allocator.set_zeroed(PhysPageNum(self.ppn.0 + i));
+            }
+        }
+    }
+}
+
 trait FrameAllocator {
     fn new() -> Self;
     fn alloc(&mut self) -> Option<PhysPageNum>;
     fn dealloc(&mut self, ppn: PhysPageNum);
+    /// Allocate `1 << order` physically contiguous frames, aligned to
+    /// their size
+    fn alloc_contiguous(&mut self, order: usize) -> Option<PhysPageNum>;
+    fn dealloc_contiguous(&mut self, ppn: PhysPageNum, order: usize);
 }
 
-/// an implementation for frame allocator
-pub struct StackFrameAllocator {
-    current: usize,
-    end: usize,
-    recycled: Vec<usize>,
+/// Orders of the blocks of the buddy allocator, the largest being 4 MiB
+const MAX_ORDER: usize = 10;
+
+/// an implementation for frame allocator: free frames are kept in blocks
+/// of `1 << order` frames, split to allocate smaller blocks and merged
+/// with their buddies when freed
+pub struct BuddyFrameAllocator {
+    /// Start ppns of the free blocks of each order
+    free_lists: [BTreeSet<usize>; MAX_ORDER + 1],
+    free: usize,
+    total: usize,
+    /// First ppn managed
+    base: usize,
+    /// Bitmap of the free frames known to be zero
+    zeroed: Vec<u64>,
 }
 
-impl StackFrameAllocator {
+impl BuddyFrameAllocator {
     pub fn init(&mut self, l: PhysPageNum, r: PhysPageNum) {
-        self.current = l.0;
-        self.end = r.0;
-        info!("last {} Physical Frames.", self.end - self.current);
+        // cut the range into the largest aligned blocks that fit
+        let mut ppn = l.0;
+        while ppn < r.0 {
+            let order = (0..=MAX_ORDER)
+                .rev()
+                .find(|order| ppn % (1 << order) == 0 && ppn + (1 << order) <= r.0)
+                .unwrap();
+            self.free_lists[order].insert(ppn);
+            ppn += 1 << order;
+        }
+        self.free = r.0 - l.0;
+        self.total = self.free;
+        self.base = l.0;
+        self.zeroed = vec![0; (self.total + 63) / 64];
+        info!("last {} Physical Frames.", self.free);
+    }
+    /// Get whether frame `ppn`, just allocated, is known to be zero,
+    /// forgetting it as the frame may now be written to
+    fn take_zeroed(&mut self, ppn: PhysPageNum) -> bool {
+        let (word, bit) = ((ppn.0 - self.base) / 64, (ppn.0 - self.base) % 64);
+        let zeroed = self.zeroed[word] & 1 << bit != 0;
+        self.zeroed[word] &= !(1 << bit);
+        zeroed
+    }
+    /// Record that frame `ppn`, just freed, is zero
+    fn set_zeroed(&mut self, ppn: PhysPageNum) {
+        let (word, bit) = ((ppn.0 - self.base) / 64, (ppn.0 - self.base) % 64);
+        self.zeroed[word] |= 1 << bit;
     }
 }
-impl FrameAllocator for StackFrameAllocator {
+impl FrameAllocator for BuddyFrameAllocator {
     fn new() -> Self {
         Self {
-            current: 0,
-            end: 0,
-            recycled: Vec::new(),
+            free_lists: Default::default(),
+            free: 0,
+            total: 0,
+            base: 0,
+            zeroed: Vec::new(),
         }
     }
     fn alloc(&mut self) -> Option<PhysPageNum> {
-        if let Some(ppn) = self.recycled.pop() {
-            Some(ppn.into())
-        } else if self.current == self.end {
-            None
-        } else {
-            self.current += 1;
-            Some((self.current - 1).into())
-        }
+        self.alloc_contiguous(0)
     }
     fn dealloc(&mut self, ppn: PhysPageNum) {
-        let ppn = ppn.0;
+        self.dealloc_contiguous(ppn, 0);
+    }
+    fn alloc_contiguous(&mut self, order: usize) -> Option<PhysPageNum> {
+        let found = (order..=MAX_ORDER).find(|o| !self.free_lists[*o].is_empty())?;
+        let ppn = *self.free_lists[found].iter().next().unwrap();
+        self.free_lists[found].remove(&ppn);
+        // give the upper halves back until the block is of the right order
+        for o in (order..found).rev() {
+            self.free_lists[o].insert(ppn + (1 << o));
+        }
+        self.free -= 1 << order;
+        Some(ppn.into())
+    }
+    fn dealloc_contiguous(&mut self, ppn: PhysPageNum, order: usize) {
+        let mut ppn = ppn.0;
         // validity check
-        if ppn >= self.current || self.recycled.iter().any(|v| *v == ppn) {
+        if ppn % (1 << order) != 0
+            || (0..=MAX_ORDER).any(|o| {
+                let block = ppn & !((1 << o) - 1);
+                self.free_lists[o].contains(&block) && ppn < block + (1 << o)
+            })
+        {
             panic!("Frame ppn={:#x} has not been allocated!", ppn);
         }
-        // recycle
-        self.recycled.push(ppn);
+        self.free += 1 << order;
+        // merge with the buddy for as long as it is free
+        let mut order = order;
+        while order < MAX_ORDER && self.free_lists[order].remove(&(ppn ^ 1 << order)) {
+            ppn &= !(1 << order);
+            order += 1;
+        }
+        self.free_lists[order].insert(ppn);
     }
 }
 
-type FrameAllocatorImpl = StackFrameAllocator;
+type FrameAllocatorImpl = BuddyFrameAllocator;
 
 lazy_static! {
     /// frame allocator instance through lazy_static!
-    pub static ref FRAME_ALLOCATOR: UPSafeCell<FrameAllocatorImpl> =
-        unsafe { UPSafeCell::new(FrameAllocatorImpl::new()) };
+    pub static ref FRAME_ALLOCATOR: SpinNoIrq<FrameAllocatorImpl> =
+        SpinNoIrq::new(FrameAllocatorImpl::new());
 }
 
 pub fn init_frame_allocator() {
     extern "C" {
         fn ekernel();
     }
-    FRAME_ALLOCATOR.exclusive_access().init(
+    FRAME_ALLOCATOR.lock().init(
         PhysAddr::from(ekernel as usize).ceil(),
         PhysAddr::from(MEMORY_END).floor(),
     );
@@ -105,15 +241,59 @@ pub fn init_frame_allocator() {
 
 /// initiate the frame allocator using `ekernel` and `MEMORY_END`
 pub fn frame_alloc() -> Option<FrameTracker> {
-    FRAME_ALLOCATOR
-        .exclusive_access()
-        .alloc()
-        .map(FrameTracker::new)
+    if should_fail(FaultKind::FrameAlloc) {
+        return None;
+    }
+    loop {
+        let frame = {
+            let mut allocator = FRAME_ALLOCATOR.lock();
+            allocator.alloc().map(|ppn| (ppn, allocator.take_zeroed(ppn)))
+        };
+        if let Some((ppn, zeroed)) = frame {
+            wakeup_kswapd();
+            notify_memory_pressure(frames_free());
+            return Some(FrameTracker::new(ppn, zeroed));
+        }
+        // user page faults have swapped out what they could before getting
+        // here; swapping needs the block device, which may be in use now
+        if !oom_kill() {
+            return None;
+        }
+    }
+}
+
+/// Allocate `1 << order` physically contiguous frames, e.g. for DMA
+/// buffers or huge pages
+pub fn frame_alloc_contiguous(order: usize) -> Option<FrameBlock> {
+    if order > MAX_ORDER || should_fail(FaultKind::FrameAlloc) {
+        return None;
+    }
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let ppn = allocator.alloc_contiguous(order)?;
+    Some(FrameBlock::new(ppn, order, |frame| allocator.take_zeroed(frame)))
+}
+
+/// Number of frames left to allocate
+pub fn frames_free() -> usize {
+    FRAME_ALLOCATOR.lock().free
+}
+
+/// Number of frames managed, free or not
+pub fn frames_total() -> usize {
+    FRAME_ALLOCATOR.lock().total
 }
 
 /// deallocate a frame
 pub fn frame_dealloc(ppn: PhysPageNum) {
-    FRAME_ALLOCATOR.exclusive_access().dealloc(ppn);
+    let on_free = scrub_on_free();
+    if on_free {
+        scrub(ppn);
+    }
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    allocator.dealloc(ppn);
+    if on_free {
+        allocator.set_zeroed(ppn);
+    }
 }
 
 #[allow(unused)]
@@ -1,11 +1,138 @@
 //! The global allocator
 
+#[cfg(feature = "heap-debug")]
+use super::heap_debug::{debug_alloc, debug_free, debug_untrack, heap_debug_stats};
+#[cfg(feature = "heap-debug")]
+use crate::backtrace::frame_pointer;
+#[cfg(feature = "heap-poison")]
+use super::heap_poison::{poison_alloc, poison_dealloc};
+use super::slab::{slab_alloc, slab_dealloc};
 use crate::config::KERNEL_HEAP_SIZE;
+use crate::fault_inject::{should_fail, FaultKind};
+use crate::sync::{PreemptGuard, TicketLock};
+use crate::syscall::errno::ENOMEM;
+use alloc::collections::TryReserveError;
+use alloc::string::String;
 use buddy_system_allocator::LockedHeap;
+use core::alloc::{GlobalAlloc, Layout};
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Counters of the kernel heap, in bytes as requested
+#[derive(Clone, Copy, Default)]
+pub struct HeapStat {
+    /// Bytes allocated now
+    pub in_use: usize,
+    /// Most bytes allocated at once
+    pub peak: usize,
+    /// Allocations since boot
+    pub allocs: usize,
+    /// Frees since boot
+    pub frees: usize,
+    /// Allocations failed since boot
+    pub failed: usize,
+}
+
+static HEAP_STAT: TicketLock<HeapStat> = TicketLock::new(HeapStat {
+    in_use: 0,
+    peak: 0,
+    allocs: 0,
+    frees: 0,
+    failed: 0,
+});
+
+/// Set while [`try_alloc`] runs, whose allocations are the only ones error
+/// injection fails: the others cannot report a failure but by panicking
+static FALLIBLE: AtomicBool = AtomicBool::new(false);
+
+/// Run `reserve`, a `try_reserve` of a collection, failing with -ENOMEM
+/// rather than panicking if the heap is full or error injection says so
+pub fn try_alloc(reserve: impl FnOnce() -> Result<(), TryReserveError>) -> Result<(), isize> {
+    // no other task may allocate while the flag is set
+    let _preempt = PreemptGuard::new();
+    FALLIBLE.store(true, Ordering::Relaxed);
+    let result = reserve();
+    FALLIBLE.store(false, Ordering::Relaxed);
+    result.map_err(|_| -ENOMEM)
+}
+
+/// The kernel heap, which fails allocations of [`try_alloc`] on request of
+/// error injection and serves the objects of the slab caches from their
+/// slabs
+struct KernelHeap(LockedHeap);
+
+impl KernelHeap {
+    unsafe fn alloc_block(&self, layout: Layout) -> *mut u8 {
+        slab_alloc(layout, &|layout| self.0.alloc(layout)).unwrap_or_else(|| self.0.alloc(layout))
+    }
+    unsafe fn dealloc_block(&self, ptr: *mut u8, layout: Layout) {
+        if !slab_dealloc(ptr, layout, &|ptr, layout| self.0.dealloc(ptr, layout)) {
+            self.0.dealloc(ptr, layout)
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for KernelHeap {
+    // not inlined, so heap-debug finds the call site right above this frame
+    #[inline(never)]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = if FALLIBLE.load(Ordering::Relaxed) && should_fail(FaultKind::HeapAlloc) {
+            core::ptr::null_mut()
+        } else {
+            // preempted with the heap locked, the next task would spin forever
+            let _preempt = PreemptGuard::new();
+            #[cfg(feature = "heap-debug")]
+            let frame = frame_pointer();
+            #[cfg(feature = "heap-debug")]
+            let alloc_tracked = |layout| debug_alloc(layout, frame, &|layout| self.alloc_block(layout));
+            #[cfg(not(feature = "heap-debug"))]
+            let alloc_tracked = |layout| self.alloc_block(layout);
+            #[cfg(feature = "heap-poison")]
+            let ptr = poison_alloc(layout, &alloc_tracked);
+            #[cfg(not(feature = "heap-poison"))]
+            let ptr = alloc_tracked(layout);
+            ptr
+        };
+        let mut stat = HEAP_STAT.lock();
+        if ptr.is_null() {
+            stat.failed += 1;
+        } else {
+            stat.in_use += layout.size();
+            stat.peak = stat.peak.max(stat.in_use);
+            stat.allocs += 1;
+        }
+        ptr
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let _preempt = PreemptGuard::new();
+        #[cfg(feature = "heap-debug")]
+        let untrack = |ptr, layout| debug_untrack(ptr, layout);
+        #[cfg(not(feature = "heap-debug"))]
+        let untrack = |_: *mut u8, _: Layout| {};
+        #[cfg(feature = "heap-debug")]
+        let dealloc_tracked =
+            |ptr, layout| debug_free(ptr, layout, &|ptr, layout| self.dealloc_block(ptr, layout));
+        #[cfg(not(feature = "heap-debug"))]
+        let dealloc_tracked = |ptr, layout| self.dealloc_block(ptr, layout);
+        // checked first, so a double free is told before the header of
+        // heap-debug is read from freed memory; a block stops being live
+        // as it enters the quarantine
+        #[cfg(feature = "heap-poison")]
+        poison_dealloc(ptr, layout, &untrack, &dealloc_tracked);
+        #[cfg(not(feature = "heap-poison"))]
+        {
+            untrack(ptr, layout);
+            dealloc_tracked(ptr, layout);
+        }
+        let mut stat = HEAP_STAT.lock();
+        stat.in_use -= layout.size();
+        stat.frees += 1;
+    }
+}
 
 #[global_allocator]
 /// heap allocator instance
-static HEAP_ALLOCATOR: LockedHeap = LockedHeap::empty();
+static HEAP_ALLOCATOR: KernelHeap = KernelHeap(LockedHeap::empty());
 
 #[alloc_error_handler]
 /// panic when heap allocation error occurs
@@ -20,11 +147,37 @@ static mut HEAP_SPACE: [u8; KERNEL_HEAP_SIZE] = [0; KERNEL_HEAP_SIZE];
 pub fn init_heap() {
     unsafe {
         HEAP_ALLOCATOR
+            .0
             .lock()
             .init(HEAP_SPACE.as_ptr() as usize, KERNEL_HEAP_SIZE);
     }
 }
 
+/// Bytes of the kernel heap allocated, counting what the allocator rounds
+/// requests up to, and bytes it has in all
+pub fn heap_usage() -> (usize, usize) {
+    let _preempt = PreemptGuard::new();
+    let heap = HEAP_ALLOCATOR.0.lock();
+    (heap.stats_alloc_actual(), heap.stats_total_bytes())
+}
+
+/// Format the counters of the kernel heap, with the blocks by call site
+/// and the leaks of exited tasks under the `heap-debug` feature
+pub fn heap_stats() -> String {
+    let stat = *HEAP_STAT.lock();
+    let (actual, total) = heap_usage();
+    let mut text = String::new();
+    writeln!(
+        text,
+        "in_use {} peak {} allocs {} frees {} failed {} actual {} total {}",
+        stat.in_use, stat.peak, stat.allocs, stat.frees, stat.failed, actual, total
+    )
+    .unwrap();
+    #[cfg(feature = "heap-debug")]
+    heap_debug_stats(&mut text);
+    text
+}
+
 #[allow(unused)]
 pub fn heap_test() {
     use alloc::boxed::Box;
@@ -0,0 +1,199 @@
+//! Leak tracking of the kernel heap, with the `heap-debug` feature
+//!
+//! Every block gets a header linking it into a list of live blocks, with
+//! the pid of the task it was allocated for and its call site, the return
+//! addresses of a few frames above the allocator found by following the
+//! frame pointers. Live blocks are counted by call site, and blocks still
+//! held for tasks that have exited are reported as leaks.
+//!
+//! Headers change the layouts, so the slab caches serve nothing then.
+
+use crate::backtrace::backtrace;
+use crate::sync::UPSafeCell;
+use crate::task::{pid2task, try_current_pid};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::alloc::Layout;
+use core::fmt::Write;
+use core::mem::{align_of, size_of};
+use core::ptr::null_mut;
+use lazy_static::*;
+
+/// Return addresses kept as the call site of a block
+const SITE_DEPTH: usize = 4;
+/// Call sites counted separately, later ones share the last entry
+const MAX_SITES: usize = 32;
+/// Tasks whose blocks are counted, the blocks of more are not checked
+const MAX_HOLDERS: usize = 16;
+
+/// Header in front of every block
+struct BlockHeader {
+    prev: *mut BlockHeader,
+    next: *mut BlockHeader,
+    size: usize,
+    pid: usize,
+    site: usize,
+}
+
+/// Live blocks of a call site
+#[derive(Clone, Copy, Default)]
+struct Site {
+    trace: [usize; SITE_DEPTH],
+    blocks: usize,
+    bytes: usize,
+    allocs: usize,
+}
+
+struct HeapDebug {
+    head: *mut BlockHeader,
+    sites: [Site; MAX_SITES],
+    nsites: usize,
+}
+
+unsafe impl Send for HeapDebug {}
+
+impl HeapDebug {
+    fn site_index(&mut self, trace: [usize; SITE_DEPTH]) -> usize {
+        if let Some(index) = self.sites[..self.nsites].iter().position(|site| site.trace == trace) {
+            return index;
+        }
+        if self.nsites == MAX_SITES {
+            // the last entry counts all the sites that did not fit
+            self.sites[MAX_SITES - 1].trace = [0; SITE_DEPTH];
+            return MAX_SITES - 1;
+        }
+        self.sites[self.nsites].trace = trace;
+        self.nsites += 1;
+        self.nsites - 1
+    }
+}
+
+lazy_static! {
+    static ref HEAP_DEBUG: UPSafeCell<HeapDebug> = unsafe {
+        UPSafeCell::new(HeapDebug {
+            head: null_mut(),
+            sites: [Site::default(); MAX_SITES],
+            nsites: 0,
+        })
+    };
+}
+
+/// Offset of the data of a block of `layout` from its start
+fn header_offset(layout: Layout) -> usize {
+    let align = layout.align().max(align_of::<BlockHeader>());
+    (size_of::<BlockHeader>() + align - 1) & !(align - 1)
+}
+
+/// Layout of a block of `layout` with its header
+fn block_layout(layout: Layout) -> Layout {
+    let align = layout.align().max(align_of::<BlockHeader>());
+    Layout::from_size_align(header_offset(layout) + layout.size(), align).unwrap()
+}
+
+/// Allocate a block of `layout` with its header from `heap`, `frame` being
+/// the frame of the global allocator, which returns to the call site
+///
+/// The allocator knows its own frame, so however its functions are
+/// inlined, the trace starts at the call site.
+pub unsafe fn debug_alloc(layout: Layout, frame: usize, heap: &dyn Fn(Layout) -> *mut u8) -> *mut u8 {
+    let block = heap(block_layout(layout));
+    if block.is_null() {
+        return block;
+    }
+    let ptr = block.add(header_offset(layout));
+    let header = (ptr as *mut BlockHeader).sub(1);
+    let trace = backtrace(frame);
+    let mut debug = HEAP_DEBUG.exclusive_access();
+    let site = debug.site_index(trace);
+    let stat = &mut debug.sites[site];
+    stat.blocks += 1;
+    stat.bytes += layout.size();
+    stat.allocs += 1;
+    header.write(BlockHeader {
+        prev: null_mut(),
+        next: debug.head,
+        size: layout.size(),
+        pid: try_current_pid().unwrap_or(0),
+        site,
+    });
+    if !debug.head.is_null() {
+        (*debug.head).prev = header;
+    }
+    debug.head = header;
+    ptr
+}
+
+/// Stop tracking a block of `layout` allocated by [`debug_alloc`] as live,
+/// before it is freed with [`debug_free`]
+pub unsafe fn debug_untrack(ptr: *mut u8, layout: Layout) {
+    let header = (ptr as *mut BlockHeader).sub(1);
+    let mut debug = HEAP_DEBUG.exclusive_access();
+    let BlockHeader { prev, next, size, site, .. } = header.read();
+    assert_eq!(size, layout.size(), "heap block {:p} freed with another size", ptr);
+    if prev.is_null() {
+        debug.head = next;
+    } else {
+        (*prev).next = next;
+    }
+    if !next.is_null() {
+        (*next).prev = prev;
+    }
+    let stat = &mut debug.sites[site];
+    stat.blocks -= 1;
+    stat.bytes -= size;
+}
+
+/// Free a block of `layout` allocated by [`debug_alloc`] to `heap`
+pub unsafe fn debug_free(ptr: *mut u8, layout: Layout, heap: &dyn Fn(*mut u8, Layout)) {
+    heap(ptr.sub(header_offset(layout)), block_layout(layout));
+}
+
+/// Format the call sites holding blocks and the blocks still held for
+/// exited tasks
+pub fn heap_debug_stats(text: &mut String) {
+    // copied out first into reserved space, as allocating here would
+    // reenter the list
+    let mut sites: Vec<Site> = Vec::with_capacity(MAX_SITES);
+    let mut holders: Vec<(usize, usize, usize)> = Vec::with_capacity(MAX_HOLDERS);
+    let mut untold = 0;
+    {
+        let debug = HEAP_DEBUG.exclusive_access();
+        sites.extend(debug.sites[..debug.nsites].iter().filter(|site| site.blocks > 0));
+        let mut header = debug.head;
+        while !header.is_null() {
+            let (pid, size, next) = unsafe { ((*header).pid, (*header).size, (*header).next) };
+            // blocks allocated outside any task are never leaked
+            if pid != 0 {
+                if let Some((_, blocks, bytes)) = holders.iter_mut().find(|(holder, ..)| *holder == pid) {
+                    *blocks += 1;
+                    *bytes += size;
+                } else if holders.len() < MAX_HOLDERS {
+                    holders.push((pid, 1, size));
+                } else {
+                    untold += 1;
+                }
+            }
+            header = next;
+        }
+    }
+    sites.sort_unstable_by(|a, b| b.bytes.cmp(&a.bytes));
+    writeln!(text, "{:>8} {:>8} {:>8}  site", "blocks", "bytes", "allocs").unwrap();
+    for site in sites {
+        write!(text, "{:>8} {:>8} {:>8} ", site.blocks, site.bytes, site.allocs).unwrap();
+        if site.trace == [0; SITE_DEPTH] {
+            write!(text, " [other]").unwrap();
+        }
+        for ra in site.trace.iter().take_while(|&&ra| ra != 0) {
+            write!(text, " {:#x}", ra).unwrap();
+        }
+        writeln!(text).unwrap();
+    }
+    for (pid, blocks, bytes) in holders {
+        if pid2task(pid).is_none() {
+            writeln!(text, "leak: exited pid {} holds {} blocks, {} bytes", pid, blocks, bytes).unwrap();
+        }
+    }
+    if untold > 0 {
+        writeln!(text, "leak: {} blocks of more tasks not checked", untold).unwrap();
+    }
+}
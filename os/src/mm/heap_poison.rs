@@ -0,0 +1,169 @@
+//! Use-after-free and overflow detection for the kernel heap, with the
+//! `heap-poison` feature
+//!
+//! Every block gets a header telling whether it is allocated, and red
+//! zones on both sides, checked when it is freed. Freed blocks are filled
+//! with a poison byte and kept in a quarantine for a while before going
+//! back to the heap, so a double free is caught by the header and a write
+//! after free by the poison found changed when the block leaves the
+//! quarantine. A pointer read from freed memory is the poison repeated, an
+//! invalid address, so following it faults and the kernel trap handler
+//! reports it with [`poisoned_address`].
+//!
+//! Like headers of `heap-debug`, this changes the layouts, so the slab
+//! caches serve nothing then.
+
+use crate::sync::UPSafeCell;
+use core::alloc::Layout;
+use core::mem::{align_of, size_of};
+use core::ptr::null_mut;
+use lazy_static::*;
+
+/// Byte filling freed blocks
+const POISON_FREE: u8 = 0x6b;
+/// Byte filling new blocks, to make reads of uninitialized data stand out
+const POISON_NEW: u8 = 0xa5;
+/// Byte filling red zones
+const POISON_RED_ZONE: u8 = 0xfb;
+/// Bytes of each red zone
+const RED_ZONE: usize = 16;
+/// Freed blocks kept before going back to the heap
+const QUARANTINE: usize = 64;
+
+const MAGIC_ALLOCATED: usize = 0xa110_ca7e_d0b1_0c05;
+const MAGIC_FREED: usize = 0xf4ee_d0b1_0c05_dead;
+
+/// Header at the start of every block
+struct BlockHeader {
+    magic: usize,
+    size: usize,
+}
+
+struct Quarantine {
+    blocks: [(*mut u8, Layout); QUARANTINE],
+    /// Next slot to reuse, holding the oldest block once all are used
+    next: usize,
+}
+
+unsafe impl Send for Quarantine {}
+
+lazy_static! {
+    static ref QUARANTINED: UPSafeCell<Quarantine> = unsafe {
+        UPSafeCell::new(Quarantine {
+            blocks: [(null_mut(), Layout::new::<u8>()); QUARANTINE],
+            next: 0,
+        })
+    };
+}
+
+/// Offset of the data of a block of `layout` from its start, past the
+/// header and the front red zone
+fn data_offset(layout: Layout) -> usize {
+    let align = layout.align().max(align_of::<BlockHeader>());
+    (size_of::<BlockHeader>() + RED_ZONE + align - 1) & !(align - 1)
+}
+
+/// Layout of a block of `layout` with its header and red zones
+fn block_layout(layout: Layout) -> Layout {
+    let align = layout.align().max(align_of::<BlockHeader>());
+    Layout::from_size_align(data_offset(layout) + layout.size() + RED_ZONE, align).unwrap()
+}
+
+/// Whether a kernel fault at `addr` came from following a pointer read
+/// from freed heap memory
+pub fn poisoned_address(addr: usize) -> bool {
+    let poison = usize::from_ne_bytes([POISON_FREE; size_of::<usize>()]);
+    addr.wrapping_sub(poison) < 0x1000
+}
+
+/// Allocate a block of `layout` with its header and red zones from `heap`
+pub unsafe fn poison_alloc(layout: Layout, heap: &dyn Fn(Layout) -> *mut u8) -> *mut u8 {
+    let block = heap(block_layout(layout));
+    if block.is_null() {
+        return block;
+    }
+    let ptr = block.add(data_offset(layout));
+    (block as *mut BlockHeader).write(BlockHeader {
+        magic: MAGIC_ALLOCATED,
+        size: layout.size(),
+    });
+    let header_end = block.add(size_of::<BlockHeader>());
+    header_end.write_bytes(POISON_RED_ZONE, ptr as usize - header_end as usize);
+    ptr.write_bytes(POISON_NEW, layout.size());
+    ptr.add(layout.size()).write_bytes(POISON_RED_ZONE, RED_ZONE);
+    ptr
+}
+
+/// Offset of the first byte of `len` bytes at `ptr` that is not `byte`
+unsafe fn find_changed(ptr: *const u8, len: usize, byte: u8) -> Option<usize> {
+    core::slice::from_raw_parts(ptr, len).iter().position(|&b| b != byte)
+}
+
+/// Check and poison a block of `layout` allocated by [`poison_alloc`],
+/// then quarantine it, telling `quarantined`, and free the oldest
+/// quarantined block to `heap`
+pub unsafe fn poison_dealloc(
+    ptr: *mut u8,
+    layout: Layout,
+    quarantined: &dyn Fn(*mut u8, Layout),
+    heap: &dyn Fn(*mut u8, Layout),
+) {
+    let block = ptr.sub(data_offset(layout));
+    let header = &mut *(block as *mut BlockHeader);
+    match header.magic {
+        MAGIC_ALLOCATED => {}
+        MAGIC_FREED => panic!("double free of heap block {:p} of {} bytes", ptr, header.size),
+        magic => panic!(
+            "free of heap block {:p} of {} bytes, never allocated or with its header overwritten ({:#x})",
+            ptr,
+            layout.size(),
+            magic
+        ),
+    }
+    if header.size != layout.size() {
+        panic!(
+            "heap block {:p} of {} bytes freed as {} bytes",
+            ptr,
+            header.size,
+            layout.size()
+        );
+    }
+    let header_end = block.add(size_of::<BlockHeader>());
+    if let Some(offset) = find_changed(header_end, ptr as usize - header_end as usize, POISON_RED_ZONE) {
+        panic!(
+            "heap block {:p} of {} bytes underflowed, red zone overwritten at {:p}",
+            ptr,
+            layout.size(),
+            header_end.add(offset)
+        );
+    }
+    if let Some(offset) = find_changed(ptr.add(layout.size()), RED_ZONE, POISON_RED_ZONE) {
+        panic!(
+            "heap block {:p} of {} bytes overflowed, red zone overwritten at {:p}",
+            ptr,
+            layout.size(),
+            ptr.add(layout.size() + offset)
+        );
+    }
+    header.magic = MAGIC_FREED;
+    ptr.write_bytes(POISON_FREE, layout.size());
+    quarantined(block, block_layout(layout));
+    let (oldest, oldest_layout) = {
+        let mut quarantine = QUARANTINED.exclusive_access();
+        let next = quarantine.next;
+        quarantine.next = (next + 1) % QUARANTINE;
+        core::mem::replace(&mut quarantine.blocks[next], (ptr, layout))
+    };
+    if oldest.is_null() {
+        return;
+    }
+    if let Some(offset) = find_changed(oldest, oldest_layout.size(), POISON_FREE) {
+        panic!(
+            "heap block {:p} of {} bytes written after free at {:p}",
+            oldest,
+            oldest_layout.size(),
+            oldest.add(offset)
+        );
+    }
+    heap(oldest.sub(data_offset(oldest_layout)), block_layout(oldest_layout));
+}
@@ -1,14 +1,18 @@
 //! Implementation of [`MapArea`] and [`MemorySet`].
 
-use super::{frame_alloc, FrameTracker};
-use super::{PTEFlags, PageTable, PageTableEntry};
+use super::swap::{swap_out, SwapSlot};
+use super::{frame_alloc, frames_free, FrameTracker};
+use super::{PTEFlags, PageTable, PageTableEntry, MEGAPAGE_PAGES};
 use super::{PhysAddr, PhysPageNum, VirtAddr, VirtPageNum};
 use super::{StepByOne, VPNRange};
-use crate::config::{MEMORY_END, PAGE_SIZE, TRAMPOLINE, TRAP_CONTEXT, USER_STACK_SIZE, MMIO};
+use crate::config::{FRAMES_LOW, MEMORY_END, MMAP_TOP, PAGE_SIZE, TIME_PAGE, TRAMPOLINE, TRAP_CONTEXT, MMIO, USER_STACK_SIZE};
 use crate::sync::UPSafeCell;
-use alloc::collections::BTreeMap;
+use crate::timer::time_page_ppn;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
+use core::fmt::Write;
 use lazy_static::*;
 use riscv::register::satp;
 
@@ -25,12 +29,39 @@ extern "C" {
     fn strampoline();
 }
 
+/// Number of protection keys of a memory set
+pub const PKEY_NUM: usize = 16;
+
+/// A file whose contents areas are loaded from on demand
+pub trait PageSource: Send + Sync {
+    /// Read at `offset` into `buf`, returning how much was read
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize;
+}
+
+/// The file part of a lazily allocated area
+#[derive(Clone)]
+struct FileBacking {
+    source: Arc<dyn PageSource>,
+    /// Offset in the file of the first page of the area
+    offset: usize,
+    /// Offset in the file where the data ends, the rest being zero
+    end: usize,
+}
+
 lazy_static! {
+    /// The frame mapped read-only at every page of an anonymous lazy area
+    /// that has only been read so far
+    static ref ZERO_FRAME: FrameTracker = frame_alloc().unwrap();
     /// a memory set instance through lazy_static! managing kernel space
     pub static ref KERNEL_SPACE: Arc<UPSafeCell<MemorySet>> =
         Arc::new(unsafe { UPSafeCell::new(MemorySet::new_kernel()) });
 }
 
+/// Physical page number of the shared zero frame
+pub fn zero_frame_ppn() -> PhysPageNum {
+    ZERO_FRAME.ppn
+}
+
 /// Get the token of the kernel memory space
 pub fn kernel_token() -> usize {
     KERNEL_SPACE.exclusive_access().token()
@@ -39,14 +70,53 @@ pub fn kernel_token() -> usize {
 /// memory set structure, controls virtual-memory space
 pub struct MemorySet {
     page_table: PageTable,
-    areas: Vec<MapArea>,
+    /// The areas by their first page
+    areas: BTreeMap<VirtPageNum, MapArea>,
+    /// Top of the user stack
+    stack_top: usize,
+    /// Lowest address the user stack may grow down to, with a guard page
+    /// below it
+    stack_floor: usize,
+    /// Start of the user heap, right above the user stack
+    heap_bottom: usize,
+    /// Current program break, the end of the user heap
+    brk: usize,
+    /// Allocated protection keys, key 0 being the default of every area
+    pkeys: u16,
+    /// Where the next scan for pages to swap out starts
+    clock_hand: VirtPageNum,
 }
 
 impl MemorySet {
-    pub fn new_bare() -> Self {
+    /// An empty address space, None if there is no frame for its page
+    /// table
+    pub fn new_bare() -> Option<Self> {
+        Some(Self {
+            page_table: PageTable::new()?,
+            areas: BTreeMap::new(),
+            stack_top: 0,
+            stack_floor: 0,
+            heap_bottom: 0,
+            brk: 0,
+            pkeys: 1,
+            clock_hand: VirtPageNum(0),
+        })
+    }
+    /// An address space without user pages, for kernel threads and tasks
+    /// whose own has been given away or freed
+    ///
+    /// It shares the page table of kernel space, so getting one needs no
+    /// frame and never fails.
+    pub fn new_empty() -> Self {
         Self {
-            page_table: PageTable::new(),
-            areas: Vec::new(),
+            page_table: PageTable::from_token(kernel_token()),
+            areas: BTreeMap::new(),
+            stack_top: 0,
+            stack_floor: 0,
+            heap_bottom: 0,
+            brk: 0,
+            pkeys: 1,
+            clock_hand: VirtPageNum(0),
         }
     }
     pub fn token(&self) -> usize {
@@ -65,63 +135,551 @@ impl MemorySet {
         )
     }
 
+    /// Map a lazy area of `len` bytes at `addr` and return its start. With
+    /// `fixed`, whatever user pages are in the way are unmapped first;
+    /// otherwise, if `addr` is 0 or taken, the kernel picks a free range.
+    pub fn mmap(
+        &mut self,
+        addr: VirtAddr,
+        len: usize,
+        permission: MapPermission,
+        fixed: bool,
+    ) -> Option<VirtAddr> {
+        let pages = VirtAddr::from(len).ceil().0;
+        if pages == 0 {
+            return None;
+        }
+        let mut start_vpn = addr.floor();
+        let end_vpn = VirtPageNum(start_vpn.0 + pages);
+        let wanted = MapArea::new(start_vpn.into(), end_vpn.into(), MapType::Framed, permission);
+        if fixed {
+            if self
+                .overlapping(start_vpn, end_vpn)
+                .any(|area| !area.map_perm.contains(MapPermission::U))
+            {
+                return None;
+            }
+            self.unmap_range(start_vpn, end_vpn);
+        } else if addr.0 == 0
+            || end_vpn > VirtAddr::from(MMAP_TOP).floor()
+            || self.overlaps(&wanted)
+        {
+            start_vpn = self.find_free_range(pages)?;
+        }
+        let end_va = VirtPageNum(start_vpn.0 + pages).into();
+        if self.insert_lazy_area(start_vpn.into(), end_va, permission) == -1 {
+            return None;
+        }
+        Some(start_vpn.into())
+    }
+    /// Insert a framed area whose pages get frames on first touch, see
+    /// [`MemorySet::fault_in`]; fails if it overlaps another area
+    pub fn insert_lazy_area(
+        &mut self,
+        start_va: VirtAddr,
+        end_va: VirtAddr,
+        permission: MapPermission,
+    ) -> isize {
+        let mut area = MapArea::new(start_va, end_va, MapType::Framed, permission);
+        if self.overlaps(&area) {
+            return -1;
+        }
+        area.lazy = true;
+        self.push(area, None)
+    }
+    /// Whether `area` overlaps one of the areas
+    fn overlaps(&self, area: &MapArea) -> bool {
+        self.overlapping(area.vpn_range.get_start(), area.vpn_range.get_end())
+            .next()
+            .is_some()
+    }
+    /// The areas overlapping a range, in address order
+    fn overlapping(
+        &self,
+        start_vpn: VirtPageNum,
+        end_vpn: VirtPageNum,
+    ) -> impl Iterator<Item = &MapArea> {
+        let first = self.first_overlapping(start_vpn);
+        self.areas.range(first..end_vpn.max(first)).map(|(_, area)| area)
+    }
+    /// Start of the first area that may overlap a range from `start_vpn`
+    /// on: as areas do not overlap, only the last one starting below it may
+    /// reach into it
+    fn first_overlapping(&self, start_vpn: VirtPageNum) -> VirtPageNum {
+        match self.areas.range(..start_vpn).next_back() {
+            Some((&start, area)) if start_vpn < area.vpn_range.get_end() => start,
+            _ => start_vpn,
+        }
+    }
+    /// The area holding the page at `vpn`
+    fn area_containing(&self, vpn: VirtPageNum) -> Option<&MapArea> {
+        self.areas
+            .range(..=vpn)
+            .next_back()
+            .map(|(_, area)| area)
+            .filter(|area| vpn < area.vpn_range.get_end())
+    }
+    /// Whether `vpn` is in an area of the process, and not in a page every
+    /// process maps like the time page
+    pub fn in_area(&self, vpn: VirtPageNum) -> bool {
+        self.area_containing(vpn).is_some()
+    }
+
+    /// Unmap the user pages in a range, splitting the areas it covers only
+    /// part of; fails if part of the range is not mapped
     pub fn remove_framed_area(
         &mut self,
         start_va: VirtAddr,
         end_va: VirtAddr,
     ) -> isize {
-        let start_vpn = start_va.floor();
-        let end_vpn = end_va.ceil();
-        let mut len = 0;
-        if let Some((idx, area)) = self
-            .areas
-            .iter_mut()
-            .enumerate()
-            .find(|(_, area)|
-                usize::from(start_vpn) <= usize::from(area.vpn_range.get_start())
-                    && usize::from(area.vpn_range.get_end()) <= usize::from(end_vpn)) {
-            len += usize::from(area.vpn_range.get_end()) - usize::from(area.vpn_range.get_start());
-            area.unmap(&mut self.page_table);
-            self.areas.remove(idx);
+        let (start_vpn, end_vpn) = (start_va.floor(), end_va.ceil());
+        if !self.covers_user_range(start_vpn, end_vpn) {
+            return -1;
         }
-        return if len == usize::from(end_vpn) - usize::from(start_vpn) {
-            0
-        } else {
-            -1
+        self.unmap_range(start_vpn, end_vpn);
+        0
+    }
+    /// Unmap whatever areas or parts of them lie in a range
+    fn unmap_range(&mut self, start_vpn: VirtPageNum, end_vpn: VirtPageNum) {
+        self.split_at(start_vpn);
+        self.split_at(end_vpn);
+        let starts: Vec<VirtPageNum> = self.areas.range(start_vpn..end_vpn).map(|(start, _)| *start).collect();
+        for start in starts {
+            let mut area = self.areas.remove(&start).unwrap();
+            area.unmap(&mut self.page_table);
         }
     }
 
     pub fn remove_area_with_start_vpn(&mut self, start_vpn: VirtPageNum) {
-        if let Some((idx, area)) = self
-            .areas
-            .iter_mut()
-            .enumerate()
-            .find(|(_, area)| area.vpn_range.get_start() == start_vpn) {
+        if let Some(mut area) = self.areas.remove(&start_vpn) {
             area.unmap(&mut self.page_table);
-            self.areas.remove(idx);
         }
     }
+    /// Get the program break
+    pub fn brk(&self) -> usize {
+        self.brk
+    }
+    /// Move the program break, growing or shrinking the heap area.
+    /// Fails if the heap would go below its bottom or run into another area.
+    pub fn set_brk(&mut self, new_brk: usize) -> bool {
+        if new_brk < self.heap_bottom || new_brk > TIME_PAGE {
+            return false;
+        }
+        let heap_start_vpn = VirtAddr::from(self.heap_bottom).floor();
+        let new_end_vpn = VirtAddr::from(new_brk).ceil();
+        // the heap area is there only while the heap is not empty
+        let heap_end_vpn = self
+            .areas
+            .get(&heap_start_vpn)
+            .map_or(heap_start_vpn, |area| area.vpn_range.get_end());
+        if new_end_vpn > heap_end_vpn && self.overlapping(heap_end_vpn, new_end_vpn).next().is_some() {
+            return false;
+        }
+        match self.areas.get_mut(&heap_start_vpn) {
+            Some(area) if new_end_vpn > heap_end_vpn => {
+                if area.append_to(&mut self.page_table, new_end_vpn) == -1 {
+                    return false;
+                }
+            }
+            Some(area) => {
+                area.shrink_to(&mut self.page_table, new_end_vpn);
+                if new_end_vpn == heap_start_vpn {
+                    self.areas.remove(&heap_start_vpn);
+                }
+            }
+            None if new_end_vpn > heap_start_vpn => {
+                let heap = MapArea::new(
+                    heap_start_vpn.into(),
+                    new_end_vpn.into(),
+                    MapType::Framed,
+                    MapPermission::R | MapPermission::W | MapPermission::U,
+                );
+                if self.push(heap, None) == -1 {
+                    return false;
+                }
+            }
+            None => {}
+        }
+        self.brk = new_brk;
+        true
+    }
+    /// Handle a user page fault at `addr`, by giving a lazily allocated
+    /// page its frame or by growing the user stack; `write` tells a store
+    /// from a load or an instruction fetch
+    pub fn handle_page_fault(&mut self, addr: usize, write: bool) -> bool {
+        let vpn = VirtAddr::from(addr).floor();
+        // a store to the zero page of a read-only area stays a fault
+        if write
+            && self.area_containing(vpn).map_or(false, |area| {
+                area.zero_pages.contains(&vpn) && !area.map_perm.contains(MapPermission::W)
+            })
+        {
+            return false;
+        }
+        self.fault_in(vpn, write) || self.grow_stack(addr)
+    }
+    /// Map a frame at `vpn` if it lies in a lazily allocated area and has
+    /// not been touched yet, or has only been read
+    ///
+    /// A page of an anonymous area read first gets the shared zero frame,
+    /// read-only; it gets a frame of its own when `write` is set.
+    pub fn fault_in(&mut self, vpn: VirtPageNum, write: bool) -> bool {
+        let area = match self.areas.range_mut(..=vpn).next_back() {
+            Some((_, area)) if area.lazy && vpn < area.vpn_range.get_end() => area,
+            _ => return false,
+        };
+        if area.zero_pages.contains(&vpn) {
+            if !write {
+                return false;
+            }
+            area.unmap_one(&mut self.page_table, vpn);
+            return area.map_one(&mut self.page_table, vpn) == 0;
+        }
+        if area.data_frames.contains_key(&vpn) {
+            return false;
+        }
+        if !write && area.backing.is_none() && !area.swapped.contains_key(&vpn) {
+            return area.map_zero(&mut self.page_table, vpn) == 0;
+        }
+        if area.map_one(&mut self.page_table, vpn) == -1 {
+            return false;
+        }
+        if let Some(slot) = area.swapped.remove(&vpn) {
+            slot.read(area.data_frames[&vpn].ppn);
+        } else if let Some(backing) = &area.backing {
+            let offset = backing.offset + (vpn.0 - area.vpn_range.get_start().0) * PAGE_SIZE;
+            if offset < backing.end {
+                let len = (backing.end - offset).min(PAGE_SIZE);
+                let page = area.data_frames[&vpn].ppn.get_bytes_array();
+                backing.source.read_at(offset, &mut page[..len]);
+            }
+        }
+        true
+    }
+    /// Swap out up to `count` resident pages of the lazy areas, returning
+    /// how many were swapped out
+    ///
+    /// This is the clock algorithm: pages are scanned in address order from
+    /// where the last scan stopped, and a page accessed since it was last
+    /// scanned is passed over once.
+    pub fn swap_out_cold(&mut self, count: usize) -> usize {
+        let pages: Vec<(VirtPageNum, VirtPageNum)> = self
+            .areas
+            .iter()
+            .filter(|(_, area)| area.lazy)
+            .flat_map(|(start, area)| area.data_frames.keys().map(move |vpn| (*start, *vpn)))
+            .collect();
+        let hand = pages
+            .iter()
+            .position(|(_, vpn)| *vpn >= self.clock_hand)
+            .unwrap_or(0);
+        let mut swapped = 0;
+        for &(start, vpn) in pages.iter().cycle().skip(hand).take(pages.len() * 2) {
+            if swapped == count {
+                break;
+            }
+            self.clock_hand = VirtPageNum(vpn.0 + 1);
+            let area = self.areas.get_mut(&start).unwrap();
+            if !area.data_frames.contains_key(&vpn) || self.page_table.take_accessed(vpn) {
+                continue;
+            }
+            let slot = match swap_out(area.data_frames[&vpn].ppn) {
+                Some(slot) => slot,
+                None => break,
+            };
+            area.unmap_one(&mut self.page_table, vpn);
+            area.swapped.insert(vpn, slot);
+            swapped += 1;
+        }
+        swapped
+    }
+    /// Grow the user stack down to cover the faulting address `addr`.
+    /// Fails if `addr` is out of the room left for the stack, which includes
+    /// the guard page, or if another area is in the way.
+    pub fn grow_stack(&mut self, addr: usize) -> bool {
+        if addr < self.stack_floor || addr >= self.stack_top {
+            return false;
+        }
+        let stack_floor_vpn = VirtAddr::from(self.stack_floor).floor();
+        let stack_end_vpn = VirtAddr::from(self.stack_top).floor();
+        let new_start_vpn = VirtAddr::from(addr).floor();
+        // mprotect may have split the stack into pieces lying end to end,
+        // the lowest of which grows
+        let mut stack_start_vpn = stack_end_vpn;
+        while let Some((&start, _)) = self
+            .areas
+            .range(stack_floor_vpn..stack_start_vpn)
+            .next_back()
+            .filter(|(_, area)| area.vpn_range.get_end() == stack_start_vpn)
+        {
+            stack_start_vpn = start;
+        }
+        if stack_start_vpn == stack_end_vpn
+            || new_start_vpn >= stack_start_vpn
+            || self.overlapping(new_start_vpn, stack_start_vpn).next().is_some()
+        {
+            return false;
+        }
+        let area = self.areas.get_mut(&stack_start_vpn).unwrap();
+        if area.prepend_to(&mut self.page_table, new_start_vpn) == -1 {
+            return false;
+        }
+        let area = self.areas.remove(&stack_start_vpn).unwrap();
+        self.areas.insert(new_start_vpn, area);
+        true
+    }
+    /// Allocate a protection key to tag a group of areas with
+    pub fn pkey_alloc(&mut self) -> Option<usize> {
+        let pkey = (!self.pkeys).trailing_zeros() as usize;
+        if pkey >= PKEY_NUM {
+            return None;
+        }
+        self.pkeys |= 1 << pkey;
+        Some(pkey)
+    }
+    /// Free a protection key, the areas tagged with it keep their protection
+    pub fn pkey_free(&mut self, pkey: usize) -> bool {
+        if pkey == 0 || pkey >= PKEY_NUM || self.pkeys & 1 << pkey == 0 {
+            return false;
+        }
+        self.pkeys &= !(1 << pkey);
+        for area in self.areas.values_mut().filter(|area| area.pkey == pkey) {
+            area.pkey = 0;
+        }
+        true
+    }
+    /// Resize the lazy area part `[old_start, old_start + old_len)`, in
+    /// place if the pages above it are free or else, with `may_move`, by
+    /// moving its pages to a free range; returns its new start
+    pub fn mremap(
+        &mut self,
+        old_start: VirtAddr,
+        old_len: usize,
+        new_len: usize,
+        may_move: bool,
+    ) -> Option<VirtAddr> {
+        let start_vpn = old_start.floor();
+        let old_end_vpn = VirtAddr::from(old_start.0 + old_len).ceil();
+        let new_pages = VirtAddr::from(new_len).ceil().0;
+        self.area_containing(start_vpn)
+            .filter(|area| area.lazy && old_end_vpn <= area.vpn_range.get_end())?;
+        self.split_at(start_vpn);
+        self.split_at(old_end_vpn);
+        let new_end_vpn = VirtPageNum(start_vpn.0 + new_pages);
+        if new_end_vpn <= old_end_vpn {
+            let area = self.areas.get_mut(&start_vpn).unwrap();
+            area.shrink_to(&mut self.page_table, new_end_vpn);
+            return Some(start_vpn.into());
+        }
+        let grown = MapArea::new(old_end_vpn.into(), new_end_vpn.into(), MapType::Framed, MapPermission::U);
+        if new_end_vpn.0 <= VirtAddr::from(MMAP_TOP).floor().0 && !self.overlaps(&grown) {
+            // a lazy area maps nothing as it grows, so it cannot fail
+            let area = self.areas.get_mut(&start_vpn).unwrap();
+            area.append_to(&mut self.page_table, new_end_vpn);
+            return Some(start_vpn.into());
+        }
+        if !may_move {
+            return None;
+        }
+        let new_start_vpn = self.find_free_range(new_pages)?;
+        let mut area = self.areas.remove(&start_vpn).unwrap();
+        area.move_to(&mut self.page_table, new_start_vpn);
+        area.append_to(&mut self.page_table, VirtPageNum(new_start_vpn.0 + new_pages));
+        self.areas.insert(new_start_vpn, area);
+        unsafe {
+            core::arch::asm!("sfence.vma");
+        }
+        Some(new_start_vpn.into())
+    }
+    /// Find `pages` free pages between the heap and [`MMAP_TOP`], as high
+    /// as possible so as to leave room for the heap to grow
+    fn find_free_range(&self, pages: usize) -> Option<VirtPageNum> {
+        let bottom = VirtAddr::from(self.brk).ceil().0;
+        let mut end = VirtAddr::from(MMAP_TOP).floor().0;
+        while end >= bottom + pages {
+            let start = end - pages;
+            match self
+                .overlapping(VirtPageNum(start), VirtPageNum(end))
+                .next()
+                .map(|area| area.vpn_range.get_start().0)
+            {
+                Some(lowest) => end = lowest,
+                None => return Some(VirtPageNum(start)),
+            }
+        }
+        None
+    }
+    /// Set the protection of the user areas in a range, splitting the areas
+    /// it covers only part of; fails if part of the range is not mapped
+    pub fn mprotect(&mut self, start_va: VirtAddr, end_va: VirtAddr, perm: MapPermission) -> bool {
+        self.protect_range(start_va.floor(), end_va.ceil(), perm, None)
+    }
+    /// Like [`MemorySet::mprotect`], also tagging the areas with `pkey`
+    pub fn pkey_mprotect(
+        &mut self,
+        start_va: VirtAddr,
+        end_va: VirtAddr,
+        perm: MapPermission,
+        pkey: usize,
+    ) -> bool {
+        if pkey >= PKEY_NUM || self.pkeys & 1 << pkey == 0 {
+            return false;
+        }
+        self.protect_range(start_va.floor(), end_va.ceil(), perm, Some(pkey))
+    }
+    fn protect_range(
+        &mut self,
+        start_vpn: VirtPageNum,
+        end_vpn: VirtPageNum,
+        perm: MapPermission,
+        pkey: Option<usize>,
+    ) -> bool {
+        if !self.covers_user_range(start_vpn, end_vpn) {
+            return false;
+        }
+        self.split_at(start_vpn);
+        self.split_at(end_vpn);
+        for area in self.areas.range_mut(start_vpn..end_vpn).map(|(_, area)| area) {
+            if let Some(pkey) = pkey {
+                area.pkey = pkey;
+            }
+            area.set_perm(&mut self.page_table, perm);
+        }
+        unsafe {
+            core::arch::asm!("sfence.vma");
+        }
+        true
+    }
+    /// Drop the frames and swap slots of the user pages in a range, which
+    /// read as zero or as their file again when next touched; fails if
+    /// part of the range is not mapped
+    ///
+    /// An area allocated up front, like the heap, is lazily allocated from
+    /// then on.
+    pub fn discard_range(&mut self, start_va: VirtAddr, end_va: VirtAddr) -> bool {
+        let (start_vpn, end_vpn) = (start_va.floor(), end_va.ceil());
+        if !self.covers_user_range(start_vpn, end_vpn) {
+            return false;
+        }
+        let first = self.first_overlapping(start_vpn);
+        for area in self.areas.range_mut(first..end_vpn).map(|(_, area)| area) {
+            area.lazy = true;
+            let start = area.vpn_range.get_start().max(start_vpn);
+            let end = area.vpn_range.get_end().min(end_vpn);
+            for vpn in VPNRange::new(start, end) {
+                if area.is_touched(vpn) {
+                    area.unmap_one(&mut self.page_table, vpn);
+                }
+                area.swapped.remove(&vpn);
+            }
+        }
+        unsafe {
+            core::arch::asm!("sfence.vma");
+        }
+        true
+    }
+    /// Read in the pages in a range that are in a file or in the swap area
+    /// and not resident yet; fails if part of the range is not mapped
+    ///
+    /// This is only a hint, so it stops at the low watermark of free frames
+    /// rather than make room.
+    pub fn prefetch_range(&mut self, start_va: VirtAddr, end_va: VirtAddr) -> bool {
+        let (start_vpn, end_vpn) = (start_va.floor(), end_va.ceil());
+        if !self.covers_user_range(start_vpn, end_vpn) {
+            return false;
+        }
+        let pages: Vec<VirtPageNum> = self
+            .overlapping(start_vpn, end_vpn)
+            .filter(|area| area.lazy)
+            .flat_map(|area| {
+                let start = area.vpn_range.get_start().max(start_vpn);
+                let end = area.vpn_range.get_end().min(end_vpn);
+                VPNRange::new(start, end).into_iter().filter(move |vpn| {
+                    !area.is_touched(*vpn)
+                        && (area.backing.is_some() || area.swapped.contains_key(vpn))
+                })
+            })
+            .collect();
+        for vpn in pages {
+            if frames_free() <= FRAMES_LOW || !self.fault_in(vpn, false) {
+                break;
+            }
+        }
+        true
+    }
+    /// Write the changed pages in a range back to their file; fails if part
+    /// of the range is not mapped
+    ///
+    /// File-backed areas are private copies, so nothing is written back.
+    pub fn sync_range(&self, start_va: VirtAddr, end_va: VirtAddr) -> bool {
+        self.covers_user_range(start_va.floor(), end_va.ceil())
+    }
+    /// Whether every page in a range belongs to a user area
+    fn covers_user_range(&self, start_vpn: VirtPageNum, end_vpn: VirtPageNum) -> bool {
+        // areas never overlap, so the range has no hole iff they cover it all
+        let mut covered = 0;
+        for area in self.overlapping(start_vpn, end_vpn) {
+            if !area.map_perm.contains(MapPermission::U) {
+                return false;
+            }
+            covered += area.vpn_range.get_end().min(end_vpn).0
+                - area.vpn_range.get_start().max(start_vpn).0;
+        }
+        covered == end_vpn.0 - start_vpn.0
+    }
+    /// Split the area strictly containing `vpn` in two at `vpn`
+    fn split_at(&mut self, vpn: VirtPageNum) {
+        if let Some((_, area)) = self.areas.range_mut(..vpn).next_back() {
+            if vpn < area.vpn_range.get_end() {
+                let upper = area.split_off(vpn);
+                self.areas.insert(vpn, upper);
+            }
+        }
+    }
+    /// Set the protection of every area tagged with `pkey` at once
+    pub fn pkey_set(&mut self, pkey: usize, perm: MapPermission) -> bool {
+        if pkey == 0 || pkey >= PKEY_NUM || self.pkeys & 1 << pkey == 0 {
+            return false;
+        }
+        for area in self.areas.values_mut().filter(|area| area.pkey == pkey) {
+            area.set_perm(&mut self.page_table, perm);
+        }
+        true
+    }
+    /// Map an area and add it, unless it is empty
     fn push(&mut self, mut map_area: MapArea, data: Option<&[u8]>) -> isize {
+        let start = map_area.vpn_range.get_start();
+        if start == map_area.vpn_range.get_end() {
+            return 0;
+        }
         if map_area.map(&mut self.page_table) == -1 {
             return -1;
         }
         if let Some(data) = data {
             map_area.copy_data(&mut self.page_table, data);
         }
-        self.areas.push(map_area);
+        self.areas.insert(start, map_area);
         0
     }
     /// Mention that trampoline is not collected by areas.
-    fn map_trampoline(&mut self) {
+    fn map_trampoline(&mut self) -> isize {
         self.page_table.map(
             VirtAddr::from(TRAMPOLINE).into(),
             PhysAddr::from(strampoline as usize).into(),
             PTEFlags::R | PTEFlags::X,
-        );
+        )
+    }
+    /// Mention that the time page is shared and not collected by areas.
+    fn map_time_page(&mut self) -> isize {
+        self.page_table.map(
+            VirtAddr::from(TIME_PAGE).into(),
+            time_page_ppn(),
+            PTEFlags::R | PTEFlags::U,
+        )
     }
     /// Without kernel stacks.
     pub fn new_kernel() -> Self {
-        let mut memory_set = Self::new_bare();
+        let mut memory_set = Self::new_bare().unwrap();
         // map trampoline
         memory_set.map_trampoline();
         // map kernel sections
@@ -195,22 +753,32 @@ impl MemorySet {
         }
         memory_set
     }
-    /// Include sections in elf and trampoline and TrapContext and user stack,
-    /// also returns user_sp and entry point.
-    pub fn from_elf(elf_data: &[u8]) -> (Self, usize, usize) {
-        let mut memory_set = Self::new_bare();
-        // map trampoline
-        memory_set.map_trampoline();
+    /// Include sections in elf and trampoline and TrapContext and a user stack
+    /// which may grow to `stack_size` bytes, also returns user_sp and entry point, or None if `elf` is not a
+    /// valid elf.
+    ///
+    /// Only the headers are read here, the pages of the segments are read
+    /// from `elf` when first touched.
+    pub fn from_elf(elf: Arc<dyn PageSource>, stack_size: usize) -> Option<(Self, usize, usize)> {
+        let mut memory_set = Self::new_bare()?;
+        // map trampoline and time page
+        if memory_set.map_trampoline() == -1 || memory_set.map_time_page() == -1 {
+            return None;
+        }
         // map program headers of elf, with U flag
-        let elf = xmas_elf::ElfFile::new(elf_data).unwrap();
+        let headers = read_elf_headers(elf.as_ref())?;
+        let source = elf;
+        let elf = xmas_elf::ElfFile::new(&headers).ok()?;
         let elf_header = elf.header;
         let magic = elf_header.pt1.magic;
-        assert_eq!(magic, [0x7f, 0x45, 0x4c, 0x46], "invalid elf!");
+        if magic != [0x7f, 0x45, 0x4c, 0x46] {
+            return None;
+        }
         let ph_count = elf_header.pt2.ph_count();
         let mut max_end_vpn = VirtPageNum(0);
         for i in 0..ph_count {
-            let ph = elf.program_header(i).unwrap();
-            if ph.get_type().unwrap() == xmas_elf::program::Type::Load {
+            let ph = elf.program_header(i).ok()?;
+            if ph.get_type().ok()? == xmas_elf::program::Type::Load {
                 let start_va: VirtAddr = (ph.virtual_addr() as usize).into();
                 let end_va: VirtAddr = ((ph.virtual_addr() + ph.mem_size()) as usize).into();
                 let mut map_perm = MapPermission::U;
@@ -224,12 +792,18 @@ impl MemorySet {
                 if ph_flags.is_execute() {
                     map_perm |= MapPermission::X;
                 }
-                let map_area = MapArea::new(start_va, end_va, MapType::Framed, map_perm);
+                let mut map_area = MapArea::new(start_va, end_va, MapType::Framed, map_perm);
                 max_end_vpn = map_area.vpn_range.get_end();
-                memory_set.push(
-                    map_area,
-                    Some(&elf.input[ph.offset() as usize..(ph.offset() + ph.file_size()) as usize]),
-                );
+                let offset = (ph.offset() as usize).checked_sub(start_va.page_offset())?;
+                map_area.lazy = true;
+                map_area.backing = Some(FileBacking {
+                    source: Arc::clone(&source),
+                    offset,
+                    end: (ph.offset() + ph.file_size()) as usize,
+                });
+                if memory_set.overlaps(&map_area) || memory_set.push(map_area, None) == -1 {
+                    return None;
+                }
             }
         }
         // map user stack with U flags
@@ -237,43 +811,82 @@ impl MemorySet {
         let mut user_stack_bottom: usize = max_end_va.into();
         // guard page
         user_stack_bottom += PAGE_SIZE;
-        let user_stack_top = user_stack_bottom + USER_STACK_SIZE;
-        memory_set.push(
-            MapArea::new(
-                user_stack_bottom.into(),
-                user_stack_top.into(),
-                MapType::Framed,
-                MapPermission::R | MapPermission::W | MapPermission::U,
-            ),
-            None,
+        let user_stack_top = user_stack_bottom + stack_size;
+        // the rest is left for the stack to grow into on page faults
+        memory_set.stack_top = user_stack_top;
+        memory_set.stack_floor = user_stack_bottom;
+        let stack_area = MapArea::new(
+            (user_stack_top - stack_size.min(USER_STACK_SIZE)).into(),
+            user_stack_top.into(),
+            MapType::Framed,
+            MapPermission::R | MapPermission::W | MapPermission::U,
         );
+        if memory_set.push(stack_area, None) == -1 {
+            return None;
+        }
+        // the heap starts empty right above the user stack, grown by brk
+        memory_set.heap_bottom = user_stack_top;
+        memory_set.brk = user_stack_top;
         // map TrapContext
-        memory_set.push(
-            MapArea::new(
-                TRAP_CONTEXT.into(),
-                TRAMPOLINE.into(),
-                MapType::Framed,
-                MapPermission::R | MapPermission::W,
-            ),
-            None,
+        let trap_cx_area = MapArea::new(
+            TRAP_CONTEXT.into(),
+            TRAMPOLINE.into(),
+            MapType::Framed,
+            MapPermission::R | MapPermission::W,
         );
-        (
+        if memory_set.push(trap_cx_area, None) == -1 {
+            return None;
+        }
+        Some((
             memory_set,
             user_stack_top,
             elf.header.pt2.entry_point() as usize,
-        )
+        ))
     }
-    /// Copy an identical user_space
-    pub fn from_existed_user(user_space: &MemorySet) -> MemorySet {
-        let mut memory_set = Self::new_bare();
-        // map trampoline
-        memory_set.map_trampoline();
-        // copy data sections/trap_context/user_stack
-        for area in user_space.areas.iter() {
+    /// Copy an identical user_space, None if frames run out
+    pub fn from_existed_user(user_space: &MemorySet) -> Option<MemorySet> {
+        let mut memory_set = Self::new_bare()?;
+        // map trampoline and time page
+        if memory_set.map_trampoline() == -1 || memory_set.map_time_page() == -1 {
+            return None;
+        }
+        memory_set.stack_top = user_space.stack_top;
+        memory_set.stack_floor = user_space.stack_floor;
+        memory_set.heap_bottom = user_space.heap_bottom;
+        memory_set.brk = user_space.brk;
+        memory_set.pkeys = user_space.pkeys;
+        // copy data sections/trap_context/user_stack/heap
+        for (start, area) in user_space.areas.iter() {
             let new_area = MapArea::from_another(area);
-            memory_set.push(new_area, None);
+            if memory_set.push(new_area, None) == -1 {
+                return None;
+            }
+            // a lazy area has frames only for the pages touched so far, and
+            // those swapped out are read back for the copy
+            if area.lazy {
+                let new_area = memory_set.areas.get_mut(start).unwrap();
+                for vpn in area.data_frames.keys() {
+                    if new_area.map_one(&mut memory_set.page_table, *vpn) == -1 {
+                        return None;
+                    }
+                }
+                for vpn in area.zero_pages.iter() {
+                    if new_area.map_zero(&mut memory_set.page_table, *vpn) == -1 {
+                        return None;
+                    }
+                }
+                for (vpn, slot) in area.swapped.iter() {
+                    if new_area.map_one(&mut memory_set.page_table, *vpn) == -1 {
+                        return None;
+                    }
+                    slot.read(new_area.data_frames[vpn].ppn);
+                }
+            }
             // copy data from another space
             for vpn in area.vpn_range {
+                if !area.is_touched(vpn) || area.zero_pages.contains(&vpn) {
+                    continue;
+                }
                 let src_ppn = user_space.translate(vpn).unwrap().ppn();
                 let dst_ppn = memory_set.translate(vpn).unwrap().ppn();
                 dst_ppn
@@ -281,7 +894,7 @@ impl MemorySet {
                     .copy_from_slice(src_ppn.get_bytes_array());
             }
         }
-        memory_set
+        Some(memory_set)
     }
     pub fn activate(&self) {
         let satp = self.page_table.token();
@@ -293,18 +906,127 @@ impl MemorySet {
     pub fn translate(&self, vpn: VirtPageNum) -> Option<PageTableEntry> {
         self.page_table.translate(vpn)
     }
+    /// Start, end and permission of the user areas
+    pub fn user_areas(&self) -> Vec<(VirtAddr, VirtAddr, MapPermission)> {
+        self.areas
+            .values()
+            .filter(|area| area.map_perm.contains(MapPermission::U))
+            .map(|area| {
+                (
+                    area.vpn_range.get_start().into(),
+                    area.vpn_range.get_end().into(),
+                    area.map_perm,
+                )
+            })
+            .collect()
+    }
+    /// Bytes of address space taken by the areas
+    pub fn mapped_size(&self) -> usize {
+        self.areas
+            .values()
+            .map(|area| {
+                (area.vpn_range.get_end().0 - area.vpn_range.get_start().0) * PAGE_SIZE
+            })
+            .sum()
+    }
+    /// Number of frames mapped for the areas
+    pub fn resident_pages(&self) -> usize {
+        self.areas.values().map(|area| area.data_frames.len()).sum()
+    }
+    /// Format the user areas like `/proc/<pid>/maps`, with the pages of
+    /// each that are resident, mapped to the zero frame and swapped out
+    pub fn maps(&self) -> String {
+        let mut text = String::new();
+        writeln!(
+            text,
+            "{:<33} {:<4} {:>8} {:>6} {:>6} {:>6} name",
+            "range", "perm", "offset", "rss", "zero", "swap"
+        )
+        .unwrap();
+        for area in self
+            .areas
+            .values()
+            .filter(|area| area.map_perm.contains(MapPermission::U))
+        {
+            let start: VirtAddr = area.vpn_range.get_start().into();
+            let end: VirtAddr = area.vpn_range.get_end().into();
+            let perm = area.map_perm;
+            let name = if area.backing.is_some() {
+                "[file]"
+            } else if start.0 == self.heap_bottom {
+                "[heap]"
+            } else if end.0 == self.stack_top {
+                "[stack]"
+            } else {
+                "[anon]"
+            };
+            writeln!(
+                text,
+                "{:016x}-{:016x} {}{}{}p {:08x} {:>6} {:>6} {:>6} {}",
+                start.0,
+                end.0,
+                if perm.contains(MapPermission::R) { 'r' } else { '-' },
+                if perm.contains(MapPermission::W) { 'w' } else { '-' },
+                if perm.contains(MapPermission::X) { 'x' } else { '-' },
+                area.backing.as_ref().map_or(0, |backing| backing.offset),
+                area.data_frames.len(),
+                area.zero_pages.len(),
+                area.swapped.len(),
+                name
+            )
+            .unwrap();
+        }
+        text
+    }
+    /// Unmap all the user areas, freeing their frames and swap slots
+    pub fn release_user_areas(&mut self) {
+        let page_table = &mut self.page_table;
+        for area in self.areas.values_mut() {
+            if area.map_perm.contains(MapPermission::U) {
+                area.unmap(page_table);
+            }
+        }
+        self.areas.retain(|_, area| !area.map_perm.contains(MapPermission::U));
+    }
     pub fn recycle_data_pages(&mut self) {
         //*self = Self::new_bare();
         self.areas.clear();
     }
 }
 
+/// Read the ELF header and the program headers at the start of `elf`
+fn read_elf_headers(elf: &dyn PageSource) -> Option<Vec<u8>> {
+    let mut headers = alloc::vec![0u8; PAGE_SIZE];
+    let len = elf.read_at(0, &mut headers);
+    headers.truncate(len);
+    let header = xmas_elf::header::parse_header(&headers).ok()?;
+    let end = header.pt2.ph_offset() as usize
+        + header.pt2.ph_count() as usize * header.pt2.ph_entry_size() as usize;
+    if end > headers.len() {
+        headers.resize(end, 0);
+        if elf.read_at(0, &mut headers) < end {
+            return None;
+        }
+    }
+    Some(headers)
+}
+
 /// map area structure, controls a contiguous piece of virtual memory
 pub struct MapArea {
     vpn_range: VPNRange,
     data_frames: BTreeMap<VirtPageNum, FrameTracker>,
     map_type: MapType,
     map_perm: MapPermission,
+    /// Protection key of the group the area belongs to, 0 if none
+    pkey: usize,
+    /// Frames are allocated on first touch rather than when mapping
+    lazy: bool,
+    /// Where the pages of a lazy area are read from, zeroed if none
+    backing: Option<FileBacking>,
+    /// Pages of a lazy area that are in the swap area
+    swapped: BTreeMap<VirtPageNum, SwapSlot>,
+    /// Pages of a lazy area mapped to the zero frame
+    zero_pages: BTreeSet<VirtPageNum>,
 }
 
 impl MapArea {
@@ -321,6 +1043,11 @@ impl MapArea {
             data_frames: BTreeMap::new(),
             map_type,
             map_perm,
+            pkey: 0,
+            lazy: false,
+            backing: None,
+            swapped: BTreeMap::new(),
+            zero_pages: BTreeSet::new(),
         }
     }
     pub fn from_another(another: &MapArea) -> Self {
@@ -329,22 +1056,93 @@ impl MapArea {
             data_frames: BTreeMap::new(),
             map_type: another.map_type,
             map_perm: another.map_perm,
+            pkey: another.pkey,
+            lazy: another.lazy,
+            backing: another.backing.clone(),
+            swapped: BTreeMap::new(),
+            zero_pages: BTreeSet::new(),
         }
     }
     pub fn map_one(&mut self, page_table: &mut PageTable, vpn: VirtPageNum) -> isize {
         let ppn: PhysPageNum;
+        let mut frame_tracker = None;
         match self.map_type {
             MapType::Identical => {
                 ppn = PhysPageNum(vpn.0);
             }
             MapType::Framed => {
-                let frame = frame_alloc().unwrap();
+                let frame = match frame_alloc() {
+                    Some(frame) => frame,
+                    None => return -1,
+                };
                 ppn = frame.ppn;
-                self.data_frames.insert(vpn, frame);
+                frame_tracker = Some(frame);
             }
         }
         let pte_flags = PTEFlags::from_bits(self.map_perm.bits).unwrap();
-        page_table.map(vpn, ppn, pte_flags)
+        if page_table.map(vpn, ppn, pte_flags) == -1 {
+            return -1;
+        }
+        // the frame belongs to the area only once it is mapped
+        if let Some(frame) = frame_tracker {
+            self.data_frames.insert(vpn, frame);
+        }
+        // an inaccessible area, see PageTable::set_flags
+        if !self.map_perm.intersects(MapPermission::R | MapPermission::W | MapPermission::X) {
+            page_table.set_flags(vpn, pte_flags);
+        }
+        0
+    }
+    /// Map the zero frame at `vpn`, read-only whatever the protection
+    fn map_zero(&mut self, page_table: &mut PageTable, vpn: VirtPageNum) -> isize {
+        let pte_flags = PTEFlags::from_bits((self.map_perm - MapPermission::W).bits).unwrap();
+        if page_table.map(vpn, zero_frame_ppn(), pte_flags) == -1 {
+            return -1;
+        }
+        if !self.map_perm.intersects(MapPermission::R | MapPermission::W | MapPermission::X) {
+            page_table.set_flags(vpn, pte_flags);
+        }
+        self.zero_pages.insert(vpn);
+        0
+    }
+    /// Change the protection of the area and of its pages
+    pub fn set_perm(&mut self, page_table: &mut PageTable, perm: MapPermission) {
+        self.map_perm = perm;
+        let pte_flags = PTEFlags::from_bits(perm.bits).unwrap();
+        let zero_flags = PTEFlags::from_bits((perm - MapPermission::W).bits).unwrap();
+        for vpn in self.vpn_range {
+            if self.zero_pages.contains(&vpn) {
+                page_table.set_flags(vpn, zero_flags);
+            } else if self.is_touched(vpn) {
+                page_table.set_flags(vpn, pte_flags);
+            }
+        }
+    }
+    /// Split the area at `at`, keeping the pages below it and returning an
+    /// area of the pages from it on
+    pub fn split_off(&mut self, at: VirtPageNum) -> Self {
+        let (start, end) = (self.vpn_range.get_start(), self.vpn_range.get_end());
+        let mut backing = self.backing.clone();
+        if let Some(backing) = &mut backing {
+            backing.offset += (at.0 - start.0) * PAGE_SIZE;
+        }
+        self.vpn_range = VPNRange::new(start, at);
+        Self {
+            vpn_range: VPNRange::new(at, end),
+            data_frames: self.data_frames.split_off(&at),
+            map_type: self.map_type,
+            map_perm: self.map_perm,
+            pkey: self.pkey,
+            lazy: self.lazy,
+            backing,
+            swapped: self.swapped.split_off(&at),
+            zero_pages: self.zero_pages.split_off(&at),
+        }
+    }
+    /// Whether the page at `vpn` is mapped, which a page of a lazy area is
+    /// only once touched and while not swapped out
+    fn is_touched(&self, vpn: VirtPageNum) -> bool {
+        !self.lazy || self.data_frames.contains_key(&vpn) || self.zero_pages.contains(&vpn)
     }
 
     pub fn unmap_one(&mut self, page_table: &mut PageTable, vpn: VirtPageNum) {
@@ -352,23 +1150,127 @@ impl MapArea {
         match self.map_type {
             MapType::Framed => {
                 self.data_frames.remove(&vpn);
+                self.zero_pages.remove(&vpn);
             }
             _ => {}
         }
         page_table.unmap(vpn);
     }
+    /// Map the pages of the area unless lazy, with megapages where an
+    /// identical area allows
     pub fn map(&mut self, page_table: &mut PageTable) -> isize {
-        for vpn in self.vpn_range {
+        if self.lazy {
+            return 0;
+        }
+        let mut vpn = self.vpn_range.get_start();
+        while vpn < self.vpn_range.get_end() {
+            if self.is_megapage(vpn) {
+                let pte_flags = PTEFlags::from_bits(self.map_perm.bits).unwrap();
+                if page_table.map_huge(vpn, PhysPageNum(vpn.0), pte_flags) == -1 {
+                    return -1;
+                }
+                vpn.0 += MEGAPAGE_PAGES;
+                continue;
+            }
             if self.map_one(page_table, vpn) == -1 {
+                // leave no page mapped to the frames freed with the area
+                let mapped: Vec<VirtPageNum> = self.data_frames.keys().copied().collect();
+                for vpn in mapped {
+                    self.unmap_one(page_table, vpn);
+                }
                 return -1;
             }
+            vpn.step();
         }
         0
     }
     pub fn unmap(&mut self, page_table: &mut PageTable) {
+        let mut vpn = self.vpn_range.get_start();
+        while vpn < self.vpn_range.get_end() {
+            if self.is_megapage(vpn) {
+                page_table.unmap_huge(vpn);
+                vpn.0 += MEGAPAGE_PAGES;
+                continue;
+            }
+            if self.is_touched(vpn) {
+                self.unmap_one(page_table, vpn);
+            }
+            vpn.step();
+        }
+        self.swapped.clear();
+    }
+    /// Whether the pages from `vpn` on are mapped as a megapage, which an
+    /// identical area has wherever one fits
+    fn is_megapage(&self, vpn: VirtPageNum) -> bool {
+        self.map_type == MapType::Identical
+            && vpn.0 % MEGAPAGE_PAGES == 0
+            && vpn.0 + MEGAPAGE_PAGES <= self.vpn_range.get_end().0
+    }
+    /// Grow the area up to `new_end`, mapping the new pages unless lazy;
+    /// fails leaving the area as it was if a page cannot be mapped
+    pub fn append_to(&mut self, page_table: &mut PageTable, new_end: VirtPageNum) -> isize {
+        let old_end = self.vpn_range.get_end();
+        if !self.lazy {
+            for vpn in VPNRange::new(old_end, new_end) {
+                if self.map_one(page_table, vpn) == -1 {
+                    for mapped in VPNRange::new(old_end, vpn) {
+                        self.unmap_one(page_table, mapped);
+                    }
+                    return -1;
+                }
+            }
+        }
+        self.vpn_range = VPNRange::new(self.vpn_range.get_start(), new_end);
+        0
+    }
+    /// Grow the area down to `new_start`, mapping the new pages; fails
+    /// leaving the area as it was if a page cannot be mapped
+    pub fn prepend_to(&mut self, page_table: &mut PageTable, new_start: VirtPageNum) -> isize {
+        for vpn in VPNRange::new(new_start, self.vpn_range.get_start()) {
+            if self.map_one(page_table, vpn) == -1 {
+                for mapped in VPNRange::new(new_start, vpn) {
+                    self.unmap_one(page_table, mapped);
+                }
+                return -1;
+            }
+        }
+        self.vpn_range = VPNRange::new(new_start, self.vpn_range.get_end());
+        0
+    }
+    /// Shrink the area down to `new_end`, unmapping the pages past it
+    pub fn shrink_to(&mut self, page_table: &mut PageTable, new_end: VirtPageNum) {
+        for vpn in VPNRange::new(new_end, self.vpn_range.get_end()) {
+            if self.is_touched(vpn) {
+                self.unmap_one(page_table, vpn);
+            }
+        }
+        self.swapped.split_off(&new_end);
+        self.vpn_range = VPNRange::new(self.vpn_range.get_start(), new_end);
+    }
+    /// Move the area to start at `new_start`, moving the page table entries
+    /// of its pages along rather than copying them
+    pub fn move_to(&mut self, page_table: &mut PageTable, new_start: VirtPageNum) {
+        let start = self.vpn_range.get_start();
+        let moved = |vpn: VirtPageNum| VirtPageNum(vpn.0 - start.0 + new_start.0);
         for vpn in self.vpn_range {
-            self.unmap_one(page_table, vpn);
+            if self.is_touched(vpn) {
+                page_table.move_entry(vpn, moved(vpn));
+            }
         }
+        self.data_frames = core::mem::take(&mut self.data_frames)
+            .into_iter()
+            .map(|(vpn, frame)| (moved(vpn), frame))
+            .collect();
+        self.swapped = core::mem::take(&mut self.swapped)
+            .into_iter()
+            .map(|(vpn, slot)| (moved(vpn), slot))
+            .collect();
+        self.zero_pages = core::mem::take(&mut self.zero_pages)
+            .into_iter()
+            .map(moved)
+            .collect();
+        let end = moved(self.vpn_range.get_end());
+        self.vpn_range = VPNRange::new(new_start, end);
     }
     /// data: start-aligned but maybe with shorter length
     /// assume that all frames were cleared before
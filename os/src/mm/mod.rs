@@ -10,20 +10,37 @@
 mod address;
 mod frame_allocator;
 mod heap_allocator;
+#[cfg(feature = "heap-debug")]
+mod heap_debug;
+#[cfg(feature = "heap-poison")]
+mod heap_poison;
 mod memory_set;
 mod page_table;
+mod slab;
+mod swap;
 
 pub use address::{PhysAddr, PhysPageNum, VirtAddr, VirtPageNum};
 pub use address::{StepByOne, VPNRange};
-pub use frame_allocator::{frame_alloc, frame_dealloc, FrameTracker};
-pub use memory_set::{remap_test, kernel_token};
-pub use memory_set::{MapPermission, MemorySet, KERNEL_SPACE};
-pub use page_table::{translated_byte_buffer, translated_refmut, translated_ref, translated_str, PageTableEntry};
-pub use page_table::{PTEFlags, PageTable, UserBuffer};
+pub use frame_allocator::{
+    frame_alloc, frame_alloc_contiguous, frame_dealloc, frames_free, frames_total, scrub_on_free,
+    set_scrub_on_free, FrameBlock, FrameTracker,
+};
+pub use heap_allocator::{heap_stats, heap_usage, try_alloc};
+#[cfg(feature = "heap-poison")]
+pub use heap_poison::poisoned_address;
+pub use memory_set::{remap_test, kernel_token, zero_frame_ppn};
+pub use memory_set::{MapPermission, MemorySet, PageSource, KERNEL_SPACE};
+pub use page_table::{copy_string_from_user, copy_usize_from_user, PageTableEntry};
+pub use page_table::{copy_from_user, copy_to_user, get_user, put_user, user_byte_buffer};
+pub use page_table::{PTEFlags, PageTable, UserBuffer, MEGAPAGE_PAGES};
+pub use slab::slab_stats;
+pub use swap::swap_free;
 
 /// initiate heap allocator, frame allocator and kernel space
 pub fn init() {
     heap_allocator::init_heap();
     frame_allocator::init_frame_allocator();
     KERNEL_SPACE.exclusive_access().activate();
+    // allocated now, as the allocations of a task may be made to fail
+    zero_frame_ppn();
 }
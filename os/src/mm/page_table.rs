@@ -1,6 +1,8 @@
 //! Implementation of [`PageTableEntry`] and [`PageTable`].
 
-use super::{frame_alloc, FrameTracker, PhysAddr, PhysPageNum, StepByOne, VirtAddr, VirtPageNum};
+use super::{frame_alloc, try_alloc, zero_frame_ppn, FrameTracker, PhysAddr, PhysPageNum, StepByOne, VirtAddr, VirtPageNum};
+use crate::syscall::errno::{EFAULT, ENAMETOOLONG};
+use crate::task::current_fault_in;
 use alloc::string::String;
 use alloc::vec;
 use alloc::vec::Vec;
@@ -45,6 +47,11 @@ impl PageTableEntry {
     pub fn is_valid(&self) -> bool {
         (self.flags() & PTEFlags::V) != PTEFlags::empty()
     }
+    /// Whether a page is mapped, including inaccessible pages which are
+    /// kept invalid with their other flags and frame
+    pub fn is_mapped(&self) -> bool {
+        self.bits != 0
+    }
     pub fn readable(&self) -> bool {
         (self.flags() & PTEFlags::R) != PTEFlags::empty()
     }
@@ -54,22 +61,31 @@ impl PageTableEntry {
     pub fn executable(&self) -> bool {
         (self.flags() & PTEFlags::X) != PTEFlags::empty()
     }
+    /// Whether the entry maps memory rather than point to the page table
+    /// of the next level
+    pub fn is_leaf(&self) -> bool {
+        self.is_valid() && self.flags().intersects(PTEFlags::R | PTEFlags::W | PTEFlags::X)
+    }
 }
 
+/// Pages a megapage spans, those mapped by a whole level-2 page table
+pub const MEGAPAGE_PAGES: usize = 512;
+
 /// page table structure
 pub struct PageTable {
     root_ppn: PhysPageNum,
     frames: Vec<FrameTracker>,
 }
 
-/// Assume that it won't oom when creating/mapping.
+/// Creating or mapping fails when a frame for a page table cannot be
+/// allocated.
 impl PageTable {
-    pub fn new() -> Self {
-        let frame = frame_alloc().unwrap();
-        PageTable {
+    pub fn new() -> Option<Self> {
+        let frame = frame_alloc()?;
+        Some(PageTable {
             root_ppn: frame.ppn,
             frames: vec![frame],
-        }
+        })
     }
     /// Temporarily used to get arguments from user space.
     pub fn from_token(satp: usize) -> Self {
@@ -79,17 +95,23 @@ impl PageTable {
         }
     }
     fn find_pte_create(&mut self, vpn: VirtPageNum) -> Option<&mut PageTableEntry> {
+        self.find_pte_create_at(vpn, 2)
+    }
+    /// Find the entry for `vpn` in its page table of `level`, 2 being the
+    /// last, creating the page tables on the way
+    fn find_pte_create_at(&mut self, vpn: VirtPageNum, level: usize) -> Option<&mut PageTableEntry> {
         let mut idxs = vpn.indexes();
         let mut ppn = self.root_ppn;
         let mut result: Option<&mut PageTableEntry> = None;
         for (i, idx) in idxs.iter_mut().enumerate() {
             let pte = &mut ppn.get_pte_array()[*idx];
-            if i == 2 {
+            if i == level {
                 result = Some(pte);
                 break;
             }
+            assert!(!pte.is_leaf(), "vpn {:?} is in a megapage", vpn);
             if !pte.is_valid() {
-                let frame = frame_alloc().unwrap();
+                let frame = frame_alloc()?;
                 *pte = PageTableEntry::new(frame.ppn, PTEFlags::V);
                 self.frames.push(frame);
             }
@@ -97,27 +119,35 @@ impl PageTable {
         }
         result
     }
-    fn find_pte(&self, vpn: VirtPageNum) -> Option<&PageTableEntry> {
+    /// Find the entry mapping `vpn`; for a page in a megapage, an entry
+    /// like the megapage's but for the frame of that page
+    fn find_pte(&self, vpn: VirtPageNum) -> Option<PageTableEntry> {
         let idxs = vpn.indexes();
         let mut ppn = self.root_ppn;
-        let mut result: Option<&PageTableEntry> = None;
         for (i, idx) in idxs.iter().enumerate() {
             let pte = &ppn.get_pte_array()[*idx];
             if i == 2 {
-                result = Some(pte);
-                break;
+                return Some(*pte);
+            }
+            if pte.is_leaf() {
+                let pages = MEGAPAGE_PAGES.pow(2 - i as u32);
+                let ppn = PhysPageNum(pte.ppn().0 + vpn.0 % pages);
+                return Some(PageTableEntry::new(ppn, pte.flags()));
             }
             if !pte.is_valid() {
                 return None;
             }
             ppn = pte.ppn();
         }
-        result
+        None
     }
     #[allow(unused)]
     pub fn map(&mut self, vpn: VirtPageNum, ppn: PhysPageNum, flags: PTEFlags) -> isize {
-        let pte = self.find_pte_create(vpn).unwrap();
-        return if !pte.is_valid() {
+        let pte = match self.find_pte_create(vpn) {
+            Some(pte) => pte,
+            None => return -1,
+        };
+        return if !pte.is_mapped() {
             *pte = PageTableEntry::new(ppn, flags | PTEFlags::V);
             0
         } else {
@@ -128,11 +158,75 @@ impl PageTable {
     #[allow(unused)]
     pub fn unmap(&mut self, vpn: VirtPageNum) {
         let pte = self.find_pte_create(vpn).unwrap();
-        assert!(pte.is_valid(), "vpn {:?} is invalid before unmapping", vpn);
+        assert!(pte.is_mapped(), "vpn {:?} is invalid before unmapping", vpn);
+        *pte = PageTableEntry::empty();
+    }
+    /// Map the megapage at `vpn` to the frames from `ppn`, both aligned to
+    /// [`MEGAPAGE_PAGES`], with a leaf entry in the level-1 page table
+    pub fn map_huge(&mut self, vpn: VirtPageNum, ppn: PhysPageNum, flags: PTEFlags) -> isize {
+        assert!(vpn.0 % MEGAPAGE_PAGES == 0 && ppn.0 % MEGAPAGE_PAGES == 0);
+        let pte = match self.find_pte_create_at(vpn, 1) {
+            Some(pte) => pte,
+            None => return -1,
+        };
+        if pte.is_valid() {
+            println!("vpn {:?} is mapped before mapping", vpn);
+            return -1;
+        }
+        *pte = PageTableEntry::new(ppn, flags | PTEFlags::V);
+        0
+    }
+    /// Unmap the megapage at `vpn`
+    pub fn unmap_huge(&mut self, vpn: VirtPageNum) {
+        let pte = self.find_pte_create_at(vpn, 1).unwrap();
+        assert!(pte.is_leaf(), "vpn {:?} is not a megapage before unmapping", vpn);
         *pte = PageTableEntry::empty();
     }
+    /// Move the mapping at `from` to `to`, which must be unmapped, keeping
+    /// its frame and flags
+    pub fn move_entry(&mut self, from: VirtPageNum, to: VirtPageNum) {
+        let src = self.find_pte_create(from).unwrap();
+        assert!(src.is_mapped(), "vpn {:?} is invalid before moving", from);
+        let pte = *src;
+        *src = PageTableEntry::empty();
+        let dst = self.find_pte_create(to).unwrap();
+        assert!(!dst.is_mapped(), "vpn {:?} is mapped before moving", to);
+        *dst = pte;
+    }
+    /// Change the flags of a mapped page; a page that is neither readable,
+    /// writable nor executable is left invalid so that any access faults
+    pub fn set_flags(&mut self, vpn: VirtPageNum, flags: PTEFlags) {
+        let pte = self.find_pte_create(vpn).unwrap();
+        assert!(pte.is_mapped(), "vpn {:?} is invalid before changing flags", vpn);
+        let flags = if flags.intersects(PTEFlags::R | PTEFlags::W | PTEFlags::X) {
+            flags | PTEFlags::V
+        } else {
+            flags - PTEFlags::V
+        };
+        *pte = PageTableEntry::new(pte.ppn(), flags);
+    }
     pub fn translate(&self, vpn: VirtPageNum) -> Option<PageTableEntry> {
-        self.find_pte(vpn).copied()
+        self.find_pte(vpn)
+    }
+    /// Clear the accessed bit of a mapped page, returning whether it was set
+    ///
+    /// The TLB may keep the old entry until the next `sfence.vma`, which
+    /// only delays the bit being set again.
+    pub fn take_accessed(&mut self, vpn: VirtPageNum) -> bool {
+        let pte = self.find_pte_create(vpn).unwrap();
+        let accessed = pte.flags().contains(PTEFlags::A);
+        pte.bits &= !(PTEFlags::A.bits() as usize);
+        accessed
+    }
+    /// Translate a virtual address the user may read, None if it is not
+    /// mapped or not accessible from user mode
+    pub fn translate_user_va(&self, va: VirtAddr) -> Option<PhysAddr> {
+        let pte = self.find_pte(va.floor())?;
+        if !pte.is_valid() || !pte.readable() || !pte.flags().contains(PTEFlags::U) {
+            return None;
+        }
+        let aligned_pa: PhysAddr = pte.ppn().into();
+        Some((usize::from(aligned_pa) + va.page_offset()).into())
     }
     pub fn translate_va(&self, va: VirtAddr) -> Option<PhysAddr> {
         self.find_pte(va.clone().floor()).map(|pte| {
@@ -149,16 +243,47 @@ impl PageTable {
     }
 }
 
-/// translate a pointer to a mutable u8 Vec through page table
-pub fn translated_byte_buffer(token: usize, ptr: *const u8, len: usize) -> Vec<&'static mut [u8]> {
+/// Make sure the user page at `vpn` is mapped to a frame of its own before
+/// the kernel accesses it, a lazily allocated page of current task getting
+/// its frame here
+///
+/// The kernel writes through physical addresses, so a page mapped to the
+/// zero frame gets its own frame even if it is only to be read.
+fn populate(page_table: &PageTable, token: usize, vpn: VirtPageNum) {
+    let mapped = page_table
+        .translate(vpn)
+        .map_or(false, |pte| pte.is_mapped() && pte.ppn() != zero_frame_ppn());
+    if !mapped {
+        current_fault_in(token, vpn);
+    }
+}
+
+/// Translate `len` bytes at `ptr` into the slices of the pages holding
+/// them, failing with -EFAULT unless every page is mapped for the user to
+/// read, and to write as well if `write` is set
+pub fn user_byte_buffer(
+    token: usize,
+    ptr: *const u8,
+    len: usize,
+    write: bool,
+) -> Result<Vec<&'static mut [u8]>, isize> {
     let page_table = PageTable::from_token(token);
     let mut start = ptr as usize;
-    let end = start + len;
+    let end = start.checked_add(len).ok_or(-EFAULT)?;
     let mut v = Vec::new();
     while start < end {
         let start_va = VirtAddr::from(start);
         let mut vpn = start_va.floor();
-        let ppn = page_table.translate(vpn).unwrap().ppn();
+        populate(&page_table, token, vpn);
+        let pte = page_table.translate(vpn).ok_or(-EFAULT)?;
+        if !pte.is_valid()
+            || !pte.flags().contains(PTEFlags::U)
+            || !pte.readable()
+            || write && !pte.writable()
+        {
+            return Err(-EFAULT);
+        }
+        let ppn = pte.ppn();
         vpn.step();
         let mut end_va: VirtAddr = vpn.into();
         end_va = end_va.min(VirtAddr::from(end));
@@ -169,42 +294,86 @@ pub fn translated_byte_buffer(token: usize, ptr: *const u8, len: usize) -> Vec<&
         }
         start = end_va.into();
     }
-    v
+    Ok(v)
+}
+
+/// Copy `src` to user space at `dst`, -EFAULT if it is not writable by the
+/// user
+pub fn copy_to_user(token: usize, dst: *mut u8, src: &[u8]) -> Result<(), isize> {
+    let mut copied = 0;
+    for chunk in user_byte_buffer(token, dst, src.len(), true)? {
+        chunk.copy_from_slice(&src[copied..copied + chunk.len()]);
+        copied += chunk.len();
+    }
+    Ok(())
+}
+
+/// Fill `dst` from user space at `src`, -EFAULT if it is not readable by
+/// the user
+pub fn copy_from_user(token: usize, src: *const u8, dst: &mut [u8]) -> Result<(), isize> {
+    let mut copied = 0;
+    for chunk in user_byte_buffer(token, src, dst.len(), false)? {
+        dst[copied..copied + chunk.len()].copy_from_slice(chunk);
+        copied += chunk.len();
+    }
+    Ok(())
+}
+
+/// Copy a value to user space, which it may straddle two pages of
+pub fn put_user<T: Copy>(token: usize, ptr: *mut T, value: T) -> Result<(), isize> {
+    let bytes = unsafe {
+        core::slice::from_raw_parts(&value as *const T as *const u8, core::mem::size_of::<T>())
+    };
+    copy_to_user(token, ptr as *mut u8, bytes)
+}
+
+/// Copy a value from user space; any bit pattern must be a valid `T`, as
+/// the user may write anything there
+pub fn get_user<T: Copy>(token: usize, ptr: *const T) -> Result<T, isize> {
+    let mut value = core::mem::MaybeUninit::<T>::uninit();
+    let bytes = unsafe {
+        core::slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, core::mem::size_of::<T>())
+    };
+    copy_from_user(token, ptr as *const u8, bytes)?;
+    Ok(unsafe { value.assume_init() })
 }
 
-pub fn translated_str(token: usize, ptr: *const u8) -> String {
+/// Copy a NUL-terminated string from user space, failing with -EFAULT if
+/// it is not readable by the user, with -ENAMETOOLONG if it does not end
+/// within `max_len` bytes, the NUL included, and with -ENOMEM if the heap
+/// has no room for it
+pub fn copy_string_from_user(token: usize, ptr: *const u8, max_len: usize) -> Result<String, isize> {
     let page_table = PageTable::from_token(token);
     let mut string = String::new();
     let mut va = ptr as usize;
-    loop {
-        let ch: u8 = *(page_table
-            .translate_va(VirtAddr::from(va))
-            .unwrap()
-            .get_mut());
+    for _ in 0..max_len {
+        populate(&page_table, token, VirtAddr::from(va).floor());
+        let ch: u8 = *page_table
+            .translate_user_va(VirtAddr::from(va))
+            .ok_or(-EFAULT)?
+            .get_mut();
         if ch == 0 {
-            break;
-        } else {
-            string.push(ch as char);
-            va += 1;
+            return Ok(string);
         }
+        try_alloc(|| string.try_reserve(1))?;
+        string.push(ch as char);
+        va = va.checked_add(1).ok_or(-EFAULT)?;
     }
-    string
+    Err(-ENAMETOOLONG)
 }
 
-pub fn translated_ref<T>(token: usize, ptr: *const T) -> &'static T {
-    let page_table = PageTable::from_token(token);
-    page_table.translate_va(VirtAddr::from(ptr as usize)).unwrap().get_mut()
-}
-
-pub fn translated_refmut<T>(token: usize, ptr: *mut T) -> &'static mut T {
-    //println!("into translated_refmut!");
+/// Copy a pointer-sized value from user space, -EFAULT if it is not
+/// readable by the user
+pub fn copy_usize_from_user(token: usize, ptr: *const usize) -> Result<usize, isize> {
+    if ptr as usize % core::mem::size_of::<usize>() != 0 {
+        return Err(-EFAULT);
+    }
     let page_table = PageTable::from_token(token);
-    let va = ptr as usize;
-    //println!("translated_refmut: before translate_va");
+    populate(&page_table, token, VirtAddr::from(ptr as usize).floor());
     page_table
-        .translate_va(VirtAddr::from(va))
-        .unwrap()
-        .get_mut()
+        .translate_user_va(VirtAddr::from(ptr as usize))
+        .map(|pa| *pa.get_mut::<usize>())
+        .ok_or(-EFAULT)
 }
 
 /// An abstraction over a buffer passed from user space to kernel space
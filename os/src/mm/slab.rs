@@ -0,0 +1,208 @@
+//! Slab caches for the kernel objects allocated most often
+//!
+//! An allocation whose layout is that of one of the [`CACHES`] is served
+//! from slabs holding objects of that type only, instead of from the
+//! general heap where objects of all sizes are mixed. Slabs are carved from
+//! the heap, aligned to their size so that the slab of an object is found
+//! by masking its address.
+
+use crate::config::PAGE_SIZE;
+use crate::fs::OSInode;
+use crate::sync::UPSafeCell;
+use crate::task::TaskControlBlock;
+use alloc::string::String;
+use core::alloc::Layout;
+use core::fmt::Write;
+use core::ptr::null_mut;
+use lazy_static::*;
+
+/// Objects a slab holds at least
+const MIN_OBJECTS: usize = 8;
+
+/// A free object, linking to the next free one of its slab
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+/// Header at the start of each slab
+struct Slab {
+    /// Next slab of the cache with free objects
+    next: *mut Slab,
+    free: *mut FreeObject,
+    in_use: usize,
+}
+
+/// Objects of a single layout
+pub struct SlabCache {
+    name: &'static str,
+    layout: Layout,
+    slab_layout: Layout,
+    /// Offset of the first object in a slab, past the header
+    first: usize,
+    /// Slabs with free objects
+    partial: *mut Slab,
+    stat: SlabStat,
+}
+
+// the slabs are only reached through the cache
+unsafe impl Send for SlabCache {}
+
+/// Counters of a slab cache
+#[derive(Clone, Copy, Default)]
+pub struct SlabStat {
+    /// Objects allocated now
+    pub in_use: usize,
+    /// Most objects allocated at once
+    pub peak: usize,
+    /// Objects allocated since boot
+    pub allocs: usize,
+    /// Slabs taken from the heap now
+    pub slabs: usize,
+}
+
+/// Layout of the heap block of an `Arc<T>`, a `#[repr(C)]` pair of
+/// counters followed by the value
+fn arc_layout<T>() -> Layout {
+    let (layout, _) = Layout::new::<[usize; 2]>().extend(Layout::new::<T>()).unwrap();
+    layout.pad_to_align()
+}
+
+impl SlabCache {
+    fn new(name: &'static str, layout: Layout) -> Self {
+        let layout = object_layout(layout);
+        let first = Layout::new::<Slab>().extend(layout).unwrap().1;
+        let size = (first + layout.size() * MIN_OBJECTS).next_power_of_two().max(PAGE_SIZE);
+        Self {
+            name,
+            layout,
+            slab_layout: Layout::from_size_align(size, size).unwrap(),
+            first,
+            partial: null_mut(),
+            stat: SlabStat::default(),
+        }
+    }
+    /// Take an object, getting a new slab from `heap` if all are full
+    unsafe fn alloc(&mut self, heap: &dyn Fn(Layout) -> *mut u8) -> *mut u8 {
+        if self.partial.is_null() {
+            let slab = heap(self.slab_layout) as *mut Slab;
+            if slab.is_null() {
+                return null_mut();
+            }
+            let mut free = null_mut();
+            let objects = (self.slab_layout.size() - self.first) / self.layout.size();
+            for i in (0..objects).rev() {
+                let object = (slab as usize + self.first + i * self.layout.size()) as *mut FreeObject;
+                (*object).next = free;
+                free = object;
+            }
+            slab.write(Slab { next: null_mut(), free, in_use: 0 });
+            self.partial = slab;
+            self.stat.slabs += 1;
+        }
+        let slab = &mut *self.partial;
+        let object = slab.free;
+        slab.free = (*object).next;
+        slab.in_use += 1;
+        if slab.free.is_null() {
+            self.partial = slab.next;
+        }
+        self.stat.in_use += 1;
+        self.stat.peak = self.stat.peak.max(self.stat.in_use);
+        self.stat.allocs += 1;
+        object as *mut u8
+    }
+    /// Give an object back, returning its slab to `heap` once empty unless
+    /// it is the only one with free objects
+    unsafe fn dealloc(&mut self, ptr: *mut u8, heap: &dyn Fn(*mut u8, Layout)) {
+        let slab_ptr = (ptr as usize & !(self.slab_layout.size() - 1)) as *mut Slab;
+        let slab = &mut *slab_ptr;
+        let was_full = slab.free.is_null();
+        let object = ptr as *mut FreeObject;
+        (*object).next = slab.free;
+        slab.free = object;
+        slab.in_use -= 1;
+        self.stat.in_use -= 1;
+        if was_full {
+            slab.next = self.partial;
+            self.partial = slab_ptr;
+        }
+        if slab.in_use == 0 && !(self.partial == slab_ptr && slab.next.is_null()) {
+            let mut link = &mut self.partial as *mut *mut Slab;
+            while *link != slab_ptr {
+                link = &mut (**link).next;
+            }
+            *link = slab.next;
+            heap(slab_ptr as *mut u8, self.slab_layout);
+            self.stat.slabs -= 1;
+        }
+    }
+}
+
+lazy_static! {
+    /// The caches, for the heap blocks of tasks and of open inodes
+    static ref CACHES: UPSafeCell<[SlabCache; 2]> = unsafe {
+        UPSafeCell::new([
+            SlabCache::new("task", arc_layout::<TaskControlBlock>()),
+            SlabCache::new("inode", arc_layout::<OSInode>()),
+        ])
+    };
+}
+
+/// Allocate from the cache of `layout` if there is one, getting slabs from
+/// `heap`; None if there is no such cache
+pub unsafe fn slab_alloc(layout: Layout, heap: &dyn Fn(Layout) -> *mut u8) -> Option<*mut u8> {
+    let mut caches = CACHES.exclusive_access();
+    let cache = caches.iter_mut().find(|cache| fits(cache, layout))?;
+    Some(cache.alloc(heap))
+}
+
+/// Free to the cache of `layout` if there is one, giving empty slabs back
+/// to `heap`; false if there is no such cache
+pub unsafe fn slab_dealloc(ptr: *mut u8, layout: Layout, heap: &dyn Fn(*mut u8, Layout)) -> bool {
+    let mut caches = CACHES.exclusive_access();
+    match caches.iter_mut().find(|cache| fits(cache, layout)) {
+        Some(cache) => {
+            cache.dealloc(ptr, heap);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Layout of the objects of the cache serving `layout`, large and aligned
+/// enough to link free objects
+fn object_layout(layout: Layout) -> Layout {
+    layout.align_to(core::mem::align_of::<FreeObject>()).unwrap().pad_to_align()
+}
+
+fn fits(cache: &SlabCache, layout: Layout) -> bool {
+    object_layout(layout) == cache.layout
+}
+
+/// Format the counters of the caches like `/proc/slabinfo`
+pub fn slab_stats() -> String {
+    // copied out first, as formatting allocates
+    let stats: [(&str, usize, usize, SlabStat); 2] = {
+        let caches = CACHES.exclusive_access();
+        [0, 1].map(|i| {
+            let cache = &caches[i];
+            (cache.name, cache.layout.size(), cache.slab_layout.size(), cache.stat)
+        })
+    };
+    let mut text = String::new();
+    writeln!(
+        text,
+        "{:<8} {:>8} {:>8} {:>8} {:>8} {:>8} {:>8}",
+        "name", "objsize", "slabsize", "in_use", "peak", "allocs", "slabs"
+    )
+    .unwrap();
+    for (name, size, slab_size, stat) in stats {
+        writeln!(
+            text,
+            "{:<8} {:>8} {:>8} {:>8} {:>8} {:>8} {:>8}",
+            name, size, slab_size, stat.in_use, stat.peak, stat.allocs, stat.slabs
+        )
+        .unwrap();
+    }
+    text
+}
@@ -0,0 +1,82 @@
+//! Swap area on the block device, right after the file system image
+//!
+//! Pages of lazily allocated user areas are written to a slot of the swap
+//! area when frames run low, and read back on their next page fault.
+
+use super::PhysPageNum;
+use crate::config::{PAGE_SIZE, SWAP_PAGES, SWAP_START_BLOCK};
+use crate::drivers::BLOCK_DEVICE;
+use crate::sync::UPSafeCell;
+use alloc::vec::Vec;
+use easy_fs::BLOCK_SZ;
+use lazy_static::*;
+
+/// Blocks a page takes in the swap area
+const BLOCKS_PER_PAGE: usize = PAGE_SIZE / BLOCK_SZ;
+
+/// Slot allocator of the swap area, in the manner of the frame allocator
+struct SwapAllocator {
+    current: usize,
+    recycled: Vec<usize>,
+}
+
+impl SwapAllocator {
+    fn alloc(&mut self) -> Option<usize> {
+        if let Some(slot) = self.recycled.pop() {
+            Some(slot)
+        } else if self.current == SWAP_PAGES {
+            None
+        } else {
+            self.current += 1;
+            Some(self.current - 1)
+        }
+    }
+    fn dealloc(&mut self, slot: usize) {
+        assert!(slot < self.current, "swap slot {} has not been allocated!", slot);
+        self.recycled.push(slot);
+    }
+}
+
+lazy_static! {
+    static ref SWAP_ALLOCATOR: UPSafeCell<SwapAllocator> = unsafe {
+        UPSafeCell::new(SwapAllocator {
+            current: 0,
+            recycled: Vec::new(),
+        })
+    };
+}
+
+/// A page in the swap area, freed when dropped
+pub struct SwapSlot(usize);
+
+impl SwapSlot {
+    /// Read the page back into the frame `ppn`
+    pub fn read(&self, ppn: PhysPageNum) {
+        let first_block = SWAP_START_BLOCK + self.0 * BLOCKS_PER_PAGE;
+        for (i, block) in ppn.get_bytes_array().chunks_mut(BLOCK_SZ).enumerate() {
+            BLOCK_DEVICE.read_block(first_block + i, block);
+        }
+    }
+}
+
+impl Drop for SwapSlot {
+    fn drop(&mut self) {
+        SWAP_ALLOCATOR.exclusive_access().dealloc(self.0);
+    }
+}
+
+/// Number of free pages in the swap area
+pub fn swap_free() -> usize {
+    let allocator = SWAP_ALLOCATOR.exclusive_access();
+    SWAP_PAGES - allocator.current + allocator.recycled.len()
+}
+
+/// Write the frame `ppn` to a free slot, None if the swap area is full
+pub fn swap_out(ppn: PhysPageNum) -> Option<SwapSlot> {
+    let slot = SwapSlot(SWAP_ALLOCATOR.exclusive_access().alloc()?);
+    let first_block = SWAP_START_BLOCK + slot.0 * BLOCKS_PER_PAGE;
+    for (i, block) in ppn.get_bytes_array().chunks(BLOCK_SZ).enumerate() {
+        BLOCK_DEVICE.write_block(first_block + i, block);
+    }
+    Some(slot)
+}
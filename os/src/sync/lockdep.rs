@@ -0,0 +1,221 @@
+//! Lock order validation, with the `lockdep` feature
+//!
+//! Every [`UPSafeCell`](super::UPSafeCell) borrow,
+//! [`SpinNoIrq`](super::SpinNoIrq) lock and easy-fs mutex lock is
+//! recorded with where it was taken and the stack it was taken from.
+//! Taking a lock while holding others adds an edge from each of them to it
+//! in a graph of lock orders; an edge closing a cycle means two code paths
+//! take the same locks in opposite orders and may deadlock, so the kernel
+//! panics with both: the locks held now and the edges seen before that
+//! lead back, each with its stacks.
+//!
+//! Locks are told apart by class, the type of the lock with the data it
+//! guards, so that orders seen on some TCBs or block caches apply to all
+//! of them. Locks of a class nested in one another add no edge.
+//!
+//! No lock is held across a task switch, which [`assert_no_locks_held`]
+//! checks, so the locks held by the hart are those of the current task.
+//! Try-locks never wait, so they are recorded as held but add no edge.
+//! Without the feature nothing is recorded.
+
+use super::TicketLock;
+use crate::backtrace::{backtrace, frame_pointer};
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::panic::Location;
+use core::sync::atomic::{AtomicBool, Ordering};
+use easy_fs::{set_lock_observer, LockObserver};
+use lazy_static::*;
+
+/// Return addresses kept of the stack a lock was taken from
+const TRACE_DEPTH: usize = 8;
+
+/// Class of a lock, the name of its type
+pub type Class = &'static str;
+
+/// Where a lock was taken
+type Site = &'static Location<'static>;
+
+/// Where and from which stack a lock was taken
+#[derive(Clone, Copy)]
+struct Taken {
+    site: Site,
+    trace: [usize; TRACE_DEPTH],
+}
+
+impl Taken {
+    /// Write the site and the stack, one return address a line
+    fn write(&self, text: &mut String) {
+        writeln!(text, " at {}", self.site).unwrap();
+        for ra in self.trace.iter().take_while(|&&ra| ra != 0) {
+            writeln!(text, "      {:#x}", ra).unwrap();
+        }
+    }
+}
+
+struct Lockdep {
+    /// Locks held by the hart, innermost last, with where they were taken
+    held: Vec<(Class, Taken)>,
+    /// Orders seen, from the outer class to the inner one, with where each
+    /// was taken the first time
+    edges: BTreeMap<(Class, Class), (Taken, Taken)>,
+}
+
+impl Lockdep {
+    /// Path of edges from class `from` to class `to`, if any
+    fn path(&self, from: Class, to: Class) -> Option<Vec<(Class, Class)>> {
+        let mut seen = Vec::new();
+        let mut stack = Vec::from([(from, Vec::new())]);
+        while let Some((class, path)) = stack.pop() {
+            if class == to {
+                return Some(path);
+            }
+            if seen.contains(&class) {
+                continue;
+            }
+            seen.push(class);
+            let edges = self.edges.range((class, "")..).map(|(edge, _)| *edge);
+            for (outer, inner) in edges.take_while(|&(outer, _)| outer == class) {
+                let mut path = path.clone();
+                path.push((outer, inner));
+                stack.push((inner, path));
+            }
+        }
+        None
+    }
+    /// Write the locks held, with where they were taken
+    fn write_held(&self, text: &mut String) {
+        for (held, held_taken) in self.held.iter() {
+            write!(text, "  {} taken", held).unwrap();
+            held_taken.write(text);
+        }
+    }
+    /// Describe the order inversion of taking `class` as `taken`, given
+    /// the `path` of edges leading from it back to a class held
+    fn report(&self, class: Class, taken: &Taken, path: &[(Class, Class)]) -> String {
+        let mut text = String::new();
+        write!(text, "lock order inversion taking {}", class).unwrap();
+        taken.write(&mut text);
+        writeln!(text, "held now:").unwrap();
+        self.write_held(&mut text);
+        writeln!(text, "taken before in the opposite order:").unwrap();
+        for edge in path {
+            let (outer_taken, inner_taken) = &self.edges[edge];
+            write!(text, "  {} taken", edge.0).unwrap();
+            outer_taken.write(&mut text);
+            write!(text, "  then {}", edge.1).unwrap();
+            inner_taken.write(&mut text);
+        }
+        text
+    }
+}
+
+lazy_static! {
+    /// Lock order state, in a ticket lock as it may not be recorded itself
+    static ref LOCKDEP: TicketLock<Lockdep> = TicketLock::new(Lockdep {
+        held: Vec::new(),
+        edges: BTreeMap::new(),
+    });
+}
+
+/// Whether the hart is inside lockdep, whose allocations take locks that
+/// are left unrecorded
+static IN_LOCKDEP: AtomicBool = AtomicBool::new(false);
+
+/// Run `f` on the state unless the hart is inside lockdep already
+fn with_lockdep<R>(f: impl FnOnce(&mut Lockdep) -> R) -> Option<R> {
+    if !cfg!(feature = "lockdep") || IN_LOCKDEP.swap(true, Ordering::Relaxed) {
+        return None;
+    }
+    let mut lockdep = LOCKDEP.lock();
+    let result = f(&mut lockdep);
+    // left before the state is unlocked, which may switch tasks
+    IN_LOCKDEP.store(false, Ordering::Relaxed);
+    drop(lockdep);
+    Some(result)
+}
+
+/// Run `f` with no lock recorded, for an interrupt taken inside a critical
+/// section: its locks are not nested in those held, and lockdep allocates
+/// while the interrupted code may hold the heap
+pub fn without_lockdep<R>(f: impl FnOnce() -> R) -> R {
+    let was_in = IN_LOCKDEP.swap(true, Ordering::Relaxed);
+    let result = f();
+    IN_LOCKDEP.store(was_in, Ordering::Relaxed);
+    result
+}
+
+/// Record that a lock of `class` was taken at `site`, waiting for it
+/// unless `try_lock`
+///
+/// Not inlined, so the stack starts at its own frame, right below the
+/// lock function.
+#[inline(never)]
+pub fn lock_acquired(class: Class, site: Site, try_lock: bool) {
+    let fp = frame_pointer();
+    let report = with_lockdep(|lockdep| {
+        let taken = Taken { site, trace: backtrace(fp) };
+        if !try_lock {
+            for i in 0..lockdep.held.len() {
+                let (outer, outer_taken) = lockdep.held[i];
+                if outer == class || lockdep.edges.contains_key(&(outer, class)) {
+                    continue;
+                }
+                if let Some(path) = lockdep.path(class, outer) {
+                    return Some(lockdep.report(class, &taken, &path));
+                }
+                lockdep.edges.insert((outer, class), (outer_taken, taken));
+            }
+        }
+        lockdep.held.push((class, taken));
+        None
+    });
+    if let Some(Some(report)) = report {
+        panic!("{}", report);
+    }
+}
+
+/// Record that a lock of `class` was released
+pub fn lock_released(class: Class) {
+    with_lockdep(|lockdep| {
+        if let Some(i) = lockdep.held.iter().rposition(|(held, _)| *held == class) {
+            lockdep.held.remove(i);
+        }
+    });
+}
+
+/// Panic if a lock is held, called before switching tasks
+pub fn assert_no_locks_held() {
+    let report = with_lockdep(|lockdep| {
+        if lockdep.held.is_empty() {
+            return None;
+        }
+        let mut text = String::from("task switch with locks held:\n");
+        lockdep.write_held(&mut text);
+        Some(text)
+    });
+    if let Some(Some(report)) = report {
+        panic!("{}", report);
+    }
+}
+
+/// Records the locks of easy-fs along with those of the kernel
+struct FsLocks;
+
+impl LockObserver for FsLocks {
+    fn acquired(&self, class: Class, site: Site) {
+        lock_acquired(class, site, false);
+    }
+    fn released(&self, class: Class) {
+        lock_released(class);
+    }
+}
+
+/// Start recording the locks of easy-fs, before the filesystem is opened
+pub fn init_lockdep() {
+    if cfg!(feature = "lockdep") {
+        set_lock_observer(&FsLocks);
+    }
+}
@@ -1,5 +1,13 @@
 //! Synchronization and interior mutability primitives
 
+mod lockdep;
+mod preempt;
+mod spin;
+mod ticket;
 mod up;
 
-pub use up::UPSafeCell;
+pub use lockdep::{assert_no_locks_held, init_lockdep, without_lockdep};
+pub use preempt::{preemptible, set_need_resched, take_need_resched, PreemptGuard};
+pub use spin::{SpinNoIrq, SpinNoIrqGuard};
+pub use ticket::{TicketGuard, TicketLock};
+pub use up::{UPRefMut, UPSafeCell};
@@ -0,0 +1,66 @@
+//! Preemption control of kernel code
+//!
+//! Kernel code may be preempted by a timer interrupt only while no
+//! critical section is open. Every [`UPSafeCell`](super::UPSafeCell) borrow
+//! and [`TicketLock`](super::TicketLock) holder opens one, and code holding
+//! other locks, e.g. the heap or the file system, opens one with
+//! [`PreemptGuard`].
+//!
+//! A timer tick inside a critical section asks for a reschedule, which
+//! happens as the outermost section closes if interrupts are on there, as
+//! a tick could have come right then.
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use riscv::register::sstatus;
+
+/// Number of critical sections open, there is only a single hart
+static PREEMPT_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether a time slice ran out inside a critical section
+static NEED_RESCHED: AtomicBool = AtomicBool::new(false);
+
+/// Open a critical section in which kernel code is not preempted
+pub fn preempt_disable() {
+    PREEMPT_COUNT.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Close a critical section opened by [`preempt_disable`], switching to
+/// the next task if a reschedule was asked for inside it
+pub fn preempt_enable() {
+    let count = PREEMPT_COUNT.fetch_sub(1, Ordering::Relaxed);
+    assert!(count > 0, "preempt_enable without preempt_disable");
+    if count == 1 && sstatus::read().sie() && take_need_resched() {
+        crate::task::resched_current();
+    }
+}
+
+/// Whether the interrupted kernel code may be preempted
+pub fn preemptible() -> bool {
+    PREEMPT_COUNT.load(Ordering::Relaxed) == 0
+}
+
+/// Ask for a reschedule at the next safe point
+pub fn set_need_resched() {
+    NEED_RESCHED.store(true, Ordering::Relaxed);
+}
+
+/// Whether a reschedule was asked for, clearing the request
+pub fn take_need_resched() -> bool {
+    NEED_RESCHED.swap(false, Ordering::Relaxed)
+}
+
+/// A critical section closed when dropped
+pub struct PreemptGuard;
+
+impl PreemptGuard {
+    pub fn new() -> Self {
+        preempt_disable();
+        Self
+    }
+}
+
+impl Drop for PreemptGuard {
+    fn drop(&mut self) {
+        preempt_enable();
+    }
+}
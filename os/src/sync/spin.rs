@@ -0,0 +1,121 @@
+//! Spinlocks keeping interrupts off while held
+//!
+//! Unlike a [`UPSafeCell`](super::UPSafeCell), a [`SpinNoIrq`] holder is
+//! not interrupted at all, and other harts wait for the lock rather than
+//! finding the data borrowed, so it stays sound once the kernel runs on
+//! several harts. The hart holding a lock locking it again would spin
+//! forever, so that panics like a second borrow did.
+
+use super::lockdep::{lock_acquired, lock_released};
+use super::ticket::{TicketGuard, TicketLock};
+use crate::task::hart_id;
+use core::any::type_name;
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use riscv::register::sstatus;
+
+/// Number of interrupt-free sections open, there is only a single hart
+static IRQ_OFF_DEPTH: AtomicUsize = AtomicUsize::new(0);
+/// Whether interrupts were on when the outermost section was opened
+static IRQ_WERE_ON: AtomicBool = AtomicBool::new(false);
+
+/// Open a section with interrupts off, remembering whether they were on
+/// if it is the outermost one
+fn irq_off() {
+    let were_on = sstatus::read().sie();
+    unsafe {
+        sstatus::clear_sie();
+    }
+    if IRQ_OFF_DEPTH.fetch_add(1, Ordering::Relaxed) == 0 {
+        IRQ_WERE_ON.store(were_on, Ordering::Relaxed);
+    }
+}
+
+/// Close a section opened by [`irq_off`], turning interrupts back on when
+/// the outermost one closes if they were on before
+fn irq_restore() {
+    let depth = IRQ_OFF_DEPTH.fetch_sub(1, Ordering::Relaxed);
+    assert!(depth > 0, "irq_restore without irq_off");
+    if depth == 1 && IRQ_WERE_ON.load(Ordering::Relaxed) {
+        unsafe {
+            sstatus::set_sie();
+        }
+    }
+}
+
+/// A spinlock that keeps interrupts and preemption off while held
+pub struct SpinNoIrq<T> {
+    lock: TicketLock<T>,
+    /// Hart holding the lock, `usize::MAX` if none
+    holder: AtomicUsize,
+}
+
+impl<T> SpinNoIrq<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            lock: TicketLock::new(data),
+            holder: AtomicUsize::new(usize::MAX),
+        }
+    }
+    /// Lock, panicking if this hart holds the lock already
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    pub fn lock(&self) -> SpinNoIrqGuard<'_, T> {
+        irq_off();
+        if self.holder.load(Ordering::Relaxed) == hart_id() {
+            panic!("SpinNoIrq locked again by its holder");
+        }
+        let guard = self.lock.lock();
+        self.holder.store(hart_id(), Ordering::Relaxed);
+        lock_acquired(type_name::<Self>(), Location::caller(), false);
+        SpinNoIrqGuard { guard: Some(guard), holder: &self.holder }
+    }
+    /// Lock, or `None` if the lock is held
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    pub fn try_lock(&self) -> Option<SpinNoIrqGuard<'_, T>> {
+        irq_off();
+        match self.lock.try_lock() {
+            Some(guard) => {
+                self.holder.store(hart_id(), Ordering::Relaxed);
+                lock_acquired(type_name::<Self>(), Location::caller(), true);
+                Some(SpinNoIrqGuard { guard: Some(guard), holder: &self.holder })
+            }
+            None => {
+                irq_restore();
+                None
+            }
+        }
+    }
+}
+
+/// Access to the data of a locked [`SpinNoIrq`], unlocking and restoring
+/// interrupts when dropped
+pub struct SpinNoIrqGuard<'a, T> {
+    guard: Option<TicketGuard<'a, T>>,
+    holder: &'a AtomicUsize,
+}
+
+impl<'a, T> Deref for SpinNoIrqGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<'a, T> DerefMut for SpinNoIrqGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+impl<'a, T> Drop for SpinNoIrqGuard<'a, T> {
+    fn drop(&mut self) {
+        // unlock before an interrupt is possible again
+        self.holder.store(usize::MAX, Ordering::Relaxed);
+        if let Some(guard) = self.guard.take() {
+            drop(guard);
+            lock_released(type_name::<SpinNoIrq<T>>());
+        }
+        irq_restore();
+    }
+}
@@ -1,6 +1,11 @@
 //! Uniprocessor interior mutability primitives
 
+use super::lockdep::{lock_acquired, lock_released};
+use super::preempt::{preempt_disable, preempt_enable};
 use core::cell::{RefCell, RefMut};
+use core::any::type_name;
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
 
 /// Wrap a static data structure inside it so that we are
 /// able to access it without any `unsafe`.
@@ -8,7 +13,8 @@ use core::cell::{RefCell, RefMut};
 /// We should only use it in uniprocessor.
 ///
 /// In order to get mutable reference of inner data, call
-/// `exclusive_access`.
+/// `exclusive_access`. Kernel code is not preempted while it holds the
+/// inner data.
 pub struct UPSafeCell<T> {
     /// inner data
     inner: RefCell<T>,
@@ -25,7 +31,47 @@ impl<T> UPSafeCell<T> {
         }
     }
     /// Panic if the data has been borrowed.
-    pub fn exclusive_access(&self) -> RefMut<'_, T> {
-        self.inner.borrow_mut()
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    pub fn exclusive_access(&self) -> UPRefMut<'_, T> {
+        preempt_disable();
+        let inner = self.inner.borrow_mut();
+        lock_acquired(type_name::<Self>(), Location::caller(), false);
+        UPRefMut(Some(inner))
+    }
+    /// Exclusive access inner data, or `None` if it is already borrowed
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    pub fn try_exclusive_access(&self) -> Option<UPRefMut<'_, T>> {
+        let inner = self.inner.try_borrow_mut().ok()?;
+        preempt_disable();
+        lock_acquired(type_name::<Self>(), Location::caller(), true);
+        Some(UPRefMut(Some(inner)))
+    }
+}
+
+/// A mutable borrow of the data in a [`UPSafeCell`], re-enabling
+/// preemption when dropped
+pub struct UPRefMut<'a, T>(Option<RefMut<'a, T>>);
+
+impl<'a, T> Deref for UPRefMut<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.0.as_ref().unwrap()
+    }
+}
+
+impl<'a, T> DerefMut for UPRefMut<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.0.as_mut().unwrap()
+    }
+}
+
+impl<'a, T> Drop for UPRefMut<'a, T> {
+    fn drop(&mut self) {
+        // release the borrow before preemption is possible again
+        if let Some(inner) = self.0.take() {
+            drop(inner);
+            lock_released(type_name::<UPSafeCell<T>>());
+        }
+        preempt_enable();
     }
 }
@@ -0,0 +1,42 @@
+//! Error numbers returned negated by syscalls, as in Linux
+
+/// Operation not permitted
+pub const EPERM: isize = 1;
+/// No such file or directory
+pub const ENOENT: isize = 2;
+/// No such process
+pub const ESRCH: isize = 3;
+/// Interrupted by a signal
+pub const EINTR: isize = 4;
+/// I/O error, or an invalid ptrace request
+pub const EIO: isize = 5;
+/// Argument list too long, over `ARG_MAX`
+pub const E2BIG: isize = 7;
+/// Not an executable format, e.g. a broken elf
+pub const ENOEXEC: isize = 8;
+/// Try again, e.g. no free pid
+pub const EAGAIN: isize = 11;
+/// Out of memory, or over `RLIMIT_AS`
+pub const ENOMEM: isize = 12;
+/// Permission denied
+pub const EACCES: isize = 13;
+/// Bad address
+pub const EFAULT: isize = 14;
+/// Invalid argument
+pub const EINVAL: isize = 22;
+/// Too many open files, over `RLIMIT_NOFILE`
+pub const EMFILE: isize = 24;
+/// Not a terminal
+pub const ENOTTY: isize = 25;
+/// Text file busy, a running program is paged in from it
+pub const ETXTBSY: isize = 26;
+/// No space left, e.g. no free protection key
+pub const ENOSPC: isize = 28;
+/// Illegal seek, e.g. on a terminal
+pub const ESPIPE: isize = 29;
+/// File name too long
+pub const ENAMETOOLONG: isize = 36;
+/// Function not implemented, e.g. an unknown syscall
+pub const ENOSYS: isize = 38;
+/// Disk quota exceeded
+pub const EDQUOT: isize = 122;
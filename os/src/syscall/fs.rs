@@ -1,30 +1,51 @@
 //! File and filesystem-related syscalls
 
-use crate::mm::{translated_byte_buffer};
-use crate::mm::translated_str;
+use crate::mm::user_byte_buffer;
+use crate::mm::copy_string_from_user;
+use crate::config::{FRAMES_HIGH, PATH_MAX};
 use crate::task::current_user_token;
-use crate::task::current_task;
-use crate::fs::{open_file, link_file, StatMode, get_nlink, unlink_file};
+use crate::task::{current_task, current_uid};
+use crate::fs::{open_file, file_immutable, file_text_busy, link_file, StatMode, get_nlink, unlink_file, access_file, AccessMode};
+use crate::fs::{get_quota, set_quota, PressureFile, Quota};
+use crate::mm::frames_total;
+use crate::mm::{get_user, put_user};
+use super::errno::{EDQUOT, EINVAL, EMFILE, EPERM, ETXTBSY};
 use crate::fs::OpenFlags;
 use crate::fs::Stat;
 use crate::mm::UserBuffer;
-use core::mem;
-use core::mem::{size_of};
+use alloc::sync::Arc;
+
+/// Check access with the effective ids instead of the real ids
+const AT_EACCESS: u32 = 0x200;
+/// sync_file_range flag waiting for write-back already in progress
+const SYNC_FILE_RANGE_WAIT_BEFORE: u32 = 1;
+/// sync_file_range flag starting write-back of dirty data
+const SYNC_FILE_RANGE_WRITE: u32 = 2;
+/// sync_file_range flag waiting for the write-back to finish
+const SYNC_FILE_RANGE_WAIT_AFTER: u32 = 4;
+/// quotactl command reading the quota of a user
+const Q_GETQUOTA: usize = 0x800007;
+/// quotactl command setting the limits of a user
+const Q_SETQUOTA: usize = 0x800008;
+/// mempressure flag making reads return nothing rather than block
+const MEMPRESSURE_NONBLOCK: u32 = 1;
 
 pub fn sys_write(fd: usize, buf: *const u8, len: usize) -> isize {
     let token = current_user_token();
     let task = current_task().unwrap();
     let inner = task.inner_exclusive_access();
-    if fd >= inner.fd_table.len() {
-        return -1;
-    }
-    if let Some(file) = &inner.fd_table[fd] {
-        let file = file.clone();
+    if let Some(file) = inner.get_file(fd) {
         // release current task TCB manually to avoid multi-borrow
         drop(inner);
-        file.write(
-            UserBuffer::new(translated_byte_buffer(token, buf, len))
-        ) as isize
+        let buffers = match user_byte_buffer(token, buf, len, false) {
+            Ok(buffers) => buffers,
+            Err(err) => return err,
+        };
+        let ret = file.write(UserBuffer::new(buffers));
+        let mut inner = task.inner_exclusive_access();
+        inner.io_stats.syscw += 1;
+        inner.io_stats.wchar += ret.max(0) as u64;
+        ret
     } else {
         -1
     }
@@ -34,16 +55,18 @@ pub fn sys_read(fd: usize, buf: *const u8, len: usize) -> isize {
     let token = current_user_token();
     let task = current_task().unwrap();
     let inner = task.inner_exclusive_access();
-    if fd >= inner.fd_table.len() {
-        return -1;
-    }
-    if let Some(file) = &inner.fd_table[fd] {
-        let file = file.clone();
+    if let Some(file) = inner.get_file(fd) {
         // release current task TCB manually to avoid multi-borrow
         drop(inner);
-        file.read(
-            UserBuffer::new(translated_byte_buffer(token, buf, len))
-        ) as isize
+        let buffers = match user_byte_buffer(token, buf, len, true) {
+            Ok(buffers) => buffers,
+            Err(err) => return err,
+        };
+        let ret = file.read(UserBuffer::new(buffers));
+        let mut inner = task.inner_exclusive_access();
+        inner.io_stats.syscr += 1;
+        inner.io_stats.rchar += ret.max(0) as u64;
+        ret
     } else {
         -1
     }
@@ -52,15 +75,26 @@ pub fn sys_read(fd: usize, buf: *const u8, len: usize) -> isize {
 pub fn sys_open(path: *const u8, flags: u32) -> isize {
     let task = current_task().unwrap();
     let token = current_user_token();
-    let path = translated_str(token, path);
-    if let Some(inode) = open_file(
-        path.as_str(),
-        OpenFlags::from_bits(flags).unwrap()
-    ) {
-        let mut inner = task.inner_exclusive_access();
-        let fd = inner.alloc_fd();
-        inner.fd_table[fd] = Some(inode);
-        fd as isize
+    let path = match copy_string_from_user(token, path, PATH_MAX) {
+        Ok(path) => path,
+        Err(err) => return err,
+    };
+    let flags = OpenFlags::from_bits(flags).unwrap();
+    let truncates = flags.intersects(OpenFlags::CREATE | OpenFlags::TRUNC);
+    if (flags.read_write().1 || truncates) && file_immutable(path.as_str()) {
+        return -EPERM;
+    }
+    if (flags.read_write().1 || truncates) && file_text_busy(path.as_str()) {
+        return -ETXTBSY;
+    }
+    if let Some(inode) = open_file(path.as_str(), flags) {
+        let inner = task.inner_exclusive_access();
+        match inner.alloc_fd(inode) {
+            Some(fd) => fd as isize,
+            None => -EMFILE,
+        }
+    } else if flags.contains(OpenFlags::CREATE) && !get_quota(current_uid()).allows(0, 1) {
+        -EDQUOT
     } else {
         -1
     }
@@ -68,14 +102,15 @@ pub fn sys_open(path: *const u8, flags: u32) -> isize {
 
 pub fn sys_close(fd: usize) -> isize {
     let task = current_task().unwrap();
-    let mut inner = task.inner_exclusive_access();
-    if fd >= inner.fd_table.len() {
+    let inner = task.inner_exclusive_access();
+    let mut fd_table = inner.fd_table.lock();
+    if fd >= fd_table.len() {
         return -1;
     }
-    if inner.fd_table[fd].is_none() {
+    if fd_table[fd].is_none() {
         return -1;
     }
-    inner.fd_table[fd].take();
+    fd_table[fd].take();
     0
 }
 
@@ -84,25 +119,20 @@ pub fn sys_fstat(_fd: usize, _st: *mut Stat) -> isize {
     let task = current_task().unwrap();
     let token = current_user_token();
     let inner = task.inner_exclusive_access();
-    if _fd >= inner.fd_table.len() {
-        return -1;
-    }
-    if let Some(file) = &inner.fd_table[_fd] {
-
-        let dsts = translated_byte_buffer(token, _st as *mut u8, size_of::<Stat>());
-        unsafe {
-            let src = mem::transmute::<Stat, [u8; 80]>(Stat {
-                dev: 0,
-                ino: file.get_ino() as u64,
-                mode: file.get_mode(),
-                nlink: get_nlink(file.get_block_id(), file.get_block_offset()),
-                pad: [0; 7],
-            });
-            for dst in dsts {
-                dst.copy_from_slice(&src);
-            }
+    if let Some(file) = inner.get_file(_fd) {
+        // release current task TCB manually to avoid multi-borrow
+        drop(inner);
+        let stat = Stat {
+            dev: 0,
+            ino: file.get_ino() as u64,
+            mode: file.get_mode(),
+            nlink: get_nlink(file.get_block_id(), file.get_block_offset()),
+            pad: [0; 7],
+        };
+        match put_user(token, _st, stat) {
+            Ok(()) => 0,
+            Err(err) => err,
         }
-        0
     } else {
         -1
     }
@@ -110,13 +140,115 @@ pub fn sys_fstat(_fd: usize, _st: *mut Stat) -> isize {
 
 pub fn sys_linkat(_old_name: *const u8, _new_name: *const u8) -> isize {
     let token = current_user_token();
-    let old_name = translated_str(token, _old_name);
-    let new_name = translated_str(token, _new_name);
+    let old_name = match copy_string_from_user(token, _old_name, PATH_MAX) {
+        Ok(old_name) => old_name,
+        Err(err) => return err,
+    };
+    let new_name = match copy_string_from_user(token, _new_name, PATH_MAX) {
+        Ok(new_name) => new_name,
+        Err(err) => return err,
+    };
     link_file(old_name.as_str(), new_name.as_str())
 }
 
 pub fn sys_unlinkat(_name: *const u8) -> isize {
     let token = current_user_token();
-    let name = translated_str(token, _name);
+    let name = match copy_string_from_user(token, _name, PATH_MAX) {
+        Ok(name) => name,
+        Err(err) => return err,
+    };
     unlink_file(name.as_str())
 }
+
+pub fn sys_faccessat(path: *const u8, mode: u32, flags: u32) -> isize {
+    if flags & !AT_EACCESS != 0 {
+        return -EINVAL;
+    }
+    let mode = match AccessMode::from_bits(mode) {
+        Some(mode) => mode,
+        None => return -EINVAL,
+    };
+    let token = current_user_token();
+    let path = match copy_string_from_user(token, path, PATH_MAX) {
+        Ok(path) => path,
+        Err(err) => return err,
+    };
+    // a process has a single uid, so real and effective ids coincide
+    access_file(path.as_str(), mode)
+}
+
+/// Get or set the disk quota of `uid`, only root may set limits
+pub fn sys_quotactl(cmd: usize, uid: u32, addr: *mut Quota) -> isize {
+    let token = current_user_token();
+    match cmd {
+        Q_GETQUOTA => match put_user(token, addr, get_quota(uid)) {
+            Ok(()) => 0,
+            Err(err) => err,
+        },
+        Q_SETQUOTA => {
+            if current_uid() != 0 {
+                return -1;
+            }
+            let quota = match get_user(token, addr as *const Quota) {
+                Ok(quota) => quota,
+                Err(err) => return err,
+            };
+            set_quota(uid, quota.block_limit, quota.inode_limit);
+            0
+        }
+        _ => -1,
+    }
+}
+
+pub fn sys_ioctl(fd: usize, cmd: usize, arg: usize) -> isize {
+    let task = current_task().unwrap();
+    let inner = task.inner_exclusive_access();
+    if let Some(file) = inner.get_file(fd) {
+        // release current task TCB manually to avoid multi-borrow
+        drop(inner);
+        file.ioctl(cmd, arg)
+    } else {
+        -1
+    }
+}
+
+/// Write back the cached data of `nbytes` bytes at `offset` of a file,
+/// up to the end of the file if `nbytes` is 0
+///
+/// Block I/O is synchronous, so write-back is finished as soon as it is
+/// started and the wait flags have nothing more to wait for.
+pub fn sys_sync_file_range(fd: usize, offset: usize, nbytes: usize, flags: u32) -> isize {
+    let all_flags = SYNC_FILE_RANGE_WAIT_BEFORE | SYNC_FILE_RANGE_WRITE | SYNC_FILE_RANGE_WAIT_AFTER;
+    if flags & !all_flags != 0 {
+        return -EINVAL;
+    }
+    let task = current_task().unwrap();
+    let inner = task.inner_exclusive_access();
+    if let Some(file) = inner.get_file(fd) {
+        // release current task TCB manually to avoid multi-borrow
+        drop(inner);
+        if flags & SYNC_FILE_RANGE_WRITE == 0 {
+            return 0;
+        }
+        let len = if nbytes == 0 { usize::MAX - offset } else { nbytes };
+        file.sync_range(offset, len)
+    } else {
+        -1
+    }
+}
+
+/// Open a file counting the drops of free frames below `threshold`, or
+/// below [`FRAMES_HIGH`] if it is 0, see [`PressureFile`]
+pub fn sys_mempressure(threshold: usize, flags: u32) -> isize {
+    if flags & !MEMPRESSURE_NONBLOCK != 0 || threshold > frames_total() {
+        return -EINVAL;
+    }
+    let threshold = if threshold == 0 { FRAMES_HIGH } else { threshold };
+    let file = Arc::new(PressureFile::new(threshold, flags & MEMPRESSURE_NONBLOCK != 0));
+    let task = current_task().unwrap();
+    let inner = task.inner_exclusive_access();
+    match inner.alloc_fd(file) {
+        Some(fd) => fd as isize,
+        None => -EMFILE,
+    }
+}
@@ -10,57 +10,271 @@
 //! `sys_` then the name of the syscall. You can find functions like this in
 //! submodules, and you should also implement syscalls this way.
 
+const SYSCALL_IOCTL: usize = 29;
 const SYSCALL_UNLINKAT: usize = 35;
 const SYSCALL_LINKAT: usize = 37;
+const SYSCALL_FACCESSAT: usize = 48;
 const SYSCALL_OPEN: usize = 56;
+const SYSCALL_QUOTACTL: usize = 60;
 const SYSCALL_CLOSE: usize = 57;
 const SYSCALL_READ: usize = 63;
 const SYSCALL_WRITE: usize = 64;
 const SYSCALL_FSTAT: usize = 80;
+const SYSCALL_SYNC_FILE_RANGE: usize = 84;
 const SYSCALL_EXIT: usize = 93;
+const SYSCALL_EXIT_GROUP: usize = 94;
+const SYSCALL_PTRACE: usize = 117;
+const SYSCALL_SCHED_SETAFFINITY: usize = 122;
+const SYSCALL_SCHED_GETAFFINITY: usize = 123;
 const SYSCALL_YIELD: usize = 124;
+const SYSCALL_KILL: usize = 129;
+const SYSCALL_SIGACTION: usize = 134;
+const SYSCALL_SIGPROCMASK: usize = 135;
+const SYSCALL_SIGRETURN: usize = 139;
+const SYSCALL_SETUID: usize = 146;
+const SYSCALL_TIMES: usize = 153;
+const SYSCALL_SETPGID: usize = 154;
+const SYSCALL_GETPGID: usize = 155;
+const SYSCALL_GETSID: usize = 156;
+const SYSCALL_SETSID: usize = 157;
+const SYSCALL_PRCTL: usize = 167;
+const SYSCALL_GETRLIMIT: usize = 163;
+const SYSCALL_SETRLIMIT: usize = 164;
+const SYSCALL_GETRUSAGE: usize = 165;
 const SYSCALL_GET_TIME: usize = 169;
 const SYSCALL_GETPID: usize = 172;
-const SYSCALL_FORK: usize = 220;
+const SYSCALL_GETPPID: usize = 173;
+const SYSCALL_GETUID: usize = 174;
+const SYSCALL_SYSINFO: usize = 179;
+const SYSCALL_CLONE: usize = 220;
 const SYSCALL_EXEC: usize = 221;
 const SYSCALL_WAITPID: usize = 260;
+const SYSCALL_PRLIMIT: usize = 261;
 const SYSCALL_SPAWN: usize = 400;
+const SYSCALL_BRK: usize = 214;
 const SYSCALL_MUNMAP: usize = 215;
+const SYSCALL_MREMAP: usize = 216;
 const SYSCALL_MMAP: usize = 222;
+const SYSCALL_MPROTECT: usize = 226;
+const SYSCALL_MSYNC: usize = 227;
+const SYSCALL_MADVISE: usize = 233;
+const SYSCALL_PKEY_MPROTECT: usize = 288;
+const SYSCALL_PKEY_ALLOC: usize = 289;
+const SYSCALL_PKEY_FREE: usize = 290;
 const SYSCALL_SET_PRIORITY: usize = 140;
+const SYSCALL_GETPRIORITY: usize = 141;
 const SYSCALL_TASK_INFO: usize = 410;
+const SYSCALL_IRQ_STATS: usize = 411;
+const SYSCALL_IO_STATS: usize = 412;
+#[cfg(feature = "fault-inject")]
+const SYSCALL_FAULT_INJECT: usize = 413;
+const SYSCALL_SBRK: usize = 414;
+const SYSCALL_CPU_SHARE: usize = 415;
+const SYSCALL_COUNTER_INFO: usize = 416;
+const SYSCALL_SYSCALL_MASK: usize = 417;
+const SYSCALL_SCHED_TUNE: usize = 418;
+const SYSCALL_PKEY_SET: usize = 419;
+const SYSCALL_NICE: usize = 420;
+const SYSCALL_PS: usize = 421;
+const SYSCALL_SCHEDSTAT: usize = 422;
+const SYSCALL_SLAB_STATS: usize = 423;
+const SYSCALL_MAPS: usize = 424;
+const SYSCALL_HEAP_STATS: usize = 425;
+const SYSCALL_FRAME_SCRUB: usize = 426;
+const SYSCALL_MEMPRESSURE: usize = 427;
+const SYSCALL_TASK_STATS: usize = 428;
 
+/// Every syscall handled by [`syscall()`], reported by `sys_syscall_mask`
+const SYSCALL_IDS: &[usize] = &[
+    SYSCALL_IOCTL,
+    SYSCALL_UNLINKAT,
+    SYSCALL_LINKAT,
+    SYSCALL_FACCESSAT,
+    SYSCALL_OPEN,
+    SYSCALL_QUOTACTL,
+    SYSCALL_CLOSE,
+    SYSCALL_READ,
+    SYSCALL_WRITE,
+    SYSCALL_FSTAT,
+    SYSCALL_SYNC_FILE_RANGE,
+    SYSCALL_EXIT,
+    SYSCALL_EXIT_GROUP,
+    SYSCALL_PTRACE,
+    SYSCALL_SCHED_SETAFFINITY,
+    SYSCALL_SCHED_GETAFFINITY,
+    SYSCALL_YIELD,
+    SYSCALL_KILL,
+    SYSCALL_SIGACTION,
+    SYSCALL_SIGPROCMASK,
+    SYSCALL_SIGRETURN,
+    SYSCALL_SETUID,
+    SYSCALL_TIMES,
+    SYSCALL_SETPGID,
+    SYSCALL_GETPGID,
+    SYSCALL_GETSID,
+    SYSCALL_SETSID,
+    SYSCALL_GETRLIMIT,
+    SYSCALL_SETRLIMIT,
+    SYSCALL_GETRUSAGE,
+    SYSCALL_SYSINFO,
+    SYSCALL_PRCTL,
+    SYSCALL_GET_TIME,
+    SYSCALL_GETPID,
+    SYSCALL_GETPPID,
+    SYSCALL_GETUID,
+    SYSCALL_CLONE,
+    SYSCALL_EXEC,
+    SYSCALL_WAITPID,
+    SYSCALL_PRLIMIT,
+    SYSCALL_SPAWN,
+    SYSCALL_BRK,
+    SYSCALL_MUNMAP,
+    SYSCALL_MREMAP,
+    SYSCALL_MMAP,
+    SYSCALL_MPROTECT,
+    SYSCALL_MSYNC,
+    SYSCALL_MADVISE,
+    SYSCALL_SET_PRIORITY,
+    SYSCALL_GETPRIORITY,
+    SYSCALL_TASK_INFO,
+    SYSCALL_IRQ_STATS,
+    SYSCALL_IO_STATS,
+    #[cfg(feature = "fault-inject")]
+    SYSCALL_FAULT_INJECT,
+    SYSCALL_SBRK,
+    SYSCALL_CPU_SHARE,
+    SYSCALL_COUNTER_INFO,
+    SYSCALL_SYSCALL_MASK,
+    SYSCALL_SCHED_TUNE,
+    SYSCALL_PKEY_MPROTECT,
+    SYSCALL_PKEY_ALLOC,
+    SYSCALL_PKEY_FREE,
+    SYSCALL_PKEY_SET,
+    SYSCALL_NICE,
+    SYSCALL_PS,
+    SYSCALL_SCHEDSTAT,
+    SYSCALL_SLAB_STATS,
+    SYSCALL_MAPS,
+    SYSCALL_HEAP_STATS,
+    SYSCALL_FRAME_SCRUB,
+    SYSCALL_MEMPRESSURE,
+    SYSCALL_TASK_STATS,
+];
+
+pub mod errno;
 mod fs;
 pub mod process;
+mod trace;
 
 use fs::*;
 use process::*;
-use crate::fs::Stat;
-use crate::task::update_current_syscall_times;
+use crate::fs::{Quota, Stat};
+use crate::task::{set_current_in_syscall, update_current_syscall_times, RLimit, SignalAction};
+use crate::timer::CounterInfo;
+use errno::ENOSYS;
 
 /// handle syscall exception with `syscall_id` and other arguments
 pub fn syscall(syscall_id: usize, args: [usize; 4]) -> isize {
     update_current_syscall_times(syscall_id);
+    set_current_in_syscall(true);
+    let result = if trace::tracing() {
+        let call = trace::format_call(syscall_id, args);
+        if syscall_id == SYSCALL_EXIT || syscall_id == SYSCALL_EXIT_GROUP {
+            println!("{} = ?", call);
+        }
+        let result = dispatch(syscall_id, args);
+        println!("{} = {}", call, result);
+        result
+    } else {
+        dispatch(syscall_id, args)
+    };
+    set_current_in_syscall(false);
+    result
+}
+
+/// Run the syscall `syscall_id`
+fn dispatch(syscall_id: usize, args: [usize; 4]) -> isize {
     match syscall_id {
+        SYSCALL_IOCTL => sys_ioctl(args[0], args[1], args[2]),
         SYSCALL_LINKAT => sys_linkat(args[1] as *const u8, args[3] as *const u8),
         SYSCALL_UNLINKAT => sys_unlinkat(args[1] as *const u8),
+        SYSCALL_FACCESSAT => sys_faccessat(args[1] as *const u8, args[2] as u32, args[3] as u32),
         SYSCALL_OPEN => sys_open(args[1] as *const u8, args[2] as u32),
+        SYSCALL_QUOTACTL => sys_quotactl(args[0], args[2] as u32, args[3] as *mut Quota),
         SYSCALL_CLOSE => sys_close(args[0]),
         SYSCALL_READ => sys_read(args[0], args[1] as *const u8, args[2]),
         SYSCALL_WRITE => sys_write(args[0], args[1] as *const u8, args[2]),
         SYSCALL_FSTAT => sys_fstat(args[0], args[1] as *mut Stat),
-        SYSCALL_EXIT => sys_exit(args[0] as i32),
+        SYSCALL_SYNC_FILE_RANGE => sys_sync_file_range(args[0], args[1], args[2], args[3] as u32),
+        // a process has a single thread, so exiting it exits the whole group
+        SYSCALL_EXIT | SYSCALL_EXIT_GROUP => sys_exit(args[0] as i32),
+        SYSCALL_PTRACE => sys_ptrace(args[0], args[1], args[2], args[3]),
+        SYSCALL_SCHED_SETAFFINITY => sys_sched_setaffinity(args[0], args[1], args[2] as *const usize),
+        SYSCALL_SCHED_GETAFFINITY => sys_sched_getaffinity(args[0], args[1], args[2] as *mut usize),
         SYSCALL_YIELD => sys_yield(),
+        SYSCALL_KILL => sys_kill(args[0] as isize, args[1]),
+        SYSCALL_SIGACTION => sys_sigaction(
+            args[0],
+            args[1] as *const SignalAction,
+            args[2] as *mut SignalAction,
+        ),
+        SYSCALL_SIGPROCMASK => sys_sigprocmask(args[0] as u32),
+        SYSCALL_SIGRETURN => sys_sigreturn(),
+        SYSCALL_SETUID => sys_setuid(args[0] as u32),
+        SYSCALL_SETPGID => sys_setpgid(args[0], args[1]),
+        SYSCALL_GETPGID => sys_getpgid(args[0]),
+        SYSCALL_GETSID => sys_getsid(args[0]),
+        SYSCALL_SETSID => sys_setsid(),
         SYSCALL_GETPID => sys_getpid(),
-        SYSCALL_FORK => sys_fork(),
-        SYSCALL_EXEC => sys_exec(args[0] as *const u8),
-        SYSCALL_WAITPID => sys_waitpid(args[0] as isize, args[1] as *mut i32),
+        SYSCALL_GETPPID => sys_getppid(),
+        SYSCALL_GETUID => sys_getuid(),
+        SYSCALL_CLONE => sys_clone(args[0], args[1]),
+        SYSCALL_EXEC => sys_exec(args[0] as *const u8, args[1] as *const usize, args[2] as *const usize),
+        SYSCALL_WAITPID => sys_waitpid(args[0] as isize, args[1] as *mut i32, args[2] as u32),
+        SYSCALL_PRCTL => sys_prctl(args[0], args[1]),
         SYSCALL_GET_TIME => sys_get_time(args[0] as *mut TimeVal, args[1]),
-        SYSCALL_MMAP => sys_mmap(args[0], args[1], args[2]),
+        SYSCALL_BRK => sys_brk(args[0]),
+        SYSCALL_SBRK => sys_sbrk(args[0] as isize),
+        SYSCALL_MMAP => sys_mmap(args[0], args[1], args[2], args[3]),
         SYSCALL_MUNMAP => sys_munmap(args[0], args[1]),
+        SYSCALL_MREMAP => sys_mremap(args[0], args[1], args[2], args[3]),
+        SYSCALL_MPROTECT => sys_mprotect(args[0], args[1], args[2]),
+        SYSCALL_MSYNC => sys_msync(args[0], args[1], args[2]),
+        SYSCALL_MADVISE => sys_madvise(args[0], args[1], args[2]),
+        SYSCALL_PKEY_MPROTECT => sys_pkey_mprotect(args[0], args[1], args[2], args[3]),
+        SYSCALL_PKEY_ALLOC => sys_pkey_alloc(args[0], args[1]),
+        SYSCALL_PKEY_FREE => sys_pkey_free(args[0]),
+        SYSCALL_PKEY_SET => sys_pkey_set(args[0], args[1]),
         SYSCALL_SET_PRIORITY => sys_set_priority(args[0] as isize),
+        SYSCALL_GETPRIORITY => sys_getpriority(args[0], args[1]),
+        SYSCALL_NICE => sys_nice(args[0] as isize),
+        SYSCALL_PS => sys_ps(args[0] as *mut PsEntry, args[1]),
+        SYSCALL_SCHEDSTAT => sys_schedstat(args[0], args[1] as *mut SchedStat),
         SYSCALL_TASK_INFO => sys_task_info(args[0] as *mut TaskInfo),
-        SYSCALL_SPAWN => sys_spawn(args[0] as *const u8),
-        _ => panic!("Unsupported syscall_id: {}", syscall_id),
+        SYSCALL_TASK_STATS => sys_task_stats(args[0], args[1] as *mut TaskStats, args[2]),
+        SYSCALL_SPAWN => sys_spawn(args[0] as *const u8, args[1] as *const usize, args[2] as *const usize),
+        SYSCALL_IRQ_STATS => sys_irq_stats(args[0] as *mut u8, args[1]),
+        SYSCALL_SLAB_STATS => sys_slab_stats(args[0] as *mut u8, args[1]),
+        SYSCALL_MAPS => sys_maps(args[0], args[1] as *mut u8, args[2]),
+        SYSCALL_HEAP_STATS => sys_heap_stats(args[0] as *mut u8, args[1]),
+        SYSCALL_FRAME_SCRUB => sys_frame_scrub(args[0]),
+        SYSCALL_MEMPRESSURE => sys_mempressure(args[0], args[1] as u32),
+        SYSCALL_CPU_SHARE => sys_cpu_share(args[0] as *mut u8, args[1], args[2]),
+        SYSCALL_COUNTER_INFO => sys_counter_info(args[0] as *mut CounterInfo),
+        SYSCALL_TIMES => sys_times(args[0] as *mut Tms),
+        SYSCALL_GETRLIMIT => sys_getrlimit(args[0], args[1] as *mut RLimit),
+        SYSCALL_SETRLIMIT => sys_setrlimit(args[0], args[1] as *const RLimit),
+        SYSCALL_PRLIMIT => sys_prlimit(args[0], args[1], args[2] as *const RLimit, args[3] as *mut RLimit),
+        SYSCALL_GETRUSAGE => sys_getrusage(args[0] as isize, args[1] as *mut Rusage),
+        SYSCALL_SYSINFO => sys_sysinfo(args[0] as *mut SysInfo),
+        SYSCALL_IO_STATS => sys_io_stats(args[0], args[1] as *mut IoStats),
+        #[cfg(feature = "fault-inject")]
+        SYSCALL_FAULT_INJECT => sys_fault_inject(args[0], args[1], args[2]),
+        SYSCALL_SYSCALL_MASK => sys_syscall_mask(args[0] as *mut u8, args[1]),
+        SYSCALL_SCHED_TUNE => sys_sched_tune(args[0], args[1]),
+        _ => {
+            warn!("[kernel] Unsupported syscall_id: {}", syscall_id);
+            -ENOSYS
+        }
     }
 }
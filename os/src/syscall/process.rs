@@ -1,22 +1,117 @@
 //! Process management syscalls
-use crate::mm::{translated_refmut, translated_str, translated_byte_buffer, VirtAddr, MapPermission};
-use crate::task::{add_task, current_begin_time, current_syscall_times, current_task, current_user_token,
+use crate::mm::{frames_free, frames_total, heap_stats, heap_usage, scrub_on_free, set_scrub_on_free, slab_stats, swap_free, copy_string_from_user, copy_to_user, copy_usize_from_user, get_user, put_user, try_alloc, MemorySet, PageTable, VirtAddr, MapPermission};
+use crate::task::{add_task, all_tasks, CloneFlags, RLimit, TaskControlBlock, CPU_MASK_ALL, RLIMIT_AS, RLIM_NLIMITS, block_current_and_run_next, cpu_share_stats, send_signal, current_begin_time, current_syscall_times, current_task, current_user_token,
                   exit_current_and_run_next, insert_current_memory_set, remove_current_memory_set, set_current_priority,
-                  suspend_current_and_run_next, TaskStatus};
-use crate::fs::{open_file, OpenFlags};
-use crate::timer::get_time_us;
+                  suspend_current_and_run_next, wait_vfork_child, pid2task, ProcessFlags, SignalAction, SignalFlags, TaskStatus, MAX_SIG};
+use crate::fs::open_exec;
+use crate::timer::{counter_info, get_time_us, set_tick_freq, set_time_slice_ticks, CounterInfo};
+use crate::trap::irq_stats;
+use alloc::string::String;
 use alloc::sync::Arc;
-use crate::config::MAX_SYSCALL_NUM;
-use core::mem;
+use alloc::vec::Vec;
+use crate::config::{MAX_ARG_STRLEN, MAX_SYSCALL_NUM, MMAP_TOP, PAGE_SIZE, PATH_MAX, SWAP_PAGES, TASK_COMM_LEN};
+use super::errno::{EFAULT, EINTR, EINVAL, EIO, ENOMEM, ENOSPC, EPERM, ESRCH};
+use super::SYSCALL_IDS;
 use core::mem::size_of;
 
+/// waitpid option returning at once if no child has exited
+const WNOHANG: u32 = 1;
+/// prctl option reading whether the process is dumpable
+const PR_GET_DUMPABLE: usize = 3;
+/// prctl option setting whether the process is dumpable
+const PR_SET_DUMPABLE: usize = 4;
+/// prctl option setting the name of the process
+const PR_SET_NAME: usize = 15;
+/// prctl option reading the name of the process
+const PR_GET_NAME: usize = 16;
+/// prctl option reading how the process reads cycle counters
+const PR_GET_TSC: usize = 25;
+/// prctl option setting how the process reads cycle counters
+const PR_SET_TSC: usize = 26;
+/// `cycle` and `instret` are read from the hardware
+const PR_TSC_ENABLE: usize = 1;
+/// `cycle` and `instret` trap and count only the process itself
+const PR_TSC_VIRTUAL: usize = 2;
+/// prctl option reading whether syscalls are traced, not in Linux
+const PR_GET_SYSCALL_TRACE: usize = 0x5243_0001;
+/// prctl option setting whether syscalls are traced, not in Linux
+const PR_SET_SYSCALL_TRACE: usize = 0x5243_0002;
+/// mmap flag placing the mapping exactly at the address given
+const MAP_FIXED: usize = 0x10;
+/// mremap flag letting the mapping move if it cannot grow in place
+const MREMAP_MAYMOVE: usize = 1;
+/// msync flag starting the write-back without waiting for it
+const MS_ASYNC: usize = 1;
+/// msync flag dropping cached copies of the pages
+const MS_INVALIDATE: usize = 2;
+/// msync flag waiting for the write-back to complete
+const MS_SYNC: usize = 4;
+/// madvise advice asking for no special treatment
+const MADV_NORMAL: usize = 0;
+/// madvise advice announcing that the pages will be needed soon
+const MADV_WILLNEED: usize = 3;
+/// madvise advice giving the frames of the pages back
+const MADV_DONTNEED: usize = 4;
+
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, Default)]
 pub struct TimeVal {
     pub sec: usize,
     pub usec: usize,
 }
 
+impl TimeVal {
+    pub fn from_us(us: usize) -> Self {
+        Self {
+            sec: us / 1_000_000,
+            usec: us % 1_000_000,
+        }
+    }
+    pub fn as_us(&self) -> usize {
+        self.sec * 1_000_000 + self.usec
+    }
+}
+
+/// Clock ticks per second of the times reported by sys_times
+const USER_HZ: usize = 100;
+
+/// CPU times of a process and its reaped children in clock ticks of
+/// `USER_HZ`, laid out as `struct tms`
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Tms {
+    pub utime: usize,
+    pub stime: usize,
+    pub cutime: usize,
+    pub cstime: usize,
+}
+
+/// Memory and process statistics of the system, reported by sys_sysinfo
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct SysInfo {
+    /// Seconds since boot
+    pub uptime: usize,
+    pub total_frames: usize,
+    pub free_frames: usize,
+    /// Bytes of the kernel heap
+    pub heap_total: usize,
+    /// Bytes of the kernel heap allocated
+    pub heap_used: usize,
+    /// Pages of the swap area
+    pub total_swap: usize,
+    pub free_swap: usize,
+    /// Tasks alive, kernel threads included
+    pub procs: usize,
+}
+
+/// getpriority of a single process
+const PRIO_PROCESS: usize = 0;
+/// getrusage reporting on the calling process
+const RUSAGE_SELF: isize = 0;
+/// getrusage reporting on the waited-for children of the calling process
+const RUSAGE_CHILDREN: isize = -1;
+
 #[derive(Clone, Copy)]
 pub struct TaskInfo {
     pub status: TaskStatus,
@@ -24,6 +119,103 @@ pub struct TaskInfo {
     pub time: usize,
 }
 
+/// Memory, context switches and run time of a process
+///
+/// Fields are only ever appended, and `sys_task_stats` copies as much as
+/// the caller asks for, so programs built against a shorter struct keep
+/// working.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct TaskStats {
+    /// Frames mapped in the address space
+    pub resident_pages: usize,
+    /// Peak of `resident_pages`
+    pub peak_pages: usize,
+    /// Context switches when the task blocked or yielded
+    pub voluntary_switches: usize,
+    /// Context switches when the task was preempted
+    pub involuntary_switches: usize,
+    /// Milliseconds spent on the CPU
+    pub run_time: usize,
+    /// Most bytes of kernel stack used so far
+    pub kernel_stack_peak: usize,
+}
+
+/// I/O counters of a process, named as in Linux /proc/<pid>/io
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct IoStats {
+    /// Bytes returned by read
+    pub rchar: u64,
+    /// Bytes accepted by write
+    pub wchar: u64,
+    /// Number of read syscalls
+    pub syscr: u64,
+    /// Number of write syscalls
+    pub syscw: u64,
+    /// Blocks read from the block device
+    pub read_blocks: u64,
+    /// Blocks written to the block device
+    pub write_blocks: u64,
+}
+
+/// Scheduling statistics of a process, to compare scheduling policies
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct SchedStat {
+    /// Times the process was picked to run
+    pub run_count: usize,
+    /// Microseconds spent waiting in the ready queue
+    pub wait_time: usize,
+    /// Longest wait in the ready queue in microseconds
+    pub max_latency: usize,
+}
+
+/// Resource usage of a process, laid out as `struct rusage` in Linux
+///
+/// The fields easy to account for are filled in, the others stay 0.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct Rusage {
+    /// Time spent in user mode
+    pub utime: TimeVal,
+    /// Time spent in the kernel
+    pub stime: TimeVal,
+    /// Peak resident set size in KiB
+    pub maxrss: usize,
+    pub ixrss: usize,
+    pub idrss: usize,
+    pub isrss: usize,
+    pub minflt: usize,
+    pub majflt: usize,
+    pub nswap: usize,
+    /// Blocks read from the block device
+    pub inblock: usize,
+    /// Blocks written to the block device
+    pub oublock: usize,
+    pub msgsnd: usize,
+    pub msgrcv: usize,
+    pub nsignals: usize,
+    /// Voluntary context switches
+    pub nvcsw: usize,
+    /// Involuntary context switches
+    pub nivcsw: usize,
+}
+
+impl Rusage {
+    /// Add the usage of a reaped child, whose peak resident set is the
+    /// largest of all children rather than a sum
+    pub fn accumulate(&mut self, other: &Rusage) {
+        self.utime = TimeVal::from_us(self.utime.as_us() + other.utime.as_us());
+        self.stime = TimeVal::from_us(self.stime.as_us() + other.stime.as_us());
+        self.maxrss = self.maxrss.max(other.maxrss);
+        self.inblock += other.inblock;
+        self.oublock += other.oublock;
+        self.nvcsw += other.nvcsw;
+        self.nivcsw += other.nivcsw;
+    }
+}
+
 pub fn sys_exit(exit_code: i32) -> ! {
     debug!("[kernel] Application exited with code {}", exit_code);
     exit_current_and_run_next(exit_code);
@@ -40,30 +232,105 @@ pub fn sys_getpid() -> isize {
     current_task().unwrap().pid.0 as isize
 }
 
-/// Syscall Fork which returns 0 for child process and child_pid for parent process
-pub fn sys_fork() -> isize {
+/// Get the pid of the parent, which is initproc once the parent has exited,
+/// or 0 for initproc itself
+pub fn sys_getppid() -> isize {
+    let task = current_task().unwrap();
+    let inner = task.inner_exclusive_access();
+    inner
+        .parent
+        .as_ref()
+        .and_then(|parent| parent.upgrade())
+        .map_or(0, |parent| parent.getpid() as isize)
+}
+
+pub fn sys_getuid() -> isize {
+    current_task().unwrap().inner_exclusive_access().uid as isize
+}
+
+/// Change the user id, which only root may do
+pub fn sys_setuid(uid: u32) -> isize {
+    let task = current_task().unwrap();
+    let mut inner = task.inner_exclusive_access();
+    if inner.uid != 0 && inner.uid != uid {
+        return -1;
+    }
+    inner.uid = uid;
+    0
+}
+
+/// Create a child process, see [`CloneFlags`]; fork is clone without flags
+///
+/// The low byte of `flags` is the signal sent to the parent when the child
+/// exits in Linux, which is not supported and ignored.
+pub fn sys_clone(flags: usize, stack: usize) -> isize {
+    let flags = match CloneFlags::from_bits((flags & !0xff) as u32) {
+        Some(flags) => flags,
+        None => return -EINVAL,
+    };
     let current_task = current_task().unwrap();
-    let new_task = current_task.fork();
+    let new_task = match current_task.clone_task(flags, stack) {
+        Some(new_task) => new_task,
+        None => return -EINVAL,
+    };
     let new_pid = new_task.pid.0;
     // modify trap context of new_task, because it returns immediately after switching
     let trap_cx = new_task.inner_exclusive_access().get_trap_cx();
     // we do not have to move to next instruction since we have done it before
-    // for child process, fork returns 0
+    // for child process, clone returns 0
     trap_cx.x[10] = 0;
     // add new task to scheduler
-    add_task(new_task);
+    add_task(Arc::clone(&new_task));
+    // the caller has no user space until the vfork child execs or exits
+    wait_vfork_child();
     new_pid as isize
 }
 
-/// Syscall Exec which accepts the elf path
-pub fn sys_exec(path: *const u8) -> isize {
+/// Collect the strings of a null-terminated user array of string pointers,
+/// a null array being empty
+fn translated_str_array(token: usize, mut ptr: *const usize) -> Result<Vec<String>, isize> {
+    let mut strings = Vec::new();
+    if ptr.is_null() {
+        return Ok(strings);
+    }
+    loop {
+        let str_ptr = copy_usize_from_user(token, ptr)?;
+        if str_ptr == 0 {
+            break;
+        }
+        let string = copy_string_from_user(token, str_ptr as *const u8, MAX_ARG_STRLEN)?;
+        try_alloc(|| strings.try_reserve(1))?;
+        strings.push(string);
+        unsafe {
+            ptr = ptr.add(1);
+        }
+    }
+    Ok(strings)
+}
+
+/// Syscall Exec which accepts the elf path, argv and envp
+///
+/// The new program starts with argc in a0, argv in a1 and envp in a2.
+pub fn sys_exec(path: *const u8, args: *const usize, envs: *const usize) -> isize {
     let token = current_user_token();
-    let path = translated_str(token, path);
-    if let Some(app_inode) = open_file(path.as_str(), OpenFlags::RDONLY) {
-        let all_data = app_inode.read_all();
+    let path = match copy_string_from_user(token, path, PATH_MAX) {
+        Ok(path) => path,
+        Err(err) => return err,
+    };
+    let args = match translated_str_array(token, args) {
+        Ok(args) => args,
+        Err(err) => return err,
+    };
+    let envs = match translated_str_array(token, envs) {
+        Ok(envs) => envs,
+        Err(err) => return err,
+    };
+    if let Some(app_inode) = open_exec(path.as_str()) {
         let task = current_task().unwrap();
-        task.exec(all_data.as_slice());
-        0
+        match task.exec(path.as_str(), app_inode, args, envs) {
+            Ok(argc) => argc as isize,
+            Err(err) => err,
+        }
     } else {
         -1
     }
@@ -71,115 +338,1182 @@ pub fn sys_exec(path: *const u8) -> isize {
 
 
 /// If there is not a child process whose pid is same as given, return -1.
-/// Else if there is a child process but it is still running, return -2.
-pub fn sys_waitpid(pid: isize, exit_code_ptr: *mut i32) -> isize {
+/// Else wait until the child exits, or return -2 at once if it is still
+/// running and `options` has [`WNOHANG`].
+pub fn sys_waitpid(pid: isize, exit_code_ptr: *mut i32, options: u32) -> isize {
     let task = current_task().unwrap();
-    // find a child process
+    loop {
+        // find a child process
 
-    // ---- access current TCB exclusively
-    let mut inner = task.inner_exclusive_access();
-    if !inner
-        .children
-        .iter()
-        .any(|p| pid == -1 || pid as usize == p.getpid()) {
-        return -1;
+        // ---- access current TCB exclusively
+        let mut inner = task.inner_exclusive_access();
+        if !inner
+            .children
+            .iter()
+            .any(|p| pid == -1 || pid as usize == p.getpid()) {
+            return -1;
+            // ---- release current PCB
+        }
+        let pair = inner.children.iter().enumerate().find(|(_, p)| {
+            // ++++ temporarily access child PCB lock exclusively
+            let p_inner = p.inner_exclusive_access();
+            (p_inner.is_zombie() || p_inner.ptrace.stop_signal.is_some() && !p_inner.ptrace.reported)
+                && (pid == -1 || pid as usize == p.getpid())
+            // ++++ release child PCB
+        });
+        // a traced child stopped is reported with `(signal << 8) | 0x7f` as
+        // in Linux, and stays a child
+        if let Some((_, child)) = pair.filter(|(_, p)| !p.inner_exclusive_access().is_zombie()) {
+            let mut child_inner = child.inner_exclusive_access();
+            child_inner.ptrace.reported = true;
+            let status = (child_inner.ptrace.stop_signal.unwrap() << 8 | 0x7f) as i32;
+            let found_pid = child.getpid();
+            drop(child_inner);
+            drop(inner);
+            if let Err(err) = put_exit_code(exit_code_ptr, status) {
+                return err;
+            }
+            return found_pid as isize;
+        }
+        if let Some((idx, _)) = pair {
+            let child = inner.children.remove(idx);
+            // confirm that child will be deallocated after removing from children list
+            assert_eq!(Arc::strong_count(&child), 1);
+            let found_pid = child.getpid();
+            // ++++ temporarily access child TCB exclusively
+            let child_inner = child.inner_exclusive_access();
+            let exit_code = child_inner.exit_code;
+            inner.usage.children.accumulate(&child_inner.rusage());
+            inner.usage.children.accumulate(&child_inner.usage.children);
+            drop(child_inner);
+            // ++++ release child PCB
+            drop(inner);
+            if let Err(err) = put_exit_code(exit_code_ptr, exit_code) {
+                return err;
+            }
+            return found_pid as isize;
+        }
+        if options & WNOHANG != 0 {
+            return -2;
+        }
+        // let a pending signal be delivered instead of sleeping through it
+        if !(inner.signals - inner.signal_mask).is_empty() {
+            return -EINTR;
+        }
+        inner.waiting_child = true;
+        drop(inner);
         // ---- release current PCB
+        block_current_and_run_next();
     }
-    let pair = inner.children.iter().enumerate().find(|(_, p)| {
-        // ++++ temporarily access child PCB lock exclusively
-        p.inner_exclusive_access().is_zombie() && (pid == -1 || pid as usize == p.getpid())
-        // ++++ release child PCB
-    });
-    if let Some((idx, _)) = pair {
-        let child = inner.children.remove(idx);
-        // confirm that child will be deallocated after removing from children list
-        assert_eq!(Arc::strong_count(&child), 1);
-        let found_pid = child.getpid();
-        // ++++ temporarily access child TCB exclusively
-        let exit_code = child.inner_exclusive_access().exit_code;
-        // ++++ release child PCB
-        *translated_refmut(inner.memory_set.token(), exit_code_ptr) = exit_code;
-        found_pid as isize
-    } else {
-        -2
+}
+
+/// Store an exit status for waitpid unless `exit_code_ptr` is null
+fn put_exit_code(exit_code_ptr: *mut i32, exit_code: i32) -> Result<(), isize> {
+    if exit_code_ptr.is_null() {
+        return Ok(());
     }
-    // ---- release current PCB lock automatically
+    put_user(current_user_token(), exit_code_ptr, exit_code)
 }
 
 // YOUR JOB: 引入虚地址后重写 sys_get_time
 pub fn sys_get_time(_ts: *mut TimeVal, _tz: usize) -> isize {
     let _us = get_time_us();
-    let dsts = translated_byte_buffer(current_user_token(), _ts as *mut u8, size_of::<TimeVal>());
-    unsafe {
-        let src = mem::transmute::<TimeVal, [u8; 16]>(TimeVal {
-            sec: _us / 1_000_000,
-            usec: _us % 1_000_000,
-        });
-        for dst in dsts {
-            dst.copy_from_slice(&src);
-        }
+    let time_val = TimeVal {
+        sec: _us / 1_000_000,
+        usec: _us % 1_000_000,
+    };
+    match put_user(current_user_token(), _ts, time_val) {
+        Ok(()) => 0,
+        Err(err) => err,
     }
-    0
 }
 
 // YOUR JOB: 引入虚地址后重写 sys_task_info
 pub fn sys_task_info(_ti: *mut TaskInfo) -> isize {
     let _us = get_time_us();
     let _now = ((_us / 1_000_000) & 0xffff) * 1000 + ((_us % 1_000_000) / 1000);
-    let dsts = translated_byte_buffer(current_user_token(), _ti as *mut u8, size_of::<TaskInfo>());
-    unsafe {
-        let src = mem::transmute::<TaskInfo, [u8; 2016]>(TaskInfo {
-            status: TaskStatus::Running,
-            syscall_times: current_syscall_times(),
-            time: _now - current_begin_time(),
-        });
-        for dst in dsts {
-            dst.copy_from_slice(&src);
-        }
+    let info = TaskInfo {
+        status: TaskStatus::Running,
+        syscall_times: current_syscall_times(),
+        time: _now - current_begin_time(),
+    };
+    match put_user(current_user_token(), _ti, info) {
+        Ok(()) => 0,
+        Err(err) => err,
+    }
+}
+
+/// Copy the first `size` bytes of the [`TaskStats`] of process `pid`, 0
+/// meaning the caller, returning how many bytes were copied
+pub fn sys_task_stats(pid: usize, stats: *mut TaskStats, size: usize) -> isize {
+    let task = match task_for_caller(pid) {
+        Ok(task) => task,
+        Err(err) => return err,
+    };
+    let mut inner = task.inner_exclusive_access();
+    inner.update_maxrss();
+    let (utime, stime) = inner.cpu_times();
+    let task_stats = TaskStats {
+        resident_pages: inner.memory_set.resident_pages(),
+        peak_pages: inner.usage.max_rss_pages,
+        voluntary_switches: inner.usage.nvcsw,
+        involuntary_switches: inner.usage.nivcsw,
+        run_time: (utime + stime) / 1000,
+        kernel_stack_peak: task.kernel_stack.high_water_mark(),
+    };
+    drop(inner);
+    let bytes = unsafe {
+        core::slice::from_raw_parts(&task_stats as *const TaskStats as *const u8, size_of::<TaskStats>())
+    };
+    let bytes = &bytes[..size.min(bytes.len())];
+    match copy_to_user(current_user_token(), stats as *mut u8, bytes) {
+        Ok(()) => bytes.len() as isize,
+        Err(err) => err,
     }
-    0
 }
 
 // YOUR JOB: 实现sys_set_priority，为任务添加优先级
+/// Only root may raise the priority
 pub fn sys_set_priority(_prio: isize) -> isize {
     if 2 <= _prio {
+        let task = current_task().unwrap();
+        let inner = task.inner_exclusive_access();
+        if _prio as usize > inner.task_priority && inner.uid != 0 {
+            return -EPERM;
+        }
+        drop(inner);
         set_current_priority(_prio as usize);
         return _prio;
     }
     -1
 }
 
+/// Add `inc` to the nice value of the caller, clamped to the valid range,
+/// and return the new nice value; only root may lower it and so raise the
+/// priority
+pub fn sys_nice(inc: isize) -> isize {
+    let task = current_task().unwrap();
+    let mut inner = task.inner_exclusive_access();
+    let nice = inner.nice.saturating_add(inc);
+    if nice < inner.nice && inner.uid != 0 {
+        return -EPERM;
+    }
+    inner.set_nice(nice);
+    inner.nice
+}
+
+/// Get the nice value of process `who`, 0 meaning the caller, as
+/// `20 - nice` so that it is never negative, as in Linux
+pub fn sys_getpriority(which: usize, who: usize) -> isize {
+    if which != PRIO_PROCESS {
+        return -EINVAL;
+    }
+    let task = if who == 0 {
+        current_task().unwrap()
+    } else {
+        match pid2task(who) {
+            Some(task) => task,
+            None => return -ESRCH,
+        }
+    };
+    let nice = task.inner_exclusive_access().nice;
+    20 - nice
+}
+
 // YOUR JOB: 扩展内核以实现 sys_mmap 和 sys_munmap
-pub fn sys_mmap(_start: usize, _len: usize, _port: usize) -> isize {
+/// Map `_len` bytes at `_start`, or where the kernel finds room unless
+/// `flags` has MAP_FIXED, and return the start of the mapping
+pub fn sys_mmap(_start: usize, _len: usize, _port: usize, flags: usize) -> isize {
     let va = VirtAddr::from(_start);
     if va.page_offset() == 0 && _port & !0x7 == 0 && _port & 0x7 != 0 {
+        if !current_may_map(VirtAddr::from(_len).ceil().0 * PAGE_SIZE) {
+            return -ENOMEM;
+        }
         let permission = MapPermission::from_bits((_port << 1 | 1 << 4) as u8).unwrap();
-        if insert_current_memory_set(_start.into(), (_start + _len).into(), permission) == 0 {
-            return 0;
+        let fixed = flags & MAP_FIXED != 0;
+        if let Some(start) = insert_current_memory_set(va, _len, permission, fixed) {
+            return start.0 as isize;
         }
     }
     -1
 }
 
+/// Convert mmap-style `prot` bits to the permission of a user area, None
+/// for combinations the hardware does not allow; 0 makes the area
+/// inaccessible
+fn prot_to_permission(prot: usize) -> Option<MapPermission> {
+    // writable pages must be readable on RISC-V
+    if prot & !0x7 != 0 || (prot & 0x2 != 0 && prot & 0x1 == 0) {
+        return None;
+    }
+    MapPermission::from_bits((prot << 1 | 1 << 4) as u8)
+}
+
+/// Change the protection of the pages in a range, e.g. for guard pages
+/// or to make code written at run time executable
+pub fn sys_mprotect(start: usize, len: usize, prot: usize) -> isize {
+    let perm = match prot_to_permission(prot) {
+        Some(perm) => perm,
+        None => return -EINVAL,
+    };
+    if VirtAddr::from(start).page_offset() != 0 {
+        return -EINVAL;
+    }
+    let task = current_task().unwrap();
+    let mut inner = task.inner_exclusive_access();
+    if inner.memory_set.mprotect(start.into(), (start + len).into(), perm) {
+        0
+    } else {
+        -ENOMEM
+    }
+}
+
+/// Allocate a protection key to group areas with, see [`sys_pkey_set`]
+pub fn sys_pkey_alloc(flags: usize, _access_rights: usize) -> isize {
+    if flags != 0 {
+        return -EINVAL;
+    }
+    let task = current_task().unwrap();
+    let mut inner = task.inner_exclusive_access();
+    match inner.memory_set.pkey_alloc() {
+        Some(pkey) => pkey as isize,
+        None => -ENOSPC,
+    }
+}
+
+/// Free a protection key, the areas of its group keep their protection
+pub fn sys_pkey_free(pkey: usize) -> isize {
+    let task = current_task().unwrap();
+    let mut inner = task.inner_exclusive_access();
+    if inner.memory_set.pkey_free(pkey) {
+        0
+    } else {
+        -EINVAL
+    }
+}
+
+/// Set the protection of the whole areas in a range and add them to the
+/// group of `pkey`
+pub fn sys_pkey_mprotect(start: usize, len: usize, prot: usize, pkey: usize) -> isize {
+    let perm = match prot_to_permission(prot) {
+        Some(perm) => perm,
+        None => return -EINVAL,
+    };
+    if VirtAddr::from(start).page_offset() != 0 {
+        return -EINVAL;
+    }
+    let task = current_task().unwrap();
+    let mut inner = task.inner_exclusive_access();
+    if inner.memory_set.pkey_mprotect(start.into(), (start + len).into(), perm, pkey) {
+        0
+    } else {
+        -EINVAL
+    }
+}
+
+/// Set the protection of every area in the group of `pkey` at once, e.g.
+/// to turn read barriers of a garbage collector on and off
+pub fn sys_pkey_set(pkey: usize, prot: usize) -> isize {
+    let perm = match prot_to_permission(prot) {
+        Some(perm) => perm,
+        None => return -EINVAL,
+    };
+    let task = current_task().unwrap();
+    let mut inner = task.inner_exclusive_access();
+    if inner.memory_set.pkey_set(pkey, perm) {
+        0
+    } else {
+        -EINVAL
+    }
+}
+
 pub fn sys_munmap(_start: usize, _len: usize) -> isize {
     remove_current_memory_set(_start.into(), (_start + _len).into())
 }
 
+/// Grow or shrink the mapping at `old_addr`, moving it if allowed by
+/// `flags`; returns its new address
+pub fn sys_mremap(old_addr: usize, old_len: usize, new_len: usize, flags: usize) -> isize {
+    if VirtAddr::from(old_addr).page_offset() != 0
+        || old_len == 0
+        || new_len == 0
+        || flags & !MREMAP_MAYMOVE != 0
+        || old_addr.checked_add(old_len).map_or(true, |end| end > MMAP_TOP)
+    {
+        return -EINVAL;
+    }
+    // no mapping can be larger than the space below MMAP_TOP
+    if new_len > MMAP_TOP {
+        return -ENOMEM;
+    }
+    let growth = VirtAddr::from(new_len).ceil().0.saturating_sub(VirtAddr::from(old_len).ceil().0);
+    if !current_may_map(growth * PAGE_SIZE) {
+        return -ENOMEM;
+    }
+    let task = current_task().unwrap();
+    let mut inner = task.inner_exclusive_access();
+    let may_move = flags & MREMAP_MAYMOVE != 0;
+    match inner.memory_set.mremap(old_addr.into(), old_len, new_len, may_move) {
+        Some(new_addr) => new_addr.0 as isize,
+        None => -ENOMEM,
+    }
+}
+
+/// Write the pages of the file mappings in a range back to their files,
+/// waiting for it with `MS_SYNC`
+pub fn sys_msync(start: usize, len: usize, flags: usize) -> isize {
+    if VirtAddr::from(start).page_offset() != 0
+        || flags & !(MS_ASYNC | MS_INVALIDATE | MS_SYNC) != 0
+        || flags & MS_ASYNC != 0 && flags & MS_SYNC != 0
+    {
+        return -EINVAL;
+    }
+    let end = match start.checked_add(len) {
+        Some(end) => end,
+        None => return -ENOMEM,
+    };
+    let task = current_task().unwrap();
+    let inner = task.inner_exclusive_access();
+    if inner.memory_set.sync_range(start.into(), end.into()) {
+        0
+    } else {
+        -ENOMEM
+    }
+}
+
+/// Advise how the pages in a range will be used: with `MADV_DONTNEED`
+/// their frames are freed, the pages reading as zero or as their file
+/// again, and with `MADV_WILLNEED` those in a file or in swap are read in
+pub fn sys_madvise(start: usize, len: usize, advice: usize) -> isize {
+    if VirtAddr::from(start).page_offset() != 0 {
+        return -EINVAL;
+    }
+    let end = match start.checked_add(len) {
+        Some(end) => end,
+        None => return -EINVAL,
+    };
+    let task = current_task().unwrap();
+    let mut inner = task.inner_exclusive_access();
+    let done = match advice {
+        MADV_NORMAL => true,
+        MADV_WILLNEED => inner.memory_set.prefetch_range(start.into(), end.into()),
+        MADV_DONTNEED => inner.memory_set.discard_range(start.into(), end.into()),
+        _ => return -EINVAL,
+    };
+    if done {
+        0
+    } else {
+        -ENOMEM
+    }
+}
+
+/// Whether current task may map `len` more bytes within its `RLIMIT_AS`
+fn current_may_map(len: usize) -> bool {
+    let task = current_task().unwrap();
+    let inner = task.inner_exclusive_access();
+    let limit = inner.rlimits.get(RLIMIT_AS).cur;
+    inner.memory_set.mapped_size().saturating_add(len) <= limit
+}
+
+/// Bytes the heap grows by when the program break moves to `new_brk`
+fn heap_growth(old_brk: usize, new_brk: usize) -> usize {
+    let old_end = VirtAddr::from(old_brk).ceil().0;
+    let new_end = VirtAddr::from(new_brk).ceil().0;
+    new_end.saturating_sub(old_end) * PAGE_SIZE
+}
+
+/// Set the program break to `addr` and return the new program break,
+/// which stays unchanged if `addr` is 0 or the heap cannot be moved there
+pub fn sys_brk(addr: usize) -> isize {
+    let old_brk = current_task().unwrap().inner_exclusive_access().memory_set.brk();
+    let may_map = current_may_map(heap_growth(old_brk, addr));
+    let task = current_task().unwrap();
+    let mut inner = task.inner_exclusive_access();
+    if addr != 0 && may_map && inner.memory_set.set_brk(addr) {
+        inner.update_maxrss();
+    }
+    inner.memory_set.brk() as isize
+}
+
+/// Grow or shrink the heap by `increment` bytes, returning the old
+/// program break
+pub fn sys_sbrk(increment: isize) -> isize {
+    let old_brk = current_task().unwrap().inner_exclusive_access().memory_set.brk();
+    let new_brk = old_brk as isize + increment;
+    if new_brk < 0 {
+        return -1;
+    }
+    if !current_may_map(heap_growth(old_brk, new_brk as usize)) {
+        return -ENOMEM;
+    }
+    let task = current_task().unwrap();
+    let mut inner = task.inner_exclusive_access();
+    if !inner.memory_set.set_brk(new_brk as usize) {
+        return -1;
+    }
+    inner.update_maxrss();
+    old_brk as isize
+}
+
 // YOUR JOB: 实现 sys_spawn 系统调用
 // ALERT: 注意在实现 SPAWN 时不需要复制父进程地址空间，SPAWN != FORK + EXEC 
-pub fn sys_spawn(_path: *const u8) -> isize {
+/// Create a child running the elf at `path`; the elf is loaded before the
+/// child exists, so a broken one makes spawn itself fail with -ENOEXEC
+pub fn sys_spawn(_path: *const u8, args: *const usize, envs: *const usize) -> isize {
     let token = current_user_token();
-    let path = translated_str(token, _path);
-    if let Some(app_inode) = open_file(path.as_str(), OpenFlags::RDONLY) {
-        let all_data = app_inode.read_all();
+    let path = match copy_string_from_user(token, _path, PATH_MAX) {
+        Ok(path) => path,
+        Err(err) => return err,
+    };
+    let args = match translated_str_array(token, args) {
+        Ok(args) => args,
+        Err(err) => return err,
+    };
+    let envs = match translated_str_array(token, envs) {
+        Ok(envs) => envs,
+        Err(err) => return err,
+    };
+    if let Some(app_inode) = open_exec(path.as_str()) {
         let task = current_task().unwrap();
-        let new_task = task.spawn(all_data.as_slice());
+        let new_task = match task.spawn(path.as_str(), app_inode, args, envs) {
+            Ok(new_task) => new_task,
+            Err(err) => return err,
+        };
         let new_pid = new_task.pid.0;
-        let trap_cx = new_task.inner_exclusive_access().get_trap_cx();
-        trap_cx.x[10] = 0;
         add_task(new_task);
         new_pid as isize
     } else {
         -1
     }
 }
+
+/// Read or change a per-process toggle
+///
+/// Toggles are inherited by children created with fork, see [`ProcessFlags`].
+/// The name is passed in a buffer of `TASK_COMM_LEN` bytes.
+pub fn sys_prctl(option: usize, arg2: usize) -> isize {
+    let token = current_user_token();
+    let task = current_task().unwrap();
+    let mut inner = task.inner_exclusive_access();
+    match option {
+        PR_SET_NAME => {
+            // longer names are truncated by set_comm
+            let name = match copy_string_from_user(token, arg2 as *const u8, MAX_ARG_STRLEN) {
+                Ok(name) => name,
+                Err(err) => return err,
+            };
+            inner.set_comm(name.as_str());
+            0
+        }
+        PR_GET_NAME => {
+            let comm = inner.comm;
+            drop(inner);
+            match copy_to_user(token, arg2 as *mut u8, &comm) {
+                Ok(()) => 0,
+                Err(err) => err,
+            }
+        }
+        PR_GET_TSC => {
+            if inner.flags.contains(ProcessFlags::VIRTUAL_COUNTERS) {
+                PR_TSC_VIRTUAL as isize
+            } else {
+                PR_TSC_ENABLE as isize
+            }
+        }
+        // takes effect when the process is next switched in
+        PR_SET_TSC => match arg2 {
+            PR_TSC_ENABLE => {
+                inner.flags.remove(ProcessFlags::VIRTUAL_COUNTERS);
+                0
+            }
+            PR_TSC_VIRTUAL => {
+                inner.flags.insert(ProcessFlags::VIRTUAL_COUNTERS);
+                0
+            }
+            _ => -1,
+        },
+        PR_GET_SYSCALL_TRACE => inner.flags.contains(ProcessFlags::TRACE_SYSCALLS) as isize,
+        PR_SET_SYSCALL_TRACE => match arg2 {
+            0 => {
+                inner.flags.remove(ProcessFlags::TRACE_SYSCALLS);
+                0
+            }
+            1 => {
+                inner.flags.insert(ProcessFlags::TRACE_SYSCALLS);
+                0
+            }
+            _ => -1,
+        },
+        PR_GET_DUMPABLE => inner.flags.contains(ProcessFlags::DUMPABLE) as isize,
+        PR_SET_DUMPABLE => match arg2 {
+            0 => {
+                inner.flags.remove(ProcessFlags::DUMPABLE);
+                0
+            }
+            1 => {
+                inner.flags.insert(ProcessFlags::DUMPABLE);
+                0
+            }
+            _ => -1,
+        },
+        _ => -1,
+    }
+}
+
+/// Copy interrupt statistics as text into a user buffer, truncated to `len`
+pub fn sys_irq_stats(buf: *mut u8, len: usize) -> isize {
+    copy_text_to_user(&irq_stats(), buf, len)
+}
+
+/// Copy slab cache statistics as text into a user buffer, truncated to
+/// `len`
+pub fn sys_slab_stats(buf: *mut u8, len: usize) -> isize {
+    copy_text_to_user(&slab_stats(), buf, len)
+}
+
+/// Copy kernel heap statistics as text into a user buffer, truncated to
+/// `len`
+pub fn sys_heap_stats(buf: *mut u8, len: usize) -> isize {
+    copy_text_to_user(&heap_stats(), buf, len)
+}
+
+/// Copy the user areas of process `pid`, 0 meaning the caller, as text
+/// like `/proc/<pid>/maps` into a user buffer, truncated to `len`
+pub fn sys_maps(pid: usize, buf: *mut u8, len: usize) -> isize {
+    let task = match task_for_caller(pid) {
+        Ok(task) => task,
+        Err(err) => return err,
+    };
+    let maps = task.inner_exclusive_access().memory_set.maps();
+    copy_text_to_user(&maps, buf, len)
+}
+
+/// Copy text into a user buffer, truncated to `len`
+fn copy_text_to_user(text: &str, buf: *mut u8, len: usize) -> isize {
+    let src = &text.as_bytes()[..len.min(text.len())];
+    match copy_to_user(current_user_token(), buf, src) {
+        Ok(()) => src.len() as isize,
+        Err(err) => err,
+    }
+}
+
+/// A process as listed by sys_ps
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct PsEntry {
+    pub pid: usize,
+    /// Pid of the parent, 0 if there is none
+    pub ppid: usize,
+    /// Numbered as [`TaskStatus`]
+    pub status: usize,
+    /// Name of the process, NUL-padded
+    pub name: [u8; TASK_COMM_LEN],
+    /// Milliseconds spent on the CPU
+    pub cpu_time: usize,
+}
+
+/// Fill the array at `entries` with at most `count` live processes,
+/// returning how many processes there are
+pub fn sys_ps(entries: *mut PsEntry, count: usize) -> isize {
+    let tasks = all_tasks();
+    let list: Vec<PsEntry> = tasks
+        .iter()
+        .take(count)
+        .map(|task| {
+            let inner = task.inner_exclusive_access();
+            let (utime, stime) = inner.cpu_times();
+            PsEntry {
+                pid: task.getpid(),
+                ppid: inner
+                    .parent
+                    .as_ref()
+                    .and_then(|parent| parent.upgrade())
+                    .map_or(0, |parent| parent.getpid()),
+                status: inner.task_status as usize,
+                name: inner.comm,
+                cpu_time: (utime + stime) / 1000,
+            }
+        })
+        .collect();
+    let bytes = unsafe {
+        core::slice::from_raw_parts(
+            list.as_ptr() as *const u8,
+            list.len() * size_of::<PsEntry>(),
+        )
+    };
+    match copy_to_user(current_user_token(), entries as *mut u8, bytes) {
+        Ok(()) => tasks.len() as isize,
+        Err(err) => err,
+    }
+}
+
+/// Get the scheduling statistics of process `pid`, 0 meaning the caller
+pub fn sys_schedstat(pid: usize, stat: *mut SchedStat) -> isize {
+    let task = match task_for_caller(pid) {
+        Ok(task) => task,
+        Err(err) => return err,
+    };
+    let sched_stat = task.inner_exclusive_access().sched_stat;
+    match put_user(current_user_token(), stat, sched_stat) {
+        Ok(()) => 0,
+        Err(err) => err,
+    }
+}
+
+/// Copy the CPU time each priority level received as text into a user
+/// buffer, truncated to `len`, and start a new window if `reset` is not 0
+pub fn sys_cpu_share(buf: *mut u8, len: usize, reset: usize) -> isize {
+    copy_text_to_user(&cpu_share_stats(reset != 0), buf, len)
+}
+
+/// Set the number of timer interrupts per second and the ticks in a time
+/// slice, leaving either unchanged if it is 0; only root may tune them
+pub fn sys_sched_tune(tick_freq: usize, slice_ticks: usize) -> isize {
+    if current_task().unwrap().inner_exclusive_access().uid != 0 {
+        return -EPERM;
+    }
+    if (tick_freq != 0 && !(10..=10_000).contains(&tick_freq)) || slice_ticks > 1000 {
+        return -EINVAL;
+    }
+    if tick_freq != 0 {
+        set_tick_freq(tick_freq);
+    }
+    if slice_ticks != 0 {
+        set_time_slice_ticks(slice_ticks);
+    }
+    0
+}
+
+/// Zero freed frames when they are freed if `on_free` is 1, or when they
+/// are allocated again if it is 0, returning the previous setting; only
+/// root may change it
+pub fn sys_frame_scrub(on_free: usize) -> isize {
+    if current_task().unwrap().inner_exclusive_access().uid != 0 {
+        return -EPERM;
+    }
+    if on_free > 1 {
+        return -EINVAL;
+    }
+    let old = scrub_on_free();
+    set_scrub_on_free(on_free == 1);
+    old as isize
+}
+
+/// Get the frequencies of the counters and whether the caller's are
+/// virtualized, see [`PR_SET_TSC`]
+pub fn sys_counter_info(info: *mut CounterInfo) -> isize {
+    let task = current_task().unwrap();
+    let virtualized = task.inner_exclusive_access().flags.contains(ProcessFlags::VIRTUAL_COUNTERS);
+    match put_user(current_user_token(), info, counter_info(virtualized)) {
+        Ok(()) => 0,
+        Err(err) => err,
+    }
+}
+
+/// Copy a bitmap of the implemented syscalls, bit `id % 8` of byte `id / 8`
+/// being set if syscall `id` exists, into a user buffer truncated to `len`
+/// bytes; ids stay below `MAX_SYSCALL_NUM`
+pub fn sys_syscall_mask(buf: *mut u8, len: usize) -> isize {
+    let mut mask = [0u8; (MAX_SYSCALL_NUM + 7) / 8];
+    for &id in SYSCALL_IDS {
+        mask[id / 8] |= 1 << (id % 8);
+    }
+    let len = len.min(mask.len());
+    match copy_to_user(current_user_token(), buf, &mask[..len]) {
+        Ok(()) => len as isize,
+        Err(err) => err,
+    }
+}
+
+/// Send signal `signum` to process `pid`, or to every process of group
+/// `-pid` if `pid` is negative and of the caller's group if it is 0;
+/// signal 0 only checks that the targets exist
+pub fn sys_kill(pid: isize, signum: usize) -> isize {
+    let signal = match (signum, SignalFlags::from_signum(signum)) {
+        (0, _) => SignalFlags::empty(),
+        (_, Some(signal)) => signal,
+        _ => return -EINVAL,
+    };
+    let task = current_task().unwrap();
+    let inner = task.inner_exclusive_access();
+    let (uid, pgid) = (inner.uid, inner.pgid);
+    drop(inner);
+    let targets: Vec<_> = if pid > 0 {
+        pid2task(pid as usize).into_iter().collect()
+    } else {
+        let pgid = if pid == 0 { pgid } else { pid.unsigned_abs() };
+        all_tasks()
+            .into_iter()
+            .filter(|task| task.inner_exclusive_access().pgid == pgid)
+            .collect()
+    };
+    if targets.is_empty() {
+        return -ESRCH;
+    }
+    let mut sent = false;
+    for target in targets {
+        let target_uid = target.inner_exclusive_access().uid;
+        if uid == 0 || uid == target_uid {
+            send_signal(target, signal);
+            sent = true;
+        }
+    }
+    if sent {
+        0
+    } else {
+        -EPERM
+    }
+}
+
+/// Move process `pid` (0 for the caller) into group `pgid` (0 for a new
+/// group led by `pid`) of the same session, only the caller and its
+/// children may be moved
+pub fn sys_setpgid(pid: usize, pgid: usize) -> isize {
+    let task = current_task().unwrap();
+    let target = if pid == 0 || pid == task.getpid() {
+        task
+    } else {
+        let inner = task.inner_exclusive_access();
+        match inner.children.iter().find(|child| child.getpid() == pid) {
+            Some(child) => child.clone(),
+            None => return -1,
+        }
+    };
+    let sid = target.inner_exclusive_access().sid;
+    // a session leader stays in its own group
+    if sid == target.getpid() {
+        return -1;
+    }
+    let pgid = if pgid == 0 { target.getpid() } else { pgid };
+    // joining a group needs a member already there
+    if pgid != target.getpid()
+        && !all_tasks().iter().any(|task| {
+            let inner = task.inner_exclusive_access();
+            inner.pgid == pgid && inner.sid == sid
+        })
+    {
+        return -1;
+    }
+    target.inner_exclusive_access().pgid = pgid;
+    0
+}
+
+/// Start a new session and group led by the caller, without a controlling
+/// terminal; group leaders cannot do so
+pub fn sys_setsid() -> isize {
+    let task = current_task().unwrap();
+    let pid = task.getpid();
+    let mut inner = task.inner_exclusive_access();
+    if inner.pgid == pid {
+        return -1;
+    }
+    inner.sid = pid;
+    inner.pgid = pid;
+    pid as isize
+}
+
+/// Get the session of process `pid`, 0 for the caller
+pub fn sys_getsid(pid: usize) -> isize {
+    let task = if pid == 0 {
+        current_task()
+    } else {
+        pid2task(pid)
+    };
+    match task {
+        Some(task) => task.inner_exclusive_access().sid as isize,
+        None => -1,
+    }
+}
+
+/// Get the group of process `pid`, 0 for the caller
+pub fn sys_getpgid(pid: usize) -> isize {
+    let task = if pid == 0 {
+        current_task()
+    } else {
+        pid2task(pid)
+    };
+    match task {
+        Some(task) => task.inner_exclusive_access().pgid as isize,
+        None => -1,
+    }
+}
+
+/// Get the process `pid` as seen by the caller, 0 meaning the caller; only
+/// root may act on processes of other users
+fn task_for_caller(pid: usize) -> Result<Arc<TaskControlBlock>, isize> {
+    let current = current_task().unwrap();
+    if pid == 0 {
+        return Ok(current);
+    }
+    let task = pid2task(pid).ok_or(-ESRCH)?;
+    let uid = current.inner_exclusive_access().uid;
+    if uid != 0 && uid != task.inner_exclusive_access().uid {
+        return Err(-EPERM);
+    }
+    Ok(task)
+}
+
+/// Pin process `pid` to the harts in the mask at `mask`, which is `len`
+/// bytes long; the mask must include a hart that exists
+pub fn sys_sched_setaffinity(pid: usize, len: usize, mask: *const usize) -> isize {
+    if len < size_of::<usize>() {
+        return -EINVAL;
+    }
+    let mask = match copy_usize_from_user(current_user_token(), mask) {
+        Ok(mask) => mask & CPU_MASK_ALL,
+        Err(err) => return err,
+    };
+    if mask == 0 {
+        return -EINVAL;
+    }
+    match task_for_caller(pid) {
+        Ok(task) => {
+            task.inner_exclusive_access().cpu_mask = mask;
+            0
+        }
+        Err(err) => err,
+    }
+}
+
+/// Store the hart mask of process `pid` at `mask`, returning its size
+pub fn sys_sched_getaffinity(pid: usize, len: usize, mask: *mut usize) -> isize {
+    if len < size_of::<usize>() {
+        return -EINVAL;
+    }
+    match task_for_caller(pid) {
+        Ok(task) => {
+            let cpu_mask = task.inner_exclusive_access().cpu_mask;
+            match put_user(current_user_token(), mask, cpu_mask) {
+                Ok(()) => size_of::<usize>() as isize,
+                Err(err) => err,
+            }
+        }
+        Err(err) => err,
+    }
+}
+
+/// Get and set the resource limits of process `pid`, 0 meaning the caller;
+/// either of `new_limit` and `old_limit` may be null
+///
+/// Only root may raise a hard limit.
+pub fn sys_prlimit(
+    pid: usize,
+    resource: usize,
+    new_limit: *const RLimit,
+    old_limit: *mut RLimit,
+) -> isize {
+    if resource >= RLIM_NLIMITS {
+        return -EINVAL;
+    }
+    let task = match task_for_caller(pid) {
+        Ok(task) => task,
+        Err(err) => return err,
+    };
+    let token = current_user_token();
+    let uid = current_task().unwrap().inner_exclusive_access().uid;
+    // no TCB may be borrowed while user memory is accessed, as a lazily
+    // allocated page is faulted in through the current one
+    let new = match (!new_limit.is_null()).then(|| get_user(token, new_limit)).transpose() {
+        Ok(new) => new,
+        Err(err) => return err,
+    };
+    let mut inner = task.inner_exclusive_access();
+    let old = inner.rlimits.get(resource);
+    if let Some(new) = new {
+        if new.cur > new.max {
+            return -EINVAL;
+        }
+        if new.max > old.max && uid != 0 {
+            return -EPERM;
+        }
+        inner.rlimits.set(resource, new);
+    }
+    drop(inner);
+    if !old_limit.is_null() {
+        if let Err(err) = put_user(token, old_limit, old) {
+            return err;
+        }
+    }
+    0
+}
+
+pub fn sys_getrlimit(resource: usize, rlim: *mut RLimit) -> isize {
+    sys_prlimit(0, resource, core::ptr::null(), rlim)
+}
+
+pub fn sys_setrlimit(resource: usize, rlim: *const RLimit) -> isize {
+    sys_prlimit(0, resource, rlim, core::ptr::null_mut())
+}
+
+/// ptrace request marking the caller as traced by its parent
+const PTRACE_TRACEME: usize = 0;
+/// ptrace request reading a word of code
+const PTRACE_PEEKTEXT: usize = 1;
+/// ptrace request reading a word of data
+const PTRACE_PEEKDATA: usize = 2;
+/// ptrace request writing a word of code
+const PTRACE_POKETEXT: usize = 4;
+/// ptrace request writing a word of data
+const PTRACE_POKEDATA: usize = 5;
+/// ptrace request resuming the tracee
+const PTRACE_CONT: usize = 7;
+/// ptrace request killing the tracee
+const PTRACE_KILL: usize = 8;
+/// ptrace request reading the registers
+const PTRACE_GETREGS: usize = 12;
+/// ptrace request writing the registers
+const PTRACE_SETREGS: usize = 13;
+/// ptrace request resuming the tracee until the next syscall entry or exit
+const PTRACE_SYSCALL: usize = 24;
+
+/// Registers of a tracee, `pc` then x1-x31 as `struct user_regs_struct`
+type UserRegs = [usize; 32];
+
+/// The aligned word at `addr` of a tracee's address space, after faulting
+/// its page in like a user access, writing if `write` is set, so that a
+/// write never lands on the shared zero frame
+///
+/// Only the areas of the tracee are reached, not the pages shared by
+/// every process, which a write would change for all of them.
+fn tracee_word(memory_set: &mut MemorySet, addr: usize, write: bool) -> Option<*mut usize> {
+    let va = VirtAddr::from(addr);
+    if addr % size_of::<usize>() != 0 || !memory_set.in_area(va.floor()) {
+        return None;
+    }
+    memory_set.fault_in(va.floor(), write);
+    PageTable::from_token(memory_set.token())
+        .translate_user_va(va)
+        .map(|pa| usize::from(pa) as *mut usize)
+}
+
+/// Trace a child stopped for its tracer, see [`crate::task::PtraceState`]
+///
+/// Words are read and written at `addr`, which must be aligned, through
+/// the address space of the tracee; the word read is stored at `data`.
+/// Register sets are copied from and to `data`, and `data` is the signal
+/// to deliver when resuming.
+pub fn sys_ptrace(request: usize, pid: usize, addr: usize, data: usize) -> isize {
+    let task = current_task().unwrap();
+    if request == PTRACE_TRACEME {
+        task.inner_exclusive_access().ptrace.traced = true;
+        return 0;
+    }
+    let tracee = match pid2task(pid) {
+        Some(tracee) => tracee,
+        None => return -ESRCH,
+    };
+    let mut tracee_inner = tracee.inner_exclusive_access();
+    let is_child = tracee_inner
+        .parent
+        .as_ref()
+        .and_then(|parent| parent.upgrade())
+        .map_or(false, |parent| Arc::ptr_eq(&parent, &task));
+    if !is_child || !tracee_inner.ptrace.traced {
+        return -ESRCH;
+    }
+    if request == PTRACE_KILL {
+        drop(tracee_inner);
+        send_signal(tracee, SignalFlags::SIGKILL);
+        return 0;
+    }
+    if tracee_inner.ptrace.stop_signal.is_none() {
+        return -ESRCH;
+    }
+    let token = task.inner_exclusive_access().memory_set.token();
+    match request {
+        PTRACE_PEEKTEXT | PTRACE_PEEKDATA => match tracee_word(&mut tracee_inner.memory_set, addr, false) {
+            Some(ptr) => {
+                tracee_inner.update_maxrss();
+                match put_user(token, data as *mut usize, unsafe { ptr.read() }) {
+                    Ok(()) => 0,
+                    Err(err) => err,
+                }
+            }
+            None => -EFAULT,
+        },
+        PTRACE_POKETEXT | PTRACE_POKEDATA => match tracee_word(&mut tracee_inner.memory_set, addr, true) {
+            Some(ptr) => {
+                unsafe { ptr.write(data) };
+                tracee_inner.update_maxrss();
+                0
+            }
+            None => -EFAULT,
+        },
+        PTRACE_GETREGS => {
+            let cx = tracee_inner.get_trap_cx();
+            let mut regs = cx.x;
+            regs[0] = cx.sepc;
+            match put_user(token, data as *mut UserRegs, regs) {
+                Ok(()) => 0,
+                Err(err) => err,
+            }
+        }
+        PTRACE_SETREGS => {
+            let regs = match get_user(token, data as *const UserRegs) {
+                Ok(regs) => regs,
+                Err(err) => return err,
+            };
+            let cx = tracee_inner.get_trap_cx();
+            cx.sepc = regs[0];
+            cx.x[1..].copy_from_slice(&regs[1..]);
+            0
+        }
+        PTRACE_CONT | PTRACE_SYSCALL => {
+            if data > MAX_SIG {
+                return -EIO;
+            }
+            tracee_inner.ptrace.syscall_trace = request == PTRACE_SYSCALL;
+            tracee_inner.ptrace.resume_signal = data;
+            tracee_inner.ptrace.stop_signal = None;
+            0
+        }
+        _ => -EIO,
+    }
+}
+
+/// Set the action of signal `signum`, storing the previous one into `old_action`
+pub fn sys_sigaction(
+    signum: usize,
+    action: *const SignalAction,
+    old_action: *mut SignalAction,
+) -> isize {
+    let signal = match SignalFlags::from_signum(signum) {
+        Some(signal) => signal,
+        None => return -1,
+    };
+    if signal.intersects(SignalFlags::unblockable()) {
+        return -1;
+    }
+    let token = current_user_token();
+    let task = current_task().unwrap();
+    let old = task.inner_exclusive_access().signal_actions.lock().table[signum];
+    if !old_action.is_null() {
+        if let Err(err) = put_user(token, old_action, old) {
+            return err;
+        }
+    }
+    if !action.is_null() {
+        let new = match get_user(token, action) {
+            Ok(new) => new,
+            Err(err) => return err,
+        };
+        task.inner_exclusive_access().signal_actions.lock().table[signum] = new;
+    }
+    0
+}
+
+/// Replace the blocked signal set, returning the previous one
+pub fn sys_sigprocmask(mask: u32) -> isize {
+    let mask = match SignalFlags::from_bits(mask) {
+        Some(mask) => mask,
+        None => return -1,
+    };
+    let task = current_task().unwrap();
+    let mut inner = task.inner_exclusive_access();
+    let old_mask = inner.signal_mask;
+    inner.signal_mask = mask - SignalFlags::unblockable();
+    old_mask.bits() as isize
+}
+
+/// Return from a user signal handler to the interrupted code
+pub fn sys_sigreturn() -> isize {
+    let task = current_task().unwrap();
+    let mut inner = task.inner_exclusive_access();
+    if let Some(backup) = inner.trap_ctx_backup.take() {
+        inner.handling_sig = None;
+        *inner.get_trap_cx() = backup;
+        // a0 is written back with the return value
+        backup.x[10] as isize
+    } else {
+        -1
+    }
+}
+
+/// Get the CPU times of the caller and of its reaped children, returning
+/// the clock ticks since boot
+pub fn sys_times(tms: *mut Tms) -> isize {
+    let us_to_ticks = |us: usize| us / (1_000_000 / USER_HZ);
+    let task = current_task().unwrap();
+    let inner = task.inner_exclusive_access();
+    let (utime, stime) = inner.cpu_times();
+    let children = &inner.usage.children;
+    let times = Tms {
+        utime: us_to_ticks(utime),
+        stime: us_to_ticks(stime),
+        cutime: us_to_ticks(children.utime.as_us()),
+        cstime: us_to_ticks(children.stime.as_us()),
+    };
+    drop(inner);
+    match put_user(current_user_token(), tms, times) {
+        Ok(()) => us_to_ticks(get_time_us()) as isize,
+        Err(err) => err,
+    }
+}
+
+/// Get the resource usage of the caller or of its reaped children
+pub fn sys_getrusage(who: isize, usage: *mut Rusage) -> isize {
+    let task = current_task().unwrap();
+    let mut inner = task.inner_exclusive_access();
+    let rusage = match who {
+        RUSAGE_SELF => {
+            inner.update_maxrss();
+            inner.rusage()
+        }
+        RUSAGE_CHILDREN => inner.usage.children,
+        _ => return -EINVAL,
+    };
+    drop(inner);
+    match put_user(current_user_token(), usage, rusage) {
+        Ok(()) => 0,
+        Err(err) => err,
+    }
+}
+
+/// Report memory usage, number of tasks and uptime
+pub fn sys_sysinfo(info: *mut SysInfo) -> isize {
+    let (heap_used, heap_total) = heap_usage();
+    let sysinfo = SysInfo {
+        uptime: get_time_us() / 1_000_000,
+        total_frames: frames_total(),
+        free_frames: frames_free(),
+        heap_total,
+        heap_used,
+        total_swap: SWAP_PAGES,
+        free_swap: swap_free(),
+        procs: all_tasks().len(),
+    };
+    match put_user(current_user_token(), info, sysinfo) {
+        Ok(()) => 0,
+        Err(err) => err,
+    }
+}
+
+/// Copy the I/O counters of process `pid` into `stats`
+pub fn sys_io_stats(pid: usize, stats: *mut IoStats) -> isize {
+    if let Some(task) = pid2task(pid) {
+        let io_stats = task.inner_exclusive_access().io_stats;
+        match put_user(current_user_token(), stats, io_stats) {
+            Ok(()) => 0,
+            Err(err) => err,
+        }
+    } else {
+        -1
+    }
+}
+
+/// Make the `nth` operation of `kind` issued by process `pid` fail, see
+/// [`crate::fault_inject`]; only root may inject faults
+#[cfg(feature = "fault-inject")]
+pub fn sys_fault_inject(kind: usize, pid: usize, nth: usize) -> isize {
+    use crate::fault_inject::{set_fault_rule, FaultKind};
+    if current_task().unwrap().inner_exclusive_access().uid != 0 {
+        return -1;
+    }
+    match FaultKind::from_usize(kind) {
+        Some(kind) => {
+            set_fault_rule(kind, pid, nth);
+            0
+        }
+        None => -1,
+    }
+}
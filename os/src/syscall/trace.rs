@@ -0,0 +1,137 @@
+//! Per-process syscall tracing to the kernel log
+//!
+//! A process with [`ProcessFlags::TRACE_SYSCALLS`] set through prctl has
+//! each syscall logged as `[pid] name(args) = result`, with path arguments
+//! decoded and the others in hex. Exit is logged before it happens, with
+//! `?` for the result.
+
+use super::*;
+use crate::config::PATH_MAX;
+use crate::mm::copy_string_from_user;
+use crate::task::{current_task, current_user_token, ProcessFlags};
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// Name of a syscall as in Linux, or as the custom syscall is called
+fn syscall_name(syscall_id: usize) -> Option<&'static str> {
+    Some(match syscall_id {
+        SYSCALL_IOCTL => "ioctl",
+        SYSCALL_UNLINKAT => "unlinkat",
+        SYSCALL_LINKAT => "linkat",
+        SYSCALL_FACCESSAT => "faccessat",
+        SYSCALL_OPEN => "open",
+        SYSCALL_QUOTACTL => "quotactl",
+        SYSCALL_CLOSE => "close",
+        SYSCALL_READ => "read",
+        SYSCALL_WRITE => "write",
+        SYSCALL_FSTAT => "fstat",
+        SYSCALL_SYNC_FILE_RANGE => "sync_file_range",
+        SYSCALL_EXIT => "exit",
+        SYSCALL_EXIT_GROUP => "exit_group",
+        SYSCALL_PTRACE => "ptrace",
+        SYSCALL_SCHED_SETAFFINITY => "sched_setaffinity",
+        SYSCALL_SCHED_GETAFFINITY => "sched_getaffinity",
+        SYSCALL_YIELD => "sched_yield",
+        SYSCALL_KILL => "kill",
+        SYSCALL_SIGACTION => "sigaction",
+        SYSCALL_SIGPROCMASK => "sigprocmask",
+        SYSCALL_SIGRETURN => "sigreturn",
+        SYSCALL_SETUID => "setuid",
+        SYSCALL_TIMES => "times",
+        SYSCALL_SETPGID => "setpgid",
+        SYSCALL_GETPGID => "getpgid",
+        SYSCALL_GETSID => "getsid",
+        SYSCALL_SETSID => "setsid",
+        SYSCALL_PRCTL => "prctl",
+        SYSCALL_GETRLIMIT => "getrlimit",
+        SYSCALL_SETRLIMIT => "setrlimit",
+        SYSCALL_GETRUSAGE => "getrusage",
+        SYSCALL_SYSINFO => "sysinfo",
+        SYSCALL_GET_TIME => "gettimeofday",
+        SYSCALL_GETPID => "getpid",
+        SYSCALL_GETPPID => "getppid",
+        SYSCALL_GETUID => "getuid",
+        SYSCALL_CLONE => "clone",
+        SYSCALL_EXEC => "execve",
+        SYSCALL_WAITPID => "wait4",
+        SYSCALL_PRLIMIT => "prlimit64",
+        SYSCALL_SPAWN => "spawn",
+        SYSCALL_BRK => "brk",
+        SYSCALL_MUNMAP => "munmap",
+        SYSCALL_MREMAP => "mremap",
+        SYSCALL_MMAP => "mmap",
+        SYSCALL_MPROTECT => "mprotect",
+        SYSCALL_MSYNC => "msync",
+        SYSCALL_MADVISE => "madvise",
+        SYSCALL_PKEY_MPROTECT => "pkey_mprotect",
+        SYSCALL_PKEY_ALLOC => "pkey_alloc",
+        SYSCALL_PKEY_FREE => "pkey_free",
+        SYSCALL_SET_PRIORITY => "set_priority",
+        SYSCALL_GETPRIORITY => "getpriority",
+        SYSCALL_TASK_INFO => "task_info",
+        SYSCALL_IRQ_STATS => "irq_stats",
+        SYSCALL_IO_STATS => "io_stats",
+        #[cfg(feature = "fault-inject")]
+        SYSCALL_FAULT_INJECT => "fault_inject",
+        SYSCALL_SBRK => "sbrk",
+        SYSCALL_CPU_SHARE => "cpu_share",
+        SYSCALL_COUNTER_INFO => "counter_info",
+        SYSCALL_SYSCALL_MASK => "syscall_mask",
+        SYSCALL_SCHED_TUNE => "sched_tune",
+        SYSCALL_PKEY_SET => "pkey_set",
+        SYSCALL_NICE => "nice",
+        SYSCALL_PS => "ps",
+        SYSCALL_SCHEDSTAT => "schedstat",
+        SYSCALL_SLAB_STATS => "slab_stats",
+        SYSCALL_MAPS => "maps",
+        SYSCALL_HEAP_STATS => "heap_stats",
+        SYSCALL_FRAME_SCRUB => "frame_scrub",
+        SYSCALL_MEMPRESSURE => "mempressure",
+        SYSCALL_TASK_STATS => "task_stats",
+        _ => return None,
+    })
+}
+
+/// Which arguments of a syscall are paths
+fn path_args(syscall_id: usize) -> &'static [usize] {
+    match syscall_id {
+        SYSCALL_LINKAT => &[1, 3],
+        SYSCALL_UNLINKAT | SYSCALL_FACCESSAT | SYSCALL_OPEN => &[1],
+        SYSCALL_EXEC | SYSCALL_SPAWN => &[0],
+        _ => &[],
+    }
+}
+
+/// Whether the syscalls of current task are traced
+pub fn tracing() -> bool {
+    current_task()
+        .unwrap()
+        .inner_exclusive_access()
+        .flags
+        .contains(ProcessFlags::TRACE_SYSCALLS)
+}
+
+/// Format a syscall and its arguments, which must be done before it runs
+/// as exec replaces the strings
+pub fn format_call(syscall_id: usize, args: [usize; 4]) -> String {
+    let token = current_user_token();
+    let args: Vec<String> = args
+        .iter()
+        .enumerate()
+        .map(|(i, &arg)| {
+            if !path_args(syscall_id).contains(&i) {
+                return format!("{:#x}", arg);
+            }
+            match copy_string_from_user(token, arg as *const u8, PATH_MAX) {
+                Ok(path) => format!("{:?}", path),
+                Err(_) => format!("{:#x}", arg),
+            }
+        })
+        .collect();
+    let pid = current_task().unwrap().getpid();
+    match syscall_name(syscall_id) {
+        Some(name) => format!("[{}] {}({})", pid, name, args.join(", ")),
+        None => format!("[{}] syscall_{}({})", pid, syscall_id, args.join(", ")),
+    }
+}
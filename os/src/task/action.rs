@@ -0,0 +1,50 @@
+//! Per-process signal actions set through sigaction
+
+use super::signal::{SignalFlags, MAX_SIG, SIG_DFL, SIG_IGN};
+
+/// How a process handles one signal
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct SignalAction {
+    /// Address of the user handler, or [`SIG_DFL`] / [`SIG_IGN`]
+    pub handler: usize,
+    /// Signals blocked while the handler runs
+    pub mask: SignalFlags,
+}
+
+impl Default for SignalAction {
+    fn default() -> Self {
+        Self {
+            handler: SIG_DFL,
+            mask: SignalFlags::empty(),
+        }
+    }
+}
+
+/// The actions of all signals of a process, indexed by signal number
+#[derive(Clone)]
+pub struct SignalActions {
+    pub table: [SignalAction; MAX_SIG + 1],
+}
+
+impl Default for SignalActions {
+    fn default() -> Self {
+        Self {
+            table: [SignalAction::default(); MAX_SIG + 1],
+        }
+    }
+}
+
+impl SignalActions {
+    /// Actions kept across exec: caught signals go back to their default
+    /// disposition since the handlers are gone, ignored ones stay ignored
+    pub fn reset_on_exec(&self) -> Self {
+        let mut actions = Self::default();
+        for (action, old) in actions.table.iter_mut().zip(self.table.iter()) {
+            if old.handler == SIG_IGN {
+                action.handler = SIG_IGN;
+            }
+        }
+        actions
+    }
+}
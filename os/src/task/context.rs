@@ -1,5 +1,6 @@
 //! Implementation of [`TaskContext`]
 
+use super::kthread::kthread_start;
 use crate::trap::trap_return;
 
 #[derive(Copy, Clone)]
@@ -29,4 +30,12 @@ impl TaskContext {
             s: [0; 12],
         }
     }
+    /// The context of a new kernel thread, which starts in [`kthread_start`]
+    pub fn goto_kthread_start(kstack_ptr: usize) -> Self {
+        Self {
+            ra: kthread_start as usize,
+            sp: kstack_ptr,
+            s: [0; 12],
+        }
+    }
 }
@@ -0,0 +1,143 @@
+//! Core dumps of processes killed by a fatal fault
+//!
+//! A core file is named `core.<pid>` and holds, in native byte order:
+//!
+//! - a [`CoreHeader`] with the trap cause and the user registers,
+//! - for each readable user area, a [`CoreSegment`] followed by its
+//!   `len` bytes.
+//!
+//! Pages swapped out or not yet paged in from their file are paged in to
+//! be dumped, and pages never touched are read as zeros; so are pages that
+//! cannot be paged in for want of frames.
+//!
+//! Only dumpable processes leave a core file, which is cut at
+//! `RLIMIT_CORE` bytes.
+
+use super::rlimit::RLIMIT_CORE;
+use super::{current_task, ProcessFlags};
+use crate::config::PAGE_SIZE;
+use crate::fs::{file_immutable, file_text_busy, open_file, OpenFlags};
+use crate::mm::{MapPermission, VirtAddr, VirtPageNum};
+use alloc::format;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::mem::size_of;
+
+/// Magic number at the start of a core file
+const CORE_MAGIC: [u8; 8] = *b"RCORE\0\0\x01";
+
+/// Header of a core file
+#[repr(C)]
+pub struct CoreHeader {
+    pub magic: [u8; 8],
+    pub pid: usize,
+    /// `scause` of the fatal trap
+    pub scause: usize,
+    /// `stval` of the fatal trap
+    pub stval: usize,
+    /// `sepc` of the fatal trap
+    pub sepc: usize,
+    /// General-purpose registers x0-x31
+    pub regs: [usize; 32],
+    /// Number of segments following the header
+    pub segments: usize,
+}
+
+/// A user area in a core file
+#[repr(C)]
+pub struct CoreSegment {
+    pub start: usize,
+    pub len: usize,
+    /// Bits of `MapPermission`
+    pub perm: usize,
+}
+
+/// The bytes of `value`
+fn struct_bytes<T>(value: &T) -> &[u8] {
+    unsafe { core::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) }
+}
+
+/// Write a core file for current task, killed by the trap of `scause` at
+/// `stval`, returning whether one was written
+///
+/// The kernel heap cannot hold a whole core, so it is written a page at a
+/// time. Only the segments whose header fits in `RLIMIT_CORE` are counted
+/// in the header, the last one possibly cut short.
+pub fn dump_core(scause: usize, stval: usize) -> bool {
+    let task = current_task().unwrap();
+    let inner = task.inner_exclusive_access();
+    let limit = inner.rlimits.get(RLIMIT_CORE).cur;
+    if !inner.flags.contains(ProcessFlags::DUMPABLE) || limit == 0 {
+        return false;
+    }
+    let areas: Vec<(usize, usize, MapPermission)> = inner
+        .memory_set
+        .user_areas()
+        .into_iter()
+        .filter(|(_, _, perm)| perm.contains(MapPermission::R))
+        .map(|(start, end, perm)| (start.into(), end.into(), perm))
+        .collect();
+    let trap_cx = inner.get_trap_cx();
+    let header = CoreHeader {
+        magic: CORE_MAGIC,
+        pid: task.getpid(),
+        scause,
+        stval,
+        sepc: trap_cx.sepc,
+        regs: trap_cx.x,
+        segments: 0,
+    };
+    drop(inner);
+    let mut size = size_of::<CoreHeader>();
+    let mut segments = 0;
+    for (start, end, _) in areas.iter() {
+        if size + size_of::<CoreSegment>() > limit {
+            break;
+        }
+        size += size_of::<CoreSegment>() + (end - start);
+        segments += 1;
+    }
+    let name = format!("core.{}", task.getpid());
+    if file_immutable(&name) || file_text_busy(&name) {
+        return false;
+    }
+    let file = match open_file(&name, OpenFlags::CREATE | OpenFlags::WRONLY) {
+        Some(file) => file,
+        None => return false,
+    };
+    let mut written = 0;
+    // write `bytes` cut at the limit, returning whether to go on
+    let mut write = |bytes: &[u8]| {
+        let len = bytes.len().min(limit - written);
+        let write_size = file.write_all(&bytes[..len]);
+        written += write_size;
+        write_size == bytes.len()
+    };
+    let mut page = vec![0u8; PAGE_SIZE];
+    if write(struct_bytes(&CoreHeader { segments, ..header })) {
+        'dump: for (start, end, perm) in areas.iter().take(segments) {
+            let segment = CoreSegment {
+                start: *start,
+                len: end - start,
+                perm: perm.bits() as usize,
+            };
+            if !write(struct_bytes(&segment)) {
+                break;
+            }
+            for va in (*start..*end).step_by(PAGE_SIZE) {
+                let vpn: VirtPageNum = VirtAddr::from(va).into();
+                let mut inner = task.inner_exclusive_access();
+                inner.memory_set.fault_in(vpn, false);
+                match inner.memory_set.translate(vpn).filter(|pte| pte.is_valid()) {
+                    Some(pte) => page.copy_from_slice(pte.ppn().get_bytes_array()),
+                    None => page.fill(0),
+                }
+                drop(inner);
+                if !write(&page) {
+                    break 'dump;
+                }
+            }
+        }
+    }
+    written == size.min(limit)
+}
@@ -0,0 +1,57 @@
+//! CPU time received per priority level
+//!
+//! Under the stride scheduler runnable tasks should share the CPU in
+//! proportion to their priorities; the histogram kept here lets that be
+//! checked over a sampling window.
+
+use crate::sync::UPSafeCell;
+use crate::timer::get_time_us;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use core::fmt::Write;
+use lazy_static::*;
+
+/// CPU time in microseconds per priority over the current window
+struct CpuShare {
+    window_start: usize,
+    time: BTreeMap<usize, usize>,
+}
+
+lazy_static! {
+    static ref CPU_SHARE: UPSafeCell<CpuShare> = unsafe {
+        UPSafeCell::new(CpuShare {
+            window_start: 0,
+            time: BTreeMap::new(),
+        })
+    };
+}
+
+/// Account `us` microseconds of CPU time to a task of priority `priority`
+pub fn record_run(priority: usize, us: usize) {
+    *CPU_SHARE
+        .exclusive_access()
+        .time
+        .entry(priority)
+        .or_insert(0) += us;
+}
+
+/// Format the histogram as text, one priority per line with its time in
+/// microseconds and its share of the busy time in permille, and start a
+/// new window if `reset` is set
+pub fn cpu_share_stats(reset: bool) -> String {
+    let mut share = CPU_SHARE.exclusive_access();
+    let now = get_time_us();
+    let busy: usize = share.time.values().sum();
+    let mut stats = String::new();
+    writeln!(stats, "window {} us, busy {} us", now - share.window_start, busy).unwrap();
+    writeln!(stats, "{:>8} {:>12} {:>8}", "prio", "time_us", "permille").unwrap();
+    for (priority, time) in share.time.iter() {
+        let permille = if busy == 0 { 0 } else { time * 1000 / busy };
+        writeln!(stats, "{:>8} {:>12} {:>8}", priority, time, permille).unwrap();
+    }
+    if reset {
+        share.window_start = now;
+        share.time.clear();
+    }
+    stats
+}
@@ -0,0 +1,34 @@
+//! Kernel threads, tasks running a kernel function for background work
+//!
+//! A kernel thread has no user address space and is scheduled like any
+//! other task. It is a child of initproc, which reaps it once its function
+//! returns.
+
+use super::{add_task, current_task, exit_current_and_run_next, TaskControlBlock, INITPROC};
+use alloc::sync::Arc;
+use riscv::register::sstatus;
+
+/// Create a kernel thread named `name` running `entry` and make it ready
+pub fn kthread_spawn(name: &str, entry: fn()) -> Arc<TaskControlBlock> {
+    let task = Arc::new(TaskControlBlock::new_kthread(name, entry));
+    task.inner_exclusive_access().parent = Some(Arc::downgrade(&INITPROC));
+    INITPROC
+        .inner_exclusive_access()
+        .children
+        .push(Arc::clone(&task));
+    add_task(Arc::clone(&task));
+    task
+}
+
+/// Where a kernel thread starts after its first switch, it exits once its
+/// function returns
+pub fn kthread_start() -> ! {
+    let entry = current_task().unwrap().kthread_fn.unwrap();
+    // kernel threads are preemptible, the idle control flow is not
+    unsafe {
+        sstatus::set_sie();
+    }
+    entry();
+    exit_current_and_run_next(0);
+    unreachable!("exited kernel thread scheduled again");
+}
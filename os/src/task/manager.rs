@@ -4,56 +4,90 @@
 //! Other CPU process monitoring functions are in Processor.
 
 
+use super::scheduler::{new_scheduler, Scheduler};
 use super::TaskControlBlock;
-use crate::sync::UPSafeCell;
+use crate::sync::SpinNoIrq;
+use crate::timer::get_time_us;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use lazy_static::*;
 
 pub struct TaskManager {
-    ready_queue: Vec<Arc<TaskControlBlock>>,
+    scheduler: Box<dyn Scheduler>,
 }
 
-// YOUR JOB: FIFO->Stride
-/// A simple FIFO scheduler.
+/// The ready queue, ordered by the configured [`Scheduler`]
 impl TaskManager {
     pub fn new() -> Self {
         Self {
-            ready_queue: Vec::new(),
+            scheduler: new_scheduler(),
         }
     }
     /// Add process back to ready queue
     pub fn add(&mut self, task: Arc<TaskControlBlock>) {
-        self.ready_queue.push(task);
+        task.inner_exclusive_access().enqueued_at = get_time_us();
+        self.scheduler.add(task);
     }
-    /// Take a process out of the ready queue
-    pub fn fetch(&mut self) -> Option<Arc<TaskControlBlock>> {
-        let mut min_stride = 0xffff_ffff_ffff_ffffusize;
-        let mut res = 0;
-        for (idx, task) in self
-            .ready_queue
-            .iter()
-            .enumerate() {
-            if task.inner_exclusive_access().task_stride < min_stride {
-                res = idx;
-                min_stride = task.inner_exclusive_access().task_stride;
-            }
-        }
-        self.ready_queue[res].inner_exclusive_access().update_stride();
-        Some(self.ready_queue.remove(res))
+    /// Take a process that may run on hart `hart` out of the ready queue
+    pub fn fetch(&mut self, hart: usize) -> Option<Arc<TaskControlBlock>> {
+        let task = self.scheduler.fetch(hart)?;
+        let mut inner = task.inner_exclusive_access();
+        let latency = get_time_us() - inner.enqueued_at;
+        let stat = &mut inner.sched_stat;
+        stat.run_count += 1;
+        stat.wait_time += latency;
+        stat.max_latency = stat.max_latency.max(latency);
+        drop(inner);
+        Some(task)
+    }
+    /// Count a timer tick of the running process, returning whether it
+    /// should be preempted
+    pub fn tick(&mut self, task: &TaskControlBlock) -> bool {
+        self.scheduler.tick(task)
     }
 }
 
 lazy_static! {
     /// TASK_MANAGER instance through lazy_static!
-    pub static ref TASK_MANAGER: UPSafeCell<TaskManager> =
-        unsafe { UPSafeCell::new(TaskManager::new()) };
+    pub static ref TASK_MANAGER: SpinNoIrq<TaskManager> =
+        SpinNoIrq::new(TaskManager::new());
+    /// All live processes by pid
+    pub static ref PID2TCB: SpinNoIrq<BTreeMap<usize, Arc<TaskControlBlock>>> =
+        SpinNoIrq::new(BTreeMap::new());
 }
 
 pub fn add_task(task: Arc<TaskControlBlock>) {
-    TASK_MANAGER.exclusive_access().add(task);
+    PID2TCB.lock().insert(task.getpid(), Arc::clone(&task));
+    TASK_MANAGER.lock().add(task);
+}
+
+pub fn fetch_task(hart: usize) -> Option<Arc<TaskControlBlock>> {
+    TASK_MANAGER.lock().fetch(hart)
 }
 
-pub fn fetch_task() -> Option<Arc<TaskControlBlock>> {
-    TASK_MANAGER.exclusive_access().fetch()
+/// Count a timer tick of the running process, see [`Scheduler::tick`]
+pub fn tick_task(task: &TaskControlBlock) -> bool {
+    TASK_MANAGER.lock().tick(task)
+}
+
+pub fn pid2task(pid: usize) -> Option<Arc<TaskControlBlock>> {
+    PID2TCB.lock().get(&pid).map(Arc::clone)
+}
+
+/// Get all live processes
+pub fn all_tasks() -> Vec<Arc<TaskControlBlock>> {
+    PID2TCB.lock().values().cloned().collect()
+}
+
+/// Get the pids of all live processes, in increasing order
+pub fn all_pids() -> Vec<usize> {
+    PID2TCB.lock().keys().copied().collect()
+}
+
+pub fn remove_from_pid2task(pid: usize) {
+    if PID2TCB.lock().remove(&pid).is_none() {
+        panic!("cannot find pid {} in pid2task!", pid);
+    }
 }
@@ -9,38 +9,85 @@
 //! Be careful when you see [`__switch`]. Control flow around this function
 //! might not be what you expect.
 
+mod action;
 mod context;
+mod coredump;
+mod cpu_share;
+mod kthread;
 mod manager;
+mod nice;
 mod pid;
 mod processor;
+mod ptrace;
+mod reclaim;
+mod rlimit;
+mod scheduler;
+mod signal;
 mod switch;
 #[allow(clippy::module_inception)]
 mod task;
 
 use alloc::sync::Arc;
 use lazy_static::*;
-use manager::fetch_task;
+use manager::{fetch_task, remove_from_pid2task, tick_task};
 use switch::__switch;
-pub use crate::syscall::process::TaskInfo;
-use crate::fs::{open_file, OpenFlags};
-pub use task::{TaskControlBlock, TaskStatus};
+use riscv::register::sstatus;
+pub use crate::syscall::process::{IoStats, Rusage, SchedStat, TaskInfo};
+use crate::fs::open_exec;
+use crate::config::KERNEL_STACK_SIZE;
+use crate::mm::MemorySet;
+use crate::sbi::shutdown;
+pub use task::{stride_test, CloneFlags, ProcessFlags, CPU_MASK_ALL, TaskControlBlock, TaskStatus};
 
+pub use action::{SignalAction, SignalActions};
 pub use context::TaskContext;
-pub use manager::add_task;
-pub use pid::{pid_alloc, KernelStack, PidHandle};
+pub use coredump::dump_core;
+pub use cpu_share::cpu_share_stats;
+pub use ptrace::{ptrace_syscall_stop, PtraceState};
+pub use kthread::kthread_spawn;
+pub use manager::{add_task, all_tasks, pid2task};
+pub use pid::{kernel_stack_pid, pid_alloc, KernelStack, PidHandle};
+pub use reclaim::{kswapd_init, oom_kill, wakeup_kswapd};
+pub use rlimit::{RLimit, RLimits, RLIMIT_AS, RLIM_NLIMITS};
+use rlimit::{RLIMIT_CPU, RLIM_INFINITY};
+pub use signal::{SignalFlags, DefaultAction, MAX_SIG, SIG_DFL, SIG_IGN};
 pub use processor::{
-    current_task, current_trap_cx, current_user_token, run_tasks, schedule, take_current_task,
+    current_account_block_io, current_enter_user, current_fault_in, handle_current_page_fault, current_leave_user, current_task, current_task_comm, try_current_pid, current_trap_cx, current_uid, current_user_token, hart_id, run_tasks, schedule, take_current_task,
     current_begin_time, current_syscall_times, insert_current_memory_set, remove_current_memory_set,
-    update_current_syscall_times, set_current_priority,
+    update_current_syscall_times, set_current_in_syscall, set_current_priority,
 };
 
 /// Make current task suspended and switch to the next task
 pub fn suspend_current_and_run_next() {
+    requeue_current_and_run_next(false);
+}
+
+/// Suspend current task as it has used up its time slice
+pub fn preempt_current_and_run_next() {
+    requeue_current_and_run_next(true);
+}
+
+/// Preempt current task at the end of a critical section, if there is one
+/// and not only the idle control flow or a task on its way out
+pub fn resched_current() {
+    if current_task().is_some() {
+        preempt_current_and_run_next();
+    }
+}
+
+/// Put current task back to the ready queue and switch to the next task,
+/// counting an involuntary context switch if it was `preempted`
+fn requeue_current_and_run_next(preempted: bool) {
     // There must be an application running.
     let task = take_current_task().unwrap();
 
     // ---- access current TCB exclusively
     let mut task_inner = task.inner_exclusive_access();
+    if preempted {
+        task_inner.usage.nivcsw += 1;
+    } else {
+        task_inner.usage.nvcsw += 1;
+    }
     let task_cx_ptr = &mut task_inner.task_cx as *mut TaskContext;
     // Change status to Ready
     task_inner.task_status = TaskStatus::Ready;
@@ -53,33 +100,184 @@ pub fn suspend_current_and_run_next() {
     schedule(task_cx_ptr);
 }
 
+/// Count a timer tick for current task, returning whether it has used up
+/// its time slice and should be preempted
+pub fn tick_current_task() -> bool {
+    // a kernel timer interrupt may come while no task is current
+    match current_task() {
+        Some(task) => {
+            check_cpu_limit(&task);
+            tick_task(&task)
+        }
+        None => false,
+    }
+}
+
+/// Signal `task` if its CPU time has gone past its RLIMIT_CPU
+fn check_cpu_limit(task: &Arc<TaskControlBlock>) {
+    let mut inner = task.inner_exclusive_access();
+    let limit = inner.rlimits.get(RLIMIT_CPU);
+    if limit.cur == RLIM_INFINITY {
+        return;
+    }
+    let (utime, stime) = inner.cpu_times();
+    let seconds = (utime + stime) / 1_000_000;
+    let signal = if seconds >= limit.max {
+        SignalFlags::SIGKILL
+    } else if seconds >= limit.cur && seconds >= inner.usage.next_xcpu {
+        inner.usage.next_xcpu = seconds + 1;
+        SignalFlags::SIGXCPU
+    } else {
+        return;
+    };
+    drop(inner);
+    send_signal(Arc::clone(task), signal);
+}
+
+/// Block current task until [`wakeup_task`] puts it back to the ready queue
+pub fn block_current_and_run_next() {
+    let task = take_current_task().unwrap();
+    let mut task_inner = task.inner_exclusive_access();
+    task_inner.usage.nvcsw += 1;
+    let task_cx_ptr = &mut task_inner.task_cx as *mut TaskContext;
+    task_inner.task_status = TaskStatus::Blocked;
+    drop(task_inner);
+    schedule(task_cx_ptr);
+}
+
+/// Block current task while its address space is lent to a vfork child,
+/// until the child gives it back by exec or exit
+///
+/// A task taken off the CPU is not preempted, so the child cannot give the
+/// space back between the last check and blocking; if it did so before,
+/// the task only yields.
+pub fn wait_vfork_child() {
+    while current_task().unwrap().inner_exclusive_access().lent_to_vfork {
+        let task = take_current_task().unwrap();
+        let mut task_inner = task.inner_exclusive_access();
+        task_inner.usage.nvcsw += 1;
+        let task_cx_ptr = &mut task_inner.task_cx as *mut TaskContext;
+        let lent = task_inner.lent_to_vfork;
+        task_inner.task_status = if lent { TaskStatus::Blocked } else { TaskStatus::Ready };
+        drop(task_inner);
+        if !lent {
+            add_task(task);
+        }
+        schedule(task_cx_ptr);
+    }
+}
+
+/// Wake the caller of a vfork given its address space back, unless it has
+/// not blocked yet or a signal woke it already
+fn wakeup_vfork_parent(parent: Arc<TaskControlBlock>) {
+    let parent_inner = parent.inner_exclusive_access();
+    if parent_inner.task_status == TaskStatus::Blocked {
+        drop(parent_inner);
+        wakeup_task(parent);
+    }
+}
+
+/// Make a blocked task ready again
+pub fn wakeup_task(task: Arc<TaskControlBlock>) {
+    task.inner_exclusive_access().task_status = TaskStatus::Ready;
+    add_task(task);
+}
+
+/// Make `signal` pending on `task`, waking it if it is blocked in the kernel
+pub fn send_signal(task: Arc<TaskControlBlock>, signal: SignalFlags) {
+    // kernel threads never return to user mode to take signals
+    if task.is_kthread() {
+        return;
+    }
+    let mut inner = task.inner_exclusive_access();
+    inner.signals |= signal;
+    if !signal.is_empty() && inner.task_status == TaskStatus::Blocked {
+        inner.waiting_child = false;
+        drop(inner);
+        wakeup_task(task);
+    }
+}
+
+/// Wake `parent` if it is blocked in waitpid
+fn wakeup_waiting_parent(parent: &Arc<TaskControlBlock>) {
+    let mut parent_inner = parent.inner_exclusive_access();
+    if parent_inner.waiting_child {
+        parent_inner.waiting_child = false;
+        drop(parent_inner);
+        wakeup_task(Arc::clone(parent));
+    }
+}
+
 /// Exit current task, recycle process resources and switch to the next task
 pub fn exit_current_and_run_next(exit_code: i32) {
+    // no longer current once taken, so the task must not be preempted
+    // before it is switched away from
+    unsafe {
+        sstatus::clear_sie();
+    }
     // take from Processor
     let task = take_current_task().unwrap();
+    // nobody is left to adopt orphans once initproc is gone
+    if Arc::ptr_eq(&task, &INITPROC) {
+        println!("[kernel] initproc exited with exit_code {} ...", exit_code);
+        shutdown();
+    }
+    remove_from_pid2task(task.getpid());
+    info!(
+        "[kernel] pid {} ({}) used {} of {} bytes of kernel stack",
+        task.getpid(),
+        task.inner_exclusive_access().comm(),
+        task.kernel_stack.high_water_mark(),
+        KERNEL_STACK_SIZE
+    );
     // **** access current TCB exclusively
     let mut inner = task.inner_exclusive_access();
     // Change status to Zombie
     inner.task_status = TaskStatus::Zombie;
     // Record exit code
     inner.exit_code = exit_code;
+    inner.update_maxrss();
+    let parent = inner.parent.as_ref().and_then(|parent| parent.upgrade());
+    let children = core::mem::take(&mut inner.children);
+    // the parent's and the scheduler's locks are taken before a child's,
+    // so they are not taken with this TCB held
+    drop(inner);
+    // **** release current PCB
+    if let Some(parent) = parent {
+        wakeup_waiting_parent(&parent);
+    }
     // do not move to its parent but under initproc
 
     // ++++++ access initproc TCB exclusively
+    let mut adopted_zombie = false;
     {
         let mut initproc_inner = INITPROC.inner_exclusive_access();
-        for child in inner.children.iter() {
-            child.inner_exclusive_access().parent = Some(Arc::downgrade(&INITPROC));
+        for child in children.iter() {
+            let mut child_inner = child.inner_exclusive_access();
+            child_inner.parent = Some(Arc::downgrade(&INITPROC));
+            // tracing ends with the tracer, resuming a stopped child
+            child_inner.ptrace = PtraceState::default();
+            adopted_zombie |= child_inner.is_zombie();
             initproc_inner.children.push(child.clone());
         }
     }
     // ++++++ release parent PCB
+    if adopted_zombie {
+        wakeup_waiting_parent(&INITPROC);
+    }
 
-    inner.children.clear();
+    drop(children);
+    // **** access current TCB exclusively
+    let mut inner = task.inner_exclusive_access();
+    // a vfork child gives the address space back rather than freeing it
+    let vfork_parent = inner.replace_memory_set(MemorySet::new_empty());
     // deallocate user space
     inner.memory_set.recycle_data_pages();
     drop(inner);
     // **** release current PCB
+    if let Some(parent) = vfork_parent {
+        wakeup_vfork_parent(parent);
+    }
     // drop task manually to maintain rc correctly
     drop(task);
     // we do not have to save task context
@@ -93,12 +291,83 @@ lazy_static! {
     /// the name "initproc" may be changed to any other app name like "usertests",
     /// but we have user_shell, so we don't need to change it.
     pub static ref INITPROC: Arc<TaskControlBlock> = Arc::new({
-        let inode = open_file("ch6b_initproc", OpenFlags::RDONLY).unwrap();
-        let v = inode.read_all();
-        TaskControlBlock::new(v.as_slice())
+        let inode = open_exec("ch6b_initproc").unwrap();
+        TaskControlBlock::new("initproc", inode)
     });
 }
 
 pub fn add_initproc() {
     add_task(INITPROC.clone());
 }
+
+/// Deliver the pending signals of current task before it returns to user
+/// mode, waiting here while it is stopped
+pub fn handle_signals() {
+    loop {
+        ptrace::ptrace_signal_stop();
+        check_pending_signals();
+        let task = current_task().unwrap();
+        let inner = task.inner_exclusive_access();
+        if let Some(signum) = inner.killed {
+            drop(inner);
+            drop(task);
+            println!("[kernel] Killed by signal {}.", signum);
+            exit_current_and_run_next(-(signum as i32));
+            return;
+        }
+        if !inner.frozen {
+            break;
+        }
+        drop(inner);
+        drop(task);
+        suspend_current_and_run_next();
+    }
+    // a signal let through by the tracer stops it again when next dequeued
+    current_task().unwrap().inner_exclusive_access().ptrace.approved = SignalFlags::empty();
+}
+
+/// Act on the deliverable pending signals, entering at most one user handler
+fn check_pending_signals() {
+    let task = current_task().unwrap();
+    let mut inner = task.inner_exclusive_access();
+    // the handler running blocks its own signal and its mask
+    let mut blocked = inner.signal_mask;
+    if let Some(signum) = inner.handling_sig {
+        blocked |= inner.signal_actions.lock().table[signum].mask | SignalFlags::from_signum(signum).unwrap();
+    }
+    blocked.remove(SignalFlags::unblockable());
+    for signum in 1..=MAX_SIG {
+        let signal = SignalFlags::from_signum(signum).unwrap();
+        if !inner.signals.contains(signal) || blocked.contains(signal) {
+            continue;
+        }
+        if signal == SignalFlags::SIGCONT {
+            inner.frozen = false;
+        }
+        let handler = inner.signal_actions.lock().table[signum].handler;
+        if signal.intersects(SignalFlags::unblockable()) || handler == SIG_DFL {
+            inner.signals.remove(signal);
+            match signal.default_action() {
+                DefaultAction::Terminate => {
+                    inner.killed = Some(signum);
+                    return;
+                }
+                DefaultAction::Ignore => {}
+                DefaultAction::Stop => inner.frozen = true,
+                DefaultAction::Continue => {}
+            }
+        } else if handler == SIG_IGN {
+            inner.signals.remove(signal);
+        } else if inner.handling_sig.is_none() {
+            // run the user handler with the signal number in a0,
+            // it comes back to the interrupted code through sigreturn
+            inner.signals.remove(signal);
+            inner.handling_sig = Some(signum);
+            let trap_cx = inner.get_trap_cx();
+            inner.trap_ctx_backup = Some(*trap_cx);
+            trap_cx.sepc = handler;
+            trap_cx.x[10] = signum;
+            return;
+        }
+    }
+}
@@ -0,0 +1,33 @@
+//! Mapping between nice values and stride priorities
+//!
+//! A task of priority `p` advances its stride by `BIG_STRIDE / p` per time
+//! slice. Nice 0 is the default priority 16 and each step of nice changes
+//! the priority by about 11.6%, so that nice 19 still has the smallest
+//! priority of 2. Priorities are integers, so neighbouring nice values at
+//! the low end share a priority.
+
+/// Smallest nice value, the highest priority
+pub const MIN_NICE: isize = -20;
+/// Largest nice value, the lowest priority
+pub const MAX_NICE: isize = 19;
+
+/// Priority of each nice value from `MIN_NICE` to `MAX_NICE`
+const NICE_TO_PRIORITY: [usize; 40] = [
+    /* -20 */ 143, 128, 115, 103, 92, 83, 74, 66, 59, 53,
+    /* -10 */ 48, 43, 38, 34, 31, 28, 25, 22, 20, 18,
+    /*   0 */ 16, 14, 13, 12, 10, 9, 8, 7, 7, 6,
+    /*  10 */ 5, 5, 4, 4, 3, 3, 3, 2, 2, 2,
+];
+
+/// Get the priority of a nice value, which is clamped to the valid range
+pub fn nice_to_priority(nice: isize) -> usize {
+    NICE_TO_PRIORITY[(nice.clamp(MIN_NICE, MAX_NICE) - MIN_NICE) as usize]
+}
+
+/// Get the smallest nice value whose priority is not above `priority`
+pub fn priority_to_nice(priority: usize) -> isize {
+    NICE_TO_PRIORITY
+        .iter()
+        .position(|&p| p <= priority)
+        .map_or(MAX_NICE, |i| i as isize + MIN_NICE)
+}
@@ -3,50 +3,51 @@
 //! Assign PID to the process here. At the same time, the position of the application KernelStack
 //! is determined according to the PID.
 
-use crate::config::{KERNEL_STACK_SIZE, PAGE_SIZE, TRAMPOLINE};
-use crate::mm::{MapPermission, VirtAddr, KERNEL_SPACE};
-use crate::sync::UPSafeCell;
-use alloc::vec::Vec;
+use crate::config::{KERNEL_STACK_SIZE, PAGE_SIZE, PID_MAX, TRAMPOLINE};
+use crate::mm::{MapPermission, VPNRange, VirtAddr, KERNEL_SPACE};
+use crate::sync::SpinNoIrq;
+use alloc::collections::BTreeSet;
 use lazy_static::*;
 
-/// Process identifier allocator using stack allocation
+/// Process identifier allocator handing out PIDs in increasing order
+///
+/// A freed PID is not reused until the allocator wraps around at
+/// [`PID_MAX`], so a PID kept by user space does not soon name another
+/// process. Lookups by PID only find live processes.
 struct PidAllocator {
-    /// A new PID to be assigned
-    current: usize,
-    /// Recycled PID sequence
-    recycled: Vec<usize>,
+    /// Where the search for a free PID starts
+    next: usize,
+    /// PIDs in use
+    used: BTreeSet<usize>,
 }
 
 impl PidAllocator {
     pub fn new() -> Self {
         PidAllocator {
-            current: 0,
-            recycled: Vec::new(),
+            next: 0,
+            used: BTreeSet::new(),
         }
     }
-    pub fn alloc(&mut self) -> PidHandle {
-        if let Some(pid) = self.recycled.pop() {
-            PidHandle(pid)
-        } else {
-            self.current += 1;
-            PidHandle(self.current - 1)
+    pub fn alloc(&mut self) -> Option<PidHandle> {
+        for _ in 0..PID_MAX {
+            let pid = self.next;
+            // pid 0 is initproc's for good, so wrap around to 1
+            self.next = if pid + 1 == PID_MAX { 1 } else { pid + 1 };
+            if self.used.insert(pid) {
+                return Some(PidHandle(pid));
+            }
         }
+        None
     }
     pub fn dealloc(&mut self, pid: usize) {
-        assert!(pid < self.current);
-        assert!(
-            !self.recycled.iter().any(|ppid| *ppid == pid),
-            "pid {} has been deallocated!",
-            pid
-        );
-        self.recycled.push(pid);
+        assert!(self.used.remove(&pid), "pid {} has been deallocated!", pid);
     }
 }
 
 lazy_static! {
     /// Pid allocator instance through lazy_static!
-    static ref PID_ALLOCATOR: UPSafeCell<PidAllocator> =
-        unsafe { UPSafeCell::new(PidAllocator::new()) };
+    static ref PID_ALLOCATOR: SpinNoIrq<PidAllocator> =
+        SpinNoIrq::new(PidAllocator::new());
 }
 
 /// Abstract structure of PID
@@ -55,12 +56,13 @@ pub struct PidHandle(pub usize);
 impl Drop for PidHandle {
     fn drop(&mut self) {
         //println!("drop pid {}", self.0);
-        PID_ALLOCATOR.exclusive_access().dealloc(self.0);
+        PID_ALLOCATOR.lock().dealloc(self.0);
     }
 }
 
-pub fn pid_alloc() -> PidHandle {
-    PID_ALLOCATOR.exclusive_access().alloc()
+/// Allocate a PID, or None if all below [`PID_MAX`] are in use
+pub fn pid_alloc() -> Option<PidHandle> {
+    PID_ALLOCATOR.lock().alloc()
 }
 
 /// Return (bottom, top) of a kernel stack in kernel space.
@@ -70,21 +72,44 @@ pub fn kernel_stack_position(app_id: usize) -> (usize, usize) {
     (bottom, top)
 }
 
+/// PID whose kernel stack, or the guard page below it, holds `addr`
+pub fn kernel_stack_pid(addr: usize) -> usize {
+    (TRAMPOLINE - addr) / (KERNEL_STACK_SIZE + PAGE_SIZE)
+}
+
+/// Pattern filled into a fresh kernel stack to find its high-water mark later
+const KERNEL_STACK_PATTERN: usize = 0xdead_beef_dead_beef;
+
 /// KernelStack corresponding to PID
 pub struct KernelStack {
     pid: usize,
 }
 
 impl KernelStack {
-    pub fn new(pid_handle: &PidHandle) -> Self {
+    /// Map the kernel stack of `pid_handle`, None if frames run out
+    pub fn new(pid_handle: &PidHandle) -> Option<Self> {
         let pid = pid_handle.0;
         let (kernel_stack_bottom, kernel_stack_top) = kernel_stack_position(pid);
-        KERNEL_SPACE.exclusive_access().insert_framed_area(
+        let mut kernel_space = KERNEL_SPACE.exclusive_access();
+        let mapped = kernel_space.insert_framed_area(
             kernel_stack_bottom.into(),
             kernel_stack_top.into(),
             MapPermission::R | MapPermission::W,
         );
-        KernelStack { pid: pid_handle.0 }
+        if mapped == -1 {
+            return None;
+        }
+        // fill the stack through its frames, the new mapping may not be visible yet
+        let vpn_range = VPNRange::new(
+            VirtAddr::from(kernel_stack_bottom).floor(),
+            VirtAddr::from(kernel_stack_top).ceil(),
+        );
+        for vpn in vpn_range {
+            let ppn = kernel_space.translate(vpn).unwrap().ppn();
+            ppn.get_mut::<[usize; PAGE_SIZE / core::mem::size_of::<usize>()]>()
+                .fill(KERNEL_STACK_PATTERN);
+        }
+        Some(KernelStack { pid: pid_handle.0 })
     }
     #[allow(unused)]
     /// Push a variable of type T into the top of the KernelStack and return its raw pointer
@@ -103,6 +128,21 @@ impl KernelStack {
         let (_, kernel_stack_top) = kernel_stack_position(self.pid);
         kernel_stack_top
     }
+    /// Get the maximum number of bytes ever used on the KernelStack
+    pub fn high_water_mark(&self) -> usize {
+        let (kernel_stack_bottom, _) = kernel_stack_position(self.pid);
+        let words = unsafe {
+            core::slice::from_raw_parts(
+                kernel_stack_bottom as *const usize,
+                KERNEL_STACK_SIZE / core::mem::size_of::<usize>(),
+            )
+        };
+        let untouched = words
+            .iter()
+            .take_while(|word| **word == KERNEL_STACK_PATTERN)
+            .count();
+        KERNEL_STACK_SIZE - untouched * core::mem::size_of::<usize>()
+    }
 }
 
 impl Drop for KernelStack {
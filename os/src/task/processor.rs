@@ -6,15 +6,19 @@
 
 
 use super::__switch;
-use super::{fetch_task, TaskStatus};
+use super::cpu_share::record_run;
+use super::reclaim::reclaim_frames;
+use super::{fetch_task, ProcessFlags, TaskStatus};
 use super::{TaskContext, TaskControlBlock};
-use crate::sync::UPSafeCell;
+use crate::sync::{assert_no_locks_held, SpinNoIrq};
 use crate::trap::TrapContext;
+use alloc::string::String;
 use alloc::sync::Arc;
 use lazy_static::*;
+use riscv::{asm, register::sstatus};
 use crate::config::MAX_SYSCALL_NUM;
-use crate::mm::{MapPermission, VirtAddr};
-use crate::timer::get_time_us;
+use crate::mm::{MapPermission, VirtAddr, VirtPageNum};
+use crate::timer::{get_time_us, set_user_counters_virtual};
 
 /// Processor management structure
 pub struct Processor {
@@ -44,7 +48,7 @@ impl Processor {
 
 lazy_static! {
     /// PROCESSOR instance through lazy_static!
-    pub static ref PROCESSOR: UPSafeCell<Processor> = unsafe { UPSafeCell::new(Processor::new()) };
+    pub static ref PROCESSOR: SpinNoIrq<Processor> = SpinNoIrq::new(Processor::new());
 }
 
 /// The main part of process execution and scheduling
@@ -53,37 +57,127 @@ lazy_static! {
 /// and switch the process through __switch
 pub fn run_tasks() {
     loop {
-        let mut processor = PROCESSOR.exclusive_access();
-        if let Some(task) = fetch_task() {
+        // the idle control flow is not preempted, the switched task brings
+        // back its own interrupt state
+        unsafe {
+            sstatus::clear_sie();
+        }
+        let mut processor = PROCESSOR.lock();
+        if let Some(task) = fetch_task(hart_id()) {
             let idle_task_cx_ptr = processor.get_idle_task_cx_ptr();
             // access coming task TCB exclusively
             let mut task_inner = task.inner_exclusive_access();
             let next_task_cx_ptr = &task_inner.task_cx as *const TaskContext;
             task_inner.task_status = TaskStatus::Running;
+            trace!("[kernel] switch to pid {} ({})", task.getpid(), task_inner.comm());
+            let priority = task_inner.task_priority;
+            task_inner.counters.start();
+            set_user_counters_virtual(task_inner.flags.contains(ProcessFlags::VIRTUAL_COUNTERS));
+            let us = get_time_us();
+            task_inner.exec_start = us;
+            task_inner.slice_ticks = 0;
             if task_inner.task_begin_time == 0 {
-                let us = get_time_us();
                 task_inner.task_begin_time = ((us / 1_000_000) & 0xffff) * 1_000 + ((us % 1_000_000) / 1_000);
             }
             drop(task_inner);
             // release coming task TCB manually
+            let switched = Arc::clone(&task);
             processor.current = Some(task);
             // release processor manually
             drop(processor);
             unsafe {
                 __switch(idle_task_cx_ptr, next_task_cx_ptr);
             }
+            // back in idle control flow, the task has given up the CPU
+            record_run(priority, get_time_us() - us);
+            switched.inner_exclusive_access().counters.stop();
+        } else {
+            drop(processor);
+            idle();
         }
     }
 }
 
+/// Wait for an interrupt when there is no task to run, and handle it
+///
+/// `wfi` wakes on a pending interrupt even with interrupts disabled, so
+/// one coming between the empty ready queue and `wfi` is not missed.
+fn idle() {
+    unsafe {
+        asm::wfi();
+        sstatus::set_sie();
+        sstatus::clear_sie();
+    }
+}
+
+/// Get the id of the hart running this code, always 0 as the kernel only
+/// runs on the boot hart
+pub fn hart_id() -> usize {
+    0
+}
+
 /// Get current task through take, leaving a None in its place
+///
+/// The task leaves the CPU, so its runtime is accounted here.
 pub fn take_current_task() -> Option<Arc<TaskControlBlock>> {
-    PROCESSOR.exclusive_access().take_current()
+    let task = PROCESSOR.lock().take_current()?;
+    let mut inner = task.inner_exclusive_access();
+    inner.sum_exec_runtime += get_time_us() - inner.exec_start;
+    drop(inner);
+    Some(task)
 }
 
 /// Get a copy of the current task
 pub fn current_task() -> Option<Arc<TaskControlBlock>> {
-    PROCESSOR.exclusive_access().current()
+    PROCESSOR.lock().current()
+}
+
+/// Get the pid of current task without panicking if it is borrowed
+pub fn try_current_pid() -> Option<usize> {
+    let processor = PROCESSOR.try_lock()?;
+    processor.current.as_ref().map(|task| task.getpid())
+}
+
+/// Get the pid and name of current task without panicking if it is borrowed,
+/// for use in panic messages
+pub fn current_task_comm() -> Option<(usize, String)> {
+    let processor = PROCESSOR.try_lock()?;
+    let task = processor.current.as_ref()?;
+    let inner = task.try_inner_exclusive_access()?;
+    Some((task.getpid(), String::from(inner.comm())))
+}
+
+/// Count a block read or write issued on behalf of current task, if any
+///
+/// Block I/O also happens with no task running or with the TCB borrowed,
+/// e.g. while loading initproc, so this never panics.
+pub fn current_account_block_io(write: bool) {
+    let task = match PROCESSOR.try_lock().and_then(|p| p.current()) {
+        Some(task) => task,
+        None => return,
+    };
+    let mut inner = match task.try_inner_exclusive_access() {
+        Some(inner) => inner,
+        None => return,
+    };
+    if write {
+        inner.io_stats.write_blocks += 1;
+    } else {
+        inner.io_stats.read_blocks += 1;
+    }
+}
+
+/// Mark current task entering user mode, where its user time is counted
+pub fn current_enter_user() {
+    let task = current_task().unwrap();
+    task.inner_exclusive_access().usage.user_enter = get_time_us();
+}
+
+/// Count the user time of current task since it entered user mode
+pub fn current_leave_user() {
+    let task = current_task().unwrap();
+    let mut inner = task.inner_exclusive_access();
+    inner.usage.user_time += get_time_us() - inner.usage.user_enter;
 }
 
 /// Get token of the address space of current task
@@ -93,6 +187,11 @@ pub fn current_user_token() -> usize {
     token
 }
 
+/// Get the user id of current task
+pub fn current_uid() -> u32 {
+    current_task().unwrap().inner_exclusive_access().uid
+}
+
 /// Get the mutable reference to trap context of current task
 pub fn current_trap_cx() -> &'static mut TrapContext {
     current_task()
@@ -119,21 +218,72 @@ pub fn current_syscall_times() -> [u32; MAX_SYSCALL_NUM] {
 pub fn update_current_syscall_times(syscall_id: usize) {
     let task = current_task().unwrap();
     let mut task_inner = task.inner_exclusive_access();
-    task_inner.task_syscall_times[syscall_id] += 1;
+    if let Some(times) = task_inner.task_syscall_times.get_mut(syscall_id) {
+        *times += 1;
+    }
+}
+
+/// Mark current task entering or leaving a syscall
+pub fn set_current_in_syscall(in_syscall: bool) {
+    current_task().unwrap().inner_exclusive_access().in_syscall = in_syscall;
 }
 
 /// Set priority of current task
 pub fn set_current_priority(priority: usize) {
     let task = current_task().unwrap();
     let mut task_inner = task.inner_exclusive_access();
-    task_inner.task_priority = priority;
+    task_inner.set_priority(priority);
 }
 
-/// Insert a framed map area into current task's memory set
-pub fn insert_current_memory_set(start_va: VirtAddr, end_va: VirtAddr, permission: MapPermission) -> isize {
+/// Insert a framed map area into current task's memory set, whose pages
+/// get frames when first touched, see [`crate::mm::MemorySet::mmap`]
+pub fn insert_current_memory_set(
+    start_va: VirtAddr,
+    len: usize,
+    permission: MapPermission,
+    fixed: bool,
+) -> Option<VirtAddr> {
     let task = current_task().unwrap();
     let mut task_inner = task.inner_exclusive_access();
-    task_inner.memory_set.insert_framed_area(start_va, end_va, permission)
+    task_inner.memory_set.mmap(start_va, len, permission, fixed)
+}
+
+/// Handle a page fault of current task at `addr`, returning whether it
+/// may go on, see [`crate::mm::MemorySet::handle_page_fault`]
+pub fn handle_current_page_fault(addr: usize, write: bool) -> bool {
+    reclaim_frames();
+    let task = current_task().unwrap();
+    let mut task_inner = task.inner_exclusive_access();
+    let handled = task_inner.memory_set.handle_page_fault(addr, write);
+    if handled {
+        task_inner.update_maxrss();
+    }
+    handled
+}
+
+/// Give the kernel a lazily allocated page at `vpn` of current task's
+/// address space `token` to access, growing the user stack down to it if
+/// need be; fails if `token` is another address space or the task is
+/// borrowed
+pub fn current_fault_in(token: usize, vpn: VirtPageNum) -> bool {
+    reclaim_frames();
+    let task = match PROCESSOR.try_lock().and_then(|p| p.current()) {
+        Some(task) => task,
+        None => return false,
+    };
+    let mut inner = match task.try_inner_exclusive_access() {
+        Some(inner) => inner,
+        None => return false,
+    };
+    if inner.memory_set.token() != token {
+        return false;
+    }
+    let memory_set = &mut inner.memory_set;
+    if !memory_set.fault_in(vpn, true) && !memory_set.grow_stack(VirtAddr::from(vpn).into()) {
+        return false;
+    }
+    inner.update_maxrss();
+    true
 }
 
 /// Remove a framed map area from current task's memory set
@@ -145,10 +295,17 @@ pub fn remove_current_memory_set(start_va: VirtAddr, end_va: VirtAddr) -> isize
 
 /// Return to idle control flow for new scheduling
 pub fn schedule(switched_task_cx_ptr: *mut TaskContext) {
-    let mut processor = PROCESSOR.exclusive_access();
+    assert_no_locks_held();
+    let mut processor = PROCESSOR.lock();
     let idle_task_cx_ptr = processor.get_idle_task_cx_ptr();
     drop(processor);
+    // no interrupt may come in the middle of the switch
+    let sie = sstatus::read().sie();
     unsafe {
+        sstatus::clear_sie();
         __switch(switched_task_cx_ptr, idle_task_cx_ptr);
+        if sie {
+            sstatus::set_sie();
+        }
     }
 }
@@ -0,0 +1,105 @@
+//! Process tracing, enough for a simple debugger or strace
+//!
+//! A child asks to be traced by its parent with `PTRACE_TRACEME`. A traced
+//! process stops before a signal is delivered and, once resumed with
+//! `PTRACE_SYSCALL`, at syscall entry and exit. The parent collects a stop
+//! through waitpid, then may read and write the registers and memory of the
+//! child until it resumes it.
+//!
+//! A stopped process yields in a loop, as processes stopped by SIGSTOP do.
+
+use super::{current_task, suspend_current_and_run_next, wakeup_waiting_parent, SignalFlags, MAX_SIG};
+
+/// Tracing state of a process, which is not inherited
+#[derive(Clone, Copy)]
+pub struct PtraceState {
+    /// Traced by the parent
+    pub traced: bool,
+    /// Stop at syscall entry and exit, set by `PTRACE_SYSCALL`
+    pub syscall_trace: bool,
+    /// Signal reported for the current stop, `None` while running
+    pub stop_signal: Option<usize>,
+    /// Whether the parent has collected the current stop through waitpid
+    pub reported: bool,
+    /// Signal the tracer asked to deliver on resume, 0 for none
+    pub resume_signal: usize,
+    /// Signals the tracer let through, which do not stop the process again
+    pub approved: SignalFlags,
+}
+
+impl Default for PtraceState {
+    fn default() -> Self {
+        Self {
+            traced: false,
+            syscall_trace: false,
+            stop_signal: None,
+            reported: false,
+            resume_signal: 0,
+            approved: SignalFlags::empty(),
+        }
+    }
+}
+
+/// Stop current task, reporting `signum` to the tracer, until the tracer
+/// resumes it or SIGKILL arrives; the signal the tracer asks for is then
+/// made pending
+fn ptrace_stop(signum: usize) {
+    let task = current_task().unwrap();
+    let mut inner = task.inner_exclusive_access();
+    inner.ptrace.stop_signal = Some(signum);
+    inner.ptrace.reported = false;
+    let parent = inner.parent.as_ref().and_then(|parent| parent.upgrade());
+    drop(inner);
+    if let Some(parent) = parent {
+        wakeup_waiting_parent(&parent);
+    }
+    loop {
+        let mut inner = task.inner_exclusive_access();
+        if inner.signals.contains(SignalFlags::SIGKILL) {
+            inner.ptrace.stop_signal = None;
+            return;
+        }
+        if inner.ptrace.stop_signal.is_none() {
+            let resume_signal = core::mem::take(&mut inner.ptrace.resume_signal);
+            if let Some(signal) = SignalFlags::from_signum(resume_signal) {
+                inner.signals |= signal;
+                inner.ptrace.approved |= signal;
+            }
+            return;
+        }
+        drop(inner);
+        suspend_current_and_run_next();
+    }
+}
+
+/// Stop current task at syscall entry or exit if its tracer asked for it
+pub fn ptrace_syscall_stop() {
+    let task = current_task().unwrap();
+    let inner = task.inner_exclusive_access();
+    let trace = inner.ptrace.traced && inner.ptrace.syscall_trace;
+    drop(inner);
+    if trace {
+        ptrace_stop(SignalFlags::SIGTRAP.bits().trailing_zeros() as usize);
+    }
+}
+
+/// Stop current task for the first deliverable signal its tracer has not
+/// seen yet, taking the signal off the pending set
+pub fn ptrace_signal_stop() {
+    let task = current_task().unwrap();
+    let mut inner = task.inner_exclusive_access();
+    if !inner.ptrace.traced {
+        return;
+    }
+    let blocked = inner.signal_mask - SignalFlags::unblockable();
+    let candidates = inner.signals - blocked - inner.ptrace.approved - SignalFlags::SIGKILL;
+    let signum = match (1..=MAX_SIG).find(|&signum| {
+        candidates.contains(SignalFlags::from_signum(signum).unwrap())
+    }) {
+        Some(signum) => signum,
+        None => return,
+    };
+    inner.signals.remove(SignalFlags::from_signum(signum).unwrap());
+    drop(inner);
+    ptrace_stop(signum);
+}
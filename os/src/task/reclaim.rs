@@ -0,0 +1,155 @@
+//! Reclaim of user frames to the swap area when free frames run low, and
+//! the out-of-memory killer for when no frame is left
+//!
+//! Processes are visited in turn by pid, each giving up the pages its own
+//! clock finds cold, see [`MemorySet::swap_out_cold`]. The `kswapd` kernel
+//! thread is woken by the frame allocator below [`FRAMES_LOW`] free frames
+//! and swaps out until [`FRAMES_HIGH`] are free; a user page fault swaps
+//! out directly if it finds frames below the low watermark all the same.
+//!
+//! [`MemorySet::swap_out_cold`]: crate::mm::MemorySet::swap_out_cold
+
+use super::manager::{all_pids, all_tasks};
+use super::{
+    add_task, block_current_and_run_next, kthread_spawn, pid2task, send_signal, SignalFlags,
+    TaskControlBlock, TaskStatus, INITPROC,
+};
+use crate::config::{FRAMES_HIGH, FRAMES_LOW};
+use crate::mm::frames_free;
+use crate::sync::{PreemptGuard, TicketLock, UPSafeCell};
+use alloc::sync::Arc;
+use lazy_static::*;
+
+/// Pages swapped out at a time past the low watermark
+const RECLAIM_BATCH: usize = 16;
+
+/// Pid of the process to visit next
+static NEXT_PID: TicketLock<usize> = TicketLock::new(0);
+
+lazy_static! {
+    /// The kswapd kernel thread once started
+    static ref KSWAPD: UPSafeCell<Option<Arc<TaskControlBlock>>> =
+        unsafe { UPSafeCell::new(None) };
+}
+
+/// Swap out pages until more than [`FRAMES_LOW`] frames are free, or
+/// until every process has been visited
+pub fn reclaim_frames() {
+    if frames_free() < FRAMES_LOW {
+        reclaim_to(FRAMES_LOW + RECLAIM_BATCH);
+    }
+}
+
+/// Swap out pages until `target` frames are free, or until every process
+/// has been visited, returning whether any page was swapped out; processes
+/// inside a syscall or whose TCB is borrowed are passed over
+///
+/// A task is only held while preemption is off: a parent reaping it in the
+/// meantime expects to hold its last reference.
+fn reclaim_to(target: usize) -> bool {
+    let mut pids = all_pids();
+    let next_pid = *NEXT_PID.lock();
+    let start = pids.iter().position(|&pid| pid >= next_pid).unwrap_or(0);
+    pids.rotate_left(start);
+    let mut swapped = 0;
+    for pid in pids {
+        let free = frames_free();
+        if free >= target {
+            break;
+        }
+        *NEXT_PID.lock() = pid + 1;
+        let _preempt = PreemptGuard::new();
+        let task = match pid2task(pid) {
+            Some(task) => task,
+            None => continue,
+        };
+        let mut inner = match task.try_inner_exclusive_access() {
+            Some(inner) => inner,
+            None => continue,
+        };
+        if !inner.in_syscall {
+            swapped += inner.memory_set.swap_out_cold(target - free);
+        }
+    }
+    swapped > 0
+}
+
+/// Start the kswapd kernel thread
+pub fn kswapd_init() {
+    *KSWAPD.exclusive_access() = Some(kthread_spawn("kswapd", kswapd));
+}
+
+/// Wake kswapd if free frames are below the low watermark and it sleeps
+///
+/// Called from the frame allocator, so it gives up if kswapd is borrowed.
+pub fn wakeup_kswapd() {
+    if frames_free() >= FRAMES_LOW {
+        return;
+    }
+    let kswapd = match KSWAPD.try_exclusive_access() {
+        Some(kswapd) => kswapd.as_ref().map(Arc::clone),
+        None => return,
+    };
+    if let Some(task) = kswapd {
+        let mut inner = match task.try_inner_exclusive_access() {
+            Some(inner) => inner,
+            None => return,
+        };
+        if inner.task_status == TaskStatus::Blocked {
+            inner.task_status = TaskStatus::Ready;
+            drop(inner);
+            add_task(task);
+        }
+    }
+}
+
+/// Swap out up to the high watermark each time it is woken; sleeping
+/// early if a whole round frees nothing
+fn kswapd() {
+    loop {
+        while frames_free() < FRAMES_HIGH && reclaim_to(FRAMES_HIGH) {}
+        block_current_and_run_next();
+    }
+}
+
+/// Kill the user process with the most resident pages to free memory,
+/// returning false if no process can be killed
+///
+/// Its user areas are unmapped at once, so their frames are free when this
+/// returns; it takes the SIGKILL the next time it runs. Processes inside a
+/// syscall or whose TCB is borrowed are spared, and so are vfork children,
+/// as the address space they run in goes back to their parent. The parent
+/// has no user pages meanwhile, so it is never picked either.
+pub fn oom_kill() -> bool {
+    // the tasks are held, which a parent reaping one does not expect
+    let _preempt = PreemptGuard::new();
+    let victim = all_tasks()
+        .into_iter()
+        .filter(|task| !task.is_kthread() && !Arc::ptr_eq(task, &INITPROC))
+        .filter_map(|task| {
+            let inner = task.try_inner_exclusive_access()?;
+            if inner.in_syscall || inner.vfork_parent.is_some() || inner.is_zombie() {
+                return None;
+            }
+            let pages = inner.memory_set.resident_pages();
+            drop(inner);
+            Some((task, pages))
+        })
+        .filter(|(_, pages)| *pages > 0)
+        .max_by_key(|(_, pages)| *pages);
+    let (task, pages) = match victim {
+        Some(victim) => victim,
+        None => return false,
+    };
+    let mut inner = task.inner_exclusive_access();
+    println!(
+        "[kernel] Out of memory: killed pid {} ({}), freeing {} pages",
+        task.getpid(),
+        inner.comm(),
+        pages
+    );
+    inner.memory_set.release_user_areas();
+    drop(inner);
+    send_signal(task, SignalFlags::SIGKILL);
+    true
+}
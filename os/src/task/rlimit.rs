@@ -0,0 +1,67 @@
+//! Resource limits of a process, numbered as in Linux
+//!
+//! Limits are inherited across fork and spawn. Only root may raise a hard
+//! limit.
+
+use crate::config::{PAGE_SIZE, USER_STACK_MAX};
+
+/// Seconds of CPU time, SIGXCPU being sent every second past the soft
+/// limit and SIGKILL at the hard limit
+pub const RLIMIT_CPU: usize = 0;
+/// Size the user stack may grow to, at most `USER_STACK_MAX`
+pub const RLIMIT_STACK: usize = 3;
+/// Largest core file written on a fatal fault, 0 disabling core dumps
+pub const RLIMIT_CORE: usize = 4;
+/// One more than the largest fd that may be allocated
+pub const RLIMIT_NOFILE: usize = 7;
+/// Bytes of address space that may be mapped
+pub const RLIMIT_AS: usize = 9;
+/// Number of resources with a limit
+pub const RLIM_NLIMITS: usize = 16;
+/// No limit
+pub const RLIM_INFINITY: usize = usize::MAX;
+
+/// Soft and hard limit of a resource, laid out as `struct rlimit`
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct RLimit {
+    /// Limit enforced by the kernel
+    pub cur: usize,
+    /// Ceiling of the soft limit
+    pub max: usize,
+}
+
+/// The limits of a process
+#[derive(Clone, Copy)]
+pub struct RLimits([RLimit; RLIM_NLIMITS]);
+
+impl Default for RLimits {
+    fn default() -> Self {
+        let mut limits = [RLimit {
+            cur: RLIM_INFINITY,
+            max: RLIM_INFINITY,
+        }; RLIM_NLIMITS];
+        limits[RLIMIT_STACK].cur = USER_STACK_MAX;
+        limits[RLIMIT_CORE].cur = 0;
+        limits[RLIMIT_NOFILE] = RLimit {
+            cur: 1024,
+            max: 4096,
+        };
+        Self(limits)
+    }
+}
+
+impl RLimits {
+    pub fn get(&self, resource: usize) -> RLimit {
+        self.0[resource]
+    }
+    pub fn set(&mut self, resource: usize, limit: RLimit) {
+        self.0[resource] = limit;
+    }
+    /// Size the user stack of a new program image may grow to, in whole
+    /// pages
+    pub fn stack_size(&self) -> usize {
+        let size = self.get(RLIMIT_STACK).cur.min(USER_STACK_MAX);
+        (size / PAGE_SIZE * PAGE_SIZE).max(PAGE_SIZE)
+    }
+}
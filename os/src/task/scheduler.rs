@@ -0,0 +1,263 @@
+//! Scheduling policies behind [`super::manager::TaskManager`]
+//!
+//! The policy is chosen by [`SCHED_POLICY`] in the kernel config.
+
+use super::task::stride_before;
+use super::TaskControlBlock;
+use crate::config::{SchedPolicy, SCHED_POLICY};
+use crate::timer::{get_time_us, time_slice_ticks};
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BinaryHeap, VecDeque};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+
+/// A policy deciding which ready task runs next
+pub trait Scheduler: Send {
+    /// Add a task to the ready queue
+    fn add(&mut self, task: Arc<TaskControlBlock>);
+    /// Take the task to run next on hart `hart` out of the ready queue,
+    /// skipping tasks whose affinity excludes the hart
+    fn fetch(&mut self, hart: usize) -> Option<Arc<TaskControlBlock>>;
+    /// Count a timer tick while `task` runs, returning whether it should
+    /// be preempted; by default a time slice lasts [`time_slice_ticks`]
+    fn tick(&mut self, task: &TaskControlBlock) -> bool {
+        let mut inner = task.inner_exclusive_access();
+        inner.slice_ticks += 1;
+        inner.slice_ticks >= time_slice_ticks()
+    }
+}
+
+/// Whether the affinity of `task` allows it to run on hart `hart`
+fn runs_on(task: &TaskControlBlock, hart: usize) -> bool {
+    task.inner_exclusive_access().cpu_mask & 1 << hart != 0
+}
+
+/// Create the scheduler of the configured policy
+pub fn new_scheduler() -> Box<dyn Scheduler> {
+    match SCHED_POLICY {
+        SchedPolicy::Stride => Box::new(StrideScheduler::new()),
+        SchedPolicy::Mlfq => Box::new(MlfqScheduler::new()),
+        SchedPolicy::Cfs => Box::new(CfsScheduler::new()),
+    }
+}
+
+/// A ready task keyed on its stride, the min-heap order of [`StrideScheduler`]
+struct ReadyTask {
+    stride: usize,
+    /// Tasks with equal strides run in the order they became ready
+    seq: usize,
+    task: Arc<TaskControlBlock>,
+}
+
+impl PartialEq for ReadyTask {
+    fn eq(&self, other: &Self) -> bool {
+        (self.stride, self.seq) == (other.stride, other.seq)
+    }
+}
+
+impl Eq for ReadyTask {}
+
+impl PartialOrd for ReadyTask {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ReadyTask {
+    /// Reversed so that the max-heap yields the smallest stride first
+    fn cmp(&self, other: &Self) -> Ordering {
+        if stride_before(self.stride, other.stride) {
+            Ordering::Greater
+        } else if stride_before(other.stride, self.stride) {
+            Ordering::Less
+        } else {
+            other.seq.cmp(&self.seq)
+        }
+    }
+}
+
+/// A stride scheduler, the ready queue is a heap keyed on stride so that
+/// the stride is read from the TCB only when a task is added.
+pub struct StrideScheduler {
+    ready_queue: BinaryHeap<ReadyTask>,
+    next_seq: usize,
+}
+
+impl StrideScheduler {
+    pub fn new() -> Self {
+        Self {
+            ready_queue: BinaryHeap::new(),
+            next_seq: 0,
+        }
+    }
+}
+
+impl Scheduler for StrideScheduler {
+    fn add(&mut self, task: Arc<TaskControlBlock>) {
+        let stride = task.inner_exclusive_access().task_stride;
+        self.ready_queue.push(ReadyTask {
+            stride,
+            seq: self.next_seq,
+            task,
+        });
+        self.next_seq += 1;
+    }
+    /// Take the task with the smallest stride
+    fn fetch(&mut self, hart: usize) -> Option<Arc<TaskControlBlock>> {
+        let mut skipped = Vec::new();
+        let task = loop {
+            match self.ready_queue.pop() {
+                Some(ready) if runs_on(&ready.task, hart) => break Some(ready.task),
+                Some(ready) => skipped.push(ready),
+                None => break None,
+            }
+        };
+        self.ready_queue.extend(skipped);
+        let task = task?;
+        task.inner_exclusive_access().update_stride();
+        Some(task)
+    }
+}
+
+/// Number of MLFQ levels, level 0 running first
+const MLFQ_LEVELS: usize = 3;
+/// Every task goes back to level 0 this often, so that none starves
+const MLFQ_BOOST_PERIOD_US: usize = 1_000_000;
+
+/// Timer ticks a task may run at `level` before it is demoted
+fn mlfq_slice(level: usize) -> usize {
+    time_slice_ticks() << level
+}
+
+/// Where a task stands in the multilevel feedback queue
+#[derive(Copy, Clone, Default)]
+pub struct MlfqState {
+    level: usize,
+    /// Ticks used of the time slice of this level
+    ticks: usize,
+    /// Boosts already applied to the task
+    epoch: usize,
+}
+
+/// A multilevel feedback queue: tasks start at level 0 and move a level
+/// down when they use up their time slice, which doubles with each level
+pub struct MlfqScheduler {
+    queues: [VecDeque<Arc<TaskControlBlock>>; MLFQ_LEVELS],
+    epoch: usize,
+    last_boost: usize,
+}
+
+impl MlfqScheduler {
+    pub fn new() -> Self {
+        Self {
+            queues: [VecDeque::new(), VecDeque::new(), VecDeque::new()],
+            epoch: 0,
+            last_boost: 0,
+        }
+    }
+}
+
+impl Scheduler for MlfqScheduler {
+    /// Queue a task at its level, or at level 0 if a boost happened since
+    /// it was last queued
+    fn add(&mut self, task: Arc<TaskControlBlock>) {
+        let mut inner = task.inner_exclusive_access();
+        if inner.mlfq.epoch != self.epoch {
+            inner.mlfq = MlfqState {
+                epoch: self.epoch,
+                ..MlfqState::default()
+            };
+        }
+        let level = inner.mlfq.level;
+        drop(inner);
+        self.queues[level].push_back(task);
+    }
+    /// Take the first task of the highest non-empty level, boosting all
+    /// tasks first once a boost period has passed
+    fn fetch(&mut self, hart: usize) -> Option<Arc<TaskControlBlock>> {
+        let now = get_time_us();
+        if now - self.last_boost >= MLFQ_BOOST_PERIOD_US {
+            self.last_boost = now;
+            // tasks not queued now are boosted when they are queued again
+            self.epoch += 1;
+            for level in 1..MLFQ_LEVELS {
+                while let Some(task) = self.queues[level].pop_front() {
+                    self.add(task);
+                }
+            }
+        }
+        self.queues.iter_mut().find_map(|queue| {
+            let idx = queue.iter().position(|task| runs_on(task, hart))?;
+            queue.remove(idx)
+        })
+    }
+    fn tick(&mut self, task: &TaskControlBlock) -> bool {
+        let mut inner = task.inner_exclusive_access();
+        let mlfq = &mut inner.mlfq;
+        mlfq.ticks += 1;
+        if mlfq.ticks < mlfq_slice(mlfq.level) {
+            return false;
+        }
+        mlfq.ticks = 0;
+        mlfq.level = (mlfq.level + 1).min(MLFQ_LEVELS - 1);
+        true
+    }
+}
+
+/// Weight of a task of the default priority
+const CFS_NICE_0_WEIGHT: usize = 16;
+
+/// Virtual runtime of a task in the CFS scheduler
+#[derive(Copy, Clone, Default)]
+pub struct CfsState {
+    vruntime: usize,
+    /// Runtime already turned into virtual runtime
+    charged: usize,
+}
+
+/// A CFS-style scheduler running the task with the smallest virtual
+/// runtime, which grows with runtime inversely to the priority
+pub struct CfsScheduler {
+    ready_queue: BTreeMap<(usize, usize), Arc<TaskControlBlock>>,
+    next_seq: usize,
+    /// Virtual runtime of the task picked last, never going backwards
+    min_vruntime: usize,
+}
+
+impl CfsScheduler {
+    pub fn new() -> Self {
+        Self {
+            ready_queue: BTreeMap::new(),
+            next_seq: 0,
+            min_vruntime: 0,
+        }
+    }
+}
+
+impl Scheduler for CfsScheduler {
+    /// Charge the runtime since the task was last queued, new and woken
+    /// tasks starting no earlier than `min_vruntime` so that they do not
+    /// monopolize the CPU
+    fn add(&mut self, task: Arc<TaskControlBlock>) {
+        let mut inner = task.inner_exclusive_access();
+        let runtime = inner.sum_exec_runtime - inner.cfs.charged;
+        let weight = inner.task_priority;
+        inner.cfs.charged = inner.sum_exec_runtime;
+        inner.cfs.vruntime += runtime * CFS_NICE_0_WEIGHT / weight;
+        inner.cfs.vruntime = inner.cfs.vruntime.max(self.min_vruntime);
+        let vruntime = inner.cfs.vruntime;
+        drop(inner);
+        self.ready_queue.insert((vruntime, self.next_seq), task);
+        self.next_seq += 1;
+    }
+    fn fetch(&mut self, hart: usize) -> Option<Arc<TaskControlBlock>> {
+        let key = *self
+            .ready_queue
+            .iter()
+            .find(|(_, task)| runs_on(task, hart))?
+            .0;
+        self.min_vruntime = self.min_vruntime.max(key.0);
+        self.ready_queue.remove(&key)
+    }
+}
@@ -0,0 +1,82 @@
+//! Signal numbers and their default dispositions
+
+use bitflags::*;
+
+/// The largest signal number
+pub const MAX_SIG: usize = 31;
+/// Handler address asking for the default disposition
+pub const SIG_DFL: usize = 0;
+/// Handler address asking for the signal to be ignored
+pub const SIG_IGN: usize = 1;
+
+bitflags! {
+    /// A set of signals, bit `n` standing for signal number `n`
+    pub struct SignalFlags: u32 {
+        const SIGHUP    = 1 << 1;
+        const SIGINT    = 1 << 2;
+        const SIGQUIT   = 1 << 3;
+        const SIGILL    = 1 << 4;
+        const SIGTRAP   = 1 << 5;
+        const SIGABRT   = 1 << 6;
+        const SIGBUS    = 1 << 7;
+        const SIGFPE    = 1 << 8;
+        const SIGKILL   = 1 << 9;
+        const SIGUSR1   = 1 << 10;
+        const SIGSEGV   = 1 << 11;
+        const SIGUSR2   = 1 << 12;
+        const SIGPIPE   = 1 << 13;
+        const SIGALRM   = 1 << 14;
+        const SIGTERM   = 1 << 15;
+        const SIGSTKFLT = 1 << 16;
+        const SIGCHLD   = 1 << 17;
+        const SIGCONT   = 1 << 18;
+        const SIGSTOP   = 1 << 19;
+        const SIGTSTP   = 1 << 20;
+        const SIGTTIN   = 1 << 21;
+        const SIGTTOU   = 1 << 22;
+        const SIGURG    = 1 << 23;
+        const SIGXCPU   = 1 << 24;
+        const SIGXFSZ   = 1 << 25;
+        const SIGVTALRM = 1 << 26;
+        const SIGPROF   = 1 << 27;
+        const SIGWINCH  = 1 << 28;
+        const SIGIO     = 1 << 29;
+        const SIGPWR    = 1 << 30;
+        const SIGSYS    = 1 << 31;
+    }
+}
+
+/// What the kernel does with a signal left to its default disposition
+#[derive(Copy, Clone, PartialEq)]
+pub enum DefaultAction {
+    Terminate,
+    Ignore,
+    Stop,
+    Continue,
+}
+
+impl SignalFlags {
+    /// The set holding only signal `signum`, if it is a valid signal number
+    pub fn from_signum(signum: usize) -> Option<Self> {
+        if signum == 0 || signum > MAX_SIG {
+            return None;
+        }
+        Self::from_bits(1 << signum)
+    }
+    /// Signals which can be neither caught, ignored nor blocked
+    pub fn unblockable() -> Self {
+        Self::SIGKILL | Self::SIGSTOP
+    }
+    /// The default disposition of a single signal
+    pub fn default_action(&self) -> DefaultAction {
+        if self.intersects(Self::SIGCHLD | Self::SIGURG | Self::SIGWINCH) {
+            DefaultAction::Ignore
+        } else if self.intersects(Self::SIGSTOP | Self::SIGTSTP | Self::SIGTTIN | Self::SIGTTOU) {
+            DefaultAction::Stop
+        } else if self.contains(Self::SIGCONT) {
+            DefaultAction::Continue
+        } else {
+            DefaultAction::Terminate
+        }
+    }
+}
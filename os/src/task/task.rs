@@ -1,15 +1,22 @@
 //! Types related to task management & Functions for completely changing TCB
 
+use super::scheduler::{CfsState, MlfqState};
 use super::TaskContext;
-use super::{pid_alloc, KernelStack, PidHandle};
-use crate::config::{BIG_STRIDE, MAX_SYSCALL_NUM, TRAP_CONTEXT};
-use crate::mm::{MemorySet, PhysPageNum, VirtAddr, KERNEL_SPACE};
-use crate::sync::UPSafeCell;
+use super::{pid_alloc, wakeup_vfork_parent, KernelStack, PidHandle};
+use super::nice::{nice_to_priority, priority_to_nice, MAX_NICE, MIN_NICE};
+use super::rlimit::{RLimits, RLIMIT_NOFILE};
+use super::{IoStats, PtraceState, Rusage, SchedStat, SignalActions, SignalFlags};
+use crate::config::{ARG_MAX, BIG_STRIDE, MAX_SYSCALL_NUM, NUM_HARTS, PAGE_SIZE, TASK_COMM_LEN, TRAP_CONTEXT};
+use crate::syscall::errno::{E2BIG, EAGAIN, ENOEXEC, ENOMEM};
+use crate::syscall::process::TimeVal;
+use crate::mm::{copy_to_user, put_user, MemorySet, PhysPageNum, VirtAddr, KERNEL_SPACE};
+use crate::sync::{SpinNoIrq, SpinNoIrqGuard};
+use crate::timer::{get_time_us, TaskCounters};
 use crate::trap::{trap_handler, TrapContext};
+use alloc::string::String;
 use alloc::sync::{Arc, Weak};
 use alloc::vec::Vec;
-use core::cell::RefMut;
-use crate::fs::{File, Stdin, Stdout};
+use crate::fs::{File, OSInode, Stdin, Stdout};
 
 /// Task control block structure
 ///
@@ -20,14 +27,16 @@ pub struct TaskControlBlock {
     pub pid: PidHandle,
     /// Kernel stack corresponding to PID
     pub kernel_stack: KernelStack,
+    /// Function run by a kernel thread, `None` for user processes
+    pub kthread_fn: Option<fn()>,
     // mutable
-    inner: UPSafeCell<TaskControlBlockInner>,
+    inner: SpinNoIrq<TaskControlBlockInner>,
 }
 
 /// Structure containing more process content
 ///
 /// Store the contents that will change during operation
-/// and are wrapped by UPSafeCell to provide mutual exclusion
+/// and are wrapped by SpinNoIrq to provide mutual exclusion
 pub struct TaskControlBlockInner {
     /// The physical page number of the frame where the trap context is placed
     pub trap_cx_ppn: PhysPageNum,
@@ -46,6 +55,20 @@ pub struct TaskControlBlockInner {
     pub task_priority: usize,
     /// Stride of the application
     pub task_stride: usize,
+    /// Nice value, kept in step with `task_priority`, see [`super::nice`]
+    pub nice: isize,
+    /// Level and time slice in the multilevel feedback queue
+    pub mlfq: MlfqState,
+    /// Virtual runtime in the CFS scheduler
+    pub cfs: CfsState,
+    /// When the task was last switched in, in microseconds
+    pub exec_start: usize,
+    /// Microseconds spent on the CPU
+    pub sum_exec_runtime: usize,
+    /// Timer ticks since the task was last switched in
+    pub slice_ticks: usize,
+    /// Harts the task may run on, bit n standing for hart n
+    pub cpu_mask: usize,
     /// Application address space
     pub memory_set: MemorySet,
     /// Parent process of the current process.
@@ -55,9 +78,62 @@ pub struct TaskControlBlockInner {
     pub children: Vec<Arc<TaskControlBlock>>,
     /// It is set when active exit or execution error occurs
     pub exit_code: i32,
-    pub fd_table: Vec<Option<Arc<dyn File + Send + Sync>>>,
+    /// Per-process toggles set through prctl
+    pub flags: ProcessFlags,
+    /// User id, whose disk quota files created by the process count against
+    pub uid: u32,
+    /// Short name of the process, NUL-padded
+    pub comm: [u8; TASK_COMM_LEN],
+    /// Signals received but not delivered yet
+    pub signals: SignalFlags,
+    /// Signals blocked through sigprocmask
+    pub signal_mask: SignalFlags,
+    /// Actions set through sigaction, shared with the processes cloned
+    /// with `CLONE_SIGHAND`
+    pub signal_actions: Arc<SpinNoIrq<SignalActions>>,
+    /// Signal whose user handler is running
+    pub handling_sig: Option<usize>,
+    /// Trap context to restore on sigreturn
+    pub trap_ctx_backup: Option<TrapContext>,
+    /// Signal which terminated the process
+    pub killed: Option<usize>,
+    /// Stopped by a signal until SIGCONT arrives
+    pub frozen: bool,
+    /// Blocked in waitpid until a child exits
+    pub waiting_child: bool,
+    /// Process group, signaled as a unit by kill(-pgid)
+    pub pgid: usize,
+    /// Session, the console signals only the foreground group of its session
+    pub sid: usize,
+    /// I/O counters, which start from zero in a new process
+    pub io_stats: IoStats,
+    /// Cycles and instructions retired while on the CPU
+    pub counters: TaskCounters,
+    /// Accounting behind getrusage
+    pub usage: TaskUsage,
+    /// Ready queue accounting, updated by the task manager
+    pub sched_stat: SchedStat,
+    /// When the task last entered the ready queue, in microseconds
+    pub enqueued_at: usize,
+    /// Running a syscall, which may hold references to its user pages so
+    /// that they must not be swapped out
+    pub in_syscall: bool,
+    /// Resource limits set through prlimit
+    pub rlimits: RLimits,
+    /// Tracing by the parent through ptrace
+    pub ptrace: PtraceState,
+    /// The parent whose address space this vfork child runs in
+    pub vfork_parent: Option<VforkParent>,
+    /// The address space is lent to a vfork child, which the task waits
+    /// for in [`wait_vfork_child`](super::wait_vfork_child)
+    pub lent_to_vfork: bool,
+    /// Open files, shared with the processes cloned with `CLONE_FILES`
+    pub fd_table: Arc<SpinNoIrq<FdTable>>,
 }
 
+/// Open files of a process indexed by fd
+pub type FdTable = Vec<Option<Arc<dyn File + Send + Sync>>>;
+
 /// Simple access to its internal fields
 impl TaskControlBlockInner {
     /*
@@ -65,6 +141,22 @@ impl TaskControlBlockInner {
         &self.task_cx_ptr as *const usize
     }
     */
+    /// Install `memory_set` as the address space, giving the borrowed one
+    /// back to the parent of a vfork child
+    ///
+    /// Returns that parent, which the caller wakes once it has released
+    /// this TCB, see [`wakeup_vfork_parent`](super::wakeup_vfork_parent).
+    #[must_use]
+    pub fn replace_memory_set(&mut self, memory_set: MemorySet) -> Option<Arc<TaskControlBlock>> {
+        let old = core::mem::replace(&mut self.memory_set, memory_set);
+        let vfork = self.vfork_parent.take()?;
+        let mut parent_inner = vfork.task.inner_exclusive_access();
+        parent_inner.memory_set = old;
+        *parent_inner.get_trap_cx() = vfork.trap_cx;
+        parent_inner.lent_to_vfork = false;
+        drop(parent_inner);
+        Some(vfork.task)
+    }
     pub fn get_trap_cx(&self) -> &'static mut TrapContext {
         self.trap_cx_ppn.get_mut()
     }
@@ -77,8 +169,62 @@ impl TaskControlBlockInner {
     pub fn get_syscall_times(&self) -> [u32; MAX_SYSCALL_NUM] {
         self.task_syscall_times
     }
+    /// Set the name of the process, truncated to fit `comm` with a NUL
+    pub fn set_comm(&mut self, name: &str) {
+        let len = name.len().min(TASK_COMM_LEN - 1);
+        self.comm = [0; TASK_COMM_LEN];
+        self.comm[..len].copy_from_slice(&name.as_bytes()[..len]);
+    }
+    /// Get the name of the process
+    pub fn comm(&self) -> &str {
+        let len = self.comm.iter().position(|&b| b == 0).unwrap_or(TASK_COMM_LEN);
+        match core::str::from_utf8(&self.comm[..len]) {
+            Ok(name) => name,
+            // truncation may split a multi-byte character
+            Err(e) => core::str::from_utf8(&self.comm[..e.valid_up_to()]).unwrap(),
+        }
+    }
+    /// Set the nice value and the priority it maps to
+    pub fn set_nice(&mut self, nice: isize) {
+        self.nice = nice.clamp(MIN_NICE, MAX_NICE);
+        self.task_priority = nice_to_priority(self.nice);
+    }
+    /// Set the priority and the nice value it maps to
+    pub fn set_priority(&mut self, priority: usize) {
+        self.task_priority = priority;
+        self.nice = priority_to_nice(priority);
+    }
+    /// Strides wrap around, see [`stride_before`]
     pub fn update_stride(&mut self) {
-        self.task_stride += BIG_STRIDE / self.task_priority;
+        self.task_stride = self.task_stride.wrapping_add(BIG_STRIDE / self.task_priority);
+    }
+    /// Raise the peak resident set to the current one
+    pub fn update_maxrss(&mut self) {
+        let pages = self.memory_set.resident_pages();
+        self.usage.max_rss_pages = self.usage.max_rss_pages.max(pages);
+    }
+    /// Microseconds spent in user mode and in the kernel, the latter being
+    /// the part of the runtime not spent in user mode
+    pub fn cpu_times(&self) -> (usize, usize) {
+        let mut runtime = self.sum_exec_runtime;
+        if self.task_status == TaskStatus::Running {
+            runtime += get_time_us() - self.exec_start;
+        }
+        (self.usage.user_time, runtime.saturating_sub(self.usage.user_time))
+    }
+    /// Resource usage of the process itself
+    pub fn rusage(&self) -> Rusage {
+        let (utime, stime) = self.cpu_times();
+        Rusage {
+            utime: TimeVal::from_us(utime),
+            stime: TimeVal::from_us(stime),
+            maxrss: self.usage.max_rss_pages * PAGE_SIZE / 1024,
+            inblock: self.io_stats.read_blocks as usize,
+            oublock: self.io_stats.write_blocks as usize,
+            nvcsw: self.usage.nvcsw,
+            nivcsw: self.usage.nivcsw,
+            ..Rusage::default()
+        }
     }
     fn get_status(&self) -> TaskStatus {
         self.task_status
@@ -86,66 +232,118 @@ impl TaskControlBlockInner {
     pub fn is_zombie(&self) -> bool {
         self.get_status() == TaskStatus::Zombie
     }
-    pub fn alloc_fd(&mut self) -> usize {
-        if let Some(fd) = (0..self.fd_table.len())
-            .find(|fd| self.fd_table[*fd].is_none()) {
+    /// Install `file` at the lowest free fd, `None` if it would exceed
+    /// `RLIMIT_NOFILE`
+    pub fn alloc_fd(&self, file: Arc<dyn File + Send + Sync>) -> Option<usize> {
+        let mut fd_table = self.fd_table.lock();
+        let fd = if let Some(fd) = (0..fd_table.len())
+            .find(|fd| fd_table[*fd].is_none()) {
             fd
         } else {
-            self.fd_table.push(None);
-            self.fd_table.len() - 1
+            fd_table.len()
+        };
+        if fd >= self.rlimits.get(RLIMIT_NOFILE).cur {
+            return None;
         }
+        if fd == fd_table.len() {
+            fd_table.push(None);
+        }
+        fd_table[fd] = Some(file);
+        Some(fd)
+    }
+    /// Get the file open at `fd`
+    pub fn get_file(&self, fd: usize) -> Option<Arc<dyn File + Send + Sync>> {
+        self.fd_table.lock().get(fd).cloned().flatten()
     }
 }
 
 impl TaskControlBlock {
     /// Get the mutex to get the RefMut TaskControlBlockInner
-    pub fn inner_exclusive_access(&self) -> RefMut<'_, TaskControlBlockInner> {
-        self.inner.exclusive_access()
+    pub fn inner_exclusive_access(&self) -> SpinNoIrqGuard<'_, TaskControlBlockInner> {
+        self.inner.lock()
+    }
+    /// Get the RefMut TaskControlBlockInner, or `None` if it is locked
+    pub fn try_inner_exclusive_access(&self) -> Option<SpinNoIrqGuard<'_, TaskControlBlockInner>> {
+        self.inner.try_lock()
     }
 
     /// Create a new process
     ///
     /// At present, it is only used for the creation of initproc
-    pub fn new(elf_data: &[u8]) -> Self {
+    pub fn new(name: &str, elf: Arc<OSInode>) -> Self {
         // memory_set with elf program headers/trampoline/trap context/user stack
-        let (memory_set, user_sp, entry_point) = MemorySet::from_elf(elf_data);
+        let (memory_set, user_sp, entry_point) =
+            MemorySet::from_elf(elf, RLimits::default().stack_size())
+                .expect("invalid elf of initial process");
         let trap_cx_ppn = memory_set
             .translate(VirtAddr::from(TRAP_CONTEXT).into())
             .unwrap()
             .ppn();
         // alloc a pid and a kernel stack in kernel space
-        let pid_handle = pid_alloc();
-        let kernel_stack = KernelStack::new(&pid_handle);
+        let pid_handle = pid_alloc().expect("no pid left for initproc");
+        let pgid = pid_handle.0;
+        let sid = pid_handle.0;
+        let kernel_stack = KernelStack::new(&pid_handle).expect("no kernel stack for initproc");
         let kernel_stack_top = kernel_stack.get_top();
         // push a task context which goes to trap_return to the top of kernel stack
         let task_control_block = Self {
             pid: pid_handle,
             kernel_stack,
-            inner: unsafe {
-                UPSafeCell::new(TaskControlBlockInner {
-                    trap_cx_ppn,
-                    base_size: user_sp,
-                    task_cx: TaskContext::goto_trap_return(kernel_stack_top),
-                    task_status: TaskStatus::Ready,
-                    task_begin_time: 0,
-                    task_syscall_times: [0; MAX_SYSCALL_NUM],
-                    task_priority: 16,
-                    task_stride: 0,
-                    memory_set,
-                    parent: None,
-                    children: Vec::new(),
-                    exit_code: 0,
-                    fd_table: alloc::vec![
-                        // 0 -> stdin
-                        Some(Arc::new(Stdin)),
-                        // 1 -> stdout
-                        Some(Arc::new(Stdout)),
-                        // 2 -> stderr
-                        Some(Arc::new(Stdout)),
-                    ],
-                })
-            },
+            kthread_fn: None,
+            inner: SpinNoIrq::new(TaskControlBlockInner {
+                trap_cx_ppn,
+                base_size: user_sp,
+                task_cx: TaskContext::goto_trap_return(kernel_stack_top),
+                task_status: TaskStatus::Ready,
+                task_begin_time: 0,
+                task_syscall_times: [0; MAX_SYSCALL_NUM],
+                task_priority: 16,
+                nice: 0,
+                task_stride: 0,
+                mlfq: MlfqState::default(),
+                cfs: CfsState::default(),
+                exec_start: 0,
+                sum_exec_runtime: 0,
+                slice_ticks: 0,
+                cpu_mask: CPU_MASK_ALL,
+                memory_set,
+                parent: None,
+                children: Vec::new(),
+                exit_code: 0,
+                flags: ProcessFlags::DUMPABLE,
+                uid: 0,
+                comm: [0; TASK_COMM_LEN],
+                signals: SignalFlags::empty(),
+                signal_mask: SignalFlags::empty(),
+                signal_actions: Arc::new(SpinNoIrq::new(SignalActions::default())),
+                handling_sig: None,
+                trap_ctx_backup: None,
+                killed: None,
+                frozen: false,
+                waiting_child: false,
+                pgid,
+                sid,
+                io_stats: IoStats::default(),
+                counters: TaskCounters::default(),
+                usage: TaskUsage::default(),
+                sched_stat: SchedStat::default(),
+                enqueued_at: 0,
+                in_syscall: false,
+                rlimits: RLimits::default(),
+                ptrace: PtraceState::default(),
+                vfork_parent: None,
+                lent_to_vfork: false,
+                fd_table: Arc::new(SpinNoIrq::new(alloc::vec![
+                    // 0 -> stdin
+                    Some(Arc::new(Stdin)),
+                    // 1 -> stdout
+                    Some(Arc::new(Stdout)),
+                    // 2 -> stderr
+                    Some(Arc::new(Stdout)),
+                ])),
+            }),
         };
+        task_control_block.inner_exclusive_access().set_comm(name);
         // prepare TrapContext in user space
         let trap_cx = task_control_block.inner_exclusive_access().get_trap_cx();
         *trap_cx = TrapContext::app_init_context(
@@ -157,10 +355,81 @@ impl TaskControlBlock {
         );
         task_control_block
     }
+    /// Create a kernel thread running `entry`, which has no user address
+    /// space and never returns to user mode
+    pub fn new_kthread(name: &str, entry: fn()) -> Self {
+        let pid_handle = pid_alloc().expect("no pid left for a kernel thread");
+        let pgid = pid_handle.0;
+        let sid = pid_handle.0;
+        let kernel_stack = KernelStack::new(&pid_handle).expect("no kernel stack for a kernel thread");
+        let kernel_stack_top = kernel_stack.get_top();
+        let task_control_block = Self {
+            pid: pid_handle,
+            kernel_stack,
+            kthread_fn: Some(entry),
+            inner: SpinNoIrq::new(TaskControlBlockInner {
+                // there is no trap context without a user address space
+                trap_cx_ppn: PhysPageNum(0),
+                base_size: 0,
+                task_cx: TaskContext::goto_kthread_start(kernel_stack_top),
+                task_status: TaskStatus::Ready,
+                task_begin_time: 0,
+                task_syscall_times: [0; MAX_SYSCALL_NUM],
+                task_priority: 16,
+                nice: 0,
+                task_stride: 0,
+                mlfq: MlfqState::default(),
+                cfs: CfsState::default(),
+                exec_start: 0,
+                sum_exec_runtime: 0,
+                slice_ticks: 0,
+                cpu_mask: CPU_MASK_ALL,
+                memory_set: MemorySet::new_empty(),
+                parent: None,
+                children: Vec::new(),
+                exit_code: 0,
+                flags: ProcessFlags::empty(),
+                uid: 0,
+                comm: [0; TASK_COMM_LEN],
+                signals: SignalFlags::empty(),
+                signal_mask: SignalFlags::empty(),
+                signal_actions: Arc::new(SpinNoIrq::new(SignalActions::default())),
+                handling_sig: None,
+                trap_ctx_backup: None,
+                killed: None,
+                frozen: false,
+                waiting_child: false,
+                pgid,
+                sid,
+                io_stats: IoStats::default(),
+                counters: TaskCounters::default(),
+                usage: TaskUsage::default(),
+                sched_stat: SchedStat::default(),
+                enqueued_at: 0,
+                in_syscall: false,
+                rlimits: RLimits::default(),
+                ptrace: PtraceState::default(),
+                vfork_parent: None,
+                lent_to_vfork: false,
+                fd_table: Arc::new(SpinNoIrq::new(Vec::new())),
+            }),
+        };
+        task_control_block.inner_exclusive_access().set_comm(name);
+        task_control_block
+    }
+    /// Whether this is a kernel thread
+    pub fn is_kthread(&self) -> bool {
+        self.kthread_fn.is_some()
+    }
     /// Load a new elf to replace the original application address space and start execution
-    pub fn exec(&self, elf_data: &[u8]) {
+    ///
+    /// Returns `argc`, which the syscall return value puts into a0, or a
+    /// negative errno.
+    pub fn exec(&self, name: &str, elf: Arc<OSInode>, args: Vec<String>, envs: Vec<String>) -> Result<usize, isize> {
         // memory_set with elf program headers/trampoline/trap context/user stack
-        let (memory_set, user_sp, entry_point) = MemorySet::from_elf(elf_data);
+        let stack_size = self.inner_exclusive_access().rlimits.stack_size();
+        let (memory_set, user_sp, entry_point) = MemorySet::from_elf(elf, stack_size).ok_or(-ENOEXEC)?;
+        let (user_sp, argv_base, envp_base) = push_args(&memory_set, user_sp, &args, &envs)?;
         let trap_cx_ppn = memory_set
             .translate(VirtAddr::from(TRAP_CONTEXT).into())
             .unwrap()
@@ -168,9 +437,20 @@ impl TaskControlBlock {
         // **** access inner exclusively
         let mut inner = self.inner_exclusive_access();
         // substitute memory_set
-        inner.memory_set = memory_set;
+        let vfork_parent = inner.replace_memory_set(memory_set);
         // update trap_cx ppn
         inner.trap_cx_ppn = trap_cx_ppn;
+        // a fresh program image may be dumped again
+        inner.flags |= ProcessFlags::DUMPABLE;
+        inner.set_comm(name);
+        // handlers are gone with the old image, and neither the actions nor
+        // the fd table are shared any longer
+        let signal_actions = inner.signal_actions.lock().reset_on_exec();
+        inner.signal_actions = Arc::new(SpinNoIrq::new(signal_actions));
+        let fd_table = inner.fd_table.lock().clone();
+        inner.fd_table = Arc::new(SpinNoIrq::new(fd_table));
+        inner.handling_sig = None;
+        inner.trap_ctx_backup = None;
         // initialize trap_cx
         let trap_cx = inner.get_trap_cx();
         *trap_cx = TrapContext::app_init_context(
@@ -180,112 +460,257 @@ impl TaskControlBlock {
             self.kernel_stack.get_top(),
             trap_handler as usize,
         );
-        // **** release inner automatically
+        trap_cx.x[11] = argv_base;
+        trap_cx.x[12] = envp_base;
+        drop(inner);
+        // **** release inner
+        if let Some(parent) = vfork_parent {
+            wakeup_vfork_parent(parent);
+        }
+        Ok(args.len())
     }
-    /// Fork from parent to child
-    pub fn fork(self: &Arc<TaskControlBlock>) -> Arc<TaskControlBlock> {
+    /// Create a child process, copying or sharing resources as `flags` say,
+    /// with its user stack pointer set to `stack` unless it is 0
+    ///
+    /// The fd table and the signal actions are shared with `CLONE_FILES`
+    /// and `CLONE_SIGHAND`. A process owns its address space, so it is only
+    /// shared by lending it to a vfork child, with `CLONE_VM` and
+    /// `CLONE_VFORK` together. As in Linux, `CLONE_SIGHAND` needs
+    /// `CLONE_VM`, for the handlers are addresses in the shared space.
+    /// There are no threads, so `CLONE_THREAD` makes it fail.
+    pub fn clone_task(
+        self: &Arc<TaskControlBlock>,
+        flags: CloneFlags,
+        stack: usize,
+    ) -> Option<Arc<TaskControlBlock>> {
+        let vfork = CloneFlags::CLONE_VM | CloneFlags::CLONE_VFORK;
+        if flags.contains(CloneFlags::CLONE_THREAD)
+            || (flags.intersects(vfork) && !flags.contains(vfork))
+            || (flags.contains(CloneFlags::CLONE_SIGHAND) && !flags.contains(CloneFlags::CLONE_VM))
+        {
+            return None;
+        }
+        // the child of a CLONE_PARENT clone is a sibling of the caller
+        let parent = if flags.contains(CloneFlags::CLONE_PARENT) {
+            self.inner_exclusive_access()
+                .parent
+                .as_ref()
+                .and_then(|parent| parent.upgrade())?
+        } else {
+            Arc::clone(self)
+        };
+        // alloc a pid and a kernel stack before anything that would need
+        // undoing
+        let pid_handle = pid_alloc()?;
+        let kernel_stack = KernelStack::new(&pid_handle)?;
         // ---- access parent PCB exclusively
         let mut parent_inner = self.inner_exclusive_access();
-        // copy user space(include trap context)
-        let memory_set = MemorySet::from_existed_user(&parent_inner.memory_set);
+        let (memory_set, vfork_parent) = if flags.contains(vfork) {
+            // lend the user space, trap context included, keeping the
+            // caller's trap context to put back when it is returned
+            let trap_cx = *parent_inner.get_trap_cx();
+            let memory_set = core::mem::replace(&mut parent_inner.memory_set, MemorySet::new_empty());
+            parent_inner.lent_to_vfork = true;
+            let vfork_parent = VforkParent {
+                task: Arc::clone(self),
+                trap_cx,
+            };
+            (memory_set, Some(vfork_parent))
+        } else {
+            // copy user space(include trap context)
+            (MemorySet::from_existed_user(&parent_inner.memory_set)?, None)
+        };
         let trap_cx_ppn = memory_set
             .translate(VirtAddr::from(TRAP_CONTEXT).into())
             .unwrap()
             .ppn();
-        // alloc a pid and a kernel stack in kernel space
-        let pid_handle = pid_alloc();
-        let kernel_stack = KernelStack::new(&pid_handle);
         let kernel_stack_top = kernel_stack.get_top();
-        let mut new_fd_table: Vec<Option<Arc<dyn File + Send + Sync>>> = Vec::new();
-        // clone all fds from parent to child
-        for fd in parent_inner.fd_table.iter() {
-            if let Some(file) = fd {
-                new_fd_table.push(Some(file.clone()));
-            } else {
-                new_fd_table.push(None);
-            }
-        }
+        let fd_table = if flags.contains(CloneFlags::CLONE_FILES) {
+            Arc::clone(&parent_inner.fd_table)
+        } else {
+            // clone all fds from parent to child
+            Arc::new(SpinNoIrq::new(parent_inner.fd_table.lock().clone()))
+        };
+        let signal_actions = if flags.contains(CloneFlags::CLONE_SIGHAND) {
+            Arc::clone(&parent_inner.signal_actions)
+        } else {
+            Arc::new(SpinNoIrq::new(parent_inner.signal_actions.lock().clone()))
+        };
         let task_control_block = Arc::new(TaskControlBlock {
             pid: pid_handle,
             kernel_stack,
-            inner: unsafe {
-                UPSafeCell::new(TaskControlBlockInner {
-                    trap_cx_ppn,
-                    base_size: parent_inner.base_size,
-                    task_cx: TaskContext::goto_trap_return(kernel_stack_top),
-                    task_status: TaskStatus::Ready,
-                    task_begin_time: 0,
-                    task_syscall_times: [0; MAX_SYSCALL_NUM],
-                    task_priority: 16,
-                    task_stride: 0,
-                    memory_set,
-                    parent: Some(Arc::downgrade(self)),
-                    children: Vec::new(),
-                    exit_code: 0,
-                    fd_table: new_fd_table,
-                })
-            },
+            kthread_fn: None,
+            inner: SpinNoIrq::new(TaskControlBlockInner {
+                trap_cx_ppn,
+                base_size: parent_inner.base_size,
+                task_cx: TaskContext::goto_trap_return(kernel_stack_top),
+                task_status: TaskStatus::Ready,
+                task_begin_time: 0,
+                task_syscall_times: [0; MAX_SYSCALL_NUM],
+                // a child may not escape a nice value its parent cannot lower
+                task_priority: parent_inner.task_priority,
+                nice: parent_inner.nice,
+                // start from the parent's stride, which is close to the smallest one
+                task_stride: parent_inner.task_stride,
+                mlfq: MlfqState::default(),
+                cfs: CfsState::default(),
+                exec_start: 0,
+                sum_exec_runtime: 0,
+                slice_ticks: 0,
+                cpu_mask: parent_inner.cpu_mask,
+                memory_set,
+                parent: Some(Arc::downgrade(&parent)),
+                children: Vec::new(),
+                exit_code: 0,
+                // toggles are inherited by the child
+                flags: parent_inner.flags,
+                uid: parent_inner.uid,
+                comm: parent_inner.comm,
+                // pending signals are not inherited, mask and actions are
+                signals: SignalFlags::empty(),
+                signal_mask: parent_inner.signal_mask,
+                signal_actions,
+                handling_sig: None,
+                trap_ctx_backup: None,
+                killed: None,
+                frozen: false,
+                waiting_child: false,
+                pgid: parent_inner.pgid,
+                sid: parent_inner.sid,
+                io_stats: IoStats::default(),
+                counters: TaskCounters::default(),
+                usage: TaskUsage::default(),
+                sched_stat: SchedStat::default(),
+                enqueued_at: 0,
+                in_syscall: false,
+                rlimits: parent_inner.rlimits,
+                ptrace: PtraceState::default(),
+                vfork_parent,
+                lent_to_vfork: false,
+                fd_table,
+            }),
         });
+        drop(parent_inner);
+        // ---- release parent PCB
         // add child
-        parent_inner.children.push(task_control_block.clone());
-        // modify kernel_sp in trap_cx
+        parent.inner_exclusive_access().children.push(task_control_block.clone());
+        // modify kernel_sp and user sp in trap_cx
         // **** access children PCB exclusively
         let trap_cx = task_control_block.inner_exclusive_access().get_trap_cx();
         trap_cx.kernel_sp = kernel_stack_top;
+        if stack != 0 {
+            trap_cx.x[2] = stack;
+        }
         // return
-        task_control_block
-        // ---- release parent PCB automatically
+        Some(task_control_block)
         // **** release children PCB automatically
     }
 
     /// Create a new child process that executes a specified file
-    pub fn spawn(self: &Arc<TaskControlBlock>, elf_data: &[u8]) -> Arc<TaskControlBlock> {
-        let pid_handle = pid_alloc();
-        let kernel_stack = KernelStack::new(&pid_handle);
+    pub fn spawn(
+        self: &Arc<TaskControlBlock>,
+        name: &str,
+        elf: Arc<OSInode>,
+        args: Vec<String>,
+        envs: Vec<String>,
+    ) -> Result<Arc<TaskControlBlock>, isize> {
+        // load the elf first, so that a bad one is reported to the caller
+        // rather than by a child exiting early
+        let stack_size = self.inner_exclusive_access().rlimits.stack_size();
+        let (memory_set, user_sp, entry_point) = MemorySet::from_elf(elf, stack_size).ok_or(-ENOEXEC)?;
+        let (stack_sp, argv_base, envp_base) = push_args(&memory_set, user_sp, &args, &envs)?;
+        let pid_handle = pid_alloc().ok_or(-EAGAIN)?;
+        let kernel_stack = KernelStack::new(&pid_handle).ok_or(-ENOMEM)?;
         let kernel_stack_top = kernel_stack.get_top();
-        let (memory_set, user_sp, entry_point) = MemorySet::from_elf(elf_data);
         let trap_cx_ppn = memory_set
             .translate(VirtAddr::from(TRAP_CONTEXT).into())
             .unwrap()
             .ppn();
+        // toggles are inherited as if forked, then reset as on exec
+        let flags = self.inner_exclusive_access().flags | ProcessFlags::DUMPABLE;
+        let uid = self.inner_exclusive_access().uid;
+        let pgid = self.inner_exclusive_access().pgid;
+        let sid = self.inner_exclusive_access().sid;
+        let signal_mask = self.inner_exclusive_access().signal_mask;
+        let signal_actions = self.inner_exclusive_access().signal_actions.lock().reset_on_exec();
+        let signal_actions = Arc::new(SpinNoIrq::new(signal_actions));
+        let task_stride = self.inner_exclusive_access().task_stride;
+        let cpu_mask = self.inner_exclusive_access().cpu_mask;
+        let rlimits = self.inner_exclusive_access().rlimits;
+        let task_priority = self.inner_exclusive_access().task_priority;
+        let nice = self.inner_exclusive_access().nice;
         let task_control_block = Arc::new(TaskControlBlock {
             pid: pid_handle,
             kernel_stack,
-            inner: unsafe {
-                UPSafeCell::new(TaskControlBlockInner {
-                    trap_cx_ppn,
-                    base_size: user_sp,
-                    task_cx: TaskContext::goto_trap_return(kernel_stack_top),
-                    task_status: TaskStatus::Ready,
-                    task_begin_time: 0,
-                    task_syscall_times: [0; MAX_SYSCALL_NUM],
-                    task_priority: 16,
-                    task_stride: 0,
-                    memory_set,
-                    parent: Some(Arc::downgrade(self)),
-                    children: Vec::new(),
-                    exit_code: 0,
-                    fd_table: alloc::vec![
-                        // 0 -> stdin
-                        Some(Arc::new(Stdin)),
-                        // 1 -> stdout
-                        Some(Arc::new(Stdout)),
-                        // 2 -> stderr
-                        Some(Arc::new(Stdout)),
-                    ],
-                })
-            },
+            kthread_fn: None,
+            inner: SpinNoIrq::new(TaskControlBlockInner {
+                trap_cx_ppn,
+                base_size: user_sp,
+                task_cx: TaskContext::goto_trap_return(kernel_stack_top),
+                task_status: TaskStatus::Ready,
+                task_begin_time: 0,
+                task_syscall_times: [0; MAX_SYSCALL_NUM],
+                task_priority,
+                nice,
+                task_stride,
+                mlfq: MlfqState::default(),
+                cfs: CfsState::default(),
+                exec_start: 0,
+                sum_exec_runtime: 0,
+                slice_ticks: 0,
+                cpu_mask,
+                memory_set,
+                parent: Some(Arc::downgrade(self)),
+                children: Vec::new(),
+                exit_code: 0,
+                flags,
+                uid,
+                comm: [0; TASK_COMM_LEN],
+                signals: SignalFlags::empty(),
+                signal_mask,
+                signal_actions,
+                handling_sig: None,
+                trap_ctx_backup: None,
+                killed: None,
+                frozen: false,
+                waiting_child: false,
+                pgid,
+                sid,
+                io_stats: IoStats::default(),
+                counters: TaskCounters::default(),
+                usage: TaskUsage::default(),
+                sched_stat: SchedStat::default(),
+                enqueued_at: 0,
+                in_syscall: false,
+                rlimits,
+                ptrace: PtraceState::default(),
+                vfork_parent: None,
+                lent_to_vfork: false,
+                fd_table: Arc::new(SpinNoIrq::new(alloc::vec![
+                    // 0 -> stdin
+                    Some(Arc::new(Stdin)),
+                    // 1 -> stdout
+                    Some(Arc::new(Stdout)),
+                    // 2 -> stderr
+                    Some(Arc::new(Stdout)),
+                ])),
+            }),
         });
         let mut parent_inner = self.inner_exclusive_access();
         parent_inner.children.push(task_control_block.clone());
-        *(trap_cx_ppn.get_mut()) = TrapContext::app_init_context(
+        task_control_block.inner_exclusive_access().set_comm(name);
+        let trap_cx: &mut TrapContext = trap_cx_ppn.get_mut();
+        *trap_cx = TrapContext::app_init_context(
             entry_point,
-            user_sp,
+            stack_sp,
             KERNEL_SPACE.exclusive_access().token(),
             kernel_stack_top,
             trap_handler as usize,
         );
-        task_control_block
+        trap_cx.x[10] = args.len();
+        trap_cx.x[11] = argv_base;
+        trap_cx.x[12] = envp_base;
+        Ok(task_control_block)
     }
 
     pub fn getpid(&self) -> usize {
@@ -293,11 +718,150 @@ impl TaskControlBlock {
     }
 }
 
+/// Push the `args` and `envs` strings and their null-terminated pointer
+/// arrays onto the user stack of `memory_set`
+///
+/// Returns the new stack pointer and the addresses of argv and envp, or
+/// -E2BIG if they take more than [`ARG_MAX`] bytes or do not fit in the
+/// stack mapped.
+fn push_args(
+    memory_set: &MemorySet,
+    mut user_sp: usize,
+    args: &[String],
+    envs: &[String],
+) -> Result<(usize, usize, usize), isize> {
+    let token = memory_set.token();
+    let ptr_size = core::mem::size_of::<usize>();
+    let ptrs_size = (args.len() + 1 + envs.len() + 1) * ptr_size;
+    let strings_size: usize = args.iter().chain(envs.iter()).map(|string| string.len() + 1).sum();
+    if ptrs_size + strings_size > ARG_MAX {
+        return Err(-E2BIG);
+    }
+    user_sp -= ptrs_size;
+    let argv_base = user_sp;
+    let envp_base = argv_base + (args.len() + 1) * ptr_size;
+    for (base, strings) in [(argv_base, args), (envp_base, envs)].iter() {
+        for (i, string) in strings.iter().enumerate() {
+            user_sp -= string.len() + 1;
+            put_user(token, (base + i * ptr_size) as *mut usize, user_sp).map_err(|_| -E2BIG)?;
+            copy_to_user(token, user_sp as *mut u8, string.as_bytes()).map_err(|_| -E2BIG)?;
+            put_user(token, (user_sp + string.len()) as *mut u8, 0u8).map_err(|_| -E2BIG)?;
+        }
+        put_user(token, (base + strings.len() * ptr_size) as *mut usize, 0).map_err(|_| -E2BIG)?;
+    }
+    // the RISC-V calling convention keeps the stack pointer 16-byte aligned
+    user_sp &= !0xf;
+    Ok((user_sp, argv_base, envp_base))
+}
+
+bitflags! {
+    /// Per-process toggles, inherited across fork
+    pub struct ProcessFlags: u32 {
+        /// The process may leave a core dump, set again on exec
+        const DUMPABLE = 1 << 0;
+        /// User reads of `cycle` and `instret` count only this process
+        const VIRTUAL_COUNTERS = 1 << 1;
+        /// Every syscall is logged, see syscall/trace.rs
+        const TRACE_SYSCALLS = 1 << 2;
+    }
+}
+
+bitflags! {
+    /// What a child created by clone shares with its parent, numbered as
+    /// in Linux
+    pub struct CloneFlags: u32 {
+        /// Share the address space
+        const CLONE_VM = 0x100;
+        /// Share filesystem information, a no-op without working directories
+        const CLONE_FS = 0x200;
+        /// Share the fd table
+        const CLONE_FILES = 0x400;
+        /// Share signal actions
+        const CLONE_SIGHAND = 0x800;
+        /// Give the child the parent of the caller
+        const CLONE_PARENT = 0x8000;
+        /// Suspend the caller until the child execs or exits
+        const CLONE_VFORK = 0x4000;
+        /// Put the child in the thread group of the caller
+        const CLONE_THREAD = 0x10000;
+    }
+}
+
+/// The caller of a vfork, waiting for its address space to be returned
+pub struct VforkParent {
+    pub task: Arc<TaskControlBlock>,
+    /// Trap context of the caller, which the child overwrites
+    pub trap_cx: TrapContext,
+}
+
+/// Accounting behind getrusage, which starts from zero in a new process
+#[derive(Clone, Copy, Default)]
+pub struct TaskUsage {
+    /// Microseconds spent in user mode
+    pub user_time: usize,
+    /// When the task last entered user mode, in microseconds
+    pub user_enter: usize,
+    /// Context switches when the task blocked or yielded
+    pub nvcsw: usize,
+    /// Context switches when the task was preempted
+    pub nivcsw: usize,
+    /// Peak resident set in pages
+    pub max_rss_pages: usize,
+    /// Usage of the reaped children and their descendants
+    pub children: Rusage,
+    /// CPU second from which the next SIGXCPU is sent
+    pub next_xcpu: usize,
+}
+
+/// Affinity allowing every hart
+pub const CPU_MASK_ALL: usize = (1 << NUM_HARTS) - 1;
+
+/// Whether stride `a` comes before stride `b` modulo 2^64
+///
+/// Priorities are at least 2, so the strides of ready tasks stay within
+/// `BIG_STRIDE / 2` of each other and their wrapping difference, read as
+/// signed, orders them even after they wrap around.
+pub fn stride_before(a: usize, b: usize) -> bool {
+    (a.wrapping_sub(b) as isize) < 0
+}
+
+/// Check that stride order survives strides wrapping around: tasks picked
+/// by [`stride_before`] from strides just below `usize::MAX` must be those
+/// picked from the same strides kept without wrapping
+pub fn stride_test() {
+    assert!(stride_before(usize::MAX - BIG_STRIDE, usize::MAX));
+    assert!(stride_before(usize::MAX, BIG_STRIDE / 2));
+    assert!(!stride_before(BIG_STRIDE / 2, usize::MAX));
+    assert!(!stride_before(usize::MAX, usize::MAX));
+    let priorities = [2, 3, 16, 100];
+    let start = usize::MAX - 2 * BIG_STRIDE;
+    let mut strides = [start; 4];
+    let mut unwrapped = [start as u128; 4];
+    for _ in 0..1000 {
+        let mut next = 0;
+        let mut expected = 0;
+        for i in 1..priorities.len() {
+            if stride_before(strides[i], strides[next]) {
+                next = i;
+            }
+            if unwrapped[i] < unwrapped[expected] {
+                expected = i;
+            }
+        }
+        assert_eq!(next, expected);
+        strides[next] = strides[next].wrapping_add(BIG_STRIDE / priorities[next]);
+        unwrapped[next] += (BIG_STRIDE / priorities[next]) as u128;
+    }
+    assert!(unwrapped.iter().all(|&stride| stride > usize::MAX as u128));
+    info!("stride_test passed!");
+}
+
 #[derive(Copy, Clone, PartialEq)]
-/// task status: UnInit, Ready, Running, Exited
+/// task status: UnInit, Ready, Running, Blocked, Exited
 pub enum TaskStatus {
     UnInit,
     Ready,
     Running,
+    Blocked,
     Zombie,
 }
@@ -1,10 +1,12 @@
 //! RISC-V timer-related functionality
 
-use crate::config::CLOCK_FREQ;
+use crate::config::{CLOCK_FREQ, TICKS_PER_SEC, TIME_SLICE_TICKS};
+use crate::mm::{frame_alloc, FrameTracker, PhysPageNum};
 use crate::sbi::set_timer;
-use riscv::register::time;
+use core::sync::atomic::{fence, AtomicUsize, Ordering};
+use lazy_static::*;
+use riscv::register::{cycle, instret, scounteren, time};
 
-const TICKS_PER_SEC: usize = 100;
 const MICRO_PER_SEC: usize = 1_000_000;
 
 /// read the `mtime` register
@@ -17,7 +19,170 @@ pub fn get_time_us() -> usize {
     time::read() / (CLOCK_FREQ / MICRO_PER_SEC)
 }
 
+/// Timer interrupts per second
+static TICK_FREQ: AtomicUsize = AtomicUsize::new(TICKS_PER_SEC);
+/// Ticks in a time slice
+static SLICE_TICKS: AtomicUsize = AtomicUsize::new(TIME_SLICE_TICKS);
+
+/// Get the number of timer interrupts per second
+pub fn tick_freq() -> usize {
+    TICK_FREQ.load(Ordering::Relaxed)
+}
+
+/// Get the number of ticks a task runs before it is preempted
+pub fn time_slice_ticks() -> usize {
+    SLICE_TICKS.load(Ordering::Relaxed)
+}
+
+/// Change the tick frequency, which takes effect from the next tick on
+pub fn set_tick_freq(freq: usize) {
+    TICK_FREQ.store(freq, Ordering::Relaxed);
+}
+
+/// Change the length of a time slice in ticks
+pub fn set_time_slice_ticks(ticks: usize) {
+    SLICE_TICKS.store(ticks, Ordering::Relaxed);
+}
+
 /// set the next timer interrupt
 pub fn set_next_trigger() {
-    set_timer(get_time() + CLOCK_FREQ / TICKS_PER_SEC);
+    set_timer(get_time() + CLOCK_FREQ / tick_freq());
+}
+
+/// Frequency of the `cycle` counter in Hz, measured at boot
+static CYCLE_FREQ: AtomicUsize = AtomicUsize::new(0);
+
+/// How the kernel sees the counters, as reported by `sys_counter_info`
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct CounterInfo {
+    /// Frequency of `time` in Hz
+    pub timebase_freq: usize,
+    /// Frequency of `cycle` in Hz, as calibrated against `time` at boot
+    pub cycle_freq: usize,
+    /// Timer interrupts per second
+    pub tick_freq: usize,
+    /// Whether `cycle` and `instret` read by the caller count only its own
+    /// execution
+    pub virtualized: usize,
+}
+
+/// Let user mode read `cycle`, `time` and `instret` directly, and
+/// calibrate the cycle counter against the timebase over 10ms
+pub fn init_counters() {
+    unsafe {
+        scounteren::set_cy();
+        scounteren::set_tm();
+        scounteren::set_ir();
+    }
+    let (time0, cycle0) = (time::read(), cycle::read());
+    while time::read() - time0 < CLOCK_FREQ / 100 {}
+    let (time1, cycle1) = (time::read(), cycle::read());
+    let freq = (cycle1 - cycle0) as u128 * CLOCK_FREQ as u128 / (time1 - time0) as u128;
+    CYCLE_FREQ.store(freq as usize, Ordering::Relaxed);
+}
+
+/// Get the counter information as seen by a task
+pub fn counter_info(virtualized: bool) -> CounterInfo {
+    CounterInfo {
+        timebase_freq: CLOCK_FREQ,
+        cycle_freq: CYCLE_FREQ.load(Ordering::Relaxed),
+        tick_freq: tick_freq(),
+        virtualized: virtualized as usize,
+    }
+}
+
+/// Make user reads of `cycle` and `instret` trap, so that they can be
+/// emulated with per-task values, or let them read the hardware again
+pub fn set_user_counters_virtual(virtualized: bool) {
+    unsafe {
+        if virtualized {
+            scounteren::clear_cy();
+            scounteren::clear_ir();
+        } else {
+            scounteren::set_cy();
+            scounteren::set_ir();
+        }
+    }
+}
+
+/// `cycle` and `instret` counted only while a task is on the CPU
+#[derive(Copy, Clone, Default)]
+pub struct TaskCounters {
+    cycles: usize,
+    instret: usize,
+    /// Counter values when the task was switched in
+    start: (usize, usize),
+}
+
+impl TaskCounters {
+    /// The task is switched in
+    pub fn start(&mut self) {
+        self.start = (cycle::read(), instret::read());
+    }
+    /// The task is switched out
+    pub fn stop(&mut self) {
+        let (cycles, instret) = self.read();
+        self.cycles = cycles;
+        self.instret = instret;
+    }
+    /// Read the counters of the running task
+    pub fn read(&self) -> (usize, usize) {
+        (
+            self.cycles + (cycle::read() - self.start.0),
+            self.instret + (instret::read() - self.start.1),
+        )
+    }
+}
+
+/// Layout of the time page, mapped read-only at `TIME_PAGE` in every user
+/// space so that time can be read without a syscall
+///
+/// `seq` is a sequence count: it is odd while the kernel updates the page,
+/// so readers retry when it is odd or has changed across their read.
+#[repr(C)]
+pub struct TimePage {
+    pub seq: AtomicUsize,
+    /// Timer ticks since boot
+    pub ticks: usize,
+    /// `mtime` at boot
+    pub boot_time: usize,
+    /// `mtime` at the last tick
+    pub tick_time: usize,
+    /// Frequency of `mtime` in Hz
+    pub timebase_freq: usize,
+}
+
+lazy_static! {
+    /// The frame shared by all user spaces as the time page
+    static ref TIME_PAGE_FRAME: FrameTracker = frame_alloc().unwrap();
+}
+
+/// Get the physical page of the time page
+pub fn time_page_ppn() -> PhysPageNum {
+    TIME_PAGE_FRAME.ppn
+}
+
+/// Fill in the time page at boot
+pub fn init_time_page() {
+    let page: &mut TimePage = TIME_PAGE_FRAME.ppn.get_mut();
+    let now = get_time();
+    page.boot_time = now;
+    page.tick_time = now;
+    page.timebase_freq = CLOCK_FREQ;
+}
+
+/// Count a timer tick in the time page
+///
+/// The timer interrupt is the only writer, so no lock is needed around the
+/// sequence count.
+pub fn update_time_page() {
+    let page: &mut TimePage = TIME_PAGE_FRAME.ppn.get_mut();
+    let seq = page.seq.load(Ordering::Relaxed);
+    page.seq.store(seq + 1, Ordering::Relaxed);
+    fence(Ordering::Release);
+    page.ticks += 1;
+    page.tick_time = get_time();
+    fence(Ordering::Release);
+    page.seq.store(seq + 2, Ordering::Relaxed);
 }
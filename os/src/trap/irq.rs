@@ -0,0 +1,42 @@
+//! Interrupt statistics per source
+
+use alloc::string::String;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Interrupt sources the kernel can tell apart
+///
+/// Only the timer interrupt is enabled, software and external interrupts
+/// are never taken: the console and the block device are polled.
+#[derive(Copy, Clone)]
+pub enum IrqSource {
+    /// Supervisor timer interrupt
+    Timer = 0,
+}
+
+/// Number of interrupt sources
+const IRQ_SOURCE_NUM: usize = 1;
+
+/// Names of interrupt sources shown in statistics
+const IRQ_SOURCE_NAMES: [&str; IRQ_SOURCE_NUM] = ["timer"];
+
+/// How many times each interrupt source has fired, counted without a
+/// lock as an interrupt may come while code that reads them holds one
+static IRQ_COUNTS: [AtomicUsize; IRQ_SOURCE_NUM] = [AtomicUsize::new(0)];
+
+/// Count an interrupt from the given source
+pub fn record_irq(source: IrqSource) {
+    IRQ_COUNTS[source as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Format interrupt statistics like `/proc/interrupts`
+///
+/// There is only a single hart, so all interrupts are reported on CPU0.
+pub fn irq_stats() -> String {
+    let mut stats = String::new();
+    writeln!(stats, "     {:>10}", "CPU0").unwrap();
+    for (irq, name) in IRQ_SOURCE_NAMES.iter().enumerate() {
+        writeln!(stats, "{:>3}: {:>10}   {}", irq, IRQ_COUNTS[irq].load(Ordering::Relaxed), name).unwrap();
+    }
+    stats
+}
@@ -11,30 +11,50 @@
 //! It then calls different functionality based on what exactly the exception
 //! was. For example, timer interrupts trigger task preemption, and syscalls go
 //! to [`syscall()`].
+//!
+//! The kernel handles user traps with interrupts enabled. Timer interrupts
+//! from S-mode go through `__alltraps_k` to [`trap_from_kernel()`], which
+//! preempts the kernel code unless it is in a critical section.
 
 mod context;
+mod irq;
 
-use crate::config::{TRAMPOLINE, TRAP_CONTEXT};
+use crate::config::{KERNEL_STACK_SIZE, PAGE_SIZE, PID_MAX, TRAMPOLINE, TRAP_CONTEXT};
+use crate::fs::tty_poll;
+use crate::mm::{PageTable, VirtAddr};
+use crate::sync::{preemptible, set_need_resched, take_need_resched, without_lockdep};
 use crate::syscall::syscall;
 use crate::task::{
-    current_trap_cx, current_user_token, exit_current_and_run_next, suspend_current_and_run_next,
+    current_enter_user, current_leave_user, current_task, dump_core, current_trap_cx, current_user_token,
+    exit_current_and_run_next, handle_current_page_fault, handle_signals, preempt_current_and_run_next, ptrace_syscall_stop,
+    tick_current_task, kernel_stack_pid,
 };
-use crate::timer::set_next_trigger;
+use crate::timer::{get_time, set_next_trigger, update_time_page};
+use irq::{record_irq, IrqSource};
 use riscv::register::{
     mtvec::TrapMode,
     scause::{self, Exception, Interrupt, Trap},
-    sie, stval, stvec,
+    sepc, sie, sstatus, stval, stvec,
 };
 
-core::arch::global_asm!(include_str!("trap.S"));
+core::arch::global_asm!(
+    include_str!("trap.S"),
+    trampoline = const TRAMPOLINE,
+    kernel_stack_size = const KERNEL_STACK_SIZE,
+    kernel_stack_stride = const KERNEL_STACK_SIZE + PAGE_SIZE,
+    kernel_stacks_size = const PID_MAX * (KERNEL_STACK_SIZE + PAGE_SIZE),
+);
 
 pub fn init() {
     set_kernel_trap_entry();
 }
 
 fn set_kernel_trap_entry() {
+    extern "C" {
+        fn __alltraps_k();
+    }
     unsafe {
-        stvec::write(trap_from_kernel as usize, TrapMode::Direct);
+        stvec::write(__alltraps_k as usize, TrapMode::Direct);
     }
 }
 
@@ -50,22 +70,88 @@ pub fn enable_timer_interrupt() {
     }
 }
 
+/// Emulate a user read of `cycle`, `time` or `instret` that trapped because
+/// the counters of current task are virtualized, returning whether the
+/// instruction was such a read
+fn emulate_counter_read() -> bool {
+    let cx = current_trap_cx();
+    // a 4-byte instruction may only start 2-byte aligned with the C extension
+    if cx.sepc % PAGE_SIZE > PAGE_SIZE - 4 {
+        return false;
+    }
+    let inst = match PageTable::from_token(current_user_token())
+        .translate_user_va(VirtAddr::from(cx.sepc))
+    {
+        Some(pa) => unsafe { (usize::from(pa) as *const u32).read_unaligned() },
+        None => return false,
+    };
+    // csrrs rd, csr, x0
+    if inst & 0x7f != 0x73 || (inst >> 12) & 0x7 != 0x2 || (inst >> 15) & 0x1f != 0 {
+        return false;
+    }
+    let (cycles, instret) = current_task().unwrap().inner_exclusive_access().counters.read();
+    let value = match inst >> 20 {
+        0xc00 => cycles,
+        0xc01 => get_time(),
+        0xc02 => instret,
+        _ => return false,
+    };
+    let rd = ((inst >> 7) & 0x1f) as usize;
+    if rd != 0 {
+        cx.x[rd] = value;
+    }
+    cx.sepc += 4;
+    true
+}
+
+/// Account a timer tick, returning whether current task has used up its
+/// time slice
+///
+/// In a critical section the console is not polled, as the tty is in a
+/// `UPSafeCell` the interrupted code may hold; its input waits for the next
+/// tick. The rest only takes locks that keep interrupts off while held.
+fn handle_timer_tick(critical: bool) -> bool {
+    record_irq(IrqSource::Timer);
+    update_time_page();
+    if !critical {
+        tty_poll();
+    }
+    set_next_trigger();
+    tick_current_task()
+}
+
 #[no_mangle]
 pub fn trap_handler() -> ! {
     set_kernel_trap_entry();
     let scause = scause::read();
     let stval = stval::read();
+    current_leave_user();
+    unsafe {
+        sstatus::set_sie();
+    }
     match scause.cause() {
         Trap::Exception(Exception::UserEnvCall) => {
             // jump to next instruction anyway
             let mut cx = current_trap_cx();
             cx.sepc += 4;
+            // a tracer may change the syscall and its arguments here
+            ptrace_syscall_stop();
             // get system call return value
             let result = syscall(cx.x[17], [cx.x[10], cx.x[11], cx.x[12], cx.x[13]]);
             // cx is changed during sys_exec, so we have to call it again
             cx = current_trap_cx();
             cx.x[10] = result as usize;
+            ptrace_syscall_stop();
         }
+        // a fault on a lazily allocated page or below the user stack may be
+        // resolved by mapping the page
+        Trap::Exception(Exception::StorePageFault)
+        | Trap::Exception(Exception::LoadPageFault)
+        | Trap::Exception(Exception::InstructionPageFault)
+            if handle_current_page_fault(
+                stval,
+                scause.cause() == Trap::Exception(Exception::StorePageFault),
+            ) => {}
         Trap::Exception(Exception::StoreFault)
         | Trap::Exception(Exception::StorePageFault)
         | Trap::Exception(Exception::InstructionFault)
@@ -73,22 +159,29 @@ pub fn trap_handler() -> ! {
         | Trap::Exception(Exception::LoadFault)
         | Trap::Exception(Exception::LoadPageFault) => {
             println!(
-                "[kernel] {:?} in application, bad addr = {:#x}, bad instruction = {:#x}, core dumped.",
+                "[kernel] {:?} in application, bad addr = {:#x}, bad instruction = {:#x}{}.",
                 scause.cause(),
                 stval,
                 current_trap_cx().sepc,
+                if dump_core(scause.bits(), stval) { ", core dumped" } else { "" },
             );
             // page fault exit code
             exit_current_and_run_next(-2);
         }
         Trap::Exception(Exception::IllegalInstruction) => {
-            println!("[kernel] IllegalInstruction in application, core dumped.");
-            // illegal instruction exit code
-            exit_current_and_run_next(-3);
+            if !emulate_counter_read() {
+                println!(
+                    "[kernel] IllegalInstruction in application{}.",
+                    if dump_core(scause.bits(), stval) { ", core dumped" } else { "" },
+                );
+                // illegal instruction exit code
+                exit_current_and_run_next(-3);
+            }
         }
         Trap::Interrupt(Interrupt::SupervisorTimer) => {
-            set_next_trigger();
-            suspend_current_and_run_next();
+            if handle_timer_tick(false) {
+                preempt_current_and_run_next();
+            }
         }
         _ => {
             panic!(
@@ -98,12 +191,22 @@ pub fn trap_handler() -> ! {
             );
         }
     }
+    // the time slice may have run out in a critical section
+    if take_need_resched() {
+        preempt_current_and_run_next();
+    }
+    handle_signals();
     trap_return();
 }
 
 #[no_mangle]
 pub fn trap_return() -> ! {
+    // a kernel trap must not come through the user trap entry
+    unsafe {
+        sstatus::clear_sie();
+    }
     set_user_trap_entry();
+    current_enter_user();
     let trap_cx_ptr = TRAP_CONTEXT;
     let user_satp = current_user_token();
     extern "C" {
@@ -123,9 +226,51 @@ pub fn trap_return() -> ! {
     }
 }
 
+/// Handle a trap from S-mode, returning to the interrupted code through
+/// `__restore_k`
+///
+/// Interrupted code in a critical section may hold a `UPSafeCell`, a
+/// `TicketLock` or the locks under a `PreemptGuard`, so then the tick is
+/// accounted but the switch is left to the next safe point: the end of the
+/// critical section, the next tick outside one or the return to user mode.
+#[no_mangle]
+pub fn trap_from_kernel() {
+    match scause::read().cause() {
+        Trap::Interrupt(Interrupt::SupervisorTimer) => {
+            if !preemptible() {
+                // its locks are not taken inside those of the interrupted code
+                if without_lockdep(|| handle_timer_tick(true)) {
+                    set_need_resched();
+                }
+            } else if (handle_timer_tick(false) | take_need_resched()) && current_task().is_some() {
+                preempt_current_and_run_next();
+            }
+        }
+        cause => {
+            #[cfg(feature = "heap-poison")]
+            if crate::mm::poisoned_address(stval::read()) {
+                panic!(
+                    "a trap {:?} from kernel, stval = {:#x}, following a pointer read from freed heap memory!",
+                    cause,
+                    stval::read()
+                );
+            }
+            panic!("a trap {:?} from kernel, stval = {:#x}!", cause, stval::read())
+        }
+    }
+}
+
+/// Report a kernel stack overflow, called by `__alltraps_k` on a stack of
+/// its own with the stack pointer found in a guard page
 #[no_mangle]
-pub fn trap_from_kernel() -> ! {
-    panic!("a trap {:?} from kernel!", scause::read().cause());
+pub fn kernel_stack_overflow(sp: usize) -> ! {
+    panic!(
+        "kernel stack of pid {} overflowed, sp = {:#x}, sepc = {:#x}",
+        kernel_stack_pid(sp),
+        sp,
+        sepc::read()
+    );
 }
 
 pub use context::TrapContext;
+pub use irq::irq_stats;